@@ -1,12 +1,28 @@
-use std::path::PathBuf;
+use std::{
+    fs::File,
+    io::{BufWriter, Read, Write},
+    path::PathBuf,
+};
 
-use clap::{Parser, Subcommand};
+use armul::{
+    assemble::{AssemblerError, AssemblerOptions, HealStrategy},
+    processor::{Cycle, PollOutcome, ProcessorBuilder, ProcessorListener},
+    profile::{CycleCounter, ProfilingListener},
+    semihosting::SemihostingHandler,
+};
+use clap::{Parser, Subcommand, ValueEnum};
 
 #[derive(Parser)]
 #[command(version, about)]
 struct Cli {
+    /// Print the capabilities of this build and exit
+    #[arg(long)]
+    capabilities: bool,
+    /// Print machine-readable JSON output
+    #[arg(long, requires = "capabilities")]
+    json: bool,
     #[command(subcommand)]
-    command: Command,
+    command: Option<Command>,
 }
 
 #[derive(Subcommand)]
@@ -15,24 +31,245 @@ enum Command {
     Assemble {
         /// `.s` file to assemble
         file: PathBuf,
+        /// Write the assembled program to this file
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// Format of the output file
+        #[arg(long, value_enum, default_value_t = Format::Bin, requires = "output")]
+        format: Format,
+        /// Write the value of each symbol to this file
+        #[arg(long)]
+        symbols: Option<PathBuf>,
+        /// Treat warnings as errors
+        #[arg(long)]
+        deny_warnings: bool,
+        /// How to deal with unencodable operands: off, simple or advanced:<register>
+        #[arg(long)]
+        heal: Option<HealStrategy>,
+        /// Define a symbol for `IF` conditions, as NAME=VALUE or just NAME for 1
+        #[arg(long = "define", value_name = "NAME=VALUE", value_parser = parse_define)]
+        defines: Vec<(String, u32)>,
     },
+    #[command(about = "Assemble and run a `.s` file, using the terminal for input and output")]
+    Run {
+        /// `.s` file to run
+        file: PathBuf,
+        /// Stop after executing this many instructions
+        #[arg(long, default_value_t = 100_000_000)]
+        max_steps: usize,
+        /// Print the cycles taken by the most expensive instructions at exit
+        #[arg(long)]
+        profile: bool,
+        /// Handle semihosting calls made with `SWI 0x123456`
+        #[arg(long)]
+        semihosting: bool,
+        /// How to deal with unencodable operands: off, simple or advanced:<register>
+        #[arg(long)]
+        heal: Option<HealStrategy>,
+        /// Define a symbol for `IF` conditions, as NAME=VALUE or just NAME for 1
+        #[arg(long = "define", value_name = "NAME=VALUE", value_parser = parse_define)]
+        defines: Vec<(String, u32)>,
+    },
+    #[command(about = "List the instructions that the assembler recognises")]
+    Isa,
+}
+
+/// Reads input from stdin and writes output to stdout.
+struct TerminalListener;
+
+impl ProcessorListener for TerminalListener {
+    fn cycle(&mut self, _cycle: Cycle, _count: usize, _pc: u32) {}
+
+    fn pipeline_flush(&mut self, _pc: u32) {}
+
+    fn getc(&mut self) -> Option<char> {
+        std::io::stdout().flush().ok()?;
+        let mut byte = [0];
+        match std::io::stdin().read(&mut byte) {
+            Ok(1) => Some(byte[0] as char),
+            _ => None,
+        }
+    }
+
+    fn putc(&mut self, c: char) {
+        print!("{c}");
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Format {
+    /// Little-endian 32-bit words
+    Bin,
+    /// One 8-digit hexadecimal word per line
+    Hex,
+    /// Intel HEX records, for tools that program real hardware
+    Ihex,
+    /// A listing with addresses and disassembly
+    Words,
+}
+
+/// Parse a `--define` argument; the value may be decimal or `0x` hexadecimal.
+fn parse_define(s: &str) -> Result<(String, u32), String> {
+    let (name, value) = s.split_once('=').unwrap_or((s, "1"));
+    let value = match value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => value.parse(),
+    }
+    .map_err(|err| format!("invalid value '{value}': {err}"))?;
+    Ok((name.to_owned(), value))
+}
+
+/// Join assembler errors into one error, with one line for each.
+fn assembler_errors(errs: Vec<AssemblerError>) -> anyhow::Error {
+    anyhow::anyhow!(
+        "{}",
+        errs.into_iter()
+            .map(|err| format!("line {}: {}", err.line_number, err.error))
+            .collect::<Vec<_>>()
+            .join("\n")
+    )
 }
 
 fn main() -> anyhow::Result<()> {
     let cli = Cli::parse();
+    if cli.capabilities {
+        let capabilities = armul::capabilities();
+        if cli.json {
+            println!("{}", serde_json::to_string_pretty(&capabilities)?);
+        } else {
+            println!("version: {}", capabilities.version);
+            println!("thumb: {}", capabilities.thumb);
+            println!(
+                "heal strategies: {}",
+                capabilities.heal_strategies.join(", ")
+            );
+            println!(
+                "swi services: {}",
+                capabilities
+                    .swi_services
+                    .iter()
+                    .map(|x| x.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+            println!("device mapping: {}", capabilities.device_mapping);
+            println!("semihosting: {}", capabilities.semihosting);
+        }
+        return Ok(());
+    }
     match cli.command {
-        Command::Assemble { file } => {
-            let output = armul::assemble::assemble(&std::fs::read_to_string(file)?).map_err(|errs| {
-                anyhow::anyhow!(
-                    "{}",
-                    errs.into_iter()
-                        .map(|err| format!("line {}: {}", err.line_number, err.error))
-                        .collect::<Vec<_>>()
-                        .join("\n")
-                )
-            })?;
+        Some(Command::Assemble {
+            file,
+            output: output_path,
+            format,
+            symbols,
+            deny_warnings,
+            heal,
+            defines,
+        }) => {
+            let output = armul::assemble::assemble_with_options(
+                &std::fs::read_to_string(file)?,
+                &AssemblerOptions {
+                    deny_warnings,
+                    heal,
+                    defines: defines.into_iter().collect(),
+                    ..Default::default()
+                },
+            )
+            .map_err(assembler_errors)?;
             println!("Assembled in {} passes.", output.passes);
+            for warning in &output.warnings {
+                eprintln!("warning: line {}: {}", warning.line_number, warning.warning);
+            }
+            for (line_number, count) in output.healed_lines() {
+                eprintln!("note: line {line_number}: healed with {count} extra instructions");
+            }
+            if let Some(path) = output_path {
+                let mut w = BufWriter::new(File::create(path)?);
+                match format {
+                    Format::Bin => armul::assemble::output::write_binary(&output, &mut w)?,
+                    Format::Hex => armul::assemble::output::write_hex(&output, &mut w)?,
+                    Format::Ihex => armul::assemble::output::write_intel_hex(&output, &mut w)?,
+                    Format::Words => armul::assemble::output::write_words(&output, &mut w)?,
+                }
+            }
+            if let Some(path) = symbols {
+                armul::assemble::output::write_symbols(
+                    &output,
+                    &mut BufWriter::new(File::create(path)?),
+                )?;
+            }
+            Ok(())
+        }
+        Some(Command::Run {
+            file,
+            max_steps,
+            profile,
+            semihosting,
+            heal,
+            defines,
+        }) => {
+            let output = armul::assemble::assemble_with_options(
+                &std::fs::read_to_string(file)?,
+                &AssemblerOptions {
+                    heal,
+                    defines: defines.into_iter().collect(),
+                    ..Default::default()
+                },
+            )
+            .map_err(assembler_errors)?;
+            let mut proc = ProcessorBuilder::default()
+                .load_words(output.origin, &output.instrs)
+                .pc(output.origin)
+                .build()
+                .map_err(|err| anyhow::anyhow!("{err}"))?;
+            if semihosting {
+                proc.set_semihosting(Some(SemihostingHandler::default()));
+            }
+            let mut counter = CycleCounter::default();
+            let mut listener = ProfilingListener {
+                listener: &mut TerminalListener,
+                counter: &mut counter,
+            };
+            let mut halted = false;
+            let mut error = None;
+            for _ in 0..max_steps {
+                let pc = proc.registers().pc();
+                match proc.poll(&mut listener) {
+                    Ok(PollOutcome::Running) => {}
+                    Ok(PollOutcome::Halted | PollOutcome::AlreadyHalted) => {
+                        halted = true;
+                        break;
+                    }
+                    Err(err) => {
+                        error = Some(anyhow::anyhow!("at 0x{pc:08X}: {err}"));
+                        break;
+                    }
+                }
+            }
+            std::io::stdout().flush()?;
+            if profile {
+                eprintln!();
+                eprint!("{counter}");
+            }
+            if let Some(err) = error {
+                return Err(err);
+            }
+            if !halted {
+                return Err(anyhow::anyhow!("stopped after {max_steps} steps"));
+            }
+            Ok(())
+        }
+        Some(Command::Isa) => {
+            for spec in armul::assemble::mnemonics() {
+                let line = format!("{:<16} {}", spec.template(), spec.syntax());
+                println!("{}", line.trim_end());
+            }
             Ok(())
         }
+        None => Err(anyhow::anyhow!("no command given; see --help")),
     }
 }
@@ -0,0 +1,37 @@
+//! Measures how long it takes to assemble a large, label-heavy program.
+//! Run with `cargo bench -p armul`.
+
+use std::{fmt::Write, time::Instant};
+
+const LABELS: usize = 20_000;
+const RUNS: u32 = 5;
+
+fn main() {
+    let src = label_heavy_source();
+    // Warm up once so that the first run isn't penalised.
+    armul::assemble::assemble(&src).unwrap();
+    let start = Instant::now();
+    for _ in 0..RUNS {
+        armul::assemble::assemble(&src).unwrap();
+    }
+    println!(
+        "assembled {LABELS} labels in {:?} per run",
+        start.elapsed() / RUNS
+    );
+}
+
+/// Generate a program in which every line defines a label and refers to another one.
+fn label_heavy_source() -> String {
+    let mut src = String::new();
+    for i in 0..LABELS {
+        let target = (i * 7919) % LABELS;
+        writeln!(src, "label_with_a_long_name_{i} cmp r0, #{}", i % 256).unwrap();
+        writeln!(src, "    beq label_with_a_long_name_{target}").unwrap();
+        writeln!(
+            src,
+            "const_with_a_long_name_{i} equ label_with_a_long_name_{target} + 4"
+        )
+        .unwrap();
+    }
+    src
+}
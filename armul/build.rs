@@ -13,7 +13,10 @@ fn main() {
     let dest_path = Path::new(&out_dir).join("tests.rs");
     let mut file = BufWriter::new(File::create(dest_path).unwrap());
 
-    traverse(&mut file, &PathBuf::from("test"));
+    // Tests are given absolute paths, so that files they refer to can be found
+    // wherever `cargo test` is run from.
+    let manifest_dir = PathBuf::from(std::env::var_os("CARGO_MANIFEST_DIR").unwrap());
+    traverse(&mut file, &manifest_dir.join("test"));
 
     file.flush().unwrap();
 }
@@ -36,8 +39,9 @@ fn traverse(file: &mut impl std::io::Write, path: &Path) {
                 entry.path().file_stem().unwrap().to_string_lossy()
             )
             .unwrap();
-            writeln!(file, "let src = std::fs::read_to_string({:?}).map_err(|x| crate::test::TestError::FileError(x.to_string()))?;", entry.path()).unwrap();
-            writeln!(file, "crate::test::test(&src)").unwrap();
+            writeln!(file, "let path = std::path::Path::new({:?});", entry.path()).unwrap();
+            writeln!(file, "let src = std::fs::read_to_string(path).map_err(|x| crate::test::TestError::FileError(x.to_string()))?;").unwrap();
+            writeln!(file, "crate::test::test(&src, path.parent().unwrap())").unwrap();
             writeln!(file, "}}").unwrap();
             writeln!(file).unwrap();
         }
@@ -0,0 +1,187 @@
+//! Distinguishes addresses from the words stored at them.
+//!
+//! Both types serialize as plain numbers, and convert to and from `u32` with [`From`].
+//! [`Addr`] is only used for real addresses, so symbol values, which may be any constant,
+//! and the raw values of registers and memory stay as `u32`.
+
+use std::{
+    fmt::{Display, LowerHex, UpperHex},
+    ops::{Add, AddAssign, Sub},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// A byte address in the 32-bit address space.
+/// Arithmetic on addresses wraps around the end of memory.
+#[derive(
+    Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(transparent)]
+pub struct Addr(pub u32);
+
+impl Addr {
+    /// Returns true if this address is 4-byte aligned.
+    pub fn is_word_aligned(self) -> bool {
+        self.0 & 0b11 == 0
+    }
+
+    /// The address of the word containing this byte.
+    pub fn align_down(self) -> Addr {
+        Addr(self.0 & !0b11)
+    }
+
+    pub fn wrapping_add_signed(self, offset: i32) -> Addr {
+        Addr(self.0.wrapping_add_signed(offset))
+    }
+}
+
+impl Add<u32> for Addr {
+    type Output = Addr;
+
+    fn add(self, rhs: u32) -> Self::Output {
+        Addr(self.0.wrapping_add(rhs))
+    }
+}
+
+impl AddAssign<u32> for Addr {
+    fn add_assign(&mut self, rhs: u32) {
+        *self = *self + rhs;
+    }
+}
+
+impl Sub<u32> for Addr {
+    type Output = Addr;
+
+    fn sub(self, rhs: u32) -> Self::Output {
+        Addr(self.0.wrapping_sub(rhs))
+    }
+}
+
+/// The number of bytes from `rhs` up to `self`.
+impl Sub<Addr> for Addr {
+    type Output = u32;
+
+    fn sub(self, rhs: Addr) -> Self::Output {
+        self.0.wrapping_sub(rhs.0)
+    }
+}
+
+impl Display for Addr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "0x{:08X}", self.0)
+    }
+}
+
+impl UpperHex for Addr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        UpperHex::fmt(&self.0, f)
+    }
+}
+
+impl LowerHex for Addr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        LowerHex::fmt(&self.0, f)
+    }
+}
+
+impl From<u32> for Addr {
+    fn from(value: u32) -> Self {
+        Addr(value)
+    }
+}
+
+impl From<Addr> for u32 {
+    fn from(value: Addr) -> Self {
+        value.0
+    }
+}
+
+/// A 32-bit value as stored in memory, such as an encoded instruction.
+/// Arithmetic on words wraps, as it does in the processor.
+#[derive(
+    Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(transparent)]
+pub struct Word(pub u32);
+
+impl Add<u32> for Word {
+    type Output = Word;
+
+    fn add(self, rhs: u32) -> Self::Output {
+        Word(self.0.wrapping_add(rhs))
+    }
+}
+
+impl AddAssign<u32> for Word {
+    fn add_assign(&mut self, rhs: u32) {
+        *self = *self + rhs;
+    }
+}
+
+impl Sub<u32> for Word {
+    type Output = Word;
+
+    fn sub(self, rhs: u32) -> Self::Output {
+        Word(self.0.wrapping_sub(rhs))
+    }
+}
+
+impl Display for Word {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "0x{:08X}", self.0)
+    }
+}
+
+impl UpperHex for Word {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        UpperHex::fmt(&self.0, f)
+    }
+}
+
+impl LowerHex for Word {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        LowerHex::fmt(&self.0, f)
+    }
+}
+
+impl From<u32> for Word {
+    fn from(value: u32) -> Self {
+        Word(value)
+    }
+}
+
+impl From<Word> for u32 {
+    fn from(value: Word) -> Self {
+        value.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Addr, Word};
+
+    #[test]
+    fn arithmetic_wraps() {
+        assert_eq!(Addr(0xFFFFFFFC) + 8, Addr(4));
+        assert_eq!(Addr(4) - 8, Addr(0xFFFFFFFC));
+        assert_eq!(Addr(0x108) - Addr(0x100), 8);
+        assert_eq!(Addr(0x103).align_down(), Addr(0x100));
+        assert_eq!(Addr(0x100).wrapping_add_signed(-4), Addr(0xFC));
+        assert_eq!(Word(0xFFFFFFFF) + 2, Word(1));
+        assert_eq!(Word(0) - 1, Word(0xFFFFFFFF));
+    }
+
+    #[test]
+    fn display_in_hex() {
+        assert_eq!(Addr(0x100).to_string(), "0x00000100");
+        assert_eq!(Word(0xE3A00001).to_string(), "0xE3A00001");
+    }
+
+    #[test]
+    fn serde_as_plain_number() {
+        assert_eq!(serde_json::to_string(&Addr(256)).unwrap(), "256");
+        assert_eq!(serde_json::to_string(&Word(1)).unwrap(), "1");
+        assert_eq!(serde_json::from_str::<Addr>("256").unwrap(), Addr(256));
+        assert_eq!(serde_json::from_str::<Word>("1").unwrap(), Word(1));
+    }
+}
@@ -1,85 +1,359 @@
 //! Assembles parsed assembly into real 32-bit instructions.
 
-use std::collections::{BTreeMap, btree_map::Entry};
+use std::{
+    collections::{BTreeMap, BTreeSet, btree_map::Entry},
+    fmt::Display,
+    str::FromStr,
+};
+
+use serde::Serialize;
 
 use crate::{
     assemble::{
-        AssemblerError, AssemblerOutput, LineError,
-        syntax::{self, AnyTransferSize, AsmInstr, AsmLine, AsmLineContents, Expression},
+        AssemblerError, AssemblerOptions, AssemblerOutput, AssemblerWarning, LineError,
+        LineWarning, MAX_PADDING, SourceSpan, parser,
+        symbol::{SymbolId, SymbolTable, SymbolValues},
+        syntax::{self, AsmInstr, AsmLine, AsmLineContents, Expression},
     },
     instr::{
-        self, DataOp, Instr, Register, RotatedConstant, Shift, SpecialOperand, TransferKind,
-        TransferSizeSpecial,
+        self, Cond, DataOp, Instr, Register, RotatedConstant, Shift, TransferKind, TransferSize,
     },
 };
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// How the assembler should deal with operands that cannot be encoded directly.
+/// This can be parsed from `off`, `simple` or `advanced:<register>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
 pub enum HealStrategy {
     Off,
     Simple,
     /// An advanced healing strategy that lets us use a dummy register.
+    /// This must be one of R0 to R12, since overwriting SP, LR or PC would break the program.
     Advanced(Register),
 }
 
+impl HealStrategy {
+    /// Every kind of strategy, with an arbitrary register for the advanced strategy.
+    pub const ALL: [HealStrategy; 3] = [
+        HealStrategy::Off,
+        HealStrategy::Simple,
+        HealStrategy::Advanced(Register::R12),
+    ];
+
+    /// The name of this kind of strategy, without its register.
+    pub fn kind(self) -> &'static str {
+        match self {
+            HealStrategy::Off => "off",
+            HealStrategy::Simple => "simple",
+            HealStrategy::Advanced(_) => "advanced",
+        }
+    }
+}
+
+impl Display for HealStrategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HealStrategy::Off | HealStrategy::Simple => write!(f, "{}", self.kind()),
+            HealStrategy::Advanced(register) => write!(f, "{}:{register}", self.kind()),
+        }
+    }
+}
+
+impl FromStr for HealStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let lower = s.to_lowercase();
+        match lower.split_once(':') {
+            None if lower == "off" => Ok(HealStrategy::Off),
+            None if lower == "simple" => Ok(HealStrategy::Simple),
+            Some(("advanced", register)) => match parser::disambiguate_register(register) {
+                Some(Register::R13 | Register::R14 | Register::R15) => Err(format!(
+                    "register '{register}' cannot be used for healing; use one of r0 to r12"
+                )),
+                Some(register) => Ok(HealStrategy::Advanced(register)),
+                None => Err(format!("unknown register '{register}'")),
+            },
+            _ => Err(format!(
+                "unknown heal strategy '{s}'; expected off, simple or advanced:<register>"
+            )),
+        }
+    }
+}
+
+/// Labels that conventionally mark where a program starts,
+/// so are not reported as unused even if nothing refers to them.
+const ENTRY_LABELS: [&str; 2] = ["main", "start"];
+
+/// The information needed to lower an [`AsmInstr`] into real instructions.
+#[derive(Debug, Clone, Copy)]
+pub struct LowerContext<'a> {
+    /// The address that the first lowered instruction will be placed at.
+    pub program_counter: u32,
+    pub heal: HealStrategy,
+    /// The names of the labels referred to by the instruction.
+    pub symbols: &'a SymbolTable,
+    /// The current values of the labels referred to by the instruction.
+    pub labels: &'a SymbolValues,
+}
+
+impl LowerContext<'_> {
+    fn label(&self, label: SymbolId) -> Result<u32, LineError> {
+        self.labels
+            .get(label)
+            .ok_or_else(|| LineError::LabelNotFound(self.symbols.resolve(label).to_owned()))
+    }
+}
+
 pub fn assemble(
-    lines: Vec<AsmLine>,
+    lines: &[AsmLine],
+    symbols: &SymbolTable,
     heal: HealStrategy,
-) -> Result<AssemblerOutput, AssemblerError> {
+    options: &AssemblerOptions,
+) -> Result<AssemblerOutput, Vec<AssemblerError>> {
     // Create a mapping of labels to their absolute addresses.
     // For the moment let's just say that every label is mapped to 0.
-    let labels = lines
-        .iter()
-        .filter_map(|line| match &line.contents {
-            AsmLineContents::Label(label) => Some(label),
-            AsmLineContents::Equ(label, _) => Some(label),
-            _ => None,
-        })
-        .map(|x| (x.to_owned(), 0))
-        .collect::<BTreeMap<String, u32>>();
+    let mut labels = SymbolValues::default();
+    for line in lines {
+        match &line.contents {
+            AsmLineContents::Label(label)
+            | AsmLineContents::Equ(label, _)
+            | AsmLineContents::Instr(
+                _,
+                AsmInstr::LoadLiteral {
+                    pool_label: label, ..
+                },
+            ) => {
+                labels.set(*label, 0);
+            }
+            _ => {}
+        }
+    }
+    for (name, value) in &options.defines {
+        if let Some(symbol) = symbols.get(name) {
+            labels.set(symbol, *value);
+        }
+    }
+    let errors = check_conditionals(lines);
+    if !errors.is_empty() {
+        return Err(errors);
+    }
     // Attempt to assemble the input given this mapping of labels.
     // Repeat using the updated mapping of labels until the mapping doesn't change.
     // This approach allows for a certain amount of dynamic error correction
     // to be done on users' code, for example allowing out-of-range values
     // by adding extra instructions to construct them.
     let mut output = AssemblerOutput {
-        labels,
+        labels: BTreeMap::new(),
+        constants: BTreeSet::new(),
+        origin: 0,
         instrs: Vec::new(),
         warnings: Vec::new(),
         passes: 0,
         comments: BTreeMap::new(),
+        source_map: Vec::new(),
     };
+    let mut errors = Vec::new();
     let mut i = 0;
     loop {
         output.instrs.clear();
         output.warnings.clear();
         output.comments.clear();
+        output.source_map.clear();
+        errors.clear();
         output.passes += 1;
-        if !single_pass(&lines, heal, &mut output)? {
+        if !single_pass(
+            lines,
+            symbols,
+            heal,
+            options,
+            &mut labels,
+            &mut output,
+            &mut errors,
+        ) {
             break;
         }
         i += 1;
         if i > 10 {
-            return Err(AssemblerError {
+            return Err(vec![AssemblerError {
                 line_number: 0,
                 error: LineError::TooManyPasses,
+            }]);
+        }
+    }
+    // Only the errors from the last pass are reported,
+    // since earlier passes may have used addresses that were out of date.
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    let referenced = referenced_labels(lines);
+    for line in lines {
+        if let AsmLineContents::Label(label) = line.contents
+            && !referenced.contains(&label)
+            && !parser::is_generated_label(symbols.resolve(label))
+            && !parser::is_local_label(symbols.resolve(label))
+            && !ENTRY_LABELS.contains(&symbols.resolve(label))
+        {
+            output.warnings.push(AssemblerWarning {
+                line_number: line.line_number,
+                warning: LineWarning::UnusedLabel(symbols.resolve(label).to_owned()),
             });
         }
     }
+    output.warnings.sort_by_key(|warning| warning.line_number);
+    if options.deny_warnings && !output.warnings.is_empty() {
+        return Err(output
+            .warnings
+            .into_iter()
+            .map(|warning| AssemblerError {
+                line_number: warning.line_number,
+                error: LineError::DeniedWarning(warning.warning),
+            })
+            .collect());
+    }
+    output.labels = labels
+        .iter()
+        .map(|(label, value)| (symbols.resolve(label).to_owned(), value))
+        .collect();
+    output.constants = lines
+        .iter()
+        .filter_map(|line| match line.contents {
+            AsmLineContents::Equ(name, _) => Some(symbols.resolve(name)),
+            _ => None,
+        })
+        .chain(options.defines.keys().map(String::as_str))
+        .filter(|name| output.labels.contains_key(*name))
+        .map(str::to_owned)
+        .collect();
     Ok(output)
 }
 
+/// Check that every `IF` has a matching `ENDIF`, with at most one `ELSE` between them.
+/// An `IF` without an `ENDIF` is reported on the line of the `IF`.
+fn check_conditionals(lines: &[AsmLine]) -> Vec<AssemblerError> {
+    let mut errors = Vec::new();
+    // The line of each open `IF`, and whether it has had an `ELSE`.
+    let mut open = Vec::<(&AsmLine, bool)>::new();
+    for line in lines {
+        let error = match &line.contents {
+            AsmLineContents::If(_) => {
+                open.push((line, false));
+                continue;
+            }
+            AsmLineContents::Else => match open.last_mut() {
+                Some((_, seen_else @ false)) => {
+                    *seen_else = true;
+                    continue;
+                }
+                _ => LineError::UnmatchedElse,
+            },
+            AsmLineContents::EndIf => match open.pop() {
+                Some(_) => continue,
+                None => LineError::UnmatchedEndIf,
+            },
+            _ => continue,
+        };
+        errors.push(AssemblerError {
+            line_number: line.line_number,
+            error: error.in_macro(line.macro_line.as_ref()),
+        });
+    }
+    errors.extend(open.into_iter().map(|(line, _)| AssemblerError {
+        line_number: line.line_number,
+        error: LineError::UnclosedIf.in_macro(line.macro_line.as_ref()),
+    }));
+    errors.sort_by_key(|error| error.line_number);
+    errors
+}
+
 /// Returns true if anything in the assembler's output changed
 /// since last pass.
+/// Errors are pushed to `errors`, and the pass carries on with the next line,
+/// so that every error can be reported at once.
 fn single_pass(
     lines: &[AsmLine],
+    symbols: &SymbolTable,
     heal: HealStrategy,
+    options: &AssemblerOptions,
+    labels: &mut SymbolValues,
     output: &mut AssemblerOutput,
-) -> Result<bool, AssemblerError> {
-    let mut program_counter = 0u32;
-    let mut anything_changed = false;
+    errors: &mut Vec<AssemblerError>,
+) -> bool {
+    output.origin = 0;
+    let mut state = PassState {
+        program_counter: 0,
+        anything_changed: false,
+        pending_labels: Vec::new(),
+        literals: Vec::new(),
+        synthesized: 0,
+        conditions: Vec::new(),
+        defined: BTreeSet::new(),
+        skipped: BTreeSet::new(),
+    };
+    for name in options.defines.keys() {
+        state.defined.extend(symbols.get(name));
+    }
     for line in lines {
+        let skipping = !state.assembling();
+        match &line.contents {
+            AsmLineContents::If(expression) => {
+                let condition = if skipping {
+                    Condition::Inactive
+                } else {
+                    match state.evaluate_condition(expression, symbols, heal, labels) {
+                        Ok(true) => Condition::Assembling,
+                        Ok(false) => Condition::Skipping,
+                        Err(error) => {
+                            errors.push(AssemblerError {
+                                line_number: line.line_number,
+                                error: error.in_macro(line.macro_line.as_ref()),
+                            });
+                            Condition::Skipping
+                        }
+                    }
+                };
+                state.conditions.push(condition);
+                continue;
+            }
+            AsmLineContents::Else => {
+                if let Some(condition) = state.conditions.last_mut() {
+                    *condition = match condition {
+                        Condition::Assembling => Condition::Skipping,
+                        Condition::Skipping => Condition::Assembling,
+                        Condition::Inactive => Condition::Inactive,
+                    };
+                }
+                continue;
+            }
+            AsmLineContents::EndIf => {
+                state.conditions.pop();
+                continue;
+            }
+            // Labels in skipped lines are not defined at all.
+            AsmLineContents::Label(label) | AsmLineContents::Equ(label, _) if skipping => {
+                state.skipped.insert(*label);
+                continue;
+            }
+            _ if skipping => continue,
+            _ => {}
+        }
+        if matches!(
+            line.contents,
+            AsmLineContents::Instr(..) | AsmLineContents::DefWord(_) | AsmLineContents::Ltorg
+        ) {
+            // The rest of any partially filled word of bytes is left as zero.
+            state.program_counter = state.program_counter.next_multiple_of(4);
+        }
+        if matches!(
+            line.contents,
+            AsmLineContents::Instr(..)
+                | AsmLineContents::DefWord(_)
+                | AsmLineContents::DefByte(_)
+                | AsmLineContents::Ltorg
+        ) {
+            state.place_pending_labels(labels);
+        }
         if !line.comment.is_empty() {
-            match output.comments.entry(program_counter) {
+            match output.comments.entry(state.program_counter) {
                 Entry::Vacant(vacant_entry) => {
                     vacant_entry.insert(line.comment.clone());
                 }
@@ -89,352 +363,781 @@ fn single_pass(
                 }
             }
         }
-        match &line.contents {
-            AsmLineContents::Empty => {}
-            AsmLineContents::Label(label) => {
-                let entry = output.labels.entry(label.to_owned()).or_default();
-                if *entry != program_counter {
-                    anything_changed = true;
-                    *entry = program_counter;
-                }
-            }
-            AsmLineContents::Instr(cond, asm_instr) => {
-                let instrs =
-                    assemble_instr(line.line_number, heal, program_counter, asm_instr, output)?;
-                program_counter += 4 * instrs.len() as u32;
-                output.instrs.extend(
-                    instrs
-                        .into_iter()
-                        .map(|i| i.encode(*cond))
-                        .collect::<Result<Vec<u32>, LineError>>()
-                        .map_err(|error| AssemblerError {
-                            line_number: line.line_number,
-                            error,
-                        })?,
-                );
-            }
-            AsmLineContents::Equ(name, expression) => {
-                let value = expression.evaluate(line.line_number, output)?;
-                let entry = output.labels.entry(name.to_owned()).or_default();
-                if *entry != value {
-                    anything_changed = true;
-                    *entry = value;
-                }
-            }
-            AsmLineContents::DefWord(expression) => {
-                let value = expression.evaluate(line.line_number, output)?;
-                program_counter += 4;
-                output.instrs.push(value);
+        let (address, first_word) = (state.program_counter, output.instrs.len());
+        state.synthesized = 0;
+        if let Err(error) = assemble_line(line, symbols, heal, options, labels, output, &mut state)
+        {
+            errors.push(AssemblerError {
+                line_number: line.line_number,
+                error: error.in_macro(line.macro_line.as_ref()),
+            });
+            // Assume that a bad instruction or word would have taken up one word,
+            // so that the addresses of later labels are still close to right.
+            if matches!(
+                line.contents,
+                AsmLineContents::Instr(..) | AsmLineContents::DefWord(_)
+            ) && output.instrs.len() == first_word
+            {
+                output.instrs.push(0);
+                state.program_counter += 4;
             }
         }
+        output
+            .source_map
+            .extend((0..output.instrs.len() - first_word).map(|i| SourceSpan {
+                // New words start after any partially filled word.
+                address: address.next_multiple_of(4) + 4 * i as u32,
+                line_number: line.line_number,
+                data: matches!(
+                    line.contents,
+                    AsmLineContents::DefWord(_)
+                        | AsmLineContents::DefByte(_)
+                        | AsmLineContents::Align(_)
+                        | AsmLineContents::Org(_)
+                        | AsmLineContents::Ltorg
+                ),
+                synthesized: i < state.synthesized,
+            }));
+    }
+    state.place_pending_labels(labels);
+    // A symbol defined only in skipped lines has no value,
+    // but one that is also defined in a branch that was assembled keeps that value.
+    for label in state.skipped.difference(&state.defined) {
+        if !options.defines.contains_key(symbols.resolve(*label)) {
+            state.anything_changed |= labels.remove(*label);
+        }
     }
-    Ok(anything_changed)
+    // Any literals after the last `LTORG` are placed at the end of the program.
+    if !state.literals.is_empty() {
+        let first_word = output.instrs.len();
+        place_literals(labels, output, &mut state);
+        let line_number = lines.last().map_or(1, |line| line.line_number);
+        output
+            .source_map
+            .extend((first_word..output.instrs.len()).map(|i| SourceSpan {
+                address: output.origin + 4 * i as u32,
+                line_number,
+                data: true,
+                synthesized: true,
+            }));
+    }
+    state.anything_changed
 }
 
-fn assemble_instr(
-    line_number: usize,
-    heal: HealStrategy,
+/// The progress of a single pass of the assembler.
+struct PassState {
     program_counter: u32,
-    asm_instr: &AsmInstr,
+    anything_changed: bool,
+    /// Labels are given an address only once we know where the next thing is placed,
+    /// since an instruction after some bytes is moved up to the next word.
+    pending_labels: Vec<SymbolId>,
+    /// The values loaded by `LDR Rd,=value` since the last literal pool,
+    /// with the labels that should point at them.
+    literals: Vec<(SymbolId, u32)>,
+    /// How many of the words produced by the current line were added by the assembler,
+    /// such as the instructions that heal an operand, rather than written by the user.
+    /// These always come first.
+    synthesized: usize,
+    /// The state of each `IF` that contains the current line, innermost last.
+    conditions: Vec<Condition>,
+    /// The symbols that have been given a value so far in this pass.
+    /// Conditions may only refer to these, so that they don't depend on their own outcome.
+    defined: BTreeSet<SymbolId>,
+    /// The symbols whose definitions were skipped in this pass.
+    skipped: BTreeSet<SymbolId>,
+}
+
+/// Whether the lines inside an `IF` are being assembled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Condition {
+    Assembling,
+    Skipping,
+    /// The `IF` itself is being skipped, so neither of its branches is assembled.
+    Inactive,
+}
+
+impl PassState {
+    /// Returns true unless the current line is in a branch of an `IF` that is being skipped.
+    fn assembling(&self) -> bool {
+        self.conditions
+            .iter()
+            .all(|condition| *condition == Condition::Assembling)
+    }
+
+    /// Give a symbol its value for this pass.
+    fn define(&mut self, labels: &mut SymbolValues, label: SymbolId, value: u32) {
+        self.anything_changed |= labels.set(label, value);
+        self.defined.insert(label);
+    }
+
+    /// Give the labels that are waiting for the next thing to be placed the current address.
+    fn place_pending_labels(&mut self, labels: &mut SymbolValues) {
+        for label in std::mem::take(&mut self.pending_labels) {
+            self.define(labels, label, self.program_counter);
+        }
+    }
+
+    /// Evaluate the condition of an `IF`, which is true if it is non-zero.
+    fn evaluate_condition(
+        &self,
+        expression: &Expression,
+        symbols: &SymbolTable,
+        heal: HealStrategy,
+        labels: &SymbolValues,
+    ) -> Result<bool, LineError> {
+        let mut referenced = BTreeSet::new();
+        expression.labels(&mut referenced);
+        if let Some(label) = referenced.difference(&self.defined).next() {
+            return Err(LineError::UnresolvedCondition(
+                symbols.resolve(*label).to_owned(),
+            ));
+        }
+        let ctx = LowerContext {
+            program_counter: self.program_counter,
+            heal,
+            symbols,
+            labels,
+        };
+        Ok(expression.evaluate(&ctx)? != 0)
+    }
+}
+
+/// Place the pending literals in a pool at the next word-aligned address,
+/// sharing a word between literals with the same value.
+fn place_literals(labels: &mut SymbolValues, output: &mut AssemblerOutput, state: &mut PassState) {
+    // The pool at the end of the program may follow a partially filled word of bytes.
+    state.program_counter = state.program_counter.next_multiple_of(4);
+    let mut addresses = BTreeMap::new();
+    for (label, value) in state.literals.drain(..) {
+        let address = *addresses.entry(value).or_insert_with(|| {
+            output.instrs.push(value);
+            state.program_counter += 4;
+            state.program_counter - 4
+        });
+        state.anything_changed |= labels.set(label, address);
+    }
+}
+
+/// Assemble a single line, appending any words that it produces to the output.
+fn assemble_line(
+    line: &AsmLine,
+    symbols: &SymbolTable,
+    heal: HealStrategy,
+    options: &AssemblerOptions,
+    labels: &mut SymbolValues,
     output: &mut AssemblerOutput,
-) -> Result<Vec<Instr>, AssemblerError> {
-    match asm_instr {
-        AsmInstr::BranchExchange { operand } => {
-            Ok(vec![Instr::BranchExchange { operand: *operand }])
-        }
-        AsmInstr::Branch { link, target } => {
-            let address = target.evaluate(line_number, output)?;
-            let offset = (address as i32).wrapping_sub(program_counter as i32 + 8);
-            // Check that the offset is 4 * some signed 24-bit value.
-            if offset % 4 != 0 {
-                return Err(AssemblerError {
-                    line_number,
-                    error: LineError::MisalignedBranchOffset,
-                });
+    state: &mut PassState,
+) -> Result<(), LineError> {
+    let ctx = LowerContext {
+        program_counter: state.program_counter,
+        heal,
+        symbols,
+        labels,
+    };
+    match &line.contents {
+        AsmLineContents::Empty => {}
+        AsmLineContents::Label(label) => {
+            state.pending_labels.push(*label);
+        }
+        AsmLineContents::Instr(cond, asm_instr) => {
+            if let AsmInstr::LoadLiteral {
+                value, pool_label, ..
+            } = asm_instr
+            {
+                let value = value.evaluate(&ctx)?;
+                if move_constant(value).is_none() {
+                    state.literals.push((*pool_label, value));
+                }
             }
-            if !(-(1 << 24)..(1 << 24)).contains(&(offset >> 2)) {
-                return Err(AssemblerError {
-                    line_number,
-                    error: LineError::OffsetOutOfRange,
+            let instrs = asm_instr.lower(&ctx)?;
+            if options.warn_pc_write && instrs.iter().any(writes_pc) {
+                output.warnings.push(AssemblerWarning {
+                    line_number: line.line_number,
+                    warning: LineWarning::PcWrite,
                 });
             }
-            Ok(vec![Instr::Branch {
-                link: *link,
-                offset,
-            }])
+            output.warnings.extend(
+                instrs
+                    .iter()
+                    .filter_map(|instr| suspicious(instr, *cond))
+                    .map(|warning| AssemblerWarning {
+                        line_number: line.line_number,
+                        warning,
+                    }),
+            );
+            let words = instrs
+                .into_iter()
+                .map(|i| i.encode(*cond))
+                .collect::<Result<Vec<u32>, LineError>>()?;
+            // Only `ADRL` asks for more than one instruction.
+            let requested = match asm_instr {
+                AsmInstr::Adr { long: true, .. } => 2,
+                _ => 1,
+            };
+            state.synthesized = words.len().saturating_sub(requested);
+            state.program_counter += 4 * words.len() as u32;
+            output.instrs.extend(words);
         }
-        AsmInstr::Adr { dest, expr } => assemble_instr(
-            line_number,
-            heal,
-            program_counter,
-            &AsmInstr::Data {
-                set_condition_codes: false,
-                op: instr::DataOp::Mov,
-                dest: *dest,
-                op1: instr::Register::R0,
-                op2: syntax::DataOperand::Constant(expr.clone()),
-            },
-            output,
-        ),
-        AsmInstr::Data {
-            set_condition_codes,
-            op,
-            dest,
-            op1,
-            op2,
-        } => with_operand(line_number, output, heal, op2, |op2| Instr::Data {
-            set_condition_codes: *set_condition_codes,
-            op: *op,
-            dest: *dest,
-            op1: *op1,
-            op2,
-        }),
-        AsmInstr::Mrs { psr, target } => Ok(vec![Instr::Mrs {
-            psr: *psr,
-            target: *target,
-        }]),
-        AsmInstr::Msr { psr, source } => Ok(vec![Instr::Msr {
-            psr: *psr,
-            source: match source {
-                syntax::MsrSource::Register(register) => instr::MsrSource::Register(*register),
-                syntax::MsrSource::RegisterFlags(register) => {
-                    instr::MsrSource::RegisterFlags(*register)
-                }
-                syntax::MsrSource::Flags(expression) => {
-                    instr::MsrSource::Flags(expression.evaluate(line_number, output)?)
-                }
-            },
-        }]),
-        AsmInstr::Multiply {
-            set_condition_codes,
-            dest,
-            op1,
-            op2,
-            addend,
-        } => Ok(vec![Instr::Multiply {
-            set_condition_codes: *set_condition_codes,
-            dest: *dest,
-            op1: *op1,
-            op2: *op2,
-            addend: *addend,
-        }]),
-        AsmInstr::MultiplyLong {
-            set_condition_codes,
-            signed,
-            accumulate,
-            dest_hi,
-            dest_lo,
-            op1,
-            op2,
-        } => Ok(vec![Instr::MultiplyLong {
-            set_condition_codes: *set_condition_codes,
-            signed: *signed,
-            accumulate: *accumulate,
-            dest_hi: *dest_hi,
-            dest_lo: *dest_lo,
-            op1: *op1,
-            op2: *op2,
-        }]),
-        AsmInstr::SingleTransfer {
-            kind,
-            size: AnyTransferSize::Normal(size),
-            write_back,
-            offset_positive,
-            pre_index,
-            data_register,
-            base_register,
-            offset,
-        } => with_transfer_operand(line_number, output, heal, offset, |offset| {
-            Instr::SingleTransfer {
-                kind: *kind,
-                size: *size,
-                write_back: *write_back,
-                offset_positive: *offset_positive,
-                pre_index: *pre_index,
-                data_register: *data_register,
-                base_register: *base_register,
-                offset,
+        AsmLineContents::Equ(name, expression) => {
+            let value = expression.evaluate(&ctx)?;
+            // Symbols defined in the options take precedence.
+            if !options.defines.contains_key(symbols.resolve(*name)) {
+                state.define(labels, *name, value);
             }
-        }),
-        AsmInstr::SingleTransfer {
-            kind,
-            size: AnyTransferSize::Special(size),
+        }
+        AsmLineContents::DefWord(expression) => {
+            let value = expression.evaluate(&ctx)?;
+            state.program_counter += 4;
+            output.instrs.push(value);
+        }
+        AsmLineContents::DefByte(expression) => {
+            let value = expression.evaluate(&ctx)?;
+            // Accept both unsigned and signed bytes.
+            if value > 0xFF && !(-0x80..0).contains(&(value as i32)) {
+                return Err(LineError::ImmediateOutOfRange(value));
+            }
+            if state.program_counter.is_multiple_of(4) {
+                output.instrs.push(0);
+            }
+            *output.instrs.last_mut().unwrap() |=
+                (value & 0xFF) << (8 * (state.program_counter % 4));
+            state.program_counter += 1;
+        }
+        AsmLineContents::Align(expression) => {
+            let alignment = expression.evaluate(&ctx)?;
+            if !alignment.is_power_of_two() {
+                return Err(LineError::InvalidAlignment(alignment));
+            }
+            let aligned = state
+                .program_counter
+                .checked_next_multiple_of(alignment)
+                .ok_or(LineError::AlignmentOverflow(alignment))?;
+            if aligned - state.program_counter > MAX_PADDING {
+                return Err(LineError::PaddingTooLarge(aligned - state.program_counter));
+            }
+            state.program_counter = aligned;
+            // Any partially filled word is already padded.
+            output.instrs.resize(
+                (state.program_counter - output.origin).div_ceil(4) as usize,
+                0,
+            );
+        }
+        AsmLineContents::Org(expression) => {
+            let origin = expression.evaluate(&ctx)?;
+            if !origin.is_multiple_of(4) {
+                return Err(LineError::MisalignedOrigin(origin));
+            }
+            if output.instrs.is_empty() {
+                // Nothing has been placed yet, so the program starts here.
+                output.origin = origin;
+            } else if origin < state.program_counter {
+                return Err(LineError::OriginBackwards(origin));
+            } else if origin - state.program_counter > MAX_PADDING {
+                return Err(LineError::PaddingTooLarge(origin - state.program_counter));
+            } else {
+                output
+                    .instrs
+                    .resize(((origin - output.origin) / 4) as usize, 0);
+            }
+            state.program_counter = origin;
+        }
+        AsmLineContents::Ltorg => {
+            let first_word = output.instrs.len();
+            place_literals(labels, output, state);
+            state.synthesized = output.instrs.len() - first_word;
+        }
+        // These are handled by `single_pass`.
+        AsmLineContents::If(_) | AsmLineContents::Else | AsmLineContents::EndIf => {}
+    }
+    Ok(())
+}
+
+/// Find a single `MOV` or `MVN` that puts this value in a register, if there is one.
+fn move_constant(value: u32) -> Option<(DataOp, RotatedConstant)> {
+    RotatedConstant::encode(value)
+        .map(|constant| (DataOp::Mov, constant))
+        .or_else(|| RotatedConstant::encode(!value).map(|constant| (DataOp::Mvn, constant)))
+}
+
+/// Split a value into two rotated constants that sum to it, if possible.
+fn split_constant(value: u32) -> Option<(RotatedConstant, RotatedConstant)> {
+    (0..16).find_map(|half_rotate| {
+        let mask = 0xFFu32.rotate_right(half_rotate * 2);
+        Some((
+            RotatedConstant::encode(value & !mask)?,
+            RotatedConstant::encode(value & mask)?,
+        ))
+    })
+}
+
+/// Returns true if this is a data-processing instruction that writes to the program counter.
+/// Setting the condition codes as well restores the CPSR, so is taken to be deliberate.
+fn writes_pc(instr: &Instr) -> bool {
+    matches!(
+        instr,
+        Instr::Data {
+            set_condition_codes: false,
+            op: DataOp::And
+                | DataOp::Eor
+                | DataOp::Sub
+                | DataOp::Rsb
+                | DataOp::Add
+                | DataOp::Adc
+                | DataOp::Sbc
+                | DataOp::Rsc
+                | DataOp::Orr
+                | DataOp::Mov
+                | DataOp::Bic
+                | DataOp::Mvn,
+            dest: Register::R15,
+            ..
+        }
+    )
+}
+
+/// Warn about instructions that are legal but probably not what was meant.
+fn suspicious(instr: &Instr, cond: Cond) -> Option<LineWarning> {
+    match *instr {
+        Instr::Data {
+            set_condition_codes: false,
+            op:
+                DataOp::And
+                | DataOp::Eor
+                | DataOp::Rsb
+                | DataOp::Adc
+                | DataOp::Sbc
+                | DataOp::Rsc
+                | DataOp::Orr
+                | DataOp::Bic
+                | DataOp::Mvn,
+            dest: Register::R13,
+            ..
+        } => Some(LineWarning::SpWrite),
+        // Post-indexed transfers always write back.
+        Instr::SingleTransfer {
             write_back,
-            offset_positive,
             pre_index,
             data_register,
             base_register,
-            offset,
-        } => {
-            if *kind == TransferKind::Store && *size != TransferSizeSpecial::HalfWord {
-                return Err(AssemblerError {
-                    line_number,
-                    error: LineError::InvalidStoreSize,
-                });
+            ..
+        } if (write_back || !pre_index) && data_register == base_register => {
+            Some(LineWarning::WriteBackToDataRegister)
+        }
+        Instr::BlockTransfer {
+            write_back: true,
+            base_register,
+            registers,
+            ..
+        } if registers & 1 << base_register as u32 != 0 => Some(LineWarning::BaseInRegisterList),
+        // A conditional branch to the next instruction is often left as a placeholder.
+        Instr::Branch {
+            link: false,
+            offset: -4,
+        } if cond == Cond::AL => Some(LineWarning::UselessBranch),
+        _ => None,
+    }
+}
+
+/// Find every label that is referred to by an expression.
+fn referenced_labels(lines: &[AsmLine]) -> BTreeSet<SymbolId> {
+    let mut referenced = BTreeSet::new();
+    for line in lines {
+        let expressions = match &line.contents {
+            AsmLineContents::Empty
+            | AsmLineContents::Label(_)
+            | AsmLineContents::Ltorg
+            | AsmLineContents::Else
+            | AsmLineContents::EndIf => Vec::new(),
+            AsmLineContents::Instr(_, instr) => instr.expressions(),
+            AsmLineContents::Equ(_, expression)
+            | AsmLineContents::DefWord(expression)
+            | AsmLineContents::DefByte(expression)
+            | AsmLineContents::Align(expression)
+            | AsmLineContents::Org(expression)
+            | AsmLineContents::If(expression) => vec![expression],
+        };
+        for expression in expressions {
+            expression.labels(&mut referenced);
+        }
+    }
+    referenced
+}
+
+impl AsmInstr {
+    /// The expressions that appear in this instruction.
+    fn expressions(&self) -> Vec<&Expression> {
+        match self {
+            AsmInstr::Branch { target, .. } => vec![target],
+            AsmInstr::Adr { expr, .. } => vec![expr],
+            AsmInstr::Data { op2, .. } => operand_expression(op2).into_iter().collect(),
+            AsmInstr::Msr {
+                source: syntax::MsrSource::Constant(expression),
+                ..
+            } => vec![expression],
+            AsmInstr::LoadLiteral { value, .. } => vec![value],
+            AsmInstr::SingleTransfer { offset, .. } => {
+                operand_expression(offset).into_iter().collect()
+            }
+            AsmInstr::SoftwareInterrupt { comment } => vec![comment],
+            AsmInstr::BranchExchange { .. }
+            | AsmInstr::Msr { .. }
+            | AsmInstr::Mrs { .. }
+            | AsmInstr::Multiply { .. }
+            | AsmInstr::MultiplyLong { .. }
+            | AsmInstr::BlockTransfer { .. }
+            | AsmInstr::Swap { .. } => Vec::new(),
+        }
+    }
+
+    /// Lower this instruction into real instructions, evaluating any expressions
+    /// and adding extra instructions to heal unencodable operands if permitted.
+    pub fn lower(&self, ctx: &LowerContext) -> Result<Vec<Instr>, LineError> {
+        match self {
+            AsmInstr::BranchExchange { operand } => {
+                Ok(vec![Instr::BranchExchange { operand: *operand }])
+            }
+            AsmInstr::Branch { link, target } => {
+                let address = target.evaluate(ctx)?;
+                let offset = (address as i32).wrapping_sub(ctx.program_counter as i32 + 8);
+                // Check that the offset is 4 * some signed 24-bit value.
+                if offset % 4 != 0 {
+                    return Err(LineError::MisalignedBranchOffset);
+                }
+                if !(-(1 << 24)..(1 << 24)).contains(&(offset >> 2)) {
+                    return Err(LineError::OffsetOutOfRange);
+                }
+                Ok(vec![Instr::Branch {
+                    link: *link,
+                    offset,
+                }])
             }
-            let mut instrs = Vec::new();
-            let offset = match offset {
-                syntax::DataOperand::Constant(expression) => {
-                    let value = expression.evaluate(line_number, output)?;
-                    if value <= 0xFF {
-                        SpecialOperand::Constant(value as u8)
-                        // TODO: What about negative offsets?
-                    } else if let HealStrategy::Advanced(register) = heal {
-                        instrs.extend(fill_register(value, register));
-                        SpecialOperand::Register(register)
-                    } else {
-                        return Err(AssemblerError {
-                            line_number,
-                            error: LineError::AddressTooComplex,
-                        });
+            AsmInstr::Adr { dest, expr, long } => {
+                let target = expr.evaluate(ctx)?;
+                // The PC is 8 bytes ahead of the first instruction.
+                let offset = target.wrapping_sub(ctx.program_counter.wrapping_add(8)) as i32;
+                let (op, magnitude) = if offset < 0 {
+                    (DataOp::Sub, offset.unsigned_abs())
+                } else {
+                    (DataOp::Add, offset as u32)
+                };
+                let adjust = |op1, constant| Instr::Data {
+                    set_condition_codes: false,
+                    op,
+                    dest: *dest,
+                    op1,
+                    op2: instr::DataOperand::Constant(constant),
+                };
+                if let Some(constant) = RotatedConstant::encode(magnitude) {
+                    Ok(vec![adjust(Register::R15, constant)])
+                } else if (*long || ctx.heal != HealStrategy::Off)
+                    && let Some((first, second)) = split_constant(magnitude)
+                {
+                    Ok(vec![adjust(Register::R15, first), adjust(*dest, second)])
+                } else if ctx.heal != HealStrategy::Off {
+                    // Load the absolute address instead.
+                    AsmInstr::Data {
+                        set_condition_codes: false,
+                        op: DataOp::Mov,
+                        dest: *dest,
+                        op1: Register::R0,
+                        op2: syntax::DataOperand::Constant(expr.clone()),
                     }
+                    .lower(ctx)
+                } else {
+                    Err(LineError::OffsetOutOfRange)
                 }
-                syntax::DataOperand::Register(register, shift) => {
-                    let shift_amount = match &shift.shift_amount {
-                        syntax::ShiftAmount::Constant(expression) => {
-                            expression.evaluate(line_number, output)?
-                        }
-                        syntax::ShiftAmount::Register(_) => {
-                            return Err(AssemblerError {
-                                line_number,
-                                error: LineError::AddressTooComplex,
-                            });
-                        }
-                    };
-                    if shift_amount == 0 {
-                        SpecialOperand::Register(*register)
-                    } else {
-                        return Err(AssemblerError {
-                            line_number,
-                            error: LineError::AddressTooComplex,
-                        });
+            }
+            AsmInstr::Data {
+                set_condition_codes,
+                op,
+                dest,
+                op1,
+                op2,
+            } => with_operand(ctx, *op, op2, |op, op2| Instr::Data {
+                set_condition_codes: *set_condition_codes,
+                op,
+                dest: *dest,
+                op1: *op1,
+                op2,
+            }),
+            AsmInstr::Mrs { psr, target } => Ok(vec![Instr::Mrs {
+                psr: *psr,
+                target: *target,
+            }]),
+            AsmInstr::Msr {
+                psr,
+                fields,
+                source,
+            } => Ok(vec![Instr::Msr {
+                psr: *psr,
+                fields: *fields,
+                source: match source {
+                    syntax::MsrSource::Register(register) => instr::MsrSource::Register(*register),
+                    syntax::MsrSource::Constant(expression) => {
+                        // Bits outside the selected fields are ignored,
+                        // so clear them to make the constant easier to encode.
+                        let value = expression.evaluate(ctx)? & fields.mask();
+                        instr::MsrSource::Constant(
+                            RotatedConstant::encode(value)
+                                .ok_or(LineError::ImmediateOutOfRange(value))?,
+                        )
                     }
+                },
+            }]),
+            AsmInstr::Multiply {
+                set_condition_codes,
+                dest,
+                op1,
+                op2,
+                addend,
+            } => Ok(vec![Instr::Multiply {
+                set_condition_codes: *set_condition_codes,
+                dest: *dest,
+                op1: *op1,
+                op2: *op2,
+                addend: *addend,
+            }]),
+            AsmInstr::MultiplyLong {
+                set_condition_codes,
+                signed,
+                accumulate,
+                dest_hi,
+                dest_lo,
+                op1,
+                op2,
+            } => Ok(vec![Instr::MultiplyLong {
+                set_condition_codes: *set_condition_codes,
+                signed: *signed,
+                accumulate: *accumulate,
+                dest_hi: *dest_hi,
+                dest_lo: *dest_lo,
+                op1: *op1,
+                op2: *op2,
+            }]),
+            AsmInstr::LoadLiteral {
+                dest,
+                value,
+                pool_label,
+            } => {
+                if let Some((op, constant)) = move_constant(value.evaluate(ctx)?) {
+                    return Ok(vec![Instr::Data {
+                        set_condition_codes: false,
+                        op,
+                        dest: *dest,
+                        op1: Register::R0,
+                        op2: instr::DataOperand::Constant(constant),
+                    }]);
                 }
-            };
-            instrs.push(Instr::SingleTransferSpecial {
-                kind: *kind,
-                size: *size,
-                write_back: *write_back,
-                offset_positive: *offset_positive,
-                pre_index: *pre_index,
-                data_register: *data_register,
-                base_register: *base_register,
+                // The PC is 8 bytes ahead of the instruction.
+                let offset = ctx
+                    .label(*pool_label)?
+                    .wrapping_sub(ctx.program_counter.wrapping_add(8))
+                    as i32;
+                if offset.unsigned_abs() >= TransferSize::Word.offset_limit() {
+                    return Err(LineError::LiteralPoolOutOfRange);
+                }
+                Ok(vec![Instr::SingleTransfer {
+                    kind: TransferKind::Load,
+                    size: TransferSize::Word,
+                    write_back: false,
+                    offset_positive: offset >= 0,
+                    pre_index: true,
+                    data_register: *dest,
+                    base_register: Register::R15,
+                    offset: instr::TransferOperand::Constant(offset.unsigned_abs() as u16),
+                }])
+            }
+            AsmInstr::SingleTransfer {
+                kind,
+                size,
+                write_back,
+                offset_positive,
+                pre_index,
+                data_register,
+                base_register,
                 offset,
-            });
-            Ok(instrs)
+            } => {
+                if *kind == TransferKind::Store && size.is_signed() {
+                    return Err(LineError::InvalidStoreSize);
+                }
+                with_transfer_operand(
+                    ctx,
+                    *size,
+                    *offset_positive,
+                    offset,
+                    |offset_positive, offset| Instr::SingleTransfer {
+                        kind: *kind,
+                        size: *size,
+                        write_back: *write_back,
+                        offset_positive,
+                        pre_index: *pre_index,
+                        data_register: *data_register,
+                        base_register: *base_register,
+                        offset,
+                    },
+                )
+            }
+            AsmInstr::BlockTransfer {
+                kind,
+                write_back,
+                offset_positive,
+                pre_index,
+                psr,
+                base_register,
+                registers,
+            } => {
+                if *registers == 0 {
+                    return Err(LineError::EmptyRegisterList);
+                }
+                Ok(vec![Instr::BlockTransfer {
+                    kind: *kind,
+                    write_back: *write_back,
+                    offset_positive: *offset_positive,
+                    pre_index: *pre_index,
+                    psr: *psr,
+                    base_register: *base_register,
+                    registers: *registers,
+                }])
+            }
+            AsmInstr::Swap {
+                byte,
+                dest,
+                source,
+                base,
+            } => {
+                if [dest, source, base].contains(&&Register::R15) {
+                    return Err(LineError::InvalidUseOfPc);
+                }
+                Ok(vec![Instr::Swap {
+                    byte: *byte,
+                    dest: *dest,
+                    source: *source,
+                    base: *base,
+                }])
+            }
+            AsmInstr::SoftwareInterrupt { comment } => Ok(vec![Instr::SoftwareInterrupt {
+                comment: comment.evaluate(ctx)?,
+            }]),
         }
-        AsmInstr::BlockTransfer {
-            kind,
-            write_back,
-            offset_positive,
-            pre_index,
-            psr,
-            base_register,
-            registers,
-        } => Ok(vec![Instr::BlockTransfer {
-            kind: *kind,
-            write_back: *write_back,
-            offset_positive: *offset_positive,
-            pre_index: *pre_index,
-            psr: *psr,
-            base_register: *base_register,
-            registers: *registers,
-        }]),
-        AsmInstr::Swap {
-            byte,
-            dest,
-            source,
-            base,
-        } => Ok(vec![Instr::Swap {
-            byte: *byte,
-            dest: *dest,
-            source: *source,
-            base: *base,
-        }]),
-        AsmInstr::SoftwareInterrupt { comment } => Ok(vec![Instr::SoftwareInterrupt {
-            comment: comment.evaluate(line_number, output)?,
-        }]),
+    }
+}
+
+/// The expression in this operand, if it has one.
+fn operand_expression(operand: &syntax::DataOperand) -> Option<&Expression> {
+    match operand {
+        syntax::DataOperand::Constant(expression)
+        | syntax::DataOperand::Register(
+            _,
+            syntax::Shift {
+                shift_amount: syntax::ShiftAmount::Constant(expression),
+                ..
+            },
+        ) => Some(expression),
+        syntax::DataOperand::Register(..) => None,
     }
 }
 
 fn with_operand(
-    line_number: usize,
-    output: &AssemblerOutput,
-    heal: HealStrategy,
+    ctx: &LowerContext,
+    data_op: DataOp,
     op: &syntax::DataOperand,
-    instr: impl FnOnce(instr::DataOperand) -> Instr,
-) -> Result<Vec<Instr>, AssemblerError> {
+    instr: impl FnOnce(DataOp, instr::DataOperand) -> Instr,
+) -> Result<Vec<Instr>, LineError> {
     match op {
         syntax::DataOperand::Constant(expression) => {
-            let value = expression.evaluate(line_number, output)?;
+            let value = expression.evaluate(ctx)?;
+            if RotatedConstant::encode(value).is_none()
+                && ctx.heal != HealStrategy::Off
+                && let Some((dual_op, constant)) = dual_constant(data_op, value)
+            {
+                return Ok(vec![instr(dual_op, instr::DataOperand::Constant(constant))]);
+            }
             // Attempt to encode this 32-bit value in just 12 bits.
-            let (mut instrs, operand) = encode_constant(line_number, heal, value)?;
-            instrs.push(instr(operand));
+            let (mut instrs, operand) = encode_constant(ctx.heal, value)?;
+            instrs.push(instr(data_op, operand));
             Ok(instrs)
         }
-        syntax::DataOperand::Register(register, shift) => {
-            Ok(vec![instr(instr::DataOperand::Register(
+        syntax::DataOperand::Register(register, shift) => Ok(vec![instr(
+            data_op,
+            instr::DataOperand::Register(
                 *register,
                 Shift {
                     shift_type: shift.shift_type,
                     shift_amount: match &shift.shift_amount {
-                        syntax::ShiftAmount::Constant(expression) => instr::ShiftAmount::Constant(
-                            expression.evaluate(line_number, output)? as u8,
-                        ),
+                        syntax::ShiftAmount::Constant(expression) => {
+                            instr::ShiftAmount::Constant(expression.evaluate(ctx)? as u8)
+                        }
                         syntax::ShiftAmount::Register(register) => {
                             instr::ShiftAmount::Register(*register)
                         }
                     },
                 },
-            ))])
-        }
+            ),
+        )]),
     }
 }
 
 fn with_transfer_operand(
-    line_number: usize,
-    output: &AssemblerOutput,
-    heal: HealStrategy,
+    ctx: &LowerContext,
+    size: TransferSize,
+    offset_positive: bool,
     op: &syntax::DataOperand,
-    instr: impl FnOnce(instr::TransferOperand) -> Instr,
-) -> Result<Vec<Instr>, AssemblerError> {
+    instr: impl FnOnce(bool, instr::TransferOperand) -> Instr,
+) -> Result<Vec<Instr>, LineError> {
     match op {
         syntax::DataOperand::Constant(expression) => {
-            let value = expression.evaluate(line_number, output)?;
-            if value < 1 << 12 {
-                Ok(vec![instr(instr::TransferOperand::Constant(value as u16))])
-            } else if let HealStrategy::Advanced(register) = heal {
+            let value = expression.evaluate(ctx)?;
+            if value < size.offset_limit() {
+                Ok(vec![instr(
+                    offset_positive,
+                    instr::TransferOperand::Constant(value as u16),
+                )])
+            } else if value.wrapping_neg() < size.offset_limit() {
+                // A negative offset such as `#-4` is encoded by subtracting its magnitude.
+                Ok(vec![instr(
+                    !offset_positive,
+                    instr::TransferOperand::Constant(value.wrapping_neg() as u16),
+                )])
+            } else if let HealStrategy::Advanced(register) = ctx.heal {
                 let mut instrs = fill_register(value, register);
-                instrs.push(instr(instr::TransferOperand::Register(
-                    register,
-                    Shift {
-                        shift_type: instr::ShiftType::LogicalLeft,
-                        shift_amount: instr::ShiftAmount::Constant(0),
-                    },
-                )));
+                instrs.push(instr(
+                    offset_positive,
+                    instr::TransferOperand::Register(
+                        register,
+                        Shift {
+                            shift_type: instr::ShiftType::LogicalLeft,
+                            shift_amount: instr::ShiftAmount::Constant(0),
+                        },
+                    ),
+                ));
                 Ok(instrs)
+            } else if size.is_special() {
+                Err(LineError::AddressTooComplex)
             } else {
-                Err(AssemblerError {
-                    line_number,
-                    error: LineError::ImmediateOutOfRange(value),
-                })
+                Err(LineError::ImmediateOutOfRange(value))
             }
         }
         syntax::DataOperand::Register(register, shift) => {
-            Ok(vec![instr(instr::TransferOperand::Register(
-                *register,
-                Shift {
-                    shift_type: shift.shift_type,
-                    shift_amount: match &shift.shift_amount {
-                        syntax::ShiftAmount::Constant(expression) => instr::ShiftAmount::Constant(
-                            expression.evaluate(line_number, output)? as u8,
-                        ),
-                        syntax::ShiftAmount::Register(register) => {
-                            instr::ShiftAmount::Register(*register)
-                        }
+            let shift_amount = match &shift.shift_amount {
+                syntax::ShiftAmount::Constant(expression) => {
+                    instr::ShiftAmount::Constant(expression.evaluate(ctx)? as u8)
+                }
+                syntax::ShiftAmount::Register(register) => instr::ShiftAmount::Register(*register),
+            };
+            // Halfword and signed transfers cannot shift their offset register.
+            if size.is_special() && shift_amount != instr::ShiftAmount::Constant(0) {
+                return Err(LineError::AddressTooComplex);
+            }
+            Ok(vec![instr(
+                offset_positive,
+                instr::TransferOperand::Register(
+                    *register,
+                    Shift {
+                        shift_type: shift.shift_type,
+                        shift_amount,
                     },
-                },
-            ))])
+                ),
+            )])
         }
     }
 }
@@ -485,11 +1188,28 @@ pub fn fill_register(value: u32, register: Register) -> Vec<Instr> {
     instrs
 }
 
+/// Find the operation that has the same result as `op` applied to `value`,
+/// when given the negation or complement of `value` as its operand instead,
+/// and that operand if it can be encoded.
+/// For example, `ADD R0,R1,#-4` is `SUB R0,R1,#4`.
+/// The carry and overflow flags set by the two operations may differ.
+fn dual_constant(op: DataOp, value: u32) -> Option<(DataOp, RotatedConstant)> {
+    let (dual_op, dual_value) = match op {
+        DataOp::Add => (DataOp::Sub, value.wrapping_neg()),
+        DataOp::Sub => (DataOp::Add, value.wrapping_neg()),
+        DataOp::Cmp => (DataOp::Cmn, value.wrapping_neg()),
+        DataOp::Cmn => (DataOp::Cmp, value.wrapping_neg()),
+        DataOp::Mov => (DataOp::Mvn, !value),
+        DataOp::Mvn => (DataOp::Mov, !value),
+        _ => return None,
+    };
+    Some((dual_op, RotatedConstant::encode(dual_value)?))
+}
+
 fn encode_constant(
-    line_number: usize,
     heal: HealStrategy,
     value: u32,
-) -> Result<(Vec<Instr>, instr::DataOperand), AssemblerError> {
+) -> Result<(Vec<Instr>, instr::DataOperand), LineError> {
     if let Some(constant) = RotatedConstant::encode(value) {
         Ok((Vec::new(), instr::DataOperand::Constant(constant)))
     } else if let HealStrategy::Advanced(reg) = heal {
@@ -504,55 +1224,854 @@ fn encode_constant(
             ),
         ))
     } else {
-        Err(AssemblerError {
-            line_number,
-            error: LineError::ImmediateOutOfRange(value),
-        })
+        Err(LineError::ImmediateOutOfRange(value))
     }
 }
 
 impl Expression {
-    pub fn evaluate(
-        &self,
-        line_number: usize,
-        output: &AssemblerOutput,
-    ) -> Result<u32, AssemblerError> {
+    /// Add every label that this expression refers to to the given set.
+    fn labels(&self, labels: &mut BTreeSet<SymbolId>) {
+        match self {
+            Expression::Constant(_) | Expression::Here => {}
+            Expression::Label(label) => {
+                labels.insert(*label);
+            }
+            Expression::Neg(x) | Expression::Not(x) => x.labels(labels),
+            Expression::Mul(lhs, rhs)
+            | Expression::Div(lhs, rhs)
+            | Expression::Mod(lhs, rhs)
+            | Expression::Add(lhs, rhs)
+            | Expression::Sub(lhs, rhs)
+            | Expression::And(lhs, rhs)
+            | Expression::Xor(lhs, rhs)
+            | Expression::Or(lhs, rhs)
+            | Expression::Lsl(lhs, rhs)
+            | Expression::Lsr(lhs, rhs)
+            | Expression::Asr(lhs, rhs)
+            | Expression::Ror(lhs, rhs) => {
+                lhs.labels(labels);
+                rhs.labels(labels);
+            }
+        }
+    }
+
+    pub fn evaluate(&self, ctx: &LowerContext) -> Result<u32, LineError> {
         match self {
             Expression::Constant(x) => Ok(*x),
-            Expression::Label(label) => match output.labels.get(label) {
-                Some(address) => Ok(*address),
-                None => Err(AssemblerError {
-                    line_number,
-                    error: LineError::LabelNotFound(label.to_owned()),
-                }),
-            },
-            Expression::Mul(lhs, rhs) => Ok(lhs
-                .evaluate(line_number, output)?
-                .wrapping_mul(rhs.evaluate(line_number, output)?)),
-            Expression::Div(lhs, rhs) => Ok(lhs
-                .evaluate(line_number, output)?
-                .wrapping_div(rhs.evaluate(line_number, output)?)),
-            Expression::Add(lhs, rhs) => Ok(lhs
-                .evaluate(line_number, output)?
-                .wrapping_add(rhs.evaluate(line_number, output)?)),
-            Expression::Sub(lhs, rhs) => Ok(lhs
-                .evaluate(line_number, output)?
-                .wrapping_sub(rhs.evaluate(line_number, output)?)),
-            Expression::Or(lhs, rhs) => {
-                Ok(lhs.evaluate(line_number, output)? | rhs.evaluate(line_number, output)?)
-            }
-            Expression::Lsl(lhs, rhs) => Ok(lhs
-                .evaluate(line_number, output)?
-                .wrapping_shl(rhs.evaluate(line_number, output)?)),
-            Expression::Lsr(lhs, rhs) => Ok(lhs
-                .evaluate(line_number, output)?
-                .wrapping_shr(rhs.evaluate(line_number, output)?)),
-            Expression::Asr(lhs, rhs) => Ok((lhs.evaluate(line_number, output)? as i32
-                >> rhs.evaluate(line_number, output)?)
-                as u32),
-            Expression::Ror(lhs, rhs) => Ok(lhs
-                .evaluate(line_number, output)?
-                .rotate_right(rhs.evaluate(line_number, output)?)),
+            Expression::Label(label) => ctx.label(*label),
+            Expression::Here => Ok(ctx.program_counter),
+            Expression::Neg(x) => Ok(x.evaluate(ctx)?.wrapping_neg()),
+            Expression::Not(x) => Ok(!x.evaluate(ctx)?),
+            Expression::Mul(lhs, rhs) => Ok(lhs.evaluate(ctx)?.wrapping_mul(rhs.evaluate(ctx)?)),
+            Expression::Div(lhs, rhs) => lhs
+                .evaluate(ctx)?
+                .checked_div(rhs.evaluate(ctx)?)
+                .ok_or(LineError::DivisionByZero),
+            Expression::Mod(lhs, rhs) => lhs
+                .evaluate(ctx)?
+                .checked_rem(rhs.evaluate(ctx)?)
+                .ok_or(LineError::DivisionByZero),
+            Expression::Add(lhs, rhs) => Ok(lhs.evaluate(ctx)?.wrapping_add(rhs.evaluate(ctx)?)),
+            Expression::Sub(lhs, rhs) => Ok(lhs.evaluate(ctx)?.wrapping_sub(rhs.evaluate(ctx)?)),
+            Expression::And(lhs, rhs) => Ok(lhs.evaluate(ctx)? & rhs.evaluate(ctx)?),
+            Expression::Xor(lhs, rhs) => Ok(lhs.evaluate(ctx)? ^ rhs.evaluate(ctx)?),
+            Expression::Or(lhs, rhs) => Ok(lhs.evaluate(ctx)? | rhs.evaluate(ctx)?),
+            Expression::Lsl(lhs, rhs) => Ok(lhs.evaluate(ctx)?.wrapping_shl(rhs.evaluate(ctx)?)),
+            Expression::Lsr(lhs, rhs) => Ok(lhs.evaluate(ctx)?.wrapping_shr(rhs.evaluate(ctx)?)),
+            Expression::Asr(lhs, rhs) => {
+                // Shifting by 32 or more fills every bit with the sign, as `ASR #32` does.
+                let shift = rhs.evaluate(ctx)?.min(31);
+                Ok((lhs.evaluate(ctx)? as i32 >> shift) as u32)
+            }
+            Expression::Ror(lhs, rhs) => Ok(lhs.evaluate(ctx)?.rotate_right(rhs.evaluate(ctx)?)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        assemble::{
+            AssemblerOptions, HealStrategy, LineError, LineWarning, LowerContext, assemble,
+            assemble_with, assemble_with_options,
+            symbol::{SymbolTable, SymbolValues},
+            syntax::{AsmInstr, DataOperand, Expression, Shift},
+        },
+        instr::{Cond, DataOp, Register},
+        processor::{Processor, ProcessorBuilder, ProcessorState, test::TestProcessorListener},
+    };
+
+    fn data(set_condition_codes: bool, op: DataOp, dest: Register, op2: u32) -> AsmInstr {
+        AsmInstr::Data {
+            set_condition_codes,
+            op,
+            dest,
+            op1: dest,
+            op2: DataOperand::Constant(Expression::Constant(op2)),
         }
     }
+
+    /// Builds and runs a program that sums the numbers from 1 to 10
+    /// without going through the parser.
+    #[test]
+    fn lower_program() {
+        let mut symbols = SymbolTable::default();
+        let top = symbols.intern("top");
+        let mut labels = SymbolValues::default();
+        labels.set(top, 8);
+
+        let program = [
+            (Cond::AL, data(false, DataOp::Mov, Register::R0, 0)),
+            (Cond::AL, data(false, DataOp::Mov, Register::R1, 10)),
+            // top
+            (
+                Cond::AL,
+                AsmInstr::Data {
+                    set_condition_codes: false,
+                    op: DataOp::Add,
+                    dest: Register::R0,
+                    op1: Register::R0,
+                    op2: DataOperand::Register(Register::R1, Shift::default()),
+                },
+            ),
+            (Cond::AL, data(true, DataOp::Sub, Register::R1, 1)),
+            (
+                Cond::NE,
+                AsmInstr::Branch {
+                    link: false,
+                    target: Expression::Label(top),
+                },
+            ),
+            // This constant can't be encoded directly, so it is healed using R12.
+            (Cond::AL, data(false, DataOp::Mov, Register::R2, 0x12345678)),
+            (
+                Cond::AL,
+                AsmInstr::SoftwareInterrupt {
+                    comment: Expression::Constant(2),
+                },
+            ),
+        ];
+
+        let mut words = Vec::new();
+        for (cond, instr) in program {
+            let ctx = LowerContext {
+                program_counter: 4 * words.len() as u32,
+                heal: HealStrategy::Advanced(Register::R12),
+                symbols: &symbols,
+                labels: &labels,
+            };
+            for instr in instr.lower(&ctx).unwrap() {
+                words.push(instr.encode(cond).unwrap());
+            }
+        }
+        assert!(words.len() > 7, "expected healing instructions");
+
+        let mut proc = Processor::default();
+        proc.memory_mut().set_words_aligned(0, &words);
+        let mut listener = TestProcessorListener::default();
+        for _ in 0..100 {
+            proc.try_execute(&mut listener).unwrap();
+            proc.registers_mut().advance_pc();
+            if proc.state() == ProcessorState::Stopped {
+                break;
+            }
+        }
+        assert_eq!(proc.state(), ProcessorState::Stopped);
+        assert_eq!(proc.registers().get(Register::R0), 55);
+        assert_eq!(proc.registers().get(Register::R2), 0x12345678);
+    }
+
+    #[test]
+    fn lower_missing_label() {
+        let mut symbols = SymbolTable::default();
+        let missing = symbols.intern("missing");
+        let ctx = LowerContext {
+            program_counter: 0,
+            heal: HealStrategy::Off,
+            symbols: &symbols,
+            labels: &SymbolValues::default(),
+        };
+        let error = AsmInstr::Branch {
+            link: false,
+            target: Expression::Label(missing),
+        }
+        .lower(&ctx)
+        .unwrap_err();
+        assert_eq!(error.to_string(), "label 'missing' not found");
+    }
+
+    /// Assembling the same source twice must give identical output,
+    /// even though label names are interned through a `HashMap`.
+    #[test]
+    fn deterministic_output() {
+        let src = "\
+SIZE    equ 0x12345678
+main    mov r0, SIZE
+        ldr r1, table
+        b after
+table   dw main, after, SIZE
+after   add r0, r0, #0x10001
+        swi 2
+";
+        let first = assemble(src).unwrap();
+        for _ in 0..10 {
+            let second = assemble(src).unwrap();
+            assert_eq!(first.instrs, second.instrs);
+            assert_eq!(first.labels, second.labels);
+            assert_eq!(first.source_map, second.source_map);
+        }
+    }
+
+    #[test]
+    fn source_map() {
+        let src = "\
+        mov r0, #1
+        mov r1, #0x12345678
+        ldr r2, =0x87654321
+        dw 5
+";
+        let output = assemble(src).unwrap();
+        assert_eq!(output.instrs.len(), 9);
+        // Every word of the healed instruction maps back to its line,
+        // but only the last was written by the user.
+        for address in (4..24).step_by(4) {
+            assert_eq!(output.line_for_address(address), Some(2));
+        }
+        assert_eq!(output.line_for_address(7), Some(2));
+        assert_eq!(
+            output
+                .source_map
+                .iter()
+                .map(|span| (span.line_number, span.data, span.synthesized))
+                .collect::<Vec<_>>(),
+            [
+                (1, false, false),
+                (2, false, true),
+                (2, false, true),
+                (2, false, true),
+                (2, false, true),
+                (2, false, false),
+                (3, false, false),
+                (4, true, false),
+                // The literal pool at the end of the program.
+                (4, true, true),
+            ]
+        );
+        assert_eq!(output.line_for_address(36), None);
+    }
+
+    #[test]
+    fn pc_write_warning() {
+        let src = "cmp pc, #4\nadd pc, pc, #4\n";
+        assert!(assemble(src).unwrap().warnings.is_empty());
+
+        let options = AssemblerOptions {
+            warn_pc_write: true,
+            ..Default::default()
+        };
+        let warnings = assemble_with_options(src, &options).unwrap().warnings;
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].line_number, 2);
+        assert_eq!(warnings[0].warning, LineWarning::PcWrite);
+    }
+
+    #[test]
+    fn warnings() {
+        for (src, warning) in [
+            ("        orr sp, sp, #4\n", LineWarning::SpWrite),
+            (
+                "        ldr r0, [r0, #4]!\n",
+                LineWarning::WriteBackToDataRegister,
+            ),
+            (
+                "        str r1, [r1], #4\n",
+                LineWarning::WriteBackToDataRegister,
+            ),
+            (
+                "        stmia r0!, {r0, r1}\n",
+                LineWarning::BaseInRegisterList,
+            ),
+            (
+                "        b next\nnext    swi 2\n",
+                LineWarning::UselessBranch,
+            ),
+            (
+                "unused  swi 2\n",
+                LineWarning::UnusedLabel("unused".to_owned()),
+            ),
+        ] {
+            let warnings = assemble(src).unwrap().warnings;
+            assert_eq!(warnings.len(), 1, "{src}");
+            assert_eq!(warnings[0].line_number, 1);
+            assert_eq!(warnings[0].warning, warning);
+
+            let options = AssemblerOptions {
+                deny_warnings: true,
+                ..Default::default()
+            };
+            let errors = assemble_with_options(src, &options).unwrap_err();
+            assert!(matches!(
+                &errors[0].error,
+                LineError::DeniedWarning(denied) if *denied == warning
+            ));
+        }
+
+        // None of these are suspicious.
+        let src = "\
+start   add sp, sp, #4
+        movs pc, lr
+        ldr r0, [r1, #4]!
+        ldmia r0, {r0, r1}
+        bl next
+next    beq next2
+next2   b start
+main    swi 2
+";
+        assert!(assemble(src).unwrap().warnings.is_empty());
+    }
+
+    #[test]
+    fn current_address() {
+        let src = "\
+        mov r0, #0
+start   mov r1, #1
+        mov r2, #2
+size    dw . - start
+here    equ .
+        b .
+";
+        let output = assemble(src).unwrap();
+        assert_eq!(output.instrs[3], 8);
+        assert_eq!(output.labels["here"], 16);
+        // Constants are not labels, even when their value is an address.
+        assert_eq!(output.label_at(4), Some("start"));
+        assert_eq!(output.label_at(16), None);
+        // A branch to itself has offset -8 due to the pipeline.
+        assert_eq!(output.instrs[4], 0xEAFFFFFE);
+    }
+
+    #[test]
+    fn swap_with_pc() {
+        for src in [
+            "swp pc, r0, [r1]\n",
+            "swpb r0, pc, [r1]\n",
+            "swp r0, r1, [pc]\n",
+        ] {
+            let errors = assemble(src).unwrap_err();
+            assert!(
+                matches!(errors[0].error, LineError::InvalidUseOfPc),
+                "{src}: {:?}",
+                errors[0].error
+            );
+        }
+    }
+
+    #[test]
+    fn adr() {
+        let src = format!(
+            "\
+; HEAL OFF
+before  adr r0, before
+        adr r1, after
+        adrl r2, far
+        swi 2
+after
+{}far     dw 0
+",
+            "        dw 0\n".repeat(1100)
+        );
+        let output = assemble(&src).unwrap();
+        // Only the ADRL needed two instructions.
+        assert_eq!(output.labels["after"], 20);
+        let mut proc = ProcessorBuilder::default()
+            .load_words(0, &output.instrs)
+            .build()
+            .unwrap();
+        assert_eq!(proc.run(10), Ok(5));
+        for (register, label) in [
+            (Register::R0, "before"),
+            (Register::R1, "after"),
+            (Register::R2, "far"),
+        ] {
+            assert_eq!(proc.registers().get(register), output.labels[label]);
+        }
+
+        let errors = assemble(&src.replace("adrl", "adr")).unwrap_err();
+        assert!(matches!(errors[0].error, LineError::OffsetOutOfRange));
+    }
+
+    #[test]
+    fn byte_data() {
+        let src = r#"
+        adr r0, second
+        ldrb r1, [r0]
+        ldrb r2, [r0, #2]
+        ldrsb r3, [r0, #5]
+        swi 2
+first   defb "a"
+second  defs "bc\n\0"
+third   db 1, -1
+after   mov r0, r0
+"#;
+        let output = assemble(src).unwrap();
+        assert_eq!(output.instrs[5..7], [0x0A636261, 0x00FF0100]);
+        // Labels before bytes have byte addresses, but instructions start on a word.
+        for (label, address) in [("first", 20), ("second", 21), ("third", 25), ("after", 28)] {
+            assert_eq!(output.labels[label], address);
+        }
+        let mut proc = ProcessorBuilder::default()
+            .load_words(0, &output.instrs)
+            .build()
+            .unwrap();
+        assert_eq!(proc.run(10), Ok(5));
+        assert_eq!(proc.registers().get(Register::R1), b'b' as u32);
+        assert_eq!(proc.registers().get(Register::R2), b'\n' as u32);
+        assert_eq!(proc.registers().get(Register::R3), -1i32 as u32);
+
+        let errors = assemble("        defb 256\n").unwrap_err();
+        assert!(matches!(
+            errors[0].error,
+            LineError::ImmediateOutOfRange(256)
+        ));
+    }
+
+    #[test]
+    fn dcb_dcd() {
+        let src = "\
+start   dcb 1, 2, 3
+bytes   dcb 4, 5
+words   dcd 0x12345678, bytes
+";
+        let output = assemble(src).unwrap();
+        // The final word of bytes is padded with zeroes.
+        assert_eq!(output.instrs, [0x04030201, 0x00000005, 0x12345678, 3]);
+        assert_eq!(output.labels["bytes"], 3);
+        assert_eq!(output.labels["words"], 8);
+    }
+
+    #[test]
+    fn dcb_strings() {
+        // Each character is a byte, so "AB",0 only fills three bytes of the word.
+        let output = assemble("msg:    dcb \"AB\",0\n        dcb \"\"\nend     dw 1\n").unwrap();
+        assert_eq!(output.instrs, [0x00004241, 1]);
+        assert_eq!(output.labels["end"], 4);
+    }
+
+    #[test]
+    fn align() {
+        for count in 0..4 {
+            let src = format!(
+                "{}        align 8\nhere    swi 2\n",
+                "        nop\n".repeat(count)
+            );
+            let output = assemble(&src).unwrap();
+            let here = output.labels["here"];
+            assert_eq!(here, (4 * count as u32).next_multiple_of(8));
+            assert_eq!(output.instrs.len() as u32, here / 4 + 1);
+        }
+
+        // Padding bytes are zero, and the default alignment is a word.
+        let output =
+            assemble("        db 1\n        align\nword    dw 2\n        align 2\n").unwrap();
+        assert_eq!(output.instrs, [1, 2]);
+        assert_eq!(output.labels["word"], 4);
+
+        // Smaller alignments pad bytes within a word, and `align 1` does nothing.
+        let output = assemble(
+            "        db 1\n        align 2\nhalf    db 2\n        align 1\n        db 3\n",
+        )
+        .unwrap();
+        assert_eq!(output.instrs, [0x03020001]);
+        assert_eq!(output.labels["half"], 2);
+
+        for alignment in [0, 3, 12] {
+            let errors = assemble(&format!("        align {alignment}\n")).unwrap_err();
+            assert!(matches!(
+                errors[0].error,
+                LineError::InvalidAlignment(n) if n == alignment
+            ));
+        }
+
+        // Alignment can't move past the end of memory, or pad too far.
+        let errors =
+            assemble("        org 0xFFFFFFF0\n        dw 0\n        align 0x100\n").unwrap_err();
+        assert!(matches!(
+            errors[0].error,
+            LineError::AlignmentOverflow(0x100)
+        ));
+        let errors = assemble("        dw 0\n        align 0x80000000\n").unwrap_err();
+        assert!(matches!(
+            errors[0].error,
+            LineError::PaddingTooLarge(0x7FFFFFFC)
+        ));
+    }
+
+    #[test]
+    fn org() {
+        let src = "\
+        org 0x8000
+start   b target
+        dw 0
+target  ldr r0, value
+        swi 2
+value   dw 42
+";
+        let output = assemble(src).unwrap();
+        assert_eq!(output.origin, 0x8000);
+        assert_eq!(output.labels["start"], 0x8000);
+        assert_eq!(output.labels["target"], 0x8008);
+        // The branch skips one word.
+        assert_eq!(output.instrs[0], 0xEA000000);
+        let mut proc = ProcessorBuilder::default()
+            .load_words(output.origin, &output.instrs)
+            .pc(output.origin)
+            .build()
+            .unwrap();
+        assert_eq!(proc.run(10), Ok(3));
+        assert_eq!(proc.registers().get(Register::R0), 42);
+
+        // Moving forwards pads with zeroes.
+        let output = assemble("        dw 1\n        org 12\nlater   dw 2\n").unwrap();
+        assert_eq!(output.instrs, [1, 0, 0, 2]);
+        assert_eq!(output.labels["later"], 12);
+
+        let errors = assemble("        org 0x100\n        dw 0\n        org 0x80\n").unwrap_err();
+        assert!(matches!(errors[0].error, LineError::OriginBackwards(0x80)));
+        let errors = assemble("        org 2\n").unwrap_err();
+        assert!(matches!(errors[0].error, LineError::MisalignedOrigin(2)));
+        // A gap is padded with zeroes, so huge gaps are rejected.
+        let errors = assemble("        dw 0\n        org 0xFFFFFFF0\n").unwrap_err();
+        assert!(matches!(
+            errors[0].error,
+            LineError::PaddingTooLarge(0xFFFFFFEC)
+        ));
+    }
+
+    #[test]
+    fn expressions() {
+        let src = "\
+        mov r0, #-(end + 4)
+end     swi 2
+        dw 1 + 2 * 3
+        dw 1 lsl 2 + 1
+        dw 1 or 2 lsl 2
+        dw -(2 + 3)
+        dw not 0 and 0xF0
+        dw 0xFF xor 0x0F
+        dw 17 mod 5
+        dw 2 * -(3 - 5)
+        dw 6 AND 3 OR 8 XOR 12
+        dw ((1 + 1) * (3 mod 2)) lsl 4
+        dw -8 asr 1
+        dw -8 asr 40
+        dw 8 asr 32
+";
+        let output = assemble(src).unwrap();
+        let end = output.labels["end"];
+        assert_eq!(
+            output.instrs[end as usize / 4 + 1..end as usize / 4 + 11],
+            [7, 8, 9, -5i32 as u32, 0xF0, 0xF0, 2, 4, 6, 0x20]
+        );
+        // Arithmetic shifts of 32 or more leave only the sign.
+        assert_eq!(
+            output.instrs[end as usize / 4 + 11..],
+            [-4i32 as u32, u32::MAX, 0]
+        );
+        let mut proc = ProcessorBuilder::default()
+            .load_words(0, &output.instrs)
+            .build()
+            .unwrap();
+        assert_eq!(proc.run(10), Ok(end as usize / 4 + 1));
+        assert_eq!(proc.registers().get(Register::R0), (end + 4).wrapping_neg());
+
+        for src in ["        dw 1 / 0\n", "\n        dw 1 mod (2 - 2)\n"] {
+            let errors = assemble(src).unwrap_err();
+            assert!(matches!(errors[0].error, LineError::DivisionByZero));
+            assert_eq!(errors[0].line_number, src.lines().count());
+        }
+    }
+
+    #[test]
+    fn conditional_assembly() {
+        let src = "\
+DEBUG   equ 1
+LEVEL   equ 2
+        if DEBUG
+        mov r0, #1
+        if LEVEL - 2
+verbose mov r1, #1
+        else
+        mov r1, #2
+        endif
+        else
+quiet   mov r0, #2
+        if 1
+        mov r1, #3
+        endif
+        endif
+        swi 2
+";
+        let output = assemble(src).unwrap();
+        let expected = assemble("mov r0, #1\nmov r1, #2\nswi 2\n").unwrap();
+        assert_eq!(output.instrs, expected.instrs);
+        // Labels in skipped lines are not defined.
+        assert!(!output.labels.contains_key("verbose"));
+        assert!(!output.labels.contains_key("quiet"));
+
+        // Symbols defined in the options take precedence over the source.
+        let options = AssemblerOptions {
+            defines: [("DEBUG".to_owned(), 0)].into(),
+            ..Default::default()
+        };
+        let output = assemble_with_options(src, &options).unwrap();
+        let expected = assemble("mov r0, #2\nmov r1, #3\nswi 2\n").unwrap();
+        assert_eq!(output.instrs, expected.instrs);
+        assert_eq!(output.labels["quiet"], 0);
+        let errors = assemble(&format!("{src}        b quiet\n")).unwrap_err();
+        assert!(matches!(&errors[0].error, LineError::LabelNotFound(label) if label == "quiet"));
+
+        // A symbol may be defined in both branches, whichever comes first.
+        for (debug, size) in [(1, 8), (0, 4)] {
+            let src = format!(
+                "DEBUG   equ {debug}\n        if DEBUG\nSIZE    equ 8\nstart   mov r0, #SIZE\n        \
+                 else\nSIZE    equ 4\nstart   mov r0, #SIZE\n        endif\n        b start\n"
+            );
+            let output = assemble(&src).unwrap();
+            assert_eq!(output.labels["SIZE"], size);
+            assert_eq!(output.labels["start"], 0);
+        }
+
+        // Conditions can only use symbols defined before them,
+        // so that they can't depend on their own outcome.
+        let errors = assemble("        if LATER\n        endif\nLATER   equ 1\n").unwrap_err();
+        assert_eq!(errors[0].line_number, 1);
+        assert!(
+            matches!(&errors[0].error, LineError::UnresolvedCondition(name) if name == "LATER")
+        );
+
+        // Unbalanced directives are reported on the line that opened them.
+        for (src, line_number, error) in [
+            (
+                "        if 1\n        if 0\n        endif\n",
+                1,
+                "IF has no matching ENDIF",
+            ),
+            ("        endif\n", 1, "ENDIF has no matching IF"),
+            (
+                "        mov r0, r0\n        else\n",
+                2,
+                "ELSE must follow an IF, and at most once",
+            ),
+            (
+                "        if 1\n        else\n        else\n        endif\n",
+                3,
+                "ELSE must follow an IF, and at most once",
+            ),
+        ] {
+            let errors = assemble(src).unwrap_err();
+            assert_eq!(errors.len(), 1, "{src}");
+            assert_eq!(errors[0].line_number, line_number, "{src}");
+            assert_eq!(errors[0].error.to_string(), error);
+        }
+        assert!(assemble("label   if 1\n        endif\n").is_err());
+        assert!(assemble("        ifeq 1\n        endif\n").is_err());
+    }
+
+    #[test]
+    fn heal_strategies() {
+        // The strategy given to the assembler overrides the one in the source.
+        let src = "; HEAL SIMPLE\n        mov r0, #0x101\n        swi 2\n";
+        let errors = assemble_with(src, HealStrategy::Off).unwrap_err();
+        assert!(matches!(
+            errors[0].error,
+            LineError::ImmediateOutOfRange(0x101)
+        ));
+        let output = assemble_with(src, "advanced:r12".parse().unwrap()).unwrap();
+        assert_eq!(output.instrs.len(), 4);
+        assert_eq!(
+            output.healed_lines().into_iter().collect::<Vec<_>>(),
+            [(2, 2)]
+        );
+        let mut proc = ProcessorBuilder::default()
+            .load_words(0, &output.instrs)
+            .build()
+            .unwrap();
+        assert_eq!(proc.run(10), Ok(4));
+        assert_eq!(proc.registers().get(Register::R0), 0x101);
+        assert!(assemble_with(src, HealStrategy::Simple).is_err());
+        assert!(assemble(src).is_err());
+
+        for (name, heal) in [
+            ("off", HealStrategy::Off),
+            ("Simple", HealStrategy::Simple),
+            ("advanced:r12", HealStrategy::Advanced(Register::R12)),
+            ("ADVANCED:R0", HealStrategy::Advanced(Register::R0)),
+        ] {
+            assert_eq!(name.parse(), Ok(heal));
+            assert_eq!(heal.to_string().parse(), Ok(heal));
+        }
+        for name in [
+            "advanced",
+            "advanced:r16",
+            "advanced:sp",
+            "advanced:lr",
+            "advanced:pc",
+            "advanced:r15",
+            "simple:r0",
+            "on",
+        ] {
+            assert!(name.parse::<HealStrategy>().is_err(), "{name}");
+        }
+    }
+
+    #[test]
+    fn dual_constants() {
+        let pairs = [
+            ("add r0, r1, #-4", "sub r0, r1, #4"),
+            ("sub r0, r1, #-4", "add r0, r1, #4"),
+            ("cmp r0, #-1", "cmn r0, #1"),
+            ("cmn r0, #-1", "cmp r0, #1"),
+            ("mov r0, #0xFFFFFF00", "mvn r0, #0xFF"),
+            ("mvn r0, #0xFFFFFF00", "mov r0, #0xFF"),
+        ];
+        for (src, dual) in pairs {
+            let healed = assemble(&format!("; HEAL SIMPLE\n{src}\n")).unwrap();
+            assert_eq!(
+                healed.instrs,
+                assemble(&format!("{dual}\n")).unwrap().instrs
+            );
+            let errors = assemble(&format!("; HEAL OFF\n{src}\n")).unwrap_err();
+            assert!(matches!(errors[0].error, LineError::ImmediateOutOfRange(_)));
+        }
+        // Other operations have no dual.
+        assert!(assemble("; HEAL SIMPLE\norr r0, r1, #-4\n").is_err());
+
+        let src = "\
+; HEAL SIMPLE
+        mov r1, #100
+        add r2, r1, #-4
+        sub r3, r1, #-4
+        mov r4, #0xFFFFFF00
+        mvn r5, #0xFFFFFF00
+        mvn r6, #0
+        cmp r6, #-1
+        moveq r7, #1
+        cmn r1, #-100
+        moveq r8, #1
+        swi 2
+";
+        let output = assemble(src).unwrap();
+        assert_eq!(output.instrs.len(), 11);
+        let mut proc = ProcessorBuilder::default()
+            .load_words(0, &output.instrs)
+            .build()
+            .unwrap();
+        assert_eq!(proc.run(20), Ok(11));
+        let registers = proc.registers();
+        assert_eq!(registers.get(Register::R2), 96);
+        assert_eq!(registers.get(Register::R3), 104);
+        assert_eq!(registers.get(Register::R4), 0xFFFFFF00);
+        assert_eq!(registers.get(Register::R5), 0xFF);
+        assert_eq!(registers.get(Register::R7), 1);
+        assert_eq!(registers.get(Register::R8), 1);
+    }
+
+    #[test]
+    fn all_errors() {
+        let src = "\
+; HEAL OFF
+        mov r0, #0x101
+        mov r1, #1
+        add r2, r2, #0x12345678
+        dw 1 / 0
+        b after
+        swp r0, r1, [pc]
+after   swi 2
+";
+        let errors = assemble(src).unwrap_err();
+        assert_eq!(
+            errors
+                .iter()
+                .map(|error| error.line_number)
+                .collect::<Vec<_>>(),
+            [2, 4, 5, 7]
+        );
+        assert!(matches!(
+            errors[0].error,
+            LineError::ImmediateOutOfRange(0x101)
+        ));
+        assert!(matches!(errors[2].error, LineError::DivisionByZero));
+        assert!(matches!(errors[3].error, LineError::InvalidUseOfPc));
+    }
+
+    #[test]
+    fn literal_pool() {
+        let src = "\
+        ldr r0, =0x12345678
+        ldr r1, =-2
+        ldr r2, =0x12345678
+        b skip
+        ltorg
+skip    ldr r3, =later + 0x10000000
+        swi 2
+later   dw 0
+";
+        let output = assemble(src).unwrap();
+        // Literals with the same value share a word of the pool.
+        assert_eq!(output.instrs[0], 0xE59F0008);
+        assert_eq!(output.instrs[1], 0xE3E01001);
+        assert_eq!(output.instrs[2], 0xE59F2000);
+        assert_eq!(output.instrs[4], 0x12345678);
+        // Literals after the last `LTORG` are placed at the end.
+        assert_eq!(output.instrs.len(), 9);
+        assert_eq!(output.instrs[8], 0x1000001C);
+        let mut proc = ProcessorBuilder::default()
+            .load_words(0, &output.instrs)
+            .build()
+            .unwrap();
+        assert_eq!(proc.run(10), Ok(6));
+        assert_eq!(proc.registers().get(Register::R0), 0x12345678);
+        assert_eq!(proc.registers().get(Register::R1), -2i32 as u32);
+        assert_eq!(proc.registers().get(Register::R2), 0x12345678);
+        assert_eq!(proc.registers().get(Register::R3), 0x1000001C);
+
+        // The pool must be within 4KiB of the load.
+        let errors = assemble("        ldr r0, =0x12345678\n        org 0x2000\n").unwrap_err();
+        assert!(matches!(errors[0].error, LineError::LiteralPoolOutOfRange));
+        assert_eq!(
+            errors[0].error.to_string(),
+            "offset to literal pool out of range; add an LTORG within 4KiB of this load"
+        );
+    }
+
+    #[test]
+    fn literal_label_between_passes() {
+        let src = "\
+        ldr r0, =table
+        ldr r1, =table
+        ldr r2, =start
+        swi 2
+        org 0x100
+start   db 1
+table   db 2
+";
+        // Neither label is known in the first pass, so whether each load needs the pool
+        // is only decided in a later pass.
+        let output = assemble(src).unwrap();
+        assert!(output.passes > 1);
+        assert_eq!(
+            output.instrs[..4],
+            [0xE59F00FC, 0xE59F10F8, 0xE3A02C01, 0xEF000002]
+        );
+        // The pool is word-aligned after the bytes, and holds `table` once.
+        assert_eq!(output.instrs[0x40..], [0x0201, 0x0101]);
+        let mut proc = ProcessorBuilder::default()
+            .load_words(0, &output.instrs)
+            .build()
+            .unwrap();
+        assert_eq!(proc.run(10), Ok(4));
+        assert_eq!(proc.registers().get(Register::R0), 0x101);
+        assert_eq!(proc.registers().get(Register::R1), 0x101);
+        assert_eq!(proc.registers().get(Register::R2), 0x100);
+    }
 }
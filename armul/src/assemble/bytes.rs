@@ -0,0 +1,264 @@
+//! A compact binary format for [`AssemblerOutput`], so that front-ends can cache
+//! assembled programs without reassembling them.
+//!
+//! The format is a magic number and a version, followed by each field of the output in turn.
+//! Integers are little-endian; strings and lists are prefixed with their length as a `u32`.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Display,
+};
+
+use crate::assemble::{AssemblerOutput, AssemblerWarning, LineWarning, SourceSpan};
+
+const MAGIC: &[u8; 4] = b"ARMO";
+const VERSION: u32 = 4;
+
+/// The reason that bytes could not be read by [`AssemblerOutput::from_bytes`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OutputBytesError {
+    /// The bytes do not start with the expected magic number.
+    BadMagic,
+    /// The bytes were written by an unsupported version of the format.
+    UnsupportedVersion(u32),
+    /// The bytes ended in the middle of a field.
+    Truncated,
+    /// There were bytes left over after the last field.
+    TrailingBytes,
+    /// A string was not valid UTF-8.
+    InvalidString,
+    /// A warning had an unknown kind.
+    InvalidWarning(u8),
+}
+
+impl Display for OutputBytesError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OutputBytesError::BadMagic => write!(f, "not an assembled program"),
+            OutputBytesError::UnsupportedVersion(version) => {
+                write!(f, "unsupported format version {version}")
+            }
+            OutputBytesError::Truncated => write!(f, "unexpected end of data"),
+            OutputBytesError::TrailingBytes => write!(f, "unexpected data after end"),
+            OutputBytesError::InvalidString => write!(f, "string was not valid UTF-8"),
+            OutputBytesError::InvalidWarning(kind) => write!(f, "unknown warning kind {kind}"),
+        }
+    }
+}
+
+impl AssemblerOutput {
+    /// Serialize this output into a compact binary format that can be read by
+    /// [`AssemblerOutput::from_bytes`].
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = Writer(Vec::new());
+        writer.0.extend_from_slice(MAGIC);
+        writer.u32(VERSION);
+
+        writer.u32(self.origin);
+        writer.len(self.labels.len());
+        for (label, value) in &self.labels {
+            writer.str(label);
+            writer.u32(*value);
+        }
+        writer.len(self.constants.len());
+        for constant in &self.constants {
+            writer.str(constant);
+        }
+        writer.len(self.instrs.len());
+        for instr in &self.instrs {
+            writer.u32(*instr);
+        }
+        writer.len(self.warnings.len());
+        for warning in &self.warnings {
+            writer.len(warning.line_number);
+            match &warning.warning {
+                LineWarning::PcWrite => writer.0.push(0),
+                LineWarning::SpWrite => writer.0.push(1),
+                LineWarning::WriteBackToDataRegister => writer.0.push(2),
+                LineWarning::UselessBranch => writer.0.push(3),
+                LineWarning::BaseInRegisterList => writer.0.push(5),
+                LineWarning::UnusedLabel(label) => {
+                    writer.0.push(4);
+                    writer.str(label);
+                }
+            }
+        }
+        writer.len(self.comments.len());
+        for (address, comment) in &self.comments {
+            writer.u32(*address);
+            writer.str(comment);
+        }
+        writer.len(self.source_map.len());
+        for span in &self.source_map {
+            writer.u32(span.address);
+            writer.len(span.line_number);
+            writer.0.push(span.data as u8);
+            writer.0.push(span.synthesized as u8);
+        }
+        writer.len(self.passes);
+        writer.0
+    }
+
+    /// Read an output that was serialized by [`AssemblerOutput::to_bytes`].
+    pub fn from_bytes(bytes: &[u8]) -> Result<AssemblerOutput, OutputBytesError> {
+        let mut reader = Reader(bytes);
+        if reader.take(4)? != MAGIC {
+            return Err(OutputBytesError::BadMagic);
+        }
+        let version = reader.u32()?;
+        if version != VERSION {
+            return Err(OutputBytesError::UnsupportedVersion(version));
+        }
+
+        let origin = reader.u32()?;
+        let mut labels = BTreeMap::new();
+        for _ in 0..reader.len()? {
+            let label = reader.str()?;
+            labels.insert(label, reader.u32()?);
+        }
+        let constants = (0..reader.len()?)
+            .map(|_| reader.str())
+            .collect::<Result<BTreeSet<_>, _>>()?;
+        let instrs = (0..reader.len()?)
+            .map(|_| reader.u32())
+            .collect::<Result<_, _>>()?;
+        let warnings = (0..reader.len()?)
+            .map(|_| {
+                let line_number = reader.len()?;
+                let warning = match reader.u8()? {
+                    0 => LineWarning::PcWrite,
+                    1 => LineWarning::SpWrite,
+                    2 => LineWarning::WriteBackToDataRegister,
+                    3 => LineWarning::UselessBranch,
+                    4 => LineWarning::UnusedLabel(reader.str()?),
+                    5 => LineWarning::BaseInRegisterList,
+                    kind => return Err(OutputBytesError::InvalidWarning(kind)),
+                };
+                Ok(AssemblerWarning {
+                    line_number,
+                    warning,
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        let mut comments = BTreeMap::new();
+        for _ in 0..reader.len()? {
+            let address = reader.u32()?;
+            comments.insert(address, reader.str()?);
+        }
+        let source_map = (0..reader.len()?)
+            .map(|_| {
+                Ok(SourceSpan {
+                    address: reader.u32()?,
+                    line_number: reader.len()?,
+                    data: reader.u8()? != 0,
+                    synthesized: reader.u8()? != 0,
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        let passes = reader.len()?;
+
+        if !reader.0.is_empty() {
+            return Err(OutputBytesError::TrailingBytes);
+        }
+        Ok(AssemblerOutput {
+            labels,
+            constants,
+            origin,
+            instrs,
+            warnings,
+            comments,
+            source_map,
+            passes,
+        })
+    }
+}
+
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn u32(&mut self, value: u32) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn len(&mut self, len: usize) {
+        self.u32(len as u32);
+    }
+
+    fn str(&mut self, s: &str) {
+        self.len(s.len());
+        self.0.extend_from_slice(s.as_bytes());
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize) -> Result<&'a [u8], OutputBytesError> {
+        if self.0.len() < count {
+            return Err(OutputBytesError::Truncated);
+        }
+        let (taken, rest) = self.0.split_at(count);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, OutputBytesError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, OutputBytesError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn len(&mut self) -> Result<usize, OutputBytesError> {
+        self.u32().map(|len| len as usize)
+    }
+
+    fn str(&mut self) -> Result<String, OutputBytesError> {
+        let len = self.len()?;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|_| OutputBytesError::InvalidString)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assemble::{
+        AssemblerOptions, AssemblerOutput, assemble_with_options, bytes::OutputBytesError,
+    };
+
+    #[test]
+    fn round_trip() {
+        let src = "\
+SIZE equ 4
+        org 0x100
+main    mov r0, SIZE
+        mov r1, 0x12345678 ; healed
+        mov pc, lr
+table   dw 1, 2
+";
+        let output = assemble_with_options(
+            src,
+            &AssemblerOptions {
+                warn_pc_write: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let bytes = output.to_bytes();
+        assert_eq!(AssemblerOutput::from_bytes(&bytes).unwrap(), output);
+        assert!(output.constants.contains("SIZE"));
+
+        assert_eq!(
+            AssemblerOutput::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(OutputBytesError::Truncated)
+        );
+        assert_eq!(
+            AssemblerOutput::from_bytes(b"ARMO\x05\x00\x00\x00"),
+            Err(OutputBytesError::UnsupportedVersion(5))
+        );
+        assert_eq!(
+            AssemblerOutput::from_bytes(b"\x7fELF\x01\x01\x01\x00"),
+            Err(OutputBytesError::BadMagic)
+        );
+    }
+}
@@ -0,0 +1,274 @@
+//! Pairs lines of source code with the words that they assembled to.
+
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use crate::{
+    addr::{Addr, Word},
+    assemble::{
+        AssemblerError, AssemblerOptions, AssemblerOutput, SourceSpan,
+        syntax::{AsmLine, AsmLineContents},
+    },
+    instr::{CycleEstimate, Instr, LineInfo},
+};
+
+/// A line of source code, together with the words that it produced.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SourceBlock {
+    pub line_number: usize,
+    pub text: String,
+    /// This is empty for lines such as comments, labels and `EQU` directives.
+    pub words: Vec<SourceWord>,
+    /// The estimated cost of executing this line once, if it produced any instructions.
+    pub cost: Option<CycleEstimate>,
+}
+
+/// A word produced by a line of source code.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SourceWord {
+    pub address: u32,
+    pub word: u32,
+    pub disassembly: String,
+}
+
+impl AssemblerOutput {
+    /// Estimate the best-case and worst-case cycle counts of each source line,
+    /// keyed by line number.
+    /// Lines that expanded into several instructions are given their summed cost.
+    /// Words written by data directives are not counted.
+    pub fn line_costs(&self) -> BTreeMap<usize, CycleEstimate> {
+        let mut costs = BTreeMap::<usize, CycleEstimate>::new();
+        for (span, word) in self.source_map.iter().zip(&self.instrs) {
+            if span.data {
+                continue;
+            }
+            if let Some((cond, instr)) = Instr::decode(*word) {
+                let cost = costs.entry(span.line_number).or_default();
+                *cost = *cost + instr.cycle_estimate(cond);
+            }
+        }
+        costs
+    }
+
+    /// Split the source code that this output was assembled from into lines,
+    /// pairing each line with the words that it produced.
+    /// Every line of the source is returned, in order.
+    pub fn interleave(&self, src: &str) -> Vec<SourceBlock> {
+        let costs = self.line_costs();
+        let mut blocks = src
+            .lines()
+            .enumerate()
+            .map(|(index, text)| SourceBlock {
+                line_number: index + 1,
+                text: text.to_owned(),
+                words: Vec::new(),
+                cost: costs.get(&(index + 1)).copied(),
+            })
+            .collect::<Vec<_>>();
+        for (span, word) in self.source_map.iter().zip(&self.instrs) {
+            if let Some(block) = blocks.get_mut(span.line_number - 1) {
+                block.words.push(SourceWord {
+                    address: span.address,
+                    word: *word,
+                    disassembly: Instr::decode(*word)
+                        .map_or_else(|| "???".to_owned(), |(cond, i)| i.display(cond)),
+                });
+            }
+        }
+        blocks
+    }
+}
+
+/// Assemble the given source code, pairing each parsed line with information about
+/// the words that it produced.
+/// A line that was healed into several instructions is paired with all of them,
+/// and lines such as labels and comments are paired with none.
+/// Literals placed after the last `LTORG` are paired with the last line, as in the source map.
+pub fn assemble_annotated(src: &str) -> Result<Vec<(AsmLine, Vec<LineInfo>)>, Vec<AssemblerError>> {
+    let (lines, output) = super::assemble_lines(src, &AssemblerOptions::default())?;
+    let mut words = output.source_map.iter().zip(&output.instrs).peekable();
+    let info = |(span, word): (&SourceSpan, &u32)| {
+        LineInfo::new(Addr(span.address), Word(*word), Some(&output))
+    };
+    let mut annotated = lines
+        .into_iter()
+        .map(|line| {
+            // Each word directive produces a single word,
+            // but an instruction produces every remaining word on its source line.
+            // Bytes share words, so the first byte on a line is paired with all of its words.
+            let count = match line.contents {
+                AsmLineContents::DefWord(_) => 1,
+                AsmLineContents::Instr(..)
+                | AsmLineContents::DefByte(_)
+                | AsmLineContents::Align(_)
+                | AsmLineContents::Org(_)
+                | AsmLineContents::Ltorg => usize::MAX,
+                _ => 0,
+            };
+            let mut infos = Vec::new();
+            while infos.len() < count
+                && let Some((span, word)) =
+                    words.next_if(|(span, _)| span.line_number == line.line_number)
+            {
+                infos.push(info((span, word)));
+            }
+            (line, infos)
+        })
+        .collect::<Vec<_>>();
+    if let Some((_, infos)) = annotated.last_mut() {
+        infos.extend(words.map(info));
+    }
+    Ok(annotated)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt::Write;
+
+    use crate::assemble::{assemble, assemble_annotated, syntax::AsmLineContents};
+
+    #[test]
+    fn interleave() {
+        let src = "\
+; Mixes instructions, constants and data.
+SIZE equ 4
+
+main
+        mov r0, SIZE
+        mov r1, 0x12345678 ; healed
+        swi 2
+table   dw 1, 2
+";
+        let assembled = assemble(src).unwrap();
+        let mut result = String::new();
+        for block in assembled.interleave(src) {
+            let line = format!("{:<2} | {}", block.line_number, block.text);
+            writeln!(result, "{}", line.trim_end()).unwrap();
+            for word in block.words {
+                writeln!(
+                    result,
+                    "   | {:08X}: {:08X} {}",
+                    word.address, word.word, word.disassembly
+                )
+                .unwrap();
+            }
+        }
+        assert_eq!(
+            result,
+            "\
+1  | ; Mixes instructions, constants and data.
+2  | SIZE equ 4
+3  |
+4  | main
+5  |         mov r0, SIZE
+   | 00000000: E3A00004 MOV R0,#4
+6  |         mov r1, 0x12345678 ; healed
+   | 00000004: E3A0C201 MOV R12,#1,ROR 4
+   | 00000008: E38CC78D ORR R12,R12,#141,ROR 14
+   | 0000000C: E38CCB15 ORR R12,R12,#21,ROR 22
+   | 00000010: E38CCF9E ORR R12,R12,#158,ROR 30
+   | 00000014: E1A0100C MOV R1,R12
+7  |         swi 2
+   | 00000018: EF000002 SWI 2
+8  | table   dw 1, 2
+   | 0000001C: 00000001 ANDEQ R0,R0,R1
+   | 00000020: 00000002 ANDEQ R0,R0,R2
+"
+        );
+    }
+
+    #[test]
+    fn line_costs() {
+        let src = "\
+main
+        mov r0, #10
+        mov r1, #0x12345678 ; healed
+        mov r2, r0, lsl r1
+loop
+        mul r3, r0, r0
+        umlal r4, r5, r0, r0
+        ldr r6, [r7]
+        str r6, [r7]
+        stmfd r13!, {r0-r3}
+        ldmfd r13!, {r0-r3, r15}
+        subs r0, r0, #1
+        bne loop
+        movne pc, lr
+        swi 2
+table   dw 1, 2
+";
+        let assembled = assemble(src).unwrap();
+        let mut result = String::new();
+        for block in assembled.interleave(src) {
+            let cost = block.cost.map_or_else(String::new, |cost| cost.to_string());
+            let line = format!("{:<2} | {cost:<12} | {}", block.line_number, block.text);
+            writeln!(result, "{}", line.trim_end()).unwrap();
+        }
+        assert_eq!(
+            result,
+            "\
+1  |              | main
+2  | 1S           |         mov r0, #10
+3  | 5S           |         mov r1, #0x12345678 ; healed
+4  | 1S+1I        |         mov r2, r0, lsl r1
+5  |              | loop
+6  | 1S+1I..1S+4I |         mul r3, r0, r0
+7  | 1S+3I..1S+6I |         umlal r4, r5, r0, r0
+8  | 1S+1N+1I     |         ldr r6, [r7]
+9  | 2N           |         str r6, [r7]
+10 | 3S+2N        |         stmfd r13!, {r0-r3}
+11 | 6S+2N+1I     |         ldmfd r13!, {r0-r3, r15}
+12 | 1S           |         subs r0, r0, #1
+13 | 1S..2S+1N    |         bne loop
+14 | 1S..2S+1N    |         movne pc, lr
+15 | 2S+1N        |         swi 2
+16 |              | table   dw 1, 2
+"
+        );
+    }
+
+    #[test]
+    fn annotated() {
+        let src = "\
+main    mov r1, 0x12345678 ; healed
+        dw 1, 2
+";
+        let annotated = assemble_annotated(src).unwrap();
+        let summary = annotated
+            .iter()
+            .map(|(line, infos)| {
+                let kind = match line.contents {
+                    AsmLineContents::Label(_) => "label",
+                    AsmLineContents::Instr(..) => "instr",
+                    AsmLineContents::DefWord(_) => "dw",
+                    _ => "other",
+                };
+                (line.line_number, kind, infos.len())
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            [(1, "label", 0), (1, "instr", 5), (2, "dw", 1), (2, "dw", 1)]
+        );
+        let json = serde_json::to_value(&annotated[1].1).unwrap();
+        assert_eq!(json[0]["value"], 0xE3A0C201u32);
+        assert_eq!(json[4]["instr"]["opcode_prefix"], "MOV");
+        assert_eq!(json[0]["comment"], "; healed");
+
+        // The literal pool at the end of the program follows the last line.
+        let src = "\
+main    ldr r0, =0x12345678
+        swi 2
+        dw 5
+";
+        let annotated = assemble_annotated(src).unwrap();
+        let counts = annotated
+            .iter()
+            .map(|(_, infos)| infos.len())
+            .collect::<Vec<_>>();
+        assert_eq!(counts, [0, 1, 1, 2]);
+        let json = serde_json::to_value(&annotated[3].1).unwrap();
+        assert_eq!(json[1]["value"], 0x12345678);
+    }
+}
@@ -0,0 +1,557 @@
+//! Expands the macros defined with `MACRO` and `MEND` before the source is parsed.
+//!
+//! A macro is defined by a `MACRO name $param1, $param2` line, followed by its body and a `MEND`
+//! line. Each line that uses the macro as its mnemonic is replaced by the body, with every
+//! `$param` replaced by the text of the corresponding argument. Labels defined in the body are
+//! renamed in each expansion, so that a macro containing a loop can be used more than once.
+
+use std::collections::{BTreeMap, BTreeSet, btree_map::Entry};
+
+use crate::assemble::{
+    AssemblerError, LineError,
+    parser::{self, GENERATED_LABEL_PREFIX},
+    syntax::MacroLine,
+};
+
+/// Macros may use other macros, but only this many levels deep,
+/// so that a macro that uses itself is reported rather than expanded forever.
+const MAX_DEPTH: usize = 16;
+
+/// A line of source code after macros have been expanded.
+pub(super) struct ExpandedLine {
+    text: String,
+    /// The line of the original source that this line came from.
+    /// For a line from the body of a macro, this is where the outermost macro was used.
+    line_number: usize,
+    /// The line of the innermost macro's body that this line came from, if any.
+    macro_line: Option<MacroLine>,
+}
+
+/// Source code whose macros have been expanded.
+#[derive(Default)]
+pub(super) struct Expansion {
+    lines: Vec<ExpandedLine>,
+    /// The names given to the labels defined inside each expansion.
+    /// These have the prefix of generated labels, so cannot clash with the user's labels.
+    pub labels: BTreeSet<String>,
+}
+
+impl Expansion {
+    /// The expanded source code.
+    pub fn text(&self) -> String {
+        self.lines
+            .iter()
+            .map(|line| line.text.as_str())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Where the given line of the expanded source code came from.
+    pub fn origin(&self, line_number: usize) -> (usize, Option<&MacroLine>) {
+        match line_number.checked_sub(1).and_then(|i| self.lines.get(i)) {
+            Some(line) => (line.line_number, line.macro_line.as_ref()),
+            // Errors at the end of the source may be past the last line.
+            None => (
+                self.lines
+                    .last()
+                    .map_or(line_number, |line| line.line_number),
+                None,
+            ),
+        }
+    }
+}
+
+struct Macro {
+    name: String,
+    params: Vec<String>,
+    /// Each line of the body, with its line number.
+    body: Vec<(usize, String)>,
+}
+
+/// Expand every use of a macro in the given source code.
+pub(super) fn expand(src: &str) -> Result<Expansion, Vec<AssemblerError>> {
+    let mut macros = BTreeMap::<String, Macro>::new();
+    let mut errors = Vec::new();
+    let mut top_level = Vec::new();
+    let mut defining = None::<(usize, Macro)>;
+    for (index, text) in src.split('\n').enumerate() {
+        let line_number = index + 1;
+        let mut error = |message: &str| {
+            errors.push(AssemblerError {
+                line_number,
+                error: LineError::ParseError(message.to_owned()),
+            })
+        };
+        let line = SplitLine::new(text, |_| false);
+        match (line.keyword(), &mut defining) {
+            (Some("macro"), Some(_)) => error("macros cannot be defined inside other macros"),
+            (Some("macro"), None) => match Macro::new(&line) {
+                Ok(definition) => defining = Some((line_number, definition)),
+                Err(message) => error(&message),
+            },
+            (Some("mend"), Some(_)) => {
+                let (_, definition) = defining.take().unwrap();
+                match macros.entry(definition.name.to_lowercase()) {
+                    Entry::Vacant(entry) => {
+                        entry.insert(definition);
+                    }
+                    Entry::Occupied(_) => {
+                        error(&format!("macro '{}' is already defined", definition.name))
+                    }
+                }
+            }
+            (Some("mend"), None) => error("MEND without a matching MACRO"),
+            (_, Some((_, definition))) => definition.body.push((line_number, text.to_owned())),
+            (_, None) => top_level.push((line_number, text)),
+        }
+    }
+    if let Some((line_number, _)) = defining {
+        errors.push(AssemblerError {
+            line_number,
+            error: LineError::ParseError("MACRO without a matching MEND".to_owned()),
+        });
+    }
+
+    let mut expander = Expander {
+        macros: &macros,
+        expansion: Expansion::default(),
+        errors,
+        count: 0,
+    };
+    for (line_number, text) in top_level {
+        expander.expand_line(text.to_owned(), line_number, None, 0);
+    }
+    if expander.errors.is_empty() {
+        Ok(expander.expansion)
+    } else {
+        Err(expander.errors)
+    }
+}
+
+struct Expander<'a> {
+    macros: &'a BTreeMap<String, Macro>,
+    expansion: Expansion,
+    errors: Vec<AssemblerError>,
+    /// The number of expansions so far, used to give their labels unique names.
+    count: usize,
+}
+
+impl Expander<'_> {
+    fn is_mnemonic(&self, word: &str) -> bool {
+        let lower = word.to_lowercase();
+        self.macros.contains_key(&lower) || parser::is_mnemonic(&lower)
+    }
+
+    /// Add this line to the expansion, replacing it with a macro's body if it uses one.
+    fn expand_line(
+        &mut self,
+        text: String,
+        line_number: usize,
+        macro_line: Option<MacroLine>,
+        depth: usize,
+    ) {
+        let macros = self.macros;
+        let line = SplitLine::new(&text, |word| self.is_mnemonic(word));
+        let Some(definition) = line
+            .mnemonic
+            .and_then(|mnemonic| macros.get(&mnemonic.to_lowercase()))
+        else {
+            self.expansion.lines.push(ExpandedLine {
+                text,
+                line_number,
+                macro_line,
+            });
+            return;
+        };
+
+        let args = split_args(line.operands);
+        let error = if depth == MAX_DEPTH {
+            Some(LineError::MacroTooDeep(definition.name.clone()))
+        } else if args.len() != definition.params.len() {
+            Some(LineError::ParseError(format!(
+                "macro '{}' takes {} arguments, but {} were given",
+                definition.name,
+                definition.params.len(),
+                args.len()
+            )))
+        } else {
+            None
+        };
+        if let Some(error) = error {
+            self.errors.push(AssemblerError {
+                line_number,
+                error: error.in_macro(macro_line.as_ref()),
+            });
+            return;
+        }
+
+        if let Some(label) = line.label {
+            self.expansion.lines.push(ExpandedLine {
+                text: label.to_owned(),
+                line_number,
+                macro_line: macro_line.clone(),
+            });
+        }
+        self.count += 1;
+        let labels = definition
+            .body
+            .iter()
+            .filter_map(|(_, body)| SplitLine::new(body, |word| self.is_mnemonic(word)).label)
+            .map(|label| {
+                let renamed = format!("{GENERATED_LABEL_PREFIX}macro{}_{label}", self.count);
+                (label, renamed)
+            })
+            .collect::<BTreeMap<_, _>>();
+        self.expansion.labels.extend(labels.values().cloned());
+        for (body_line, body) in &definition.body {
+            let text = substitute(body, &definition.params, &args, &labels);
+            let macro_line = MacroLine {
+                name: definition.name.clone(),
+                line_number: *body_line,
+            };
+            self.expand_line(text, line_number, Some(macro_line), depth + 1);
+        }
+    }
+}
+
+impl Macro {
+    /// Read the name and parameters of a macro from the line that starts its definition.
+    fn new(line: &SplitLine) -> Result<Macro, String> {
+        if line.label.is_some() {
+            return Err("a MACRO line cannot have a label".to_owned());
+        }
+        let (name, params) = line
+            .operands
+            .split_once(char::is_whitespace)
+            .unwrap_or((line.operands, ""));
+        if !is_identifier(name) {
+            return Err("expected the name of the macro after MACRO".to_owned());
+        }
+        if parser::is_mnemonic(&name.to_lowercase()) {
+            return Err(format!(
+                "macro '{name}' has the same name as an instruction"
+            ));
+        }
+        let params = split_args(params)
+            .into_iter()
+            .map(|param| match param.strip_prefix('$') {
+                Some(param) if is_identifier(param) => Ok(param.to_owned()),
+                _ => Err(format!("macro parameter '{param}' must be '$' then a name")),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Macro {
+            name: name.to_owned(),
+            params,
+            body: Vec::new(),
+        })
+    }
+}
+
+/// A line of source code, split into the parts that matter for expanding macros.
+struct SplitLine<'a> {
+    label: Option<&'a str>,
+    mnemonic: Option<&'a str>,
+    /// Everything after the mnemonic, without any comment.
+    operands: &'a str,
+}
+
+impl<'a> SplitLine<'a> {
+    /// As in the parser, the first word is a label unless it is a mnemonic.
+    fn new(text: &'a str, is_mnemonic: impl Fn(&str) -> bool) -> SplitLine<'a> {
+        let code = text[..comment_start(text)].trim();
+        let (first, rest) = split_word(code);
+        let is_mnemonic = |word: &str| {
+            is_mnemonic(word)
+                || word.eq_ignore_ascii_case("macro")
+                || word.eq_ignore_ascii_case("mend")
+        };
+        if first.is_empty() || is_mnemonic(first) {
+            return SplitLine {
+                label: None,
+                mnemonic: (!first.is_empty()).then_some(first),
+                operands: rest.trim(),
+            };
+        }
+        let rest = rest.strip_prefix(':').unwrap_or(rest).trim_start();
+        let (mnemonic, operands) = split_word(rest);
+        SplitLine {
+            label: Some(first),
+            mnemonic: (!mnemonic.is_empty()).then_some(mnemonic),
+            operands: operands.trim(),
+        }
+    }
+
+    /// The mnemonic in lowercase if it is `MACRO` or `MEND`.
+    fn keyword(&self) -> Option<&'static str> {
+        ["macro", "mend"].into_iter().find(|keyword| {
+            self.mnemonic
+                .is_some_and(|m| m.eq_ignore_ascii_case(keyword))
+        })
+    }
+}
+
+pub(super) fn is_identifier(word: &str) -> bool {
+    word.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && word.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Split off the word at the start of this text, if it starts with one.
+fn split_word(text: &str) -> (&str, &str) {
+    if !text.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+        return ("", text);
+    }
+    text.split_at(
+        text.find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+            .unwrap_or(text.len()),
+    )
+}
+
+/// The index of the `;` that starts the comment on this line, or its length if it has none.
+fn comment_start(text: &str) -> usize {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (index, c) in text.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            ';' if !in_string => return index,
+            _ => {}
+        }
+    }
+    text.len()
+}
+
+/// Split the arguments of a macro at each comma that is not in brackets or a string.
+fn split_args(operands: &str) -> Vec<&str> {
+    if operands.trim().is_empty() {
+        return Vec::new();
+    }
+    let mut args = Vec::new();
+    let (mut depth, mut in_string, mut escaped, mut start) = (0, false, false, 0);
+    for (index, c) in operands.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            _ if in_string => {}
+            '(' | '[' | '{' => depth += 1,
+            ')' | ']' | '}' => depth -= 1,
+            ',' if depth == 0 => {
+                args.push(operands[start..index].trim());
+                start = index + 1;
+            }
+            _ => {}
+        }
+    }
+    args.push(operands[start..].trim());
+    args
+}
+
+/// Replace each `$param` with its argument, and each label defined in the macro with its new name.
+/// Labels are not renamed inside strings or comments.
+fn substitute(
+    text: &str,
+    params: &[String],
+    args: &[&str],
+    labels: &BTreeMap<&str, String>,
+) -> String {
+    let comment = comment_start(text);
+    let mut result = String::new();
+    let mut rest = text;
+    let mut in_string = false;
+    while let Some(c) = rest.chars().next() {
+        let in_comment = text.len() - rest.len() >= comment;
+        let param = rest.strip_prefix('$').map(split_word);
+        let (word, tail) = split_word(rest);
+        if let Some((name, tail)) = param
+            && let Some(index) = params.iter().position(|param| param == name)
+        {
+            result.push_str(args[index]);
+            rest = tail;
+        } else if !word.is_empty() {
+            result.push_str(match labels.get(word) {
+                Some(renamed) if !in_string && !in_comment => renamed,
+                _ => word,
+            });
+            rest = tail;
+        } else {
+            // Numbers are copied whole, so that the digits of `0x10` are not taken as a word.
+            let len = if c.is_ascii_digit() {
+                rest.find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                    .unwrap_or(rest.len())
+            } else {
+                c.len_utf8()
+            };
+            match c {
+                '"' if !in_comment => in_string = !in_string,
+                '\\' if in_string => {
+                    // Copy the escaped character too.
+                    result.push('\\');
+                    rest = &rest[1..];
+                    let Some(escaped) = rest.chars().next() else {
+                        break;
+                    };
+                    result.push(escaped);
+                    rest = &rest[escaped.len_utf8()..];
+                    continue;
+                }
+                _ => {}
+            }
+            result.push_str(&rest[..len]);
+            rest = &rest[len..];
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        assemble::{LineError, assemble},
+        instr::Register,
+        processor::ProcessorBuilder,
+    };
+
+    #[test]
+    fn push_pop() {
+        let src = "\
+        macro push2 $a, $b
+        stmfd sp!, {$a, $b} ; push $a first
+        mend
+        MACRO pop2 $a,$b
+        ldmfd sp!, {$a, $b}
+        MEND
+
+        mov sp, #0x1000
+        mov r0, #1
+        mov r1, #2
+        push2 r0, r1
+        mov r0, #0
+        mov r1, #0
+save    pop2 r2, r3
+        swi 2
+";
+        let output = assemble(src).unwrap();
+        let expected = assemble(
+            "\
+        mov sp, #0x1000
+        mov r0, #1
+        mov r1, #2
+        stmfd sp!, {r0, r1}
+        mov r0, #0
+        mov r1, #0
+save    ldmfd sp!, {r2, r3}
+        swi 2
+",
+        )
+        .unwrap();
+        assert_eq!(output.instrs, expected.instrs);
+        // Words from an expansion belong to the line that used the macro.
+        assert_eq!(output.line_for_address(12), Some(11));
+        assert_eq!(output.line_for_address(24), Some(14));
+        assert_eq!(output.labels["save"], 24);
+        assert_eq!(output.comments[&12], "; push r0 first");
+
+        let mut proc = ProcessorBuilder::default()
+            .load_words(0, &output.instrs)
+            .build()
+            .unwrap();
+        assert_eq!(proc.run(10), Ok(8));
+        assert_eq!(proc.registers().get(Register::R2), 1);
+        assert_eq!(proc.registers().get(Register::R3), 2);
+    }
+
+    #[test]
+    fn local_labels() {
+        let src = "\
+        macro count $n
+        mov r0, $n
+loop    add r1, r1, #1
+        subs r0, r0, #1
+        bne loop
+        mend
+
+        macro count_twice $n
+        count $n
+        count $n * 2
+        mend
+
+        mov r1, #0
+        count 3
+        count_twice 0x10
+        swi 2
+";
+        let output = assemble(src).unwrap();
+        assert!(output.warnings.is_empty());
+        assert!(!output.labels.contains_key("loop"));
+        assert_eq!(output.label_at(8), None);
+        let mut proc = ProcessorBuilder::default()
+            .load_words(0, &output.instrs)
+            .build()
+            .unwrap();
+        proc.run(1000).unwrap();
+        assert_eq!(proc.registers().get(Register::R1), 3 + 0x10 + 0x20);
+    }
+
+    #[test]
+    fn errors() {
+        // Errors inside an expansion give the line of the use and the line in the macro.
+        let src = "\
+        macro load $reg, $value
+        mov r0, #1
+        mov $reg, $value
+        mend
+; HEAL OFF
+        load r1, #2
+        load r2, #0x101
+";
+        let errors = assemble(src).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].line_number, 7);
+        let LineError::InMacro {
+            name,
+            line_number,
+            error,
+        } = &errors[0].error
+        else {
+            panic!("expected an error in a macro, found {:?}", errors[0].error);
+        };
+        assert_eq!((name.as_str(), *line_number), ("load", 3));
+        assert!(matches!(**error, LineError::ImmediateOutOfRange(0x101)));
+        assert_eq!(
+            errors[0].error.to_string(),
+            "value 257 out of range (in macro 'load' on line 3)"
+        );
+        // Parse errors are found before assembly, and reported in the same way.
+        let errors = assemble(&format!("{src}        load r3, [r0]\n")).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].line_number, 8);
+        assert!(matches!(
+            errors[0].error,
+            LineError::InMacro { line_number: 3, .. }
+        ));
+
+        let errors =
+            assemble("        macro self\n        self\n        mend\n        self\n").unwrap_err();
+        assert_eq!(errors[0].line_number, 4);
+        assert!(matches!(
+            &errors[0].error,
+            LineError::InMacro { error, .. }
+                if matches!(&**error, LineError::MacroTooDeep(name) if name == "self")
+        ));
+
+        for (src, line) in [
+            ("        macro m $a\n        mend\n        m\n", 3),
+            ("        macro m\n        mov r0, r0\n", 1),
+            ("        mend\n", 1),
+            ("        macro m\n        macro n\n        mend\n", 2),
+            ("        macro mov\n        mend\n", 1),
+            ("        macro m a\n        mend\n", 1),
+        ] {
+            let errors = assemble(src).unwrap_err();
+            assert!(matches!(errors[0].error, LineError::ParseError(_)), "{src}");
+            assert_eq!(errors[0].line_number, line, "{src}");
+        }
+    }
+}
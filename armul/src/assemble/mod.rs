@@ -1,20 +1,109 @@
-use std::{collections::BTreeMap, fmt::Display};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Display,
+};
+
+use crate::assemble::syntax::{AsmLine, MacroLine};
 
 mod assembler;
+mod bytes;
+mod interleave;
+mod macros;
+pub mod output;
 mod parser;
-mod syntax;
+pub mod symbol;
+pub mod syntax;
 
-use assembler::HealStrategy;
+pub use assembler::{HealStrategy, LowerContext};
+pub use bytes::OutputBytesError;
+pub use interleave::{SourceBlock, SourceWord, assemble_annotated};
+pub use parser::{MnemonicSpec, encodings_for, mnemonic_category, mnemonics};
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
 pub struct AssemblerOutput {
+    /// The value of each symbol.
+    /// This is usually an address, but symbols defined with `EQU` may hold any constant,
+    /// so the values are not [`crate::addr::Addr`]s.
+    /// See [`AssemblerOutput::code_labels`] for just the addresses.
     pub labels: BTreeMap<String, u32>,
+    /// The symbols in `labels` that were defined with `EQU` or in the options,
+    /// rather than by labelling a line.
+    pub constants: BTreeSet<String>,
+    /// The address of the first word in `instrs`, set by `ORG`.
+    pub origin: u32,
     pub instrs: Vec<u32>,
     pub warnings: Vec<AssemblerWarning>,
     pub comments: BTreeMap<u32, String>,
+    /// The source line that produced each word in `instrs`, in order.
+    pub source_map: Vec<SourceSpan>,
     pub passes: usize,
 }
 
+impl AssemblerOutput {
+    /// The name of a label with the given value, if there is one.
+    /// Labels that the assembler generated for itself and constants are ignored.
+    pub fn label_at(&self, address: u32) -> Option<&str> {
+        self.code_labels()
+            .find(|(_, value)| *value == address)
+            .map(|(name, _)| name)
+    }
+
+    /// The labels that name a line of the program, with their addresses.
+    /// Labels that the assembler generated for itself and constants are omitted.
+    pub fn code_labels(&self) -> impl Iterator<Item = (&str, u32)> {
+        self.labels
+            .iter()
+            .filter(|(name, _)| {
+                !parser::is_generated_label(name) && !self.constants.contains(*name)
+            })
+            .map(|(name, value)| (name.as_str(), *value))
+    }
+
+    /// The number of instructions that the assembler added to heal each line,
+    /// keyed by line number.
+    /// Lines that were not healed are omitted, as are words in literal pools.
+    pub fn healed_lines(&self) -> BTreeMap<usize, usize> {
+        let mut lines = BTreeMap::new();
+        for span in &self.source_map {
+            if span.synthesized && !span.data {
+                *lines.entry(span.line_number).or_default() += 1;
+            }
+        }
+        lines
+    }
+
+    /// The number of the source line that produced the word containing this address.
+    pub fn line_for_address(&self, address: u32) -> Option<usize> {
+        self.source_map
+            .iter()
+            .find(|span| span.address == address & !0b11)
+            .map(|span| span.line_number)
+    }
+}
+
+/// Whether a label with this name can be written back into source code.
+/// Local labels are stored under mangled names such as `1.0`,
+/// and generated labels use a prefix that users may not define.
+pub(crate) fn is_source_label(name: &str) -> bool {
+    macros::is_identifier(name) && !parser::is_generated_label(name)
+}
+
+/// Records which source line produced a word of the assembler's output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SourceSpan {
+    pub address: u32,
+    pub line_number: usize,
+    /// True if this word was written by a directive such as `DW`, rather than by an instruction.
+    pub data: bool,
+    /// True if the assembler added this word itself, such as to heal an operand
+    /// or in a literal pool, rather than it being written by the user.
+    pub synthesized: bool,
+}
+
+/// The most bytes of padding that a single directive may add to the program,
+/// so that a mistaken `ALIGN` or `ORG` can't take up gigabytes of memory.
+pub const MAX_PADDING: u32 = 16 << 20;
+
 #[derive(Debug)]
 pub struct AssemblerError {
     pub line_number: usize,
@@ -28,51 +117,248 @@ pub enum LineError {
     ShiftOutOfRange,
     MisalignedBranchOffset,
     OffsetOutOfRange,
+    /// The literal pool used by an `LDR Rd,=value` was too far from it to be loaded.
+    LiteralPoolOutOfRange,
     ImmediateOutOfRange(u32),
     InvalidShiftType,
     InvalidStoreSize,
     AddressTooComplex,
+    /// A block data transfer had no registers to transfer.
+    EmptyRegisterList,
+    /// R15 was used where the data sheet forbids it, such as in a swap.
+    InvalidUseOfPc,
+    /// The argument of `ALIGN` was not a power of two.
+    InvalidAlignment(u32),
+    /// `ALIGN` would move past the end of memory.
+    AlignmentOverflow(u32),
+    /// A directive would pad the program with more than [`MAX_PADDING`] bytes.
+    PaddingTooLarge(u32),
+    /// An expression divided by zero or took a remainder modulo zero.
+    DivisionByZero,
+    /// The argument of `ORG` was not word-aligned.
+    MisalignedOrigin(u32),
+    /// `ORG` tried to move back over words that were already assembled.
+    OriginBackwards(u32),
+    /// A warning was raised while [`AssemblerOptions::deny_warnings`] was set.
+    DeniedWarning(LineWarning),
     TooManyPasses,
+    /// An `IF` had no matching `ENDIF`.
+    UnclosedIf,
+    /// An `ELSE` was not inside an `IF`, or followed another `ELSE` for the same `IF`.
+    UnmatchedElse,
+    /// An `ENDIF` had no matching `IF`.
+    UnmatchedEndIf,
+    /// The condition of an `IF` referred to a symbol that was not defined before it.
+    UnresolvedCondition(String),
+    /// A macro was used inside itself, or macros were nested too deeply.
+    MacroTooDeep(String),
+    /// An error on a line that was expanded from the body of a macro.
+    InMacro {
+        name: String,
+        /// The line of the macro's body.
+        line_number: usize,
+        error: Box<LineError>,
+    },
+}
+
+impl LineError {
+    /// Record that this error came from the given line of a macro's body, if any.
+    fn in_macro(self, macro_line: Option<&MacroLine>) -> LineError {
+        match macro_line {
+            Some(macro_line) => LineError::InMacro {
+                name: macro_line.name.clone(),
+                line_number: macro_line.line_number,
+                error: Box::new(self),
+            },
+            None => self,
+        }
+    }
 }
 
 impl Display for LineError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             LineError::ParseError(s) => write!(f, "{s}"),
+            LineError::LabelNotFound(label)
+                if label.ends_with('b') && parser::is_local_label(label) =>
+            {
+                write!(
+                    f,
+                    "local label '{label}' not found; there is no '{}:' before it",
+                    &label[..label.len() - 1]
+                )
+            }
+            LineError::LabelNotFound(label)
+                if label.ends_with('f') && parser::is_local_label(label) =>
+            {
+                write!(
+                    f,
+                    "local label '{label}' not found; there is no '{}:' after it",
+                    &label[..label.len() - 1]
+                )
+            }
             LineError::LabelNotFound(label) => write!(f, "label '{label}' not found"),
             LineError::ShiftOutOfRange => write!(f, "shift out of range"),
             LineError::MisalignedBranchOffset => write!(f, "branch offset was not 4-byte aligned"),
             LineError::OffsetOutOfRange => write!(f, "offset out of range"),
+            LineError::LiteralPoolOutOfRange => write!(
+                f,
+                "offset to literal pool out of range; add an LTORG within 4KiB of this load"
+            ),
             LineError::ImmediateOutOfRange(n) => write!(f, "value {n} out of range"),
             LineError::InvalidShiftType => write!(f, "invalid shift type"),
             LineError::InvalidStoreSize => write!(f, "invalid store size"),
             LineError::AddressTooComplex => write!(f, "address too complex for this instruction"),
+            LineError::EmptyRegisterList => write!(f, "register list is empty"),
+            LineError::InvalidUseOfPc => write!(f, "R15 cannot be used in this instruction"),
+            LineError::InvalidAlignment(n) => write!(f, "alignment {n} is not a power of two"),
+            LineError::AlignmentOverflow(n) => {
+                write!(f, "aligning to {n} would move past the end of memory")
+            }
+            LineError::PaddingTooLarge(n) => write!(
+                f,
+                "padding of {n} bytes is larger than the limit of {MAX_PADDING} bytes"
+            ),
+            LineError::DivisionByZero => write!(f, "division by zero"),
+            LineError::MisalignedOrigin(n) => {
+                write!(f, "origin 0x{n:08X} was not 4-byte aligned")
+            }
+            LineError::OriginBackwards(n) => write!(
+                f,
+                "origin 0x{n:08X} is behind code that was already assembled"
+            ),
+            LineError::DeniedWarning(warning) => write!(f, "{warning}"),
             LineError::TooManyPasses => {
                 write!(f, "too many passes were needed to assemble; aborting")
             }
+            LineError::UnclosedIf => write!(f, "IF has no matching ENDIF"),
+            LineError::UnmatchedElse => write!(f, "ELSE must follow an IF, and at most once"),
+            LineError::UnmatchedEndIf => write!(f, "ENDIF has no matching IF"),
+            LineError::UnresolvedCondition(name) => write!(
+                f,
+                "condition refers to '{name}', which must be defined before the IF"
+            ),
+            LineError::MacroTooDeep(name) => write!(
+                f,
+                "macro '{name}' was nested too deeply; does it use itself?"
+            ),
+            LineError::InMacro {
+                name,
+                line_number,
+                error,
+            } => write!(f, "{error} (in macro '{name}' on line {line_number})"),
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
 pub struct AssemblerWarning {
     pub line_number: usize,
     pub warning: LineWarning,
 }
 
-#[derive(Debug)]
-pub enum LineWarning {}
+#[derive(Debug, PartialEq, Eq)]
+pub enum LineWarning {
+    /// A data-processing instruction wrote to the program counter.
+    PcWrite,
+    /// A data-processing instruction other than `ADD`, `SUB` or `MOV` wrote to the stack pointer.
+    SpWrite,
+    /// A single data transfer wrote back to its base register, which was also its data register.
+    WriteBackToDataRegister,
+    /// A block data transfer wrote back to its base register, which was also in its register list.
+    BaseInRegisterList,
+    /// An unconditional branch went to the instruction immediately after it.
+    UselessBranch,
+    /// A label other than `main` or `start` was defined but never referred to.
+    UnusedLabel(String),
+}
+
+impl Display for LineWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LineWarning::PcWrite => write!(
+                f,
+                "data-processing instruction writes to PC; use B or BX if a simple branch was intended"
+            ),
+            LineWarning::SpWrite => write!(
+                f,
+                "data-processing instruction writes to SP; only ADD, SUB and MOV usually keep it valid"
+            ),
+            LineWarning::WriteBackToDataRegister => write!(
+                f,
+                "base register is written back, but is also the data register"
+            ),
+            LineWarning::BaseInRegisterList => write!(
+                f,
+                "base register is written back, but is also in the register list"
+            ),
+            LineWarning::UselessBranch => write!(f, "branch to the next instruction has no effect"),
+            LineWarning::UnusedLabel(label) => write!(f, "label '{label}' is never used"),
+        }
+    }
+}
+
+/// Optional behaviour of the assembler.
+/// The default options are used by [`assemble`].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct AssemblerOptions {
+    /// Emit [`LineWarning::PcWrite`] for data-processing instructions whose destination is R15.
+    pub warn_pc_write: bool,
+    /// Report every warning as an error instead.
+    pub deny_warnings: bool,
+    /// How to deal with operands that cannot be encoded directly.
+    /// If this is `None`, a `; HEAL OFF` or `; HEAL SIMPLE` line in the source chooses,
+    /// and otherwise R12 is used as a scratch register.
+    pub heal: Option<HealStrategy>,
+    /// Symbols to define before assembly, as if with `EQU`, for use in `IF` conditions.
+    /// These take precedence over any `EQU` in the source with the same name.
+    pub defines: BTreeMap<String, u32>,
+}
 
 pub fn assemble(src: &str) -> Result<AssemblerOutput, Vec<AssemblerError>> {
-    crate::assemble::assembler::assemble(
-        crate::assemble::parser::parse(src)?,
-        if src.lines().any(|line| line.trim() == "; HEAL OFF") {
-            HealStrategy::Off
-        } else if src.lines().any(|line| line.trim() == "; HEAL SIMPLE") {
-            HealStrategy::Simple
-        } else {
-            HealStrategy::Advanced(crate::instr::Register::R12)
+    assemble_with_options(src, &AssemblerOptions::default())
+}
+
+/// Assemble the given source code, healing unencodable operands with the given strategy.
+pub fn assemble_with(
+    src: &str,
+    heal: HealStrategy,
+) -> Result<AssemblerOutput, Vec<AssemblerError>> {
+    assemble_with_options(
+        src,
+        &AssemblerOptions {
+            heal: Some(heal),
+            ..Default::default()
         },
     )
-    .map_err(|e| vec![e])
+}
+
+pub fn assemble_with_options(
+    src: &str,
+    options: &AssemblerOptions,
+) -> Result<AssemblerOutput, Vec<AssemblerError>> {
+    assemble_lines(src, options).map(|(_, output)| output)
+}
+
+/// Assemble the given source code, also returning the lines that it was parsed into.
+fn assemble_lines(
+    src: &str,
+    options: &AssemblerOptions,
+) -> Result<(Vec<AsmLine>, AssemblerOutput), Vec<AssemblerError>> {
+    let (lines, symbols) = crate::assemble::parser::parse(src)?;
+    let output = crate::assemble::assembler::assemble(
+        &lines,
+        &symbols,
+        options.heal.unwrap_or_else(|| {
+            if src.lines().any(|line| line.trim() == "; HEAL OFF") {
+                HealStrategy::Off
+            } else if src.lines().any(|line| line.trim() == "; HEAL SIMPLE") {
+                HealStrategy::Simple
+            } else {
+                HealStrategy::Advanced(crate::instr::Register::R12)
+            }
+        }),
+        options,
+    )?;
+    Ok((lines, output))
 }
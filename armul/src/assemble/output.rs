@@ -0,0 +1,181 @@
+//! Writes the assembler's output in formats that can be saved to files.
+
+use std::io::{self, Write};
+
+use crate::{
+    assemble::{AssemblerOutput, parser},
+    instr::Instr,
+};
+
+/// Write the assembled words as little-endian bytes.
+pub fn write_binary(output: &AssemblerOutput, w: &mut impl Write) -> io::Result<()> {
+    for word in &output.instrs {
+        w.write_all(&word.to_le_bytes())?;
+    }
+    w.flush()
+}
+
+/// Write each assembled word as 8 hexadecimal digits on its own line.
+pub fn write_hex(output: &AssemblerOutput, w: &mut impl Write) -> io::Result<()> {
+    for word in &output.instrs {
+        writeln!(w, "{word:08X}")?;
+    }
+    w.flush()
+}
+
+/// Check that the output does not run past the end of the 32-bit address space,
+/// so that the address of every word can be written.
+fn check_fits(output: &AssemblerOutput) -> io::Result<()> {
+    if output.origin as u64 + 4 * output.instrs.len() as u64 > 1 << 32 {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the program runs past the end of the address space",
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Write the assembled words as an Intel HEX file, starting at the origin,
+/// so that they can be given to tools that program real hardware.
+pub fn write_intel_hex(output: &AssemblerOutput, w: &mut impl Write) -> io::Result<()> {
+    check_fits(output)?;
+    let bytes = output
+        .instrs
+        .iter()
+        .flat_map(|word| word.to_le_bytes())
+        .collect::<Vec<_>>();
+    let mut address = output.origin;
+    let mut upper = 0;
+    let mut rest = &bytes[..];
+    while !rest.is_empty() {
+        // Each data record holds only the low 16 bits of its address.
+        if address >> 16 != upper {
+            upper = address >> 16;
+            write_intel_hex_record(w, 0, 4, &(upper as u16).to_be_bytes())?;
+        }
+        // Records may not cross a 64KiB boundary.
+        let len = rest
+            .len()
+            .min(16)
+            .min(0x10000 - (address & 0xFFFF) as usize);
+        let (chunk, tail) = rest.split_at(len);
+        write_intel_hex_record(w, address as u16, 0, chunk)?;
+        // This only wraps after the last record of a program that ends at the top of memory.
+        address = address.wrapping_add(len as u32);
+        rest = tail;
+    }
+    write_intel_hex_record(w, 0, 1, &[])?;
+    w.flush()
+}
+
+fn write_intel_hex_record(
+    w: &mut impl Write,
+    address: u16,
+    record_type: u8,
+    data: &[u8],
+) -> io::Result<()> {
+    write!(w, ":{:02X}{address:04X}{record_type:02X}", data.len())?;
+    let mut sum = (data.len() as u8)
+        .wrapping_add((address >> 8) as u8)
+        .wrapping_add(address as u8)
+        .wrapping_add(record_type);
+    for byte in data {
+        write!(w, "{byte:02X}")?;
+        sum = sum.wrapping_add(*byte);
+    }
+    writeln!(w, "{:02X}", sum.wrapping_neg())
+}
+
+/// Write a listing of each assembled word with its address and disassembly.
+pub fn write_words(output: &AssemblerOutput, w: &mut impl Write) -> io::Result<()> {
+    check_fits(output)?;
+    for (index, word) in output.instrs.iter().enumerate() {
+        let disassembly =
+            Instr::decode(*word).map_or_else(|| "???".to_owned(), |(cond, i)| i.display(cond));
+        writeln!(
+            w,
+            "{:08X}: {word:08X} {disassembly}",
+            output.origin + 4 * index as u32
+        )?;
+    }
+    w.flush()
+}
+
+/// Write the value of each symbol as a line of the form `name = 0x00000000`.
+/// Labels that the assembler made up, such as those of literal pool entries, are left out.
+pub fn write_symbols(output: &AssemblerOutput, w: &mut impl Write) -> io::Result<()> {
+    for (name, value) in &output.labels {
+        if !parser::is_generated_label(name) {
+            writeln!(w, "{name} = 0x{value:08X}")?;
+        }
+    }
+    w.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assemble::{
+        AssemblerOutput, assemble,
+        output::{write_binary, write_hex, write_intel_hex, write_symbols, write_words},
+    };
+
+    #[test]
+    fn formats() {
+        let output = assemble("main    mov r0, #1\nend     swi 2\n").unwrap();
+        let write = |f: fn(&AssemblerOutput, &mut Vec<u8>) -> std::io::Result<()>| {
+            let mut buf = Vec::new();
+            f(&output, &mut buf).unwrap();
+            buf
+        };
+        assert_eq!(
+            write(write_binary),
+            [0x01, 0x00, 0xA0, 0xE3, 0x02, 0x00, 0x00, 0xEF]
+        );
+        assert_eq!(
+            String::from_utf8(write(write_hex)).unwrap(),
+            "E3A00001\nEF000002\n"
+        );
+        assert_eq!(
+            String::from_utf8(write(write_intel_hex)).unwrap(),
+            ":080000000100A0E3020000EF83\n:00000001FF\n"
+        );
+        assert_eq!(
+            String::from_utf8(write(write_words)).unwrap(),
+            "00000000: E3A00001 MOV R0,#1\n00000004: EF000002 SWI 2\n"
+        );
+        assert_eq!(
+            String::from_utf8(write(write_symbols)).unwrap(),
+            "end = 0x00000004\nmain = 0x00000000\n"
+        );
+
+        // Code above 64KiB needs an extended address record.
+        let output = assemble("        org 0x10000\n        mov r0, #1\n").unwrap();
+        let mut buf = Vec::new();
+        write_intel_hex(&output, &mut buf).unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            ":020000040001F9\n:040000000100A0E378\n:00000001FF\n"
+        );
+
+        // The label of the literal pool entry is not a symbol.
+        let output = assemble("main    ldr r0, =0x12345678\n").unwrap();
+        let mut buf = Vec::new();
+        write_symbols(&output, &mut buf).unwrap();
+        assert_eq!(String::from_utf8(buf).unwrap(), "main = 0x00000000\n");
+
+        // A program may end at the top of memory, but not run past it.
+        let mut output = assemble("        dw 1, 2\n").unwrap();
+        output.origin = 0xFFFFFFF8;
+        let mut buf = Vec::new();
+        write_words(&output, &mut buf).unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "FFFFFFF8: 00000001 ANDEQ R0,R0,R1\nFFFFFFFC: 00000002 ANDEQ R0,R0,R2\n"
+        );
+        assert!(write_intel_hex(&output, &mut Vec::new()).is_ok());
+        output.origin = 0xFFFFFFFC;
+        assert!(write_words(&output, &mut Vec::new()).is_err());
+        assert!(write_intel_hex(&output, &mut Vec::new()).is_err());
+    }
+}
@@ -1,36 +1,52 @@
 //! A parser for ARM assembly.
 
 use std::{
-    cell::Cell,
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet},
     fmt::{Debug, Display},
     rc::Rc,
 };
 
 use chumsky::{
     input::{Stream, ValueInput},
-    pratt::{infix, left},
+    pratt::{infix, left, prefix},
     prelude::*,
 };
 use logos::Logos;
 
 use crate::{
     assemble::{
-        AssemblerError, LineError,
+        AssemblerError, LineError, macros,
+        symbol::{SymbolId, SymbolTable},
         syntax::{
-            AnyTransferSize, AsmInstr, AsmLine, AsmLineContents, DataOperand, Expression,
-            MsrSource, Shift, ShiftAmount,
+            AsmInstr, AsmLine, AsmLineContents, DataOperand, Expression, MsrSource, Shift,
+            ShiftAmount,
         },
     },
     instr::{
-        Cond, DataOp, Psr, Register, ShiftType, TransferKind, TransferSize, TransferSizeSpecial,
+        Cond, DataOp, InstrCategory, Psr, PsrFields, Register, ShiftType, TransferKind,
+        TransferSize,
     },
 };
 
-pub fn parse(src: &str) -> Result<Vec<AsmLine>, Vec<AssemblerError>> {
-    let token_iter = Token::lexer(src).spanned().map(|(tok, span)| match tok {
-        Ok(tok) => (tok.disambiguate(), span.into()),
-        Err(err) => (Token::Error(err), span.into()),
-    });
+/// Parse the given source, returning its lines along with the table
+/// of label names that they refer to.
+/// Macros are expanded first, and each line is given the line number that it came from.
+pub fn parse(src: &str) -> Result<(Vec<AsmLine>, SymbolTable), Vec<AssemblerError>> {
+    let expansion = macros::expand(src)?;
+    let src = &expansion.text();
+    let tokens = Token::lexer(src)
+        .spanned()
+        .map(|(tok, span)| match tok {
+            Ok(tok) => (tok.disambiguate(), span.into()),
+            Err(err) => (Token::Error(err), span.into()),
+        })
+        .collect::<Vec<(Token, SimpleSpan)>>();
+    let local_labels = local_label_names(&tokens);
+    let token_iter = tokens
+        .into_iter()
+        .zip(&local_labels)
+        .map(|((tok, span), name)| (name.as_deref().map_or(tok, Token::Name), span));
 
     let token_stream =
         Stream::from_iter(token_iter).map((0..src.len()).into(), |(t, s): (_, _)| (t, s));
@@ -41,7 +57,8 @@ pub fn parse(src: &str) -> Result<Vec<AsmLine>, Vec<AssemblerError>> {
         .map(|(index, _)| index)
         .collect::<Vec<_>>();
 
-    parser(&line_indices, &Default::default())
+    let symbols = Rc::new(RefCell::new(SymbolTable::default()));
+    let mut lines = parser(&line_indices, &symbols, &expansion.labels)
         .parse(token_stream)
         .into_result()
         .map_err(|errs| {
@@ -54,13 +71,21 @@ pub fn parse(src: &str) -> Result<Vec<AsmLine>, Vec<AssemblerError>> {
                             .copied()
                             .unwrap_or(line_indices.last().copied().unwrap_or_default()),
                     ) + 1;
+                    let (line, macro_line) = expansion.origin(line);
                     AssemblerError {
                         line_number: line,
-                        error: LineError::ParseError(format!("{line}:{col}: {err}")),
+                        error: LineError::ParseError(format!("{line}:{col}: {err}"))
+                            .in_macro(macro_line),
                     }
                 })
-                .collect()
-        })
+                .collect::<Vec<_>>()
+        })?;
+    for line in &mut lines {
+        let (line_number, macro_line) = expansion.origin(line.line_number);
+        line.line_number = line_number;
+        line.macro_line = macro_line.cloned();
+    }
+    Ok((lines, symbols.take()))
 }
 
 #[derive(Logos, Clone, PartialEq)]
@@ -74,8 +99,8 @@ enum Token<'a> {
 
     Register(Register),
     Opcode((Cond, Opcode)),
-    /// The bool is whether a `_flg` suffix was present.
-    Psr((Psr, bool)),
+    /// A PSR, with the fields selected by its suffix.
+    Psr((Psr, PsrFields)),
 
     #[regex("[0-9](?&numbertail)", |lex| lex.slice().parse::<u32>())]
     Integer(u32),
@@ -86,6 +111,15 @@ enum Token<'a> {
     #[regex("0[xX](?&numbertail)", |lex| u32::from_str_radix(&lex.slice()[2..], 16))]
     HexInteger(u32),
 
+    /// A reference to a numeric local label, such as `1b` for the previous `1:`,
+    /// or `1f` for the next. The bool is true for a forward reference.
+    #[regex("[0-9]+[bf]", priority = 10, callback = |lex| {
+        let slice = lex.slice();
+        let forward = slice.ends_with('f');
+        slice[..slice.len() - 1].parse::<u32>().map(|label| (label, forward))
+    })]
+    LocalLabel((u32, bool)),
+
     #[regex("0[oO](?&numbertail)", |lex| u32::from_str_radix(&lex.slice()[2..], 8))]
     OctalInteger(u32),
 
@@ -121,6 +155,12 @@ enum Token<'a> {
     Exclamation,
     #[token("^")]
     Caret,
+    #[token(".")]
+    Dot,
+    #[token("=")]
+    Equals,
+    #[token(":")]
+    Colon,
 
     #[regex(r"[ \t\f]+")]
     Whitespace,
@@ -130,153 +170,54 @@ enum Token<'a> {
 
     #[regex(r";[^\n]*", allow_greedy = true)]
     Comment(&'a str),
+
+    /// A double-quoted string, with its escape sequences already replaced.
+    #[regex(r#""([^"\\\n]|\\[^\n])*""#, |lex| {
+        let slice = lex.slice();
+        unescape_string(&slice[1..slice.len() - 1])
+    })]
+    String(String),
+}
+
+/// The register with this lowercase name, such as `r12` or `lr`.
+pub(super) fn disambiguate_register(name: &str) -> Option<Register> {
+    match name {
+        "r0" => Some(Register::R0),
+        "r1" => Some(Register::R1),
+        "r2" => Some(Register::R2),
+        "r3" => Some(Register::R3),
+        "r4" => Some(Register::R4),
+        "r5" => Some(Register::R5),
+        "r6" => Some(Register::R6),
+        "r7" => Some(Register::R7),
+        "r8" => Some(Register::R8),
+        "r9" => Some(Register::R9),
+        "r10" => Some(Register::R10),
+        "r11" => Some(Register::R11),
+        "r12" => Some(Register::R12),
+        "r13" => Some(Register::R13),
+        "sp" => Some(Register::R13),
+        "r14" => Some(Register::R14),
+        "lr" => Some(Register::R14),
+        "r15" => Some(Register::R15),
+        "pc" => Some(Register::R15),
+        _ => None,
+    }
 }
 
 impl<'a> Token<'a> {
     fn disambiguate(self) -> Token<'a> {
-        fn disambiguate_register(name: &str) -> Option<Register> {
-            match name {
-                "r0" => Some(Register::R0),
-                "r1" => Some(Register::R1),
-                "r2" => Some(Register::R2),
-                "r3" => Some(Register::R3),
-                "r4" => Some(Register::R4),
-                "r5" => Some(Register::R5),
-                "r6" => Some(Register::R6),
-                "r7" => Some(Register::R7),
-                "r8" => Some(Register::R8),
-                "r9" => Some(Register::R9),
-                "r10" => Some(Register::R10),
-                "r11" => Some(Register::R11),
-                "r12" => Some(Register::R12),
-                "r13" => Some(Register::R13),
-                "sp" => Some(Register::R13),
-                "r14" => Some(Register::R14),
-                "lr" => Some(Register::R14),
-                "r15" => Some(Register::R15),
-                "pc" => Some(Register::R15),
-                _ => None,
-            }
-        }
-
-        #[rustfmt::skip]
-        fn disambiguate_mnemonic(name: &str) -> Option<(Cond, Opcode)> {
-            for (prefix, suffix, opcode) in [
-                ("bx", "", Opcode::BranchExchange),
-                ("b", "", Opcode::Branch { link: false }),
-                ("bl", "", Opcode::Branch { link: true }),
-                ("adr", "", Opcode::Adr),
-                ("adrl", "", Opcode::Adr),
-                ("nop", "", Opcode::Nop),
-                ("and", "", Opcode::Data(false, DataOp::And)),
-                ("and", "s", Opcode::Data(true, DataOp::And)),
-                ("eor", "", Opcode::Data(false, DataOp::Eor)),
-                ("eor", "s", Opcode::Data(true, DataOp::Eor)),
-                ("sub", "", Opcode::Data(false, DataOp::Sub)),
-                ("sub", "s", Opcode::Data(true, DataOp::Sub)),
-                ("rsb", "", Opcode::Data(false, DataOp::Rsb)),
-                ("rsb", "s", Opcode::Data(true, DataOp::Rsb)),
-                ("add", "", Opcode::Data(false, DataOp::Add)),
-                ("add", "s", Opcode::Data(true, DataOp::Add)),
-                ("adc", "", Opcode::Data(false, DataOp::Adc)),
-                ("adc", "s", Opcode::Data(true, DataOp::Adc)),
-                ("sbc", "", Opcode::Data(false, DataOp::Sbc)),
-                ("sbc", "s", Opcode::Data(true, DataOp::Sbc)),
-                ("rsc", "", Opcode::Data(false, DataOp::Rsc)),
-                ("rsc", "s", Opcode::Data(true, DataOp::Rsc)),
-                ("tst", "", Opcode::Data(true, DataOp::Tst)),
-                ("teq", "", Opcode::Data(true, DataOp::Teq)),
-                ("cmp", "", Opcode::Data(true, DataOp::Cmp)),
-                ("cmn", "", Opcode::Data(true, DataOp::Cmn)),
-                ("orr", "", Opcode::Data(false, DataOp::Orr)),
-                ("orr", "s", Opcode::Data(true, DataOp::Orr)),
-                ("mov", "", Opcode::Data(false, DataOp::Mov)),
-                ("mov", "s", Opcode::Data(true, DataOp::Mov)),
-                ("bic", "", Opcode::Data(false, DataOp::Bic)),
-                ("bic", "s", Opcode::Data(true, DataOp::Bic)),
-                ("mvn", "", Opcode::Data(false, DataOp::Mvn)),
-                ("mvn", "s", Opcode::Data(true, DataOp::Mvn)),
-                ("lsl", "", Opcode::Shift(false, ShiftType::LogicalLeft)),
-                ("lsl", "s", Opcode::Shift(true, ShiftType::LogicalLeft)),
-                ("asl", "", Opcode::Shift(false, ShiftType::LogicalLeft)),
-                ("asl", "s", Opcode::Shift(true, ShiftType::LogicalLeft)),
-                ("lsr", "", Opcode::Shift(false, ShiftType::LogicalRight)),
-                ("lsr", "s", Opcode::Shift(true, ShiftType::LogicalRight)),
-                ("asr", "", Opcode::Shift(false, ShiftType::ArithmeticRight)),
-                ("asr", "s", Opcode::Shift(true, ShiftType::ArithmeticRight)),
-                ("ror", "", Opcode::Shift(false, ShiftType::RotateRight)),
-                ("ror", "s", Opcode::Shift(true, ShiftType::RotateRight)),
-                ("rrx", "", Opcode::Shift(false, ShiftType::RotateRightExtended)),
-                ("rrx", "s", Opcode::Shift(true, ShiftType::RotateRightExtended)),
-                ("mrs", "", Opcode::Mrs),
-                ("msr", "", Opcode::Msr),
-                ("mul", "", Opcode::Mul(false, false)),
-                ("mul", "s", Opcode::Mul(true, false)),
-                ("mla", "", Opcode::Mul(false, true)),
-                ("mla", "s", Opcode::Mul(true, true)),
-                ("umull", "", Opcode::MulLong(false, false, false)),
-                ("umull", "s", Opcode::MulLong(true, false, false)),
-                ("umlal", "", Opcode::MulLong(false, false, true)),
-                ("umlal", "s", Opcode::MulLong(true, false, true)),
-                ("smull", "", Opcode::MulLong(false, true, false)),
-                ("smull", "s", Opcode::MulLong(true, true, false)),
-                ("smlal", "", Opcode::MulLong(false, true, true)),
-                ("smlal", "s", Opcode::MulLong(true, true, true)),
-                ("ldr", "", Opcode::SingleTransfer(TransferKind::Load, AnyTransferSize::Normal(TransferSize::Word), false)),
-                ("ldr", "b", Opcode::SingleTransfer(TransferKind::Load, AnyTransferSize::Normal(TransferSize::Byte), false)),
-                ("ldr", "t", Opcode::SingleTransfer(TransferKind::Load, AnyTransferSize::Normal(TransferSize::Word), true)),
-                ("ldr", "bt", Opcode::SingleTransfer(TransferKind::Load, AnyTransferSize::Normal(TransferSize::Byte), true)),
-                ("ldr", "h", Opcode::SingleTransfer(TransferKind::Load, AnyTransferSize::Special(TransferSizeSpecial::HalfWord), false)),
-                ("ldr", "sh", Opcode::SingleTransfer(TransferKind::Load, AnyTransferSize::Special(TransferSizeSpecial::SignExtendedHalfWord), false)),
-                ("ldr", "sb", Opcode::SingleTransfer(TransferKind::Load, AnyTransferSize::Special(TransferSizeSpecial::SignExtendedByte), false)),
-                ("str", "", Opcode::SingleTransfer(TransferKind::Store, AnyTransferSize::Normal(TransferSize::Word), false)),
-                ("str", "b", Opcode::SingleTransfer(TransferKind::Store, AnyTransferSize::Normal(TransferSize::Byte), false)),
-                ("str", "t", Opcode::SingleTransfer(TransferKind::Store, AnyTransferSize::Normal(TransferSize::Word), true)),
-                ("str", "bt", Opcode::SingleTransfer(TransferKind::Store, AnyTransferSize::Normal(TransferSize::Byte), true)),
-                ("str", "h", Opcode::SingleTransfer(TransferKind::Store, AnyTransferSize::Special(TransferSizeSpecial::HalfWord), false)),
-                ("str", "sh", Opcode::SingleTransfer(TransferKind::Store, AnyTransferSize::Special(TransferSizeSpecial::SignExtendedHalfWord), false)),
-                ("str", "sb", Opcode::SingleTransfer(TransferKind::Store, AnyTransferSize::Special(TransferSizeSpecial::SignExtendedByte), false)),
-                ("ldm", "fd", Opcode::BlockTransfer(TransferKind::Load, true, false)),
-                ("ldm", "ed", Opcode::BlockTransfer(TransferKind::Load, true, true)),
-                ("ldm", "fa", Opcode::BlockTransfer(TransferKind::Load, false, false)),
-                ("ldm", "ea", Opcode::BlockTransfer(TransferKind::Load, false, true)),
-                ("ldm", "ia", Opcode::BlockTransfer(TransferKind::Load, true, false)),
-                ("ldm", "ib", Opcode::BlockTransfer(TransferKind::Load, true, true)),
-                ("ldm", "da", Opcode::BlockTransfer(TransferKind::Load, false, false)),
-                ("ldm", "db", Opcode::BlockTransfer(TransferKind::Load, false, true)),
-                ("stm", "ea", Opcode::BlockTransfer(TransferKind::Store, true, false)),
-                ("stm", "fa", Opcode::BlockTransfer(TransferKind::Store, true, true)),
-                ("stm", "ed", Opcode::BlockTransfer(TransferKind::Store, false, false)),
-                ("stm", "fd", Opcode::BlockTransfer(TransferKind::Store, false, true)),
-                ("stm", "db", Opcode::BlockTransfer(TransferKind::Store, true, false)),
-                ("stm", "da", Opcode::BlockTransfer(TransferKind::Store, true, true)),
-                ("stm", "ib", Opcode::BlockTransfer(TransferKind::Store, false, false)),
-                ("stm", "ia", Opcode::BlockTransfer(TransferKind::Store, false, true)),
-                ("swp", "", Opcode::Swap(false)),
-                ("swp", "b", Opcode::Swap(true)),
-                ("swi", "", Opcode::Swi),
-                ("equ", "", Opcode::Equ),
-                ("dw", "", Opcode::DefW),
-                ("defw", "", Opcode::DefW),
-            ] {
-                if let Some(tail) = name.strip_prefix(prefix)
-                    && let Some(cond) = tail.strip_suffix(suffix)
-                    && let Ok(cond) = cond.parse()
-                {
-                    return Some((cond, opcode));
-                }
-            }
-            None
-        }
-
-        fn disambiguate_psr(name: &str) -> Option<(Psr, bool)> {
-            match name {
-                "cpsr" | "cpsr_all" => Some((Psr::Cpsr, false)),
-                "cpsr_flg" => Some((Psr::Cpsr, true)),
-                "spsr" | "spsr_all" => Some((Psr::Spsr, false)),
-                "spsr_flg" => Some((Psr::Spsr, true)),
-                _ => None,
-            }
+        fn disambiguate_psr(name: &str) -> Option<(Psr, PsrFields)> {
+            let (psr, fields) = match name.split_once('_') {
+                Some((psr, suffix)) => (psr, PsrFields::from_suffix(suffix)?),
+                None => (name, PsrFields::DEFAULT),
+            };
+            let psr = match psr {
+                "cpsr" => Psr::Cpsr,
+                "spsr" => Psr::Spsr,
+                _ => return None,
+            };
+            Some((psr, fields))
         }
 
         match self {
@@ -297,13 +238,249 @@ impl<'a> Token<'a> {
     }
 }
 
+/// Describes a mnemonic that the assembler recognises.
+/// Every instruction is described exactly once, in [`MNEMONICS`].
+#[derive(Debug, Clone, Copy)]
+pub struct MnemonicSpec {
+    /// The mnemonic without its condition code or suffix, such as `ldr`.
+    base: &'static str,
+    /// The part of the mnemonic after the condition code, such as `b` in `ldreqb`.
+    suffix: &'static str,
+    opcode: Opcode,
+    /// The operands that the instruction takes, such as `Rd,<address>`.
+    syntax: &'static str,
+}
+
+impl MnemonicSpec {
+    const fn new(
+        base: &'static str,
+        suffix: &'static str,
+        opcode: Opcode,
+        syntax: &'static str,
+    ) -> Self {
+        Self {
+            base,
+            suffix,
+            opcode,
+            syntax,
+        }
+    }
+
+    /// The mnemonic in upper case, with `{cond}` where the condition code goes
+    /// if this mnemonic may have one.
+    pub fn template(&self) -> String {
+        let cond = match self.opcode {
+            Opcode::Equ
+            | Opcode::DefW
+            | Opcode::DefB
+            | Opcode::Align
+            | Opcode::Org
+            | Opcode::Ltorg
+            | Opcode::If
+            | Opcode::Else
+            | Opcode::EndIf => "",
+            _ => "{cond}",
+        };
+        format!(
+            "{}{cond}{}",
+            self.base.to_uppercase(),
+            self.suffix.to_uppercase()
+        )
+    }
+
+    pub fn syntax(&self) -> &'static str {
+        self.syntax
+    }
+
+    pub fn category(&self) -> InstrCategory {
+        self.opcode.category()
+    }
+
+    fn find(opcode: Opcode) -> &'static MnemonicSpec {
+        MNEMONICS
+            .iter()
+            .find(|spec| spec.opcode == opcode)
+            .expect("every opcode has a mnemonic")
+    }
+}
+
+/// Every mnemonic that the assembler recognises.
+/// Where several mnemonics share an opcode, the first is the one that is displayed.
+/// The order matters when disambiguating: `bls` is `b` with condition `ls`, not `bl` with `s`.
+#[rustfmt::skip]
+static MNEMONICS: &[MnemonicSpec] = &[
+    MnemonicSpec::new("bx", "", Opcode::BranchExchange, "Rn"),
+    MnemonicSpec::new("b", "", Opcode::Branch { link: false }, "<offset>"),
+    MnemonicSpec::new("bl", "", Opcode::Branch { link: true }, "<offset>"),
+    MnemonicSpec::new("adr", "", Opcode::Adr { long: false }, "Rd,<expression>"),
+    MnemonicSpec::new("adrl", "", Opcode::Adr { long: true }, "Rd,<expression>"),
+    MnemonicSpec::new("nop", "", Opcode::Nop, ""),
+    MnemonicSpec::new("and", "", Opcode::Data(false, DataOp::And), "Rd,Rn,<Op2>"),
+    MnemonicSpec::new("and", "s", Opcode::Data(true, DataOp::And), "Rd,Rn,<Op2>"),
+    MnemonicSpec::new("eor", "", Opcode::Data(false, DataOp::Eor), "Rd,Rn,<Op2>"),
+    MnemonicSpec::new("eor", "s", Opcode::Data(true, DataOp::Eor), "Rd,Rn,<Op2>"),
+    MnemonicSpec::new("sub", "", Opcode::Data(false, DataOp::Sub), "Rd,Rn,<Op2>"),
+    MnemonicSpec::new("sub", "s", Opcode::Data(true, DataOp::Sub), "Rd,Rn,<Op2>"),
+    MnemonicSpec::new("rsb", "", Opcode::Data(false, DataOp::Rsb), "Rd,Rn,<Op2>"),
+    MnemonicSpec::new("rsb", "s", Opcode::Data(true, DataOp::Rsb), "Rd,Rn,<Op2>"),
+    MnemonicSpec::new("add", "", Opcode::Data(false, DataOp::Add), "Rd,Rn,<Op2>"),
+    MnemonicSpec::new("add", "s", Opcode::Data(true, DataOp::Add), "Rd,Rn,<Op2>"),
+    MnemonicSpec::new("adc", "", Opcode::Data(false, DataOp::Adc), "Rd,Rn,<Op2>"),
+    MnemonicSpec::new("adc", "s", Opcode::Data(true, DataOp::Adc), "Rd,Rn,<Op2>"),
+    MnemonicSpec::new("sbc", "", Opcode::Data(false, DataOp::Sbc), "Rd,Rn,<Op2>"),
+    MnemonicSpec::new("sbc", "s", Opcode::Data(true, DataOp::Sbc), "Rd,Rn,<Op2>"),
+    MnemonicSpec::new("rsc", "", Opcode::Data(false, DataOp::Rsc), "Rd,Rn,<Op2>"),
+    MnemonicSpec::new("rsc", "s", Opcode::Data(true, DataOp::Rsc), "Rd,Rn,<Op2>"),
+    MnemonicSpec::new("tst", "", Opcode::Data(true, DataOp::Tst), "Rn,<Op2>"),
+    MnemonicSpec::new("teq", "", Opcode::Data(true, DataOp::Teq), "Rn,<Op2>"),
+    MnemonicSpec::new("cmp", "", Opcode::Data(true, DataOp::Cmp), "Rn,<Op2>"),
+    MnemonicSpec::new("cmn", "", Opcode::Data(true, DataOp::Cmn), "Rn,<Op2>"),
+    MnemonicSpec::new("orr", "", Opcode::Data(false, DataOp::Orr), "Rd,Rn,<Op2>"),
+    MnemonicSpec::new("orr", "s", Opcode::Data(true, DataOp::Orr), "Rd,Rn,<Op2>"),
+    MnemonicSpec::new("mov", "", Opcode::Data(false, DataOp::Mov), "Rd,<Op2>"),
+    MnemonicSpec::new("mov", "s", Opcode::Data(true, DataOp::Mov), "Rd,<Op2>"),
+    MnemonicSpec::new("bic", "", Opcode::Data(false, DataOp::Bic), "Rd,Rn,<Op2>"),
+    MnemonicSpec::new("bic", "s", Opcode::Data(true, DataOp::Bic), "Rd,Rn,<Op2>"),
+    MnemonicSpec::new("mvn", "", Opcode::Data(false, DataOp::Mvn), "Rd,<Op2>"),
+    MnemonicSpec::new("mvn", "s", Opcode::Data(true, DataOp::Mvn), "Rd,<Op2>"),
+    MnemonicSpec::new("lsl", "", Opcode::Shift(false, ShiftType::LogicalLeft), "Rd,Rn,<Op2>"),
+    MnemonicSpec::new("lsl", "s", Opcode::Shift(true, ShiftType::LogicalLeft), "Rd,Rn,<Op2>"),
+    MnemonicSpec::new("asl", "", Opcode::Shift(false, ShiftType::LogicalLeft), "Rd,Rn,<Op2>"),
+    MnemonicSpec::new("asl", "s", Opcode::Shift(true, ShiftType::LogicalLeft), "Rd,Rn,<Op2>"),
+    MnemonicSpec::new("lsr", "", Opcode::Shift(false, ShiftType::LogicalRight), "Rd,Rn,<Op2>"),
+    MnemonicSpec::new("lsr", "s", Opcode::Shift(true, ShiftType::LogicalRight), "Rd,Rn,<Op2>"),
+    MnemonicSpec::new("asr", "", Opcode::Shift(false, ShiftType::ArithmeticRight), "Rd,Rn,<Op2>"),
+    MnemonicSpec::new("asr", "s", Opcode::Shift(true, ShiftType::ArithmeticRight), "Rd,Rn,<Op2>"),
+    MnemonicSpec::new("ror", "", Opcode::Shift(false, ShiftType::RotateRight), "Rd,Rn,<Op2>"),
+    MnemonicSpec::new("ror", "s", Opcode::Shift(true, ShiftType::RotateRight), "Rd,Rn,<Op2>"),
+    MnemonicSpec::new("rrx", "", Opcode::Shift(false, ShiftType::RotateRightExtended), "Rd,Rm"),
+    MnemonicSpec::new("rrx", "s", Opcode::Shift(true, ShiftType::RotateRightExtended), "Rd,Rm"),
+    MnemonicSpec::new("mrs", "", Opcode::Mrs, "Rd,<psr>"),
+    MnemonicSpec::new("msr", "", Opcode::Msr, "<psr>,Rm"),
+    MnemonicSpec::new("mul", "", Opcode::Mul(false, false), "Rd,Rm,Rs"),
+    MnemonicSpec::new("mul", "s", Opcode::Mul(true, false), "Rd,Rm,Rs"),
+    MnemonicSpec::new("mla", "", Opcode::Mul(false, true), "Rd,Rm,Rs,Rn"),
+    MnemonicSpec::new("mla", "s", Opcode::Mul(true, true), "Rd,Rm,Rs,Rn"),
+    MnemonicSpec::new("umull", "", Opcode::MulLong(false, false, false), "RdLo,RdHi,Rm,Rs"),
+    MnemonicSpec::new("umull", "s", Opcode::MulLong(true, false, false), "RdLo,RdHi,Rm,Rs"),
+    MnemonicSpec::new("umlal", "", Opcode::MulLong(false, false, true), "RdLo,RdHi,Rm,Rs"),
+    MnemonicSpec::new("umlal", "s", Opcode::MulLong(true, false, true), "RdLo,RdHi,Rm,Rs"),
+    MnemonicSpec::new("smull", "", Opcode::MulLong(false, true, false), "RdLo,RdHi,Rm,Rs"),
+    MnemonicSpec::new("smull", "s", Opcode::MulLong(true, true, false), "RdLo,RdHi,Rm,Rs"),
+    MnemonicSpec::new("smlal", "", Opcode::MulLong(false, true, true), "RdLo,RdHi,Rm,Rs"),
+    MnemonicSpec::new("smlal", "s", Opcode::MulLong(true, true, true), "RdLo,RdHi,Rm,Rs"),
+    MnemonicSpec::new("ldr", "", Opcode::SingleTransfer(TransferKind::Load, TransferSize::Word, false), "Rd,<address>"),
+    MnemonicSpec::new("ldr", "b", Opcode::SingleTransfer(TransferKind::Load, TransferSize::Byte, false), "Rd,<address>"),
+    MnemonicSpec::new("ldr", "t", Opcode::SingleTransfer(TransferKind::Load, TransferSize::Word, true), "Rd,<address>"),
+    MnemonicSpec::new("ldr", "bt", Opcode::SingleTransfer(TransferKind::Load, TransferSize::Byte, true), "Rd,<address>"),
+    MnemonicSpec::new("ldr", "h", Opcode::SingleTransfer(TransferKind::Load, TransferSize::HalfWord, false), "Rd,<address>"),
+    MnemonicSpec::new("ldr", "sh", Opcode::SingleTransfer(TransferKind::Load, TransferSize::SignExtendedHalfWord, false), "Rd,<address>"),
+    MnemonicSpec::new("ldr", "sb", Opcode::SingleTransfer(TransferKind::Load, TransferSize::SignExtendedByte, false), "Rd,<address>"),
+    MnemonicSpec::new("str", "", Opcode::SingleTransfer(TransferKind::Store, TransferSize::Word, false), "Rd,<address>"),
+    MnemonicSpec::new("str", "b", Opcode::SingleTransfer(TransferKind::Store, TransferSize::Byte, false), "Rd,<address>"),
+    MnemonicSpec::new("str", "t", Opcode::SingleTransfer(TransferKind::Store, TransferSize::Word, true), "Rd,<address>"),
+    MnemonicSpec::new("str", "bt", Opcode::SingleTransfer(TransferKind::Store, TransferSize::Byte, true), "Rd,<address>"),
+    MnemonicSpec::new("str", "h", Opcode::SingleTransfer(TransferKind::Store, TransferSize::HalfWord, false), "Rd,<address>"),
+    MnemonicSpec::new("str", "sh", Opcode::SingleTransfer(TransferKind::Store, TransferSize::SignExtendedHalfWord, false), "Rd,<address>"),
+    MnemonicSpec::new("str", "sb", Opcode::SingleTransfer(TransferKind::Store, TransferSize::SignExtendedByte, false), "Rd,<address>"),
+    MnemonicSpec::new("ldm", "fd", Opcode::BlockTransfer(TransferKind::Load, true, false), "Rn{!},<registers>{^}"),
+    MnemonicSpec::new("ldm", "ed", Opcode::BlockTransfer(TransferKind::Load, true, true), "Rn{!},<registers>{^}"),
+    MnemonicSpec::new("ldm", "fa", Opcode::BlockTransfer(TransferKind::Load, false, false), "Rn{!},<registers>{^}"),
+    MnemonicSpec::new("ldm", "ea", Opcode::BlockTransfer(TransferKind::Load, false, true), "Rn{!},<registers>{^}"),
+    MnemonicSpec::new("ldm", "ia", Opcode::BlockTransfer(TransferKind::Load, true, false), "Rn{!},<registers>{^}"),
+    MnemonicSpec::new("ldm", "ib", Opcode::BlockTransfer(TransferKind::Load, true, true), "Rn{!},<registers>{^}"),
+    MnemonicSpec::new("ldm", "da", Opcode::BlockTransfer(TransferKind::Load, false, false), "Rn{!},<registers>{^}"),
+    MnemonicSpec::new("ldm", "db", Opcode::BlockTransfer(TransferKind::Load, false, true), "Rn{!},<registers>{^}"),
+    MnemonicSpec::new("stm", "ea", Opcode::BlockTransfer(TransferKind::Store, true, false), "Rn{!},<registers>{^}"),
+    MnemonicSpec::new("stm", "fa", Opcode::BlockTransfer(TransferKind::Store, true, true), "Rn{!},<registers>{^}"),
+    MnemonicSpec::new("stm", "ed", Opcode::BlockTransfer(TransferKind::Store, false, false), "Rn{!},<registers>{^}"),
+    MnemonicSpec::new("stm", "fd", Opcode::BlockTransfer(TransferKind::Store, false, true), "Rn{!},<registers>{^}"),
+    MnemonicSpec::new("stm", "db", Opcode::BlockTransfer(TransferKind::Store, false, true), "Rn{!},<registers>{^}"),
+    MnemonicSpec::new("stm", "da", Opcode::BlockTransfer(TransferKind::Store, false, false), "Rn{!},<registers>{^}"),
+    MnemonicSpec::new("stm", "ib", Opcode::BlockTransfer(TransferKind::Store, true, true), "Rn{!},<registers>{^}"),
+    MnemonicSpec::new("stm", "ia", Opcode::BlockTransfer(TransferKind::Store, true, false), "Rn{!},<registers>{^}"),
+    MnemonicSpec::new("ldr", "d", Opcode::Unavailable("LDRD"), "Rd,<address>"),
+    MnemonicSpec::new("str", "d", Opcode::Unavailable("STRD"), "Rd,<address>"),
+    MnemonicSpec::new("swp", "", Opcode::Swap(false), "Rd,Rm,[Rn]"),
+    MnemonicSpec::new("swp", "b", Opcode::Swap(true), "Rd,Rm,[Rn]"),
+    MnemonicSpec::new("swi", "", Opcode::Swi, "<expression>"),
+    MnemonicSpec::new("equ", "", Opcode::Equ, "<expression>"),
+    MnemonicSpec::new("defw", "", Opcode::DefW, "<expression>,...,<expression>"),
+    MnemonicSpec::new("dw", "", Opcode::DefW, "<expression>,...,<expression>"),
+    MnemonicSpec::new("defb", "", Opcode::DefB, "<expression|string>,...,<expression|string>"),
+    MnemonicSpec::new("db", "", Opcode::DefB, "<expression|string>,...,<expression|string>"),
+    MnemonicSpec::new("defs", "", Opcode::DefB, "<expression|string>,...,<expression|string>"),
+    MnemonicSpec::new("dcd", "", Opcode::DefW, "<expression>,...,<expression>"),
+    MnemonicSpec::new("dcb", "", Opcode::DefB, "<expression|string>,...,<expression|string>"),
+    MnemonicSpec::new("align", "", Opcode::Align, "{<expression>}"),
+    MnemonicSpec::new("org", "", Opcode::Org, "<expression>"),
+    MnemonicSpec::new("ltorg", "", Opcode::Ltorg, ""),
+    MnemonicSpec::new("if", "", Opcode::If, "<expression>"),
+    MnemonicSpec::new("else", "", Opcode::Else, ""),
+    MnemonicSpec::new("endif", "", Opcode::EndIf, ""),
+];
+
+/// All of the mnemonics that the assembler recognises.
+pub fn mnemonics() -> &'static [MnemonicSpec] {
+    MNEMONICS
+}
+
+fn disambiguate_mnemonic(name: &str) -> Option<(Cond, Opcode)> {
+    for spec in MNEMONICS {
+        if let Some(tail) = name.strip_prefix(spec.base)
+            && let Some(cond) = tail.strip_suffix(spec.suffix)
+            && let Ok(cond) = cond.parse()
+        {
+            return Some((cond, spec.opcode));
+        }
+    }
+    None
+}
+
+/// Whether the given lowercase name is a mnemonic that the assembler recognises.
+pub(super) fn is_mnemonic(name: &str) -> bool {
+    disambiguate_mnemonic(name).is_some()
+}
+
+/// Classify the given mnemonic, which may have a condition code and suffixes.
+/// Returns [`None`] if this is not a mnemonic that the assembler recognises.
+pub fn mnemonic_category(mnemonic: &str) -> Option<InstrCategory> {
+    disambiguate_mnemonic(&mnemonic.to_lowercase()).map(|(_, opcode)| opcode.category())
+}
+
+/// Representative encodings that the assembler produces for the given mnemonic,
+/// which may have a condition code and suffixes, across the shapes of operand it accepts.
+/// Operand shapes that this mnemonic rejects, such as a store of a signed byte, are skipped.
+/// Returns an empty list for directives and for mnemonics that the assembler doesn't recognise.
+pub fn encodings_for(mnemonic: &str) -> Vec<u32> {
+    let Some((_, opcode)) = disambiguate_mnemonic(&mnemonic.to_lowercase()) else {
+        return Vec::new();
+    };
+    let mut encodings = Vec::new();
+    for operands in opcode.operand_shapes() {
+        // Healing would mix in the encodings of other instructions.
+        let src = format!("; HEAL OFF\n{mnemonic} {operands}\n");
+        if let Ok(output) = crate::assemble::assemble(&src) {
+            for word in output.instrs {
+                if !encodings.contains(&word) {
+                    encodings.push(word);
+                }
+            }
+        }
+    }
+    encodings
+}
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 enum Opcode {
     BranchExchange,
     Branch {
         link: bool,
     },
-    Adr,
+    /// The long form may expand into two instructions.
+    Adr {
+        long: bool,
+    },
     Nop,
     Data(bool, DataOp),
     Shift(bool, ShiftType),
@@ -314,7 +491,7 @@ enum Opcode {
     /// Set condition codes; signed; accumulate.
     MulLong(bool, bool, bool),
     /// The bool is for forced writeback (the T flag).
-    SingleTransfer(TransferKind, AnyTransferSize, bool),
+    SingleTransfer(TransferKind, TransferSize, bool),
     /// The bool flags are positive offset and pre index.
     BlockTransfer(TransferKind, bool, bool),
     /// The bool is whether to swap a byte.
@@ -322,98 +499,113 @@ enum Opcode {
     Swi,
     Equ,
     DefW,
+    /// Bytes, which may be written as strings.
+    DefB,
+    /// Pads to a power of two, which is 4 if not given.
+    Align,
+    Org,
+    /// Places the literals loaded since the last pool.
+    Ltorg,
+    /// Conditional assembly.
+    If,
+    Else,
+    EndIf,
+    /// An instruction from a later architecture that the ARM7TDMI doesn't have.
+    Unavailable(&'static str),
 }
 
-impl Display for Opcode {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match *self {
-            Opcode::BranchExchange => write!(f, "BX"),
-            Opcode::Branch { link: false } => write!(f, "B"),
-            Opcode::Branch { link: true } => write!(f, "BL"),
-            Opcode::Data(set_condition_codes, op) => {
-                write!(f, "{op}")?;
-                if set_condition_codes {
-                    write!(f, "S")
-                } else {
-                    Ok(())
-                }
-            }
-            Opcode::Shift(set_condition_codes, shift_type) => {
-                write!(f, "{shift_type}")?;
-                if set_condition_codes {
-                    write!(f, "S")
-                } else {
-                    Ok(())
-                }
-            }
-            Opcode::Adr => write!(f, "ADR"),
-            Opcode::Nop => write!(f, "NOP"),
-            Opcode::Mrs => write!(f, "MRS"),
-            Opcode::Msr => write!(f, "MSR"),
-            Opcode::Mul(set_condition_codes, accumulate) => {
-                if accumulate {
-                    write!(f, "MLA")?;
-                } else {
-                    write!(f, "MUL")?;
-                }
-                if set_condition_codes {
-                    write!(f, "S")
-                } else {
-                    Ok(())
-                }
-            }
-            Opcode::MulLong(set_condition_codes, signed, accumulate) => {
-                if signed {
-                    write!(f, "S")?;
-                } else {
-                    write!(f, "U")?;
-                }
-                if accumulate {
-                    write!(f, "MLA")?;
-                } else {
-                    write!(f, "MUL")?;
-                }
-                if set_condition_codes {
-                    write!(f, "LS")
-                } else {
-                    write!(f, "L")
-                }
-            }
-            Opcode::SingleTransfer(transfer_kind, transfer_size, t) => {
-                match transfer_kind {
-                    TransferKind::Store => write!(f, "STR")?,
-                    TransferKind::Load => write!(f, "LDR")?,
-                };
-                write!(f, "{transfer_size}")?;
-                if t { write!(f, "T") } else { Ok(()) }
-            }
-            Opcode::BlockTransfer(transfer_kind, pos_offset, pre_index) => {
-                match transfer_kind {
-                    TransferKind::Store => write!(f, "STM")?,
-                    TransferKind::Load => write!(f, "LDM")?,
-                };
-                if pre_index {
-                    write!(f, "E")?;
-                } else {
-                    write!(f, "F")?;
-                }
-                if pos_offset {
-                    write!(f, "D")
-                } else {
-                    write!(f, "A")
-                }
+impl Opcode {
+    fn category(self) -> InstrCategory {
+        match self {
+            Opcode::BranchExchange | Opcode::Branch { .. } => InstrCategory::Branch,
+            Opcode::Adr { .. } | Opcode::Data(..) | Opcode::Shift(..) => {
+                InstrCategory::DataProcessing
             }
-            Opcode::Swap(byte) => {
-                write!(f, "SWP")?;
-                if byte { write!(f, "B") } else { Ok(()) }
+            Opcode::Mrs | Opcode::Msr => InstrCategory::Psr,
+            Opcode::Mul(..) | Opcode::MulLong(..) => InstrCategory::Multiply,
+            Opcode::SingleTransfer(..) | Opcode::BlockTransfer(..) | Opcode::Swap(..) => {
+                InstrCategory::LoadStore
             }
-            Opcode::Swi => write!(f, "SWI"),
-            Opcode::Equ => write!(f, "EQU"),
-            Opcode::DefW => write!(f, "DEFW"),
+            Opcode::Nop
+            | Opcode::Swi
+            | Opcode::Equ
+            | Opcode::DefW
+            | Opcode::DefB
+            | Opcode::Align
+            | Opcode::Org
+            | Opcode::Ltorg
+            | Opcode::If
+            | Opcode::Else
+            | Opcode::EndIf
+            | Opcode::Unavailable(_) => InstrCategory::Misc,
         }
     }
 }
 
+impl Opcode {
+    /// Example operands for each syntax that this opcode accepts.
+    fn operand_shapes(self) -> &'static [&'static str] {
+        match self {
+            Opcode::BranchExchange => &["r0", "lr"],
+            Opcode::Branch { .. } => &["0", "0x100"],
+            Opcode::Adr { .. } => &["r0, 8"],
+            Opcode::Nop => &[""],
+            Opcode::Data(_, op) => match op.kind() {
+                DataOpKind::NoDest => &["r1, #1", "r1, r2", "r1, r2, lsl #3", "r1, r2, ror r3"],
+                DataOpKind::NoOp1 => &["r0, #1", "r0, r2", "r0, r2, lsl #3", "r0, r2, asr r3"],
+                DataOpKind::ThreeArg => &[
+                    "r0, r1, #255",
+                    "r0, r1, r2",
+                    "r0, r1, r2, lsr #1",
+                    "r0, r1, r2, lsl r3",
+                ],
+            },
+            Opcode::Shift(_, ShiftType::RotateRightExtended) => &["r0, r1"],
+            Opcode::Shift(..) => &["r0, r1, #3", "r0, r1, r2"],
+            Opcode::Mrs => &["r0, cpsr", "r0, spsr"],
+            Opcode::Msr => &["cpsr, r0", "spsr, r0", "cpsr_flg, r0", "cpsr_c, #0x1F"],
+            Opcode::Mul(_, false) => &["r0, r1, r2"],
+            Opcode::Mul(_, true) => &["r0, r1, r2, r3"],
+            Opcode::MulLong(..) => &["r0, r1, r2, r3"],
+            Opcode::SingleTransfer(_, _, true) => &["r0, [r1], #4", "r0, [r1], r2"],
+            Opcode::SingleTransfer(..) => &[
+                "r0, [r1]",
+                "r0, [r1, #4]",
+                "r0, [r1, #4]!",
+                "r0, [r1], #4",
+                "r0, [r1, r2]",
+                "r0, [r1, -r2]",
+                "r0, [r1, r2, lsl #2]",
+            ],
+            Opcode::BlockTransfer(..) => &["r0, {r1}", "r13!, {r0-r3, lr}", "r0, {r1}^"],
+            Opcode::Swap(_) => &["r0, r1, [r2]"],
+            Opcode::Swi => &["0", "0x123456"],
+            Opcode::Equ
+            | Opcode::DefW
+            | Opcode::DefB
+            | Opcode::Align
+            | Opcode::Org
+            | Opcode::Ltorg
+            | Opcode::If
+            | Opcode::Else
+            | Opcode::EndIf
+            | Opcode::Unavailable(_) => &[],
+        }
+    }
+}
+
+impl Display for Opcode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let spec = MnemonicSpec::find(*self);
+        write!(
+            f,
+            "{}{}",
+            spec.base.to_uppercase(),
+            spec.suffix.to_uppercase()
+        )
+    }
+}
+
 #[derive(Default, Debug, Clone, PartialEq)]
 enum LexError {
     Error(String),
@@ -441,6 +633,37 @@ impl LexError {
     }
 }
 
+/// Replace the escape sequences `\n`, `\r`, `\t`, `\0`, `\\`, `\"`, `\'` and `\xHH`
+/// in the contents of a string literal.
+/// Strings are assembled as UTF-8, so `\xHH` may only give an ASCII character.
+fn unescape_string(s: &str) -> Result<String, LexError> {
+    let mut result = String::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        let escaped = match chars.next() {
+            Some('n') => '\n',
+            Some('r') => '\r',
+            Some('t') => '\t',
+            Some('0') => '\0',
+            Some(c @ ('\\' | '"' | '\'')) => c,
+            Some('x') => chars
+                .next()
+                .zip(chars.next())
+                .and_then(|(hi, lo)| u8::from_str_radix(&format!("{hi}{lo}"), 16).ok())
+                .filter(u8::is_ascii)
+                .map(char::from)
+                .ok_or_else(|| LexError::Error("invalid escape sequence".to_owned()))?,
+            _ => return Err(LexError::Error("invalid escape sequence".to_owned())),
+        };
+        result.push(escaped);
+    }
+    Ok(result)
+}
+
 impl Display for LexError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -465,11 +688,13 @@ impl<'a> Display for Token<'a> {
             Token::HexInteger(i) => write!(f, "{i}"),
             Token::OctalInteger(i) => write!(f, "{i}"),
             Token::BinaryInteger(i) => write!(f, "{i}"),
+            Token::LocalLabel((label, forward)) => {
+                write!(f, "{label}{}", if *forward { 'f' } else { 'b' })
+            }
             Token::Name(x) => write!(f, "{x}"),
             Token::Register(register) => write!(f, "{register}"),
             Token::Opcode((cond, opcode)) => write!(f, "{opcode} ({cond})"),
-            Token::Psr((psr, false)) => write!(f, "{psr}"),
-            Token::Psr((psr, true)) => write!(f, "{psr}_flg"),
+            Token::Psr((psr, fields)) => write!(f, "{psr}{fields}"),
             Token::Add => write!(f, "+"),
             Token::Sub => write!(f, "-"),
             Token::Mul => write!(f, "*"),
@@ -484,13 +709,67 @@ impl<'a> Display for Token<'a> {
             Token::Hash => write!(f, "#"),
             Token::Exclamation => write!(f, "!"),
             Token::Caret => write!(f, "^"),
+            Token::Dot => write!(f, "."),
+            Token::Equals => write!(f, "="),
+            Token::Colon => write!(f, ":"),
             Token::Whitespace => write!(f, "whitespace"),
             Token::Newline => write!(f, "newline"),
             Token::Comment(_) => write!(f, "comment"),
+            Token::String(s) => write!(f, "{s:?}"),
         }
     }
 }
 
+/// Give each definition of a numeric local label, such as `1:` at the start of a line,
+/// a unique name, and resolve each reference to one, such as `1b` or `1f`, to that name.
+/// The `k`th definition of `1:` is named `1.k`, which cannot clash with other labels.
+/// A reference with no matching definition keeps its own name, such as `1b`,
+/// so that it is reported as a label that was not found.
+fn local_label_names(tokens: &[(Token, SimpleSpan)]) -> Vec<Option<String>> {
+    let is_definition = |index: usize| {
+        matches!(tokens[index].0, Token::Integer(_))
+            && (index == 0 || tokens[index - 1].0 == Token::Newline)
+            && tokens
+                .get(index + 1)
+                .is_some_and(|(tok, _)| *tok == Token::Colon)
+    };
+    let mut totals = BTreeMap::<u32, usize>::new();
+    for (index, (tok, _)) in tokens.iter().enumerate() {
+        if let Token::Integer(label) = tok
+            && is_definition(index)
+        {
+            *totals.entry(*label).or_default() += 1;
+        }
+    }
+    let mut seen = BTreeMap::<u32, usize>::new();
+    tokens
+        .iter()
+        .enumerate()
+        .map(|(index, (tok, _))| match tok {
+            Token::Integer(label) if is_definition(index) => {
+                let count = seen.entry(*label).or_default();
+                *count += 1;
+                Some(format!("{label}.{}", *count - 1))
+            }
+            Token::LocalLabel((label, forward)) => {
+                let seen = seen.get(label).copied().unwrap_or_default();
+                let total = totals.get(label).copied().unwrap_or_default();
+                Some(match forward {
+                    false if seen > 0 => format!("{label}.{}", seen - 1),
+                    true if seen < total => format!("{label}.{seen}"),
+                    _ => tok.to_string(),
+                })
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Returns true if this is the name given to a numeric local label by [`local_label_names`].
+pub(super) fn is_local_label(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_digit())
+}
+
 fn line_number(line_indices: &[usize], span: SimpleSpan) -> usize {
     line_indices
         .binary_search(&span.start)
@@ -498,23 +777,42 @@ fn line_number(line_indices: &[usize], span: SimpleSpan) -> usize {
         + 1
 }
 
-#[derive(Default, Clone, Copy)]
-struct LabelGenerator(u32);
+/// Labels generated by the parser start with this prefix.
+/// Users may not define labels with this prefix, so that they don't collide.
+pub(super) const GENERATED_LABEL_PREFIX: &str = "__generatedlabel_";
+
+pub(super) fn is_generated_label(name: &str) -> bool {
+    name.starts_with(GENERATED_LABEL_PREFIX)
+}
 
-fn generate_label(generator: &Rc<Cell<LabelGenerator>>) -> String {
-    let index = generator.get().0;
-    generator.set(LabelGenerator(index + 1));
-    format!("__generatedlabel_{index}")
+/// Generate a label for the instruction at the given span.
+/// The name depends only on the line and column of the instruction and the given suffix,
+/// so it doesn't change when other lines are added or removed.
+fn generate_label(
+    line_indices: &[usize],
+    span: SimpleSpan,
+    symbols: &Rc<RefCell<SymbolTable>>,
+    suffix: &str,
+) -> SymbolId {
+    let line = line_number(line_indices, span);
+    let line_start = line
+        .checked_sub(2)
+        .map_or(0, |index| line_indices[index] + 1);
+    let column = span.start - line_start + 1;
+    symbols
+        .borrow_mut()
+        .intern(&format!("{GENERATED_LABEL_PREFIX}{line}_{column}{suffix}"))
 }
 
 fn parser<'tokens, 'src: 'tokens, I>(
     line_indices: &[usize],
-    generator: &Rc<Cell<LabelGenerator>>,
+    symbols: &Rc<RefCell<SymbolTable>>,
+    macro_labels: &BTreeSet<String>,
 ) -> impl Parser<'tokens, I, Vec<AsmLine>, extra::Err<Rich<'tokens, Token<'src>>>>
 where
     I: ValueInput<'tokens, Token = Token<'src>, Span = SimpleSpan>,
 {
-    line_contents(generator)
+    line_contents(line_indices, symbols, macro_labels)
         .or_not()
         .map(|x| x.unwrap_or_default())
         .spanned()
@@ -526,6 +824,7 @@ where
                 if contents.is_empty() {
                     vec![AsmLine {
                         line_number,
+                        macro_line: None,
                         contents: AsmLineContents::Empty,
                         comment: comment.unwrap_or_default().to_owned(),
                     }]
@@ -535,6 +834,7 @@ where
                         .into_iter()
                         .map(|contents| AsmLine {
                             line_number,
+                            macro_line: None,
                             contents,
                             comment: comment.take().unwrap_or_default().to_owned(),
                         })
@@ -548,41 +848,61 @@ where
 }
 
 fn line_contents<'tokens, 'src: 'tokens, I>(
-    generator: &Rc<Cell<LabelGenerator>>,
+    line_indices: &[usize],
+    symbols: &Rc<RefCell<SymbolTable>>,
+    macro_labels: &BTreeSet<String>,
 ) -> impl Parser<'tokens, I, Vec<AsmLineContents>, extra::Err<Rich<'tokens, Token<'src>>>>
 where
     I: ValueInput<'tokens, Token = Token<'src>, Span = SimpleSpan>,
 {
-    let label = select! { Token::Name(label) => label };
+    let label = {
+        let symbols = symbols.clone();
+        // Labels defined inside macros are renamed with the reserved prefix.
+        let macro_labels = macro_labels.clone();
+        select! { Token::Name(label) => label }.try_map(move |label, span| {
+            if is_generated_label(label) && !macro_labels.contains(label) {
+                Err(Rich::custom(
+                    span,
+                    format!("labels starting with '{GENERATED_LABEL_PREFIX}' are reserved"),
+                ))
+            } else {
+                Ok(symbols.borrow_mut().intern(label))
+            }
+        })
+    };
     let mnemonic = select! { Token::Opcode(mnemonic) => mnemonic };
-    let args = argument()
+    let args = argument(symbols)
         .padded_by(whitespace())
         .separated_by(just(Token::Comma))
         .collect::<Vec<_>>();
 
+    // A label may optionally be followed by a colon.
     label
+        .then_ignore(just(Token::Colon).or_not())
         .or_not()
         .then_ignore(whitespace())
         .then((mnemonic.then_ignore(whitespace()).then(args)).or_not())
         .try_map(|(label, instr), span| match instr {
-            Some(((cond, opcode), args)) => process_instruction(opcode, args, span, generator)
-                .map(|instr| (label, Some((cond, instr)))),
+            Some(((cond, opcode), args)) => {
+                process_instruction(opcode, args, span, line_indices, symbols)
+                    .map(|instr| (label, Some((cond, instr))))
+            }
             None => Ok((label, None)),
         })
         .try_map(|(label, instr), span| process_line_contents(label, instr, span))
 }
 
-fn process_line_contents(
-    label: Option<&str>,
+fn process_line_contents<'tokens, 'src: 'tokens>(
+    label: Option<SymbolId>,
     instr: Option<(Cond, Processed)>,
     span: SimpleSpan,
-) -> Result<Vec<AsmLineContents>, Rich<'_, Token<'_>>> {
+) -> Result<Vec<AsmLineContents>, Rich<'tokens, Token<'src>>> {
     match (label, instr) {
         (None, None) => Ok(Vec::new()),
         (label, Some((cond, Processed::Instr(instr)))) => {
             let mut result = Vec::new();
             if let Some(label) = label {
-                result.push(AsmLineContents::Label(label.to_owned()))
+                result.push(AsmLineContents::Label(label))
             }
             result.push(AsmLineContents::Instr(cond, instr));
             Ok(result)
@@ -590,7 +910,7 @@ fn process_line_contents(
         (label, Some((cond, Processed::DefW(expr)))) => {
             let mut result = Vec::new();
             if let Some(label) = label {
-                result.push(AsmLineContents::Label(label.to_owned()))
+                result.push(AsmLineContents::Label(label))
             }
             if cond != Cond::AL {
                 return Err(Rich::custom(span, "'defw' cannot have a condition flag"));
@@ -598,12 +918,69 @@ fn process_line_contents(
             result.push(AsmLineContents::DefWord(expr));
             Ok(result)
         }
+        (label, Some((cond, Processed::DefB(expr)))) => {
+            let mut result = Vec::new();
+            if let Some(label) = label {
+                result.push(AsmLineContents::Label(label))
+            }
+            if cond != Cond::AL {
+                return Err(Rich::custom(span, "'defb' cannot have a condition flag"));
+            }
+            result.push(AsmLineContents::DefByte(expr));
+            Ok(result)
+        }
+        (label, Some((cond, Processed::Align(expr)))) => {
+            let mut result = Vec::new();
+            if let Some(label) = label {
+                result.push(AsmLineContents::Label(label))
+            }
+            if cond != Cond::AL {
+                return Err(Rich::custom(span, "'align' cannot have a condition flag"));
+            }
+            result.push(AsmLineContents::Align(expr));
+            Ok(result)
+        }
+        (label, Some((cond, Processed::Org(expr)))) => {
+            let mut result = Vec::new();
+            if let Some(label) = label {
+                result.push(AsmLineContents::Label(label))
+            }
+            if cond != Cond::AL {
+                return Err(Rich::custom(span, "'org' cannot have a condition flag"));
+            }
+            result.push(AsmLineContents::Org(expr));
+            Ok(result)
+        }
+        (label, Some((cond, Processed::Ltorg))) => {
+            let mut result = Vec::new();
+            if let Some(label) = label {
+                result.push(AsmLineContents::Label(label))
+            }
+            if cond != Cond::AL {
+                return Err(Rich::custom(span, "'ltorg' cannot have a condition flag"));
+            }
+            result.push(AsmLineContents::Ltorg);
+            Ok(result)
+        }
+        (Some(_), Some((_, Processed::Conditional(_)))) => Err(Rich::custom(
+            span,
+            "conditional assembly directives cannot have a label",
+        )),
+        (None, Some((cond, Processed::Conditional(contents)))) => {
+            if cond != Cond::AL {
+                return Err(Rich::custom(
+                    span,
+                    "conditional assembly directives cannot have a condition flag",
+                ));
+            }
+            Ok(vec![contents])
+        }
         (None, Some((_, Processed::Equ(_)))) => Err(Rich::custom(span, "'equ' needs a label")),
         (Some(label), Some((cond, Processed::Equ(expr)))) => {
             if cond != Cond::AL {
                 return Err(Rich::custom(span, "'equ' cannot have a condition flag"));
             }
-            Ok(vec![AsmLineContents::Equ(label.to_owned(), expr)])
+            Ok(vec![AsmLineContents::Equ(label, expr)])
         }
         (mut label, Some((cond, Processed::Vec(items)))) => {
             let mut result = Vec::new();
@@ -615,19 +992,19 @@ fn process_line_contents(
                 )?);
             }
             if let Some(label) = label {
-                result.push(AsmLineContents::Label(label.to_owned()))
+                result.push(AsmLineContents::Label(label))
             }
             Ok(result)
         }
         (label, Some((_, Processed::Label(second_label)))) => {
             let mut result = Vec::new();
             if let Some(label) = label {
-                result.push(AsmLineContents::Label(label.to_owned()))
+                result.push(AsmLineContents::Label(label))
             }
-            result.push(AsmLineContents::Label(second_label.to_owned()));
+            result.push(AsmLineContents::Label(second_label));
             Ok(result)
         }
-        (Some(label), None) => Ok(vec![AsmLineContents::Label(label.to_owned())]),
+        (Some(label), None) => Ok(vec![AsmLineContents::Label(label)]),
     }
 }
 
@@ -640,7 +1017,7 @@ enum Argument {
     WriteBackRegister(Register),
     Psr {
         psr: Psr,
-        flag: bool,
+        fields: PsrFields,
     },
     Shift(Shift),
     Expression(Expression),
@@ -655,13 +1032,19 @@ enum Argument {
         registers: Vec<Register>,
         caret: bool,
     },
+    String(String),
+    /// `=<expression>`, a value to be loaded from a literal pool.
+    Literal(Expression),
 }
 
-fn argument<'tokens, 'src: 'tokens, I>()
--> impl Parser<'tokens, I, Argument, extra::Err<Rich<'tokens, Token<'src>>>>
+fn argument<'tokens, 'src: 'tokens, I>(
+    symbols: &Rc<RefCell<SymbolTable>>,
+) -> impl Parser<'tokens, I, Argument, extra::Err<Rich<'tokens, Token<'src>>>>
 where
     I: ValueInput<'tokens, Token = Token<'src>, Span = SimpleSpan>,
 {
+    let shift = shift(symbols);
+    let expression = expression(symbols);
     recursive(|arg| {
         choice((
             register()
@@ -689,12 +1072,16 @@ where
                 inp.rewind(checkpoint);
                 Err(Rich::custom(span, "expected signed register"))
             }),
-            shift().map(Argument::Shift),
-            expression().map(Argument::Expression),
+            shift.map(Argument::Shift),
+            expression.clone().map(Argument::Expression),
+            just(Token::Equals)
+                .ignore_then(expression)
+                .map(Argument::Literal),
+            select! { Token::String(s) => Argument::String(s) },
             select! {
-                Token::Psr((psr, flag)) => (psr, flag)
+                Token::Psr((psr, fields)) => (psr, fields)
             }
-            .map(|(psr, flag)| Argument::Psr { psr, flag }),
+            .map(|(psr, fields)| Argument::Psr { psr, fields }),
             just(Token::LSquare)
                 .ignore_then(register())
                 .then(
@@ -717,17 +1104,22 @@ where
                 .ignore_then(
                     register()
                         .then(just(Token::Sub).ignore_then(register()).or_not())
-                        .try_map(|(first, second), span| match second {
+                        .validate(|(first, second), e, emitter| match second {
                             Some(second) => {
-                                if first <= second {
-                                    Ok((first as u32..=second as u32)
-                                        .map(|x| Register::from_u4(x, 0))
-                                        .collect())
-                                } else {
-                                    Err(Rich::custom(span, "register range contains no registers"))
+                                if first > second {
+                                    emitter.emit(Rich::custom(
+                                        e.span(),
+                                        format!(
+                                            "register range {first}-{second} goes backwards; write {second}-{first}"
+                                        ),
+                                    ));
                                 }
+                                // Recover as if the range were written the right way round.
+                                (first.min(second) as u32..=first.max(second) as u32)
+                                    .map(|x| Register::from_u4(x, 0))
+                                    .collect()
                             }
-                            None => Ok(vec![first]),
+                            None => vec![first],
                         })
                         .padded_by(whitespace())
                         .separated_by(just(Token::Comma))
@@ -743,8 +1135,9 @@ where
     })
 }
 
-fn shift<'tokens, 'src: 'tokens, I>()
--> impl Parser<'tokens, I, Shift, extra::Err<Rich<'tokens, Token<'src>>>> + Clone
+fn shift<'tokens, 'src: 'tokens, I>(
+    symbols: &Rc<RefCell<SymbolTable>>,
+) -> impl Parser<'tokens, I, Shift, extra::Err<Rich<'tokens, Token<'src>>>> + Clone + use<'tokens, 'src, I>
 where
     I: ValueInput<'tokens, Token = Token<'src>, Span = SimpleSpan>,
 {
@@ -761,7 +1154,7 @@ where
             .then_ignore(whitespace().or_not())
             .then(choice((
                 register().map(ShiftAmount::Register),
-                expression().map(ShiftAmount::Constant),
+                expression(symbols).map(ShiftAmount::Constant),
             )))
             .map(|(shift_type, shift_amount)| Shift {
                 shift_type,
@@ -780,8 +1173,11 @@ where
     }
 }
 
-fn expression<'tokens, 'src: 'tokens, I>()
--> impl Parser<'tokens, I, Expression, extra::Err<Rich<'tokens, Token<'src>>>> + Clone
+fn expression<'tokens, 'src: 'tokens, I>(
+    symbols: &Rc<RefCell<SymbolTable>>,
+) -> impl Parser<'tokens, I, Expression, extra::Err<Rich<'tokens, Token<'src>>>>
++ Clone
++ use<'tokens, 'src, I>
 where
     I: ValueInput<'tokens, Token = Token<'src>, Span = SimpleSpan>,
 {
@@ -795,26 +1191,41 @@ where
         };
         let atom = choice((
             just(Token::Hash).or_not().ignore_then(number),
-            select! { Token::Name(name) => Expression::Label(name.to_owned()) },
+            just(Token::Dot).to(Expression::Here),
+            {
+                let symbols = symbols.clone();
+                select! { Token::Name(name) => name }
+                    .map(move |name| Expression::Label(symbols.borrow_mut().intern(name)))
+            },
             just(Token::LParen)
                 .ignore_then(e)
                 .then_ignore(just(Token::RParen)),
         ));
-        atom.padded_by(whitespace().or_not()).pratt((
-            infix(left(3), just(Token::Mul), |l, _, r, _| {
+        // Operators with higher precedence bind more tightly, as in C.
+        let expression = atom.padded_by(whitespace().or_not()).pratt((
+            prefix(13, whitespace().ignore_then(just(Token::Sub)), |_, x, _| {
+                Expression::Neg(Box::new(x))
+            }),
+            prefix(13, whitespace().ignore_then(keyword("not")), |_, x, _| {
+                Expression::Not(Box::new(x))
+            }),
+            infix(left(12), just(Token::Mul), |l, _, r, _| {
                 Expression::Mul(Box::new(l), Box::new(r))
             }),
-            infix(left(3), just(Token::Div), |l, _, r, _| {
+            infix(left(12), just(Token::Div), |l, _, r, _| {
                 Expression::Div(Box::new(l), Box::new(r))
             }),
-            infix(left(4), just(Token::Add), |l, _, r, _| {
+            infix(left(12), keyword("mod"), |l, _, r, _| {
+                Expression::Mod(Box::new(l), Box::new(r))
+            }),
+            infix(left(11), just(Token::Add), |l, _, r, _| {
                 Expression::Add(Box::new(l), Box::new(r))
             }),
-            infix(left(4), just(Token::Sub), |l, _, r, _| {
+            infix(left(11), just(Token::Sub), |l, _, r, _| {
                 Expression::Sub(Box::new(l), Box::new(r))
             }),
             infix(
-                left(5),
+                left(10),
                 select! { Token::Opcode((Cond::AL, Opcode::Shift(false, s))) if s != ShiftType::RotateRightExtended => s },
                 |l, s, r, _| match s {
                     ShiftType::LogicalLeft => Expression::Lsl(Box::new(l), Box::new(r)),
@@ -824,13 +1235,34 @@ where
                     _ => unreachable!(),
                 },
             ),
-            infix(left(10), just(Token::Name("or")), |l, _, r, _| {
+            // `and` is also a mnemonic.
+            infix(
+                left(9),
+                just(Token::Opcode((Cond::AL, Opcode::Data(false, DataOp::And)))),
+                |l, _, r, _| Expression::And(Box::new(l), Box::new(r)),
+            ),
+            infix(left(8), keyword("xor"), |l, _, r, _| {
+                Expression::Xor(Box::new(l), Box::new(r))
+            }),
+            infix(left(7), keyword("or"), |l, _, r, _| {
                 Expression::Or(Box::new(l), Box::new(r))
             }),
-        ))
+        ));
+        // An immediate may be marked with `#` before any expression.
+        just(Token::Hash).or_not().ignore_then(expression)
     })
 }
 
+/// An operator written as a word, in any case.
+fn keyword<'tokens, 'src: 'tokens, I>(
+    name: &'static str,
+) -> impl Parser<'tokens, I, (), extra::Err<Rich<'tokens, Token<'src>>>> + Clone
+where
+    I: ValueInput<'tokens, Token = Token<'src>, Span = SimpleSpan>,
+{
+    select! { Token::Name(word) if word.eq_ignore_ascii_case(name) => () }
+}
+
 fn whitespace<'tokens, 'src: 'tokens, I>()
 -> impl Parser<'tokens, I, (), extra::Err<Rich<'tokens, Token<'src>>>> + Clone
 where
@@ -840,18 +1272,36 @@ where
 }
 
 enum Processed {
-    Label(String),
+    Label(SymbolId),
     Instr(AsmInstr),
     Equ(Expression),
     DefW(Expression),
+    DefB(Expression),
+    Align(Expression),
+    Org(Expression),
+    Ltorg,
+    /// A directive that controls conditional assembly, which cannot have a label.
+    Conditional(AsmLineContents),
     Vec(Vec<Processed>),
 }
 
+/// An error showing the expected syntax of the given instruction.
+fn syntax_error<'tokens, 'src: 'tokens>(
+    span: SimpleSpan,
+    opcode: Opcode,
+) -> Rich<'tokens, Token<'src>> {
+    Rich::custom(
+        span,
+        format!("syntax: {opcode} {}", MnemonicSpec::find(opcode).syntax),
+    )
+}
+
 fn process_instruction<'tokens, 'src: 'tokens>(
     opcode: Opcode,
     mut args: Vec<Argument>,
     span: SimpleSpan,
-    generator: &Rc<Cell<LabelGenerator>>,
+    line_indices: &[usize],
+    symbols: &Rc<RefCell<SymbolTable>>,
 ) -> Result<Processed, Rich<'tokens, Token<'src>>> {
     match opcode {
         Opcode::BranchExchange => {
@@ -862,7 +1312,7 @@ fn process_instruction<'tokens, 'src: 'tokens>(
                 Argument::Register(operand) => {
                     Ok(Processed::Instr(AsmInstr::BranchExchange { operand }))
                 }
-                _ => Err(Rich::custom(span, format!("syntax: {opcode} <offset>"))),
+                _ => Err(syntax_error(span, opcode)),
             }
         }
         Opcode::Branch { link } => {
@@ -873,21 +1323,18 @@ fn process_instruction<'tokens, 'src: 'tokens>(
                 Argument::Expression(target) => {
                     Ok(Processed::Instr(AsmInstr::Branch { link, target }))
                 }
-                _ => Err(Rich::custom(span, format!("syntax: {opcode} <offset>"))),
+                _ => Err(syntax_error(span, opcode)),
             }
         }
-        Opcode::Adr => {
+        Opcode::Adr { long } => {
             let [dest, expr] = args
                 .try_into()
                 .map_err(|_| Rich::custom(span, "expected 2 arguments"))?;
             match (dest, expr) {
                 (Argument::Register(dest), Argument::Expression(expr)) => {
-                    Ok(Processed::Instr(AsmInstr::Adr { dest, expr }))
+                    Ok(Processed::Instr(AsmInstr::Adr { dest, expr, long }))
                 }
-                _ => Err(Rich::custom(
-                    span,
-                    format!("syntax: {opcode} Rd,<expression>"),
-                )),
+                _ => Err(syntax_error(span, opcode)),
             }
         }
         Opcode::Nop => {
@@ -934,7 +1381,7 @@ fn process_instruction<'tokens, 'src: 'tokens>(
                             op1,
                             op2,
                         })),
-                        _ => Err(Rich::custom(span, format!("syntax: {opcode} Rn,<Op2>"))),
+                        _ => Err(syntax_error(span, opcode)),
                     }
                 }
                 DataOpKind::NoOp1 => {
@@ -949,7 +1396,7 @@ fn process_instruction<'tokens, 'src: 'tokens>(
                             op1: Register::R0,
                             op2,
                         })),
-                        _ => Err(Rich::custom(span, format!("syntax: {opcode} Rd,<Op2>"))),
+                        _ => Err(syntax_error(span, opcode)),
                     }
                 }
                 DataOpKind::ThreeArg => match TryInto::<[Argument; 1]>::try_into(args) {
@@ -961,7 +1408,7 @@ fn process_instruction<'tokens, 'src: 'tokens>(
                             op1,
                             op2,
                         })),
-                        _ => Err(Rich::custom(span, format!("syntax: {opcode} Rd,<Op2>"))),
+                        _ => Err(syntax_error(span, opcode)),
                     },
                     Err(args) => {
                         let [dest, op1] = args
@@ -977,7 +1424,7 @@ fn process_instruction<'tokens, 'src: 'tokens>(
                                     op2,
                                 }))
                             }
-                            _ => Err(Rich::custom(span, format!("syntax: {opcode} Rd,Rn,<Op2>"))),
+                            _ => Err(syntax_error(span, opcode)),
                         }
                     }
                 },
@@ -999,7 +1446,7 @@ fn process_instruction<'tokens, 'src: 'tokens>(
                             },
                         ),
                     })),
-                    _ => Err(Rich::custom(span, format!("syntax: {opcode} Rd"))),
+                    _ => Err(syntax_error(span, opcode)),
                 },
                 Err(args) => {
                     let [dest, op1] = args
@@ -1023,7 +1470,7 @@ fn process_instruction<'tokens, 'src: 'tokens>(
                                 ),
                             }))
                         }
-                        _ => Err(Rich::custom(span, format!("syntax: {opcode} Rd,Rn"))),
+                        _ => Err(syntax_error(span, opcode)),
                     }
                 }
             }
@@ -1061,7 +1508,7 @@ fn process_instruction<'tokens, 'src: 'tokens>(
                             ),
                         }))
                     }
-                    _ => Err(Rich::custom(span, format!("syntax: {opcode} Rd,<Op2>"))),
+                    _ => Err(syntax_error(span, opcode)),
                 },
                 Err(args) => {
                     let [dest, op1, shift] = args
@@ -1102,7 +1549,7 @@ fn process_instruction<'tokens, 'src: 'tokens>(
                                 },
                             ),
                         })),
-                        _ => Err(Rich::custom(span, format!("syntax: {opcode} Rd,Rn,<Op2>"))),
+                        _ => Err(syntax_error(span, opcode)),
                     }
                 }
             }
@@ -1112,10 +1559,15 @@ fn process_instruction<'tokens, 'src: 'tokens>(
                 .try_into()
                 .map_err(|_| Rich::custom(span, "expected 2 arguments"))?;
             match (target, psr) {
-                (Argument::Register(target), Argument::Psr { psr, flag: false }) => {
-                    Ok(Processed::Instr(AsmInstr::Mrs { psr, target }))
-                }
-                _ => Err(Rich::custom(span, format!("syntax: {opcode} Rd,<psr>"))),
+                (
+                    Argument::Register(target),
+                    // The whole PSR is always read, but older code names it `CPSR_all`.
+                    Argument::Psr {
+                        psr,
+                        fields: PsrFields::DEFAULT | PsrFields::ALL,
+                    },
+                ) => Ok(Processed::Instr(AsmInstr::Mrs { psr, target })),
+                _ => Err(syntax_error(span, opcode)),
             }
         }
         Opcode::Msr => {
@@ -1123,28 +1575,21 @@ fn process_instruction<'tokens, 'src: 'tokens>(
                 .try_into()
                 .map_err(|_| Rich::custom(span, "expected 2 arguments"))?;
             match (psr, op) {
-                (Argument::Psr { psr, flag: false }, Argument::Register(reg)) => {
+                (Argument::Psr { psr, fields }, Argument::Register(reg)) => {
                     Ok(Processed::Instr(AsmInstr::Msr {
                         psr,
+                        fields,
                         source: MsrSource::Register(reg),
                     }))
                 }
-                (Argument::Psr { psr, flag: true }, Argument::Register(reg)) => {
-                    Ok(Processed::Instr(AsmInstr::Msr {
-                        psr,
-                        source: MsrSource::RegisterFlags(reg),
-                    }))
-                }
-                (Argument::Psr { psr, flag: true }, Argument::Expression(expr)) => {
+                (Argument::Psr { psr, fields }, Argument::Expression(expr)) => {
                     Ok(Processed::Instr(AsmInstr::Msr {
                         psr,
-                        source: MsrSource::Flags(expr),
+                        fields,
+                        source: MsrSource::Constant(expr),
                     }))
                 }
-                _ => Err(Rich::custom(
-                    span,
-                    format!("syntax: {opcode} <psr>,Rm (for example)"),
-                )),
+                _ => Err(syntax_error(span, opcode)),
             }
         }
         Opcode::Mul(set_condition_codes, false) => {
@@ -1161,7 +1606,7 @@ fn process_instruction<'tokens, 'src: 'tokens>(
                         addend: None,
                     }))
                 }
-                _ => Err(Rich::custom(span, format!("syntax: {opcode} Rd,Rm,Rs"))),
+                _ => Err(syntax_error(span, opcode)),
             }
         }
         Opcode::Mul(set_condition_codes, true) => {
@@ -1181,7 +1626,7 @@ fn process_instruction<'tokens, 'src: 'tokens>(
                     op2,
                     addend: Some(addend),
                 })),
-                _ => Err(Rich::custom(span, format!("syntax: {opcode} Rd,Rm,Rs,Rn"))),
+                _ => Err(syntax_error(span, opcode)),
             }
         }
         Opcode::MulLong(set_condition_codes, signed, accumulate) => {
@@ -1203,10 +1648,7 @@ fn process_instruction<'tokens, 'src: 'tokens>(
                     op1,
                     op2,
                 })),
-                _ => Err(Rich::custom(
-                    span,
-                    format!("syntax: {opcode} RdLo,RdHi,Rm,Rs"),
-                )),
+                _ => Err(syntax_error(span, opcode)),
             }
         }
         Opcode::SingleTransfer(kind, size, t_flag) => match args.len() {
@@ -1225,7 +1667,7 @@ fn process_instruction<'tokens, 'src: 'tokens>(
                             // Because we might generate extra healing instructions between
                             // the start and the end of execution, we put the label *after*
                             // the PC location it's referencing.
-                            let here = generate_label(generator);
+                            let here = generate_label(line_indices, span, symbols, "");
                             Ok(Processed::Vec(vec![
                                 Processed::Instr(AsmInstr::SingleTransfer {
                                     kind,
@@ -1238,7 +1680,7 @@ fn process_instruction<'tokens, 'src: 'tokens>(
                                     offset: DataOperand::Constant(Expression::Sub(
                                         Box::new(addr),
                                         Box::new(Expression::Add(
-                                            Box::new(Expression::Label(here.clone())),
+                                            Box::new(Expression::Label(here)),
                                             Box::new(Expression::Constant(4)),
                                         )),
                                     )),
@@ -1247,6 +1689,17 @@ fn process_instruction<'tokens, 'src: 'tokens>(
                             ]))
                         }
                     }
+                    (Argument::Register(dest), Argument::Literal(value)) => {
+                        if kind == TransferKind::Load && size == TransferSize::Word && !t_flag {
+                            Ok(Processed::Instr(AsmInstr::LoadLiteral {
+                                dest,
+                                value,
+                                pool_label: generate_label(line_indices, span, symbols, "_literal"),
+                            }))
+                        } else {
+                            Err(Rich::custom(span, "only LDR can load an '=' literal"))
+                        }
+                    }
                     (
                         Argument::Register(data_register),
                         Argument::Address {
@@ -1331,10 +1784,8 @@ fn process_instruction<'tokens, 'src: 'tokens>(
                 // This is a post-indexed addressing specification.
                 let shift = if args.len() == 4 { args.pop() } else { None };
                 let [data_register, base_register, offset] = args.try_into().unwrap();
-                let (offset_positive, data_register) = match data_register {
-                    Argument::Register(data_register) => (true, data_register),
-                    Argument::SignedRegister(sign, data_register) => (sign, data_register),
-                    _ => return Err(Rich::custom(span, "expected register")),
+                let Argument::Register(data_register) = data_register else {
+                    return Err(Rich::custom(span, "expected register"));
                 };
                 let (data_register, base_register) = match base_register {
                     Argument::Address {
@@ -1366,7 +1817,12 @@ fn process_instruction<'tokens, 'src: 'tokens>(
                         ));
                     }
                 };
+                // The offset register may be subtracted from the base, as in `[R1],-R2`.
                 let offset = match offset {
+                    Argument::Register(register) => Argument::SignedRegister(true, register),
+                    offset => offset,
+                };
+                let (offset_positive, offset) = match offset {
                     Argument::Expression(expression) => {
                         if shift.is_some() {
                             return Err(Rich::custom(
@@ -1374,11 +1830,13 @@ fn process_instruction<'tokens, 'src: 'tokens>(
                                 "shift cannot be specified with expression offset",
                             ));
                         }
-                        DataOperand::Constant(expression)
+                        (true, DataOperand::Constant(expression))
                     }
-                    Argument::Register(register) => match shift {
-                        Some(Argument::Shift(shift)) => DataOperand::Register(register, shift),
-                        None => DataOperand::Register(register, Shift::default()),
+                    Argument::SignedRegister(sign, register) => match shift {
+                        Some(Argument::Shift(shift)) => {
+                            (sign, DataOperand::Register(register, shift))
+                        }
+                        None => (sign, DataOperand::Register(register, Shift::default())),
                         _ => {
                             return Err(Rich::custom(span, "invalid offset, expected shift"));
                         }
@@ -1396,7 +1854,7 @@ fn process_instruction<'tokens, 'src: 'tokens>(
                     offset,
                 }))
             }
-            _ => Err(Rich::custom(span, format!("syntax: {opcode} Rd,<address>"))),
+            _ => Err(syntax_error(span, opcode)),
         },
         Opcode::BlockTransfer(kind, offset_positive, pre_index) => {
             let [dest, registers] = args
@@ -1410,14 +1868,17 @@ fn process_instruction<'tokens, 'src: 'tokens>(
                     (dest, true, registers, caret)
                 }
                 _ => {
+                    return Err(syntax_error(span, opcode));
+                }
+            };
+            for (i, register) in registers.iter().enumerate() {
+                if registers[..i].contains(register) {
                     return Err(Rich::custom(
                         span,
-                        format!("syntax: {opcode} Rn{{!}},<registers>{{^}}"),
+                        format!("register {register} appears more than once"),
                     ));
                 }
-            };
-            // TODO: Check that the list of registers is increasing, is nonempty,
-            // and contains no duplicates.
+            }
             Ok(Processed::Instr(AsmInstr::BlockTransfer {
                 kind,
                 write_back,
@@ -1447,7 +1908,7 @@ fn process_instruction<'tokens, 'src: 'tokens>(
                     source,
                     base,
                 })),
-                _ => Err(Rich::custom(span, format!("syntax: {opcode} Rd,Rm,[Rn]"))),
+                _ => Err(syntax_error(span, opcode)),
             }
         }
         Opcode::Swi => {
@@ -1458,7 +1919,7 @@ fn process_instruction<'tokens, 'src: 'tokens>(
                 Argument::Expression(comment) => {
                     Ok(Processed::Instr(AsmInstr::SoftwareInterrupt { comment }))
                 }
-                _ => Err(Rich::custom(span, format!("syntax: {opcode} <expression>"))),
+                _ => Err(syntax_error(span, opcode)),
             }
         }
         Opcode::Equ => {
@@ -1467,7 +1928,7 @@ fn process_instruction<'tokens, 'src: 'tokens>(
                 .map_err(|_| Rich::custom(span, "expected 1 argument"))?;
             match expr {
                 Argument::Expression(expr) => Ok(Processed::Equ(expr)),
-                _ => Err(Rich::custom(span, format!("syntax: {opcode} <expression>"))),
+                _ => Err(syntax_error(span, opcode)),
             }
         }
         Opcode::DefW => {
@@ -1475,14 +1936,71 @@ fn process_instruction<'tokens, 'src: 'tokens>(
                 .into_iter()
                 .map(|arg| match arg {
                     Argument::Expression(expression) => Ok(Processed::DefW(expression)),
-                    _ => Err(Rich::custom(
-                        span,
-                        format!("syntax: {opcode} <expression>,...,<expression>"),
-                    )),
+                    _ => Err(syntax_error(span, opcode)),
                 })
                 .collect::<Result<Vec<_>, _>>()?;
             Ok(Processed::Vec(exprs))
         }
+        Opcode::DefB => {
+            let mut bytes = Vec::new();
+            for arg in args {
+                match arg {
+                    Argument::Expression(expression) => bytes.push(Processed::DefB(expression)),
+                    // Strings are written as UTF-8, without a terminator.
+                    Argument::String(s) => bytes.extend(
+                        s.bytes()
+                            .map(|byte| Processed::DefB(Expression::Constant(byte as u32))),
+                    ),
+                    _ => return Err(syntax_error(span, opcode)),
+                }
+            }
+            Ok(Processed::Vec(bytes))
+        }
+        Opcode::Align => match <[Argument; 1]>::try_from(args) {
+            Ok([Argument::Expression(expr)]) => Ok(Processed::Align(expr)),
+            Ok(_) => Err(syntax_error(span, opcode)),
+            Err(args) if args.is_empty() => Ok(Processed::Align(Expression::Constant(4))),
+            Err(_) => Err(Rich::custom(span, "expected at most 1 argument")),
+        },
+        Opcode::Org => {
+            let [expr] = args
+                .try_into()
+                .map_err(|_| Rich::custom(span, "expected 1 argument"))?;
+            match expr {
+                Argument::Expression(expr) => Ok(Processed::Org(expr)),
+                _ => Err(syntax_error(span, opcode)),
+            }
+        }
+        Opcode::Ltorg => {
+            if args.is_empty() {
+                Ok(Processed::Ltorg)
+            } else {
+                Err(Rich::custom(span, "expected 0 arguments"))
+            }
+        }
+        Opcode::If => {
+            let [expr] = args
+                .try_into()
+                .map_err(|_| Rich::custom(span, "expected 1 argument"))?;
+            match expr {
+                Argument::Expression(expr) => Ok(Processed::Conditional(AsmLineContents::If(expr))),
+                _ => Err(syntax_error(span, opcode)),
+            }
+        }
+        Opcode::Else | Opcode::EndIf => {
+            if !args.is_empty() {
+                return Err(Rich::custom(span, "expected 0 arguments"));
+            }
+            Ok(Processed::Conditional(if opcode == Opcode::Else {
+                AsmLineContents::Else
+            } else {
+                AsmLineContents::EndIf
+            }))
+        }
+        Opcode::Unavailable(name) => Err(Rich::custom(
+            span,
+            format!("'{name}' is not available on the ARM7TDMI"),
+        )),
     }
 }
 
@@ -1505,3 +2023,276 @@ impl DataOp {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        assemble::{
+            LineError, assemble, mnemonic_category,
+            parser::{MNEMONICS, Opcode, disambiguate_mnemonic, encodings_for},
+        },
+        instr::{Cond, Instr, InstrCategory},
+    };
+
+    /// Every mnemonic in the table must lex to its own opcode,
+    /// and that opcode must display as something that lexes back to it.
+    #[test]
+    fn mnemonic_table_round_trips() {
+        for spec in MNEMONICS {
+            for (cond_name, cond) in [("", Cond::AL), ("eq", Cond::EQ)] {
+                let name = format!("{}{cond_name}{}", spec.base, spec.suffix);
+                assert_eq!(
+                    disambiguate_mnemonic(&name),
+                    Some((cond, spec.opcode)),
+                    "{name}"
+                );
+            }
+            let displayed = spec.opcode.to_string();
+            assert_eq!(
+                disambiguate_mnemonic(&displayed.to_lowercase()),
+                Some((Cond::AL, spec.opcode)),
+                "{displayed}"
+            );
+        }
+    }
+
+    /// The encodings for each instruction must decode back to that instruction,
+    /// although possibly displayed under another name, such as `LDMIA` as `LDMFD`.
+    #[test]
+    fn encodings_decode_to_their_mnemonic() {
+        for spec in MNEMONICS {
+            if matches!(
+                spec.opcode,
+                Opcode::Adr { .. }
+                    | Opcode::Nop
+                    | Opcode::Shift(..)
+                    | Opcode::Equ
+                    | Opcode::DefW
+                    | Opcode::Unavailable(_)
+            ) {
+                // These don't assemble to an instruction of their own name.
+                continue;
+            }
+            let name = format!("{}{}", spec.base, spec.suffix);
+            let encodings = encodings_for(&name);
+            for encoding in &encodings {
+                let (cond, instr) = Instr::decode(*encoding).unwrap();
+                let displayed = instr.display(cond);
+                let decoded_name = displayed.split(' ').next().unwrap().to_lowercase();
+                assert_eq!(
+                    disambiguate_mnemonic(&decoded_name),
+                    Some((Cond::AL, spec.opcode)),
+                    "{name} assembled to {displayed}"
+                );
+            }
+        }
+        assert_eq!(encodings_for("mul"), [0xE0000291]);
+        assert_eq!(encodings_for("ADDEQ").len(), 4);
+        assert!(encodings_for("strsb").is_empty());
+        assert!(encodings_for("dw").is_empty());
+        assert!(encodings_for("frobnicate").is_empty());
+    }
+
+    #[test]
+    fn categories() {
+        for (mnemonic, category) in [
+            ("b", Some(InstrCategory::Branch)),
+            ("BLNE", Some(InstrCategory::Branch)),
+            ("addeqs", Some(InstrCategory::DataProcessing)),
+            ("lsl", Some(InstrCategory::DataProcessing)),
+            ("ldrh", Some(InstrCategory::LoadStore)),
+            ("stmfd", Some(InstrCategory::LoadStore)),
+            ("umlals", Some(InstrCategory::Multiply)),
+            ("mrs", Some(InstrCategory::Psr)),
+            ("swi", Some(InstrCategory::Misc)),
+            ("defw", Some(InstrCategory::Misc)),
+            ("r0", None),
+            ("loop", None),
+        ] {
+            assert_eq!(mnemonic_category(mnemonic), category, "{mnemonic}");
+        }
+    }
+
+    fn generated_labels(src: &str) -> Vec<String> {
+        assemble(src)
+            .unwrap()
+            .labels
+            .into_keys()
+            .filter(|label| label.starts_with("__generatedlabel_"))
+            .collect()
+    }
+
+    #[test]
+    fn deterministic_generated_labels() {
+        let src = "ldr r0, value\n  ldr r1, value\nvalue dw 1\n";
+        let superset = "ldr r0, value\n  ldr r1, value\n  ldr r2, value\nvalue dw 1\n";
+        let labels = generated_labels(src);
+        assert_eq!(labels, ["__generatedlabel_1_1", "__generatedlabel_2_1"]);
+        assert_eq!(generated_labels(src), labels);
+        let superset_labels = generated_labels(superset);
+        assert_eq!(superset_labels.len(), 3);
+        assert!(labels.iter().all(|label| superset_labels.contains(label)));
+    }
+
+    #[test]
+    fn reserved_label() {
+        let errors = assemble("__generatedlabel_0 ldr r0, value\nvalue dw 1\n").unwrap_err();
+        let LineError::ParseError(message) = &errors[0].error else {
+            panic!("expected a parse error, found {:?}", errors[0].error);
+        };
+        assert!(
+            message.ends_with("labels starting with '__generatedlabel_' are reserved"),
+            "{message}"
+        );
+    }
+
+    #[test]
+    fn ldrd_unavailable() {
+        let errors = assemble("ldrd r0, [r1]\n").unwrap_err();
+        assert_eq!(errors.len(), 1);
+        let LineError::ParseError(message) = &errors[0].error else {
+            panic!("expected a parse error, found {:?}", errors[0].error);
+        };
+        assert!(
+            message.ends_with("'LDRD' is not available on the ARM7TDMI"),
+            "{message}"
+        );
+    }
+
+    #[test]
+    fn block_transfer() {
+        let src = "\
+        stmfd sp!, {r0-r2, lr}
+        ldmfd sp!, {r0-r4, lr}
+        ldmia r0, {r1}^
+        stmia r0, {r1}
+";
+        assert_eq!(
+            assemble(src).unwrap().instrs,
+            [0xE92D4007, 0xE8BD401F, 0xE8D00002, 0xE8800002]
+        );
+
+        for (src, expected) in [
+            (
+                "stmfd sp!, {r0-r2, r1}\n",
+                "register R1 appears more than once",
+            ),
+            (
+                "stmfd sp!, {r3-r1}\n",
+                "register range R3-R1 goes backwards; write R1-R3",
+            ),
+        ] {
+            let errors = assemble(src).unwrap_err();
+            let LineError::ParseError(message) = &errors[0].error else {
+                panic!("expected a parse error, found {:?}", errors[0].error);
+            };
+            assert!(message.ends_with(expected), "{message}");
+        }
+
+        let errors = assemble("ldmfd sp!, {}\n").unwrap_err();
+        assert!(matches!(errors[0].error, LineError::EmptyRegisterList));
+    }
+
+    #[test]
+    fn stack_suffixes() {
+        // Loads and stores map each kind of stack to opposite addressing modes,
+        // so that a pop undoes a push.
+        for (mnemonic, word) in [
+            ("stmfd", 0xE92D4FF0),
+            ("stmed", 0xE82D4FF0),
+            ("stmfa", 0xE9AD4FF0),
+            ("stmea", 0xE8AD4FF0),
+            ("ldmfd", 0xE8BD4FF0),
+            ("ldmed", 0xE9BD4FF0),
+            ("ldmfa", 0xE83D4FF0),
+            ("ldmea", 0xE93D4FF0),
+        ] {
+            let output = assemble(&format!("{mnemonic} sp!, {{r4-r11, lr}}\n")).unwrap();
+            assert_eq!(output.instrs, [word], "{mnemonic}");
+        }
+        let output = assemble("STMFD SP!,{R4-R11,LR}\nLDMFD SP!,{R4-R11,PC}\n").unwrap();
+        assert_eq!(output.instrs, [0xE92D4FF0, 0xE8BD8FF0]);
+    }
+
+    #[test]
+    fn local_labels() {
+        // Two loops use the same local label, and a forward reference skips the second.
+        let src = "\
+        mov r0, #3
+1:      subs r0, r0, #1
+        bne 1b
+        b 1f
+        mov r1, #1
+1:      mov r0, #2
+1       subs r0, r0, #1
+        bne 1b
+        swi 2
+";
+        let output = assemble(&src.replace("1       subs", "1:      subs")).unwrap();
+        let expected = assemble(
+            "\
+        mov r0, #3
+one     subs r0, r0, #1
+        bne one
+        b two
+        mov r1, #1
+two     mov r0, #2
+three   subs r0, r0, #1
+        bne three
+        swi 2
+",
+        )
+        .unwrap();
+        assert_eq!(output.instrs, expected.instrs);
+        assert!(output.warnings.is_empty());
+        assert_eq!(output.labels["1.0"], 4);
+        assert_eq!(output.labels["1.1"], 20);
+        assert_eq!(output.labels["1.2"], 24);
+        assert_eq!(output.label_at(24), Some("1.2"));
+        // Only a label followed by a colon at the start of a line is a local label.
+        assert!(assemble(src).is_err());
+
+        let errors = assemble("        b 1b\n1:      b 2f\n").unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert!(matches!(&errors[0].error, LineError::LabelNotFound(label) if label == "1b"));
+        assert_eq!(
+            errors[0].error.to_string(),
+            "local label '1b' not found; there is no '1:' before it"
+        );
+        assert_eq!(
+            errors[1].error.to_string(),
+            "local label '2f' not found; there is no '2:' after it"
+        );
+    }
+
+    #[test]
+    fn psr_fields() {
+        // Each field letter selects one bit of the mask in bits 19..16.
+        for (psr, word) in [
+            ("cpsr", 0xE129F000),
+            ("cpsr_c", 0xE121F000),
+            ("cpsr_x", 0xE122F000),
+            ("cpsr_s", 0xE124F000),
+            ("cpsr_f", 0xE128F000),
+            ("cpsr_cf", 0xE129F000),
+            ("cpsr_fc", 0xE129F000),
+            ("cpsr_sx", 0xE126F000),
+            ("cpsr_cxsf", 0xE12FF000),
+            ("cpsr_all", 0xE12FF000),
+            ("cpsr_flg", 0xE128F000),
+            ("cpsr_ctl", 0xE121F000),
+            ("SPSR_FSXC", 0xE16FF000),
+            ("spsr_flg", 0xE168F000),
+        ] {
+            let output = assemble(&format!("msr {psr}, r0\n")).unwrap();
+            assert_eq!(output.instrs, [word], "{psr}");
+        }
+        for psr in ["cpsr_", "cpsr_cc", "cpsr_q", "cpsr_flgc", "spsr_all_c"] {
+            assert!(assemble(&format!("msr {psr}, r0\n")).is_err(), "{psr}");
+        }
+        // MRS always reads the whole PSR.
+        let output = assemble("mrs r0, cpsr\nmrs r1, cpsr_all\n").unwrap();
+        assert_eq!(output.instrs, [0xE10F0000, 0xE10F1000]);
+        assert!(assemble("mrs r0, cpsr_c\n").is_err());
+    }
+}
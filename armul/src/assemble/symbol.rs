@@ -0,0 +1,103 @@
+//! Interns label names so that the assembler can refer to them by index.
+
+use std::collections::HashMap;
+
+/// A label name that has been interned in a [`SymbolTable`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SymbolId(u32);
+
+/// Maps label names to [`SymbolId`]s and back.
+/// Interning the same name twice gives the same ID.
+#[derive(Debug, Default)]
+pub struct SymbolTable {
+    names: Vec<String>,
+    ids: HashMap<String, SymbolId>,
+}
+
+impl SymbolTable {
+    /// Get the ID of the given name, interning it if we haven't seen it before.
+    pub fn intern(&mut self, name: &str) -> SymbolId {
+        if let Some(id) = self.ids.get(name) {
+            return *id;
+        }
+        let id = SymbolId(self.names.len() as u32);
+        self.names.push(name.to_owned());
+        self.ids.insert(name.to_owned(), id);
+        id
+    }
+
+    /// Get the ID of the given name, if it has been interned.
+    pub fn get(&self, name: &str) -> Option<SymbolId> {
+        self.ids.get(name).copied()
+    }
+
+    /// Get the name that this ID was interned from.
+    pub fn resolve(&self, id: SymbolId) -> &str {
+        &self.names[id.0 as usize]
+    }
+}
+
+/// A mapping from symbols to values, stored densely by [`SymbolId`].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SymbolValues {
+    values: Vec<Option<u32>>,
+}
+
+impl SymbolValues {
+    pub fn get(&self, id: SymbolId) -> Option<u32> {
+        self.values.get(id.0 as usize).copied().flatten()
+    }
+
+    /// Set the value of the given symbol, returning true if it changed.
+    pub fn set(&mut self, id: SymbolId, value: u32) -> bool {
+        let index = id.0 as usize;
+        if index >= self.values.len() {
+            self.values.resize(index + 1, None);
+        }
+        let changed = self.values[index] != Some(value);
+        self.values[index] = Some(value);
+        changed
+    }
+
+    /// Remove the value of the given symbol, returning true if it had one.
+    pub fn remove(&mut self, id: SymbolId) -> bool {
+        self.values
+            .get_mut(id.0 as usize)
+            .is_some_and(|value| value.take().is_some())
+    }
+
+    /// Iterate over all symbols that have a value.
+    pub fn iter(&self) -> impl Iterator<Item = (SymbolId, u32)> + '_ {
+        self.values
+            .iter()
+            .enumerate()
+            .filter_map(|(index, value)| value.map(|value| (SymbolId(index as u32), value)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assemble::symbol::{SymbolTable, SymbolValues};
+
+    #[test]
+    fn intern() {
+        let mut table = SymbolTable::default();
+        let a = table.intern("loop");
+        let b = table.intern("end");
+        assert_ne!(a, b);
+        assert_eq!(table.intern("loop"), a);
+        assert_eq!(table.resolve(b), "end");
+        assert_eq!(table.get("end"), Some(b));
+        assert_eq!(table.get("start"), None);
+
+        let mut values = SymbolValues::default();
+        assert!(values.set(b, 8));
+        assert!(!values.set(b, 8));
+        assert_eq!(values.get(a), None);
+        assert_eq!(values.get(b), Some(8));
+        assert!(values.remove(b));
+        assert!(!values.remove(b));
+        assert!(!values.remove(a));
+        assert_eq!(values.get(b), None);
+    }
+}
@@ -1,25 +1,49 @@
 //! Abstract syntax for ARM assembly.
 
-use std::fmt::Display;
-
-use crate::instr::{
-    Cond, DataOp, Psr, Register, ShiftType, TransferKind, TransferSize, TransferSizeSpecial,
+use crate::{
+    assemble::symbol::SymbolId,
+    instr::{Cond, DataOp, Psr, PsrFields, Register, ShiftType, TransferKind, TransferSize},
 };
 
 #[derive(Debug)]
 pub struct AsmLine {
     pub line_number: usize,
+    /// The line of a macro's body that this line came from, if it was expanded from a macro.
+    /// In that case, `line_number` is the line where the macro was used.
+    pub macro_line: Option<MacroLine>,
     pub contents: AsmLineContents,
     pub comment: String,
 }
 
+/// A line in the body of a macro.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MacroLine {
+    /// The name of the macro, as it was defined.
+    pub name: String,
+    pub line_number: usize,
+}
+
 #[derive(Debug)]
 pub enum AsmLineContents {
     Empty,
-    Label(String),
+    Label(SymbolId),
     Instr(Cond, AsmInstr),
-    Equ(String, Expression),
+    Equ(SymbolId, Expression),
     DefWord(Expression),
+    /// A single byte of data, packed with its neighbours into little-endian words.
+    DefByte(Expression),
+    /// Pads with zeroes up to the next multiple of the given power of two.
+    /// Every address is a multiple of 1, so `ALIGN 1` does nothing.
+    Align(Expression),
+    /// Sets the address of the following lines.
+    Org(Expression),
+    /// Places the literal pool of the preceding `LDR Rd,=value` instructions.
+    Ltorg,
+    /// Assembles the lines up to the matching `ELSE` or `ENDIF` only if the expression is non-zero.
+    If(Expression),
+    /// Assembles the lines up to the matching `ENDIF` only if those after the `IF` were not.
+    Else,
+    EndIf,
 }
 
 /// An instruction that might contain expressions or labels.
@@ -33,9 +57,12 @@ pub enum AsmInstr {
         link: bool,
         target: Expression,
     },
+    /// Computes an address relative to the program counter.
     Adr {
         dest: Register,
         expr: Expression,
+        /// `ADRL` may use two instructions to reach further than `ADR`.
+        long: bool,
     },
     Data {
         set_condition_codes: bool,
@@ -50,6 +77,7 @@ pub enum AsmInstr {
     },
     Msr {
         psr: Psr,
+        fields: PsrFields,
         source: MsrSource,
     },
     Multiply {
@@ -68,9 +96,17 @@ pub enum AsmInstr {
         op1: Register,
         op2: Register,
     },
+    /// `LDR Rd,=value`, which moves the value if it can be encoded as a constant,
+    /// or otherwise loads it from the next literal pool.
+    LoadLiteral {
+        dest: Register,
+        value: Expression,
+        /// The address of this value in the literal pool.
+        pool_label: SymbolId,
+    },
     SingleTransfer {
         kind: TransferKind,
-        size: AnyTransferSize,
+        size: TransferSize,
         write_back: bool,
         offset_positive: bool,
         pre_index: bool,
@@ -104,21 +140,6 @@ pub enum DataOperand {
     Register(Register, Shift),
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum AnyTransferSize {
-    Normal(TransferSize),
-    Special(TransferSizeSpecial),
-}
-
-impl Display for AnyTransferSize {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            AnyTransferSize::Normal(transfer_size) => write!(f, "{transfer_size}"),
-            AnyTransferSize::Special(transfer_size_special) => write!(f, "{transfer_size_special}"),
-        }
-    }
-}
-
 #[derive(Debug)]
 pub struct Shift {
     pub shift_type: ShiftType,
@@ -142,22 +163,27 @@ pub enum ShiftAmount {
 
 #[derive(Debug)]
 pub enum MsrSource {
-    /// Transfer entirely from a register.
     Register(Register),
-    /// Transfer only the flag bits from a register.
-    RegisterFlags(Register),
-    /// Transfer the flag bits of the given 32-bit value.
-    Flags(Expression),
+    /// A constant, of which only the bits in the selected fields are used.
+    Constant(Expression),
 }
 
 #[derive(Debug, Clone)]
 pub enum Expression {
     Constant(u32),
-    Label(String),
+    Label(SymbolId),
+    /// The address of the current instruction or word, written `.`.
+    Here,
+    Neg(Box<Expression>),
+    /// Bitwise complement.
+    Not(Box<Expression>),
     Mul(Box<Expression>, Box<Expression>),
     Div(Box<Expression>, Box<Expression>),
+    Mod(Box<Expression>, Box<Expression>),
     Add(Box<Expression>, Box<Expression>),
     Sub(Box<Expression>, Box<Expression>),
+    And(Box<Expression>, Box<Expression>),
+    Xor(Box<Expression>, Box<Expression>),
     Or(Box<Expression>, Box<Expression>),
     Lsl(Box<Expression>, Box<Expression>),
     Lsr(Box<Expression>, Box<Expression>),
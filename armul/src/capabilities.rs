@@ -0,0 +1,108 @@
+//! Describes which optional features this build of the emulator supports.
+//! Front-ends can query this before offering UI for a feature.
+
+use serde::Serialize;
+
+use crate::{assemble::HealStrategy, processor::BUILTIN_SWIS};
+
+/// The optional capabilities of this build of the emulator.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Capabilities {
+    /// The version of the `armul` crate.
+    pub version: &'static str,
+    /// Whether THUMB instructions can be executed.
+    pub thumb: bool,
+    /// The healing strategies that the assembler understands.
+    pub heal_strategies: Vec<&'static str>,
+    /// The software interrupt comments that have a built-in meaning.
+    pub swi_services: Vec<u32>,
+    /// Whether peripherals can be mapped into the address space.
+    pub device_mapping: bool,
+    /// Whether programs can make semihosting calls, if enabled.
+    pub semihosting: bool,
+}
+
+/// Return the capabilities of this build of the emulator.
+pub fn capabilities() -> Capabilities {
+    Capabilities {
+        version: env!("CARGO_PKG_VERSION"),
+        thumb: cfg!(feature = "thumb"),
+        heal_strategies: HealStrategy::ALL.map(HealStrategy::kind).to_vec(),
+        swi_services: BUILTIN_SWIS.to_vec(),
+        device_mapping: cfg!(feature = "device-mapping"),
+        semihosting: cfg!(feature = "semihosting"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        assemble::HealStrategy,
+        capabilities,
+        processor::{
+            ProcessorError,
+            test::{TestProcessorListener, load},
+        },
+        semihosting::SemihostingHandler,
+    };
+
+    #[test]
+    fn version() {
+        assert_eq!(capabilities().version, env!("CARGO_PKG_VERSION"));
+    }
+
+    #[test]
+    fn heal_strategies_parse() {
+        for kind in capabilities().heal_strategies {
+            let name = if kind == "advanced" {
+                "advanced:r0".to_owned()
+            } else {
+                kind.to_owned()
+            };
+            let strategy: HealStrategy = name.parse().unwrap();
+            assert_eq!(strategy.kind(), kind);
+        }
+    }
+
+    #[test]
+    fn swi_services_are_handled() {
+        let services = capabilities().swi_services;
+        for comment in 0..16 {
+            let mut proc = load(&format!("swi {comment}\n"));
+            let mut listener = TestProcessorListener::default();
+            listener.input_reversed.push('a');
+            let result = proc.try_execute(&mut listener);
+            assert_eq!(
+                result != Err(ProcessorError::InvalidSwi),
+                services.contains(&comment),
+                "swi {comment}"
+            );
+        }
+    }
+
+    #[test]
+    fn thumb() {
+        // Branch to THUMB state and halt with `swi 2`.
+        let mut proc = load("mov r0, #9\nbx r0\ndw 0xDF02\n");
+        let result = proc.run(10);
+        if capabilities().thumb {
+            assert_eq!(result, Ok(3));
+        } else {
+            assert_eq!(result, Err(ProcessorError::UnrecognisedInstruction));
+        }
+    }
+
+    #[test]
+    fn semihosting() {
+        // Exit with reason code 0x20026 through `SYS_EXIT`.
+        let mut proc = load("mov r0, #0x18\nldr r1, =0x20026\nswi 0x123456\n");
+        proc.set_semihosting(Some(SemihostingHandler::default()));
+        let result = proc.run(10);
+        if capabilities().semihosting {
+            assert_eq!(result, Ok(3));
+            assert_eq!(proc.semihosting().unwrap().exit_reason, Some(0x20026));
+        } else {
+            assert_eq!(result, Err(ProcessorError::InvalidSwi));
+        }
+    }
+}
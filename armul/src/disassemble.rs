@@ -0,0 +1,296 @@
+//! Disassembles images that have no symbol information.
+//!
+//! Words that are obviously data are rendered as `DCD` rather than as instructions.
+//! We use two deliberately conservative heuristics:
+//! - a word loaded by a PC-relative `LDR` is part of a literal pool;
+//! - a run of plausible code addresses after a dispatch of the form
+//!   `LDR PC,[PC,Rx,LSL #2]`, or after an unconditional branch, is a jump table.
+
+use std::{collections::BTreeSet, fmt::Display};
+
+use crate::{
+    addr::{Addr, Word},
+    instr::{
+        Cond, Instr, Register, Shift, ShiftAmount, ShiftType, TransferKind, TransferOperand,
+        TransferSize,
+    },
+};
+
+/// A word of a disassembled image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DisassembledWord {
+    pub address: Addr,
+    pub word: Word,
+    pub kind: WordKind,
+}
+
+/// What we believe a word of the image to be.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WordKind {
+    Instr(Cond, Instr),
+    /// A literal, loaded by the PC-relative `LDR`s at these addresses.
+    Literal {
+        users: Vec<Addr>,
+    },
+    /// An entry of the jump table whose dispatching instruction is at the given address.
+    JumpTableEntry {
+        dispatch: Addr,
+        index: usize,
+    },
+    /// A word that does not decode as an instruction.
+    Unknown,
+}
+
+impl Display for DisassembledWord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.kind {
+            WordKind::Instr(cond, instr) => write!(f, "{}", instr.display(*cond)),
+            WordKind::Literal { users } => write!(
+                f,
+                "DCD {} ; literal for {}",
+                self.word,
+                users
+                    .iter()
+                    .map(|user| user.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            WordKind::JumpTableEntry { dispatch, index } => write!(
+                f,
+                "DCD {} ; entry {index} of jump table at {dispatch}",
+                self.word
+            ),
+            WordKind::Unknown => write!(f, "DCD {}", self.word),
+        }
+    }
+}
+
+/// Disassemble an image whose first word is at the given address.
+pub fn disassemble(base: impl Into<Addr>, words: &[u32]) -> Vec<DisassembledWord> {
+    let base = base.into();
+    let end = base + 4 * words.len() as u32;
+    let index_of = |address: Addr| -> Option<usize> {
+        (address >= base && address < end && address.is_word_aligned())
+            .then(|| ((address - base) / 4) as usize)
+    };
+
+    let mut result = words
+        .iter()
+        .enumerate()
+        .map(|(index, word)| DisassembledWord {
+            address: base + 4 * index as u32,
+            word: Word(*word),
+            kind: Instr::decode(*word).map_or(WordKind::Unknown, |(cond, instr)| {
+                WordKind::Instr(cond, instr)
+            }),
+        })
+        .collect::<Vec<_>>();
+
+    // Code that is branched to can't be part of a jump table.
+    let branch_targets = result
+        .iter()
+        .filter_map(|word| match word.kind {
+            WordKind::Instr(_, Instr::Branch { offset, .. }) => {
+                Some((word.address + 8).wrapping_add_signed(offset))
+            }
+            _ => None,
+        })
+        .collect::<BTreeSet<_>>();
+    // An entry of a jump table must be the address of an instruction in the image.
+    let is_code_address = |address: Addr| {
+        index_of(address).is_some_and(|index| Instr::decode(words[index]).is_some())
+    };
+
+    // Find literal pools.
+    let mut literals = Vec::<Vec<Addr>>::new();
+    literals.resize(words.len(), Vec::new());
+    for word in &result {
+        if let Some(target) = literal_address(word)
+            && let Some(index) = index_of(target)
+        {
+            literals[index].push(word.address);
+        }
+    }
+
+    // Find jump tables.
+    let mut in_table = vec![false; words.len()];
+    let mut tables = Vec::new();
+    for (index, word) in result.iter().enumerate() {
+        if !literals[index].is_empty() || in_table[index] {
+            continue;
+        }
+        let (start, min_entries) = match word.kind {
+            WordKind::Instr(Cond::AL, instr) if is_dispatch(instr) => (index + 2, 1),
+            // Without a dispatch, only a longer run of addresses convinces us.
+            WordKind::Instr(Cond::AL, Instr::Branch { link: false, .. }) => (index + 1, 2),
+            _ => continue,
+        };
+        let entries = result
+            .iter()
+            .enumerate()
+            .skip(start)
+            .take_while(|(entry_index, entry)| {
+                literals[*entry_index].is_empty()
+                    && !in_table[*entry_index]
+                    && !branch_targets.contains(&entry.address)
+                    && is_code_address(Addr(entry.word.0))
+            })
+            .count();
+        if entries >= min_entries {
+            in_table[start..start + entries].fill(true);
+            tables.push((word.address, start, entries));
+        }
+    }
+
+    for (dispatch, start, entries) in tables {
+        for index in 0..entries {
+            result[start + index].kind = WordKind::JumpTableEntry { dispatch, index };
+        }
+    }
+    for (word, users) in result.iter_mut().zip(literals) {
+        if !users.is_empty() {
+            word.kind = WordKind::Literal { users };
+        }
+    }
+    result
+}
+
+/// If this word is an `LDR Rd,[PC,#offset]`, returns the address that it loads from.
+fn literal_address(word: &DisassembledWord) -> Option<Addr> {
+    match word.kind {
+        WordKind::Instr(
+            _,
+            Instr::SingleTransfer {
+                kind: TransferKind::Load,
+                size: TransferSize::Word,
+                write_back: false,
+                offset_positive,
+                pre_index: true,
+                base_register: Register::R15,
+                offset: TransferOperand::Constant(offset),
+                ..
+            },
+        ) => {
+            let pc = word.address + 8;
+            Some(if offset_positive {
+                pc + offset as u32
+            } else {
+                pc - offset as u32
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Returns true if this is an `LDR PC,[PC,Rx,LSL #2]`, which jumps through a table
+/// starting two words after it.
+fn is_dispatch(instr: Instr) -> bool {
+    matches!(
+        instr,
+        Instr::SingleTransfer {
+            kind: TransferKind::Load,
+            size: TransferSize::Word,
+            write_back: false,
+            offset_positive: true,
+            pre_index: true,
+            data_register: Register::R15,
+            base_register: Register::R15,
+            offset: TransferOperand::Register(
+                _,
+                Shift {
+                    shift_type: ShiftType::LogicalLeft,
+                    shift_amount: ShiftAmount::Constant(2),
+                },
+            ),
+        }
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{assemble::assemble, disassemble::disassemble};
+
+    fn render(src: &str) -> String {
+        let words = assemble(src).unwrap().instrs;
+        disassemble(0, &words)
+            .into_iter()
+            .map(|word| format!("{:08X}: {word}\n", word.address))
+            .collect()
+    }
+
+    #[test]
+    fn literal_pool() {
+        let src = "\
+        ldr r0, [pc, #4]
+        ldr r1, [pc, #0]
+        swi 2
+        dw 0x12345678
+";
+        assert_eq!(
+            render(src),
+            "\
+00000000: LDR R0,[R15,#4]
+00000004: LDR R1,[R15]
+00000008: SWI 2
+0000000C: DCD 0x12345678 ; literal for 0x00000000, 0x00000004
+"
+        );
+    }
+
+    #[test]
+    fn jump_table() {
+        let src = "\
+        ldr pc, [pc, r0, lsl #2]
+        b default
+        dw case0
+        dw case1
+case0   mov r1, #0
+        b default
+        dw case1
+        dw default
+case1   mov r1, #1
+default swi 2
+";
+        assert_eq!(
+            render(src),
+            "\
+00000000: LDR R15,[R15,R0,LSL #2]
+00000004: B PC+#24
+00000008: DCD 0x00000010 ; entry 0 of jump table at 0x00000000
+0000000C: DCD 0x00000020 ; entry 1 of jump table at 0x00000000
+00000010: MOV R1,#0
+00000014: B PC+#8
+00000018: DCD 0x00000020 ; entry 0 of jump table at 0x00000014
+0000001C: DCD 0x00000024 ; entry 1 of jump table at 0x00000014
+00000020: MOV R1,#1
+00000024: SWI 2
+"
+        );
+    }
+
+    #[test]
+    fn no_false_positives() {
+        // The words after each branch are small, so look like addresses in the image.
+        // However, the run after the first branch ends at a branch target,
+        // and the run after the second is only one word long.
+        let src = "\
+        b skip
+        andeq r0, r0, r0
+skip    andeq r0, r0, r4
+        b end
+        dw 4
+end     swi 2
+";
+        assert_eq!(
+            render(src),
+            "\
+00000000: B PC+#0
+00000004: ANDEQ R0,R0,R0
+00000008: ANDEQ R0,R0,R4
+0000000C: B PC+#0
+00000010: ANDEQ R0,R0,R4
+00000014: SWI 2
+"
+        );
+    }
+}
@@ -0,0 +1,181 @@
+//! Static estimates of how many cycles an instruction takes to execute.
+
+use std::{fmt::Display, ops::Add};
+
+use serde::Serialize;
+
+use super::{Cond, DataOp, Instr, Register, TransferKind};
+
+/// A number of cycles of each type, written in the data sheet as e.g. `2S + 1N + 1I`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+pub struct Cycles {
+    pub seq: usize,
+    pub nonseq: usize,
+    pub internal: usize,
+}
+
+impl Cycles {
+    pub const fn new(seq: usize, nonseq: usize, internal: usize) -> Self {
+        Self {
+            seq,
+            nonseq,
+            internal,
+        }
+    }
+
+    /// The time that these cycles take, in units of S-cycles,
+    /// assuming that N-cycles take 2.5 times as long as the others.
+    pub fn estimated_time(&self) -> f64 {
+        (self.seq + self.internal) as f64 + 2.5 * self.nonseq as f64
+    }
+}
+
+impl Add for Cycles {
+    type Output = Cycles;
+
+    fn add(self, rhs: Self) -> Self::Output {
+        Cycles::new(
+            self.seq + rhs.seq,
+            self.nonseq + rhs.nonseq,
+            self.internal + rhs.internal,
+        )
+    }
+}
+
+impl Display for Cycles {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let terms = [(self.seq, 'S'), (self.nonseq, 'N'), (self.internal, 'I')]
+            .into_iter()
+            .filter(|(count, _)| *count != 0)
+            .map(|(count, ty)| format!("{count}{ty}"))
+            .collect::<Vec<_>>();
+        if terms.is_empty() {
+            write!(f, "0")
+        } else {
+            write!(f, "{}", terms.join("+"))
+        }
+    }
+}
+
+/// The fewest and most cycles that some code could take to execute,
+/// as counted by [`crate::processor::Processor`],
+/// except that software interrupts are charged as on the ARM7TDMI.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+pub struct CycleEstimate {
+    pub best: Cycles,
+    pub worst: Cycles,
+}
+
+impl CycleEstimate {
+    pub const fn exact(cycles: Cycles) -> Self {
+        Self {
+            best: cycles,
+            worst: cycles,
+        }
+    }
+}
+
+impl Add for CycleEstimate {
+    type Output = CycleEstimate;
+
+    fn add(self, rhs: Self) -> Self::Output {
+        CycleEstimate {
+            best: self.best + rhs.best,
+            worst: self.worst + rhs.worst,
+        }
+    }
+}
+
+impl Display for CycleEstimate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.best == self.worst {
+            write!(f, "{}", self.best)
+        } else {
+            write!(f, "{}..{}", self.best, self.worst)
+        }
+    }
+}
+
+impl Instr {
+    /// Estimate the number of cycles this instruction takes to execute
+    /// under the given condition, without knowing the state of the processor.
+    ///
+    /// Conditional instructions are assumed to take one S-cycle at best,
+    /// which is the cost of skipping them.
+    /// Multiplies range over all possible early terminations of the multiplier.
+    pub fn cycle_estimate(&self, cond: Cond) -> CycleEstimate {
+        let executed = self.executed_cycles();
+        if cond == Cond::AL {
+            executed
+        } else {
+            CycleEstimate {
+                best: Cycles::new(1, 0, 0),
+                worst: executed.worst,
+            }
+        }
+    }
+
+    /// The cycles taken if this instruction's condition holds.
+    fn executed_cycles(&self) -> CycleEstimate {
+        // A write to the program counter flushes the pipeline, costing 1S + 1N.
+        const FLUSH: Cycles = Cycles::new(1, 1, 0);
+        match *self {
+            Instr::BranchExchange { .. } | Instr::Branch { .. } => {
+                CycleEstimate::exact(Cycles::new(1, 0, 0) + FLUSH)
+            }
+            Instr::Data { op, dest, op2, .. } => {
+                let mut cycles = Cycles::new(1, 0, 0);
+                if op2.is_register_specified_shift() {
+                    cycles.internal += 1;
+                }
+                if dest == Register::R15
+                    && !matches!(op, DataOp::Tst | DataOp::Teq | DataOp::Cmp | DataOp::Cmn)
+                {
+                    cycles = cycles + FLUSH;
+                }
+                CycleEstimate::exact(cycles)
+            }
+            Instr::Mrs { .. } | Instr::Msr { .. } => CycleEstimate::exact(Cycles::new(1, 0, 0)),
+            Instr::Multiply { addend, .. } => {
+                let extra = addend.is_some() as usize;
+                CycleEstimate {
+                    best: Cycles::new(1, 0, 1 + extra),
+                    worst: Cycles::new(1, 0, 4 + extra),
+                }
+            }
+            Instr::MultiplyLong { accumulate, .. } => {
+                let extra = accumulate as usize;
+                CycleEstimate {
+                    best: Cycles::new(1, 0, 2 + extra),
+                    worst: Cycles::new(1, 0, 5 + extra),
+                }
+            }
+            Instr::SingleTransfer {
+                kind,
+                data_register,
+                ..
+            } => CycleEstimate::exact(match kind {
+                TransferKind::Store => Cycles::new(0, 2, 0),
+                TransferKind::Load if data_register == Register::R15 => Cycles::new(2, 2, 1),
+                TransferKind::Load => Cycles::new(1, 1, 1),
+            }),
+            Instr::BlockTransfer {
+                kind, registers, ..
+            } => {
+                let count = registers.count_ones() as usize;
+                CycleEstimate::exact(match kind {
+                    TransferKind::Store => Cycles::new(count.saturating_sub(1), 2, 0),
+                    TransferKind::Load if registers & (1 << 15) != 0 => {
+                        Cycles::new(count, 1, 1) + FLUSH
+                    }
+                    TransferKind::Load => Cycles::new(count, 1, 1),
+                })
+            }
+            Instr::Swap { .. } => CycleEstimate::exact(Cycles::new(1, 2, 1)),
+            // Entering the software interrupt vector costs 2S + 1N on the ARM7TDMI.
+            // The emulator services the built-in comments without entering it,
+            // but a program would still pay for this on real hardware.
+            Instr::SoftwareInterrupt { .. } => CycleEstimate::exact(Cycles::new(1, 0, 0) + FLUSH),
+        }
+    }
+}
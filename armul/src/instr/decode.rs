@@ -1,9 +1,8 @@
 use num_traits::FromPrimitive;
 
 use crate::instr::{
-    Cond, DataOp, DataOperand, Instr, MsrSource, Psr, Register, RotatedConstant, Shift,
-    ShiftAmount, ShiftType, SpecialOperand, TransferKind, TransferOperand, TransferSize,
-    TransferSizeSpecial,
+    Cond, DataOp, DataOperand, Instr, MsrSource, Psr, PsrFields, Register, RotatedConstant, Shift,
+    ShiftAmount, ShiftType, TransferKind, TransferOperand, TransferSize,
 };
 
 impl Instr {
@@ -77,20 +76,20 @@ impl Instr {
                             })
                         }
                     } else {
-                        // This is special data transfer.
+                        // This is halfword or signed data transfer.
                         // Note that SH can never be 00.
-                        Some(Instr::SingleTransferSpecial {
+                        Some(Instr::SingleTransfer {
                             kind: if instr & (1 << 20) == 0 {
                                 TransferKind::Store
                             } else {
                                 TransferKind::Load
                             },
                             size: if instr & (1 << 6) == 0 {
-                                TransferSizeSpecial::HalfWord
+                                TransferSize::HalfWord
                             } else if instr & (1 << 5) == 0 {
-                                TransferSizeSpecial::SignExtendedByte
+                                TransferSize::SignExtendedByte
                             } else {
-                                TransferSizeSpecial::SignExtendedHalfWord
+                                TransferSize::SignExtendedHalfWord
                             },
                             write_back: instr & (1 << 21) != 0,
                             offset_positive: instr & (1 << 23) != 0,
@@ -98,10 +97,16 @@ impl Instr {
                             data_register: Register::from_u4(instr, 12),
                             base_register: Register::from_u4(instr, 16),
                             offset: if instr & (1 << 22) == 0 {
-                                SpecialOperand::Register(Register::from_u4(instr, 0))
+                                TransferOperand::Register(
+                                    Register::from_u4(instr, 0),
+                                    Shift {
+                                        shift_type: ShiftType::LogicalLeft,
+                                        shift_amount: ShiftAmount::Constant(0),
+                                    },
+                                )
                             } else {
-                                SpecialOperand::Constant(
-                                    (((instr >> 4) & 0xF0) | instr & 0xF) as u8,
+                                TransferOperand::Constant(
+                                    (((instr >> 4) & 0xF0) | instr & 0xF) as u16,
                                 )
                             },
                         })
@@ -126,36 +131,27 @@ impl Instr {
                             },
                             target: Register::from_u4(instr, 12),
                         })
-                    } else if instr & (0b1_1011_1111_1111 << 12) == 0b1_0010_1000_1111 << 12 {
-                        // This is an MSR flag instruction.
+                    } else if instr & (0b1_1011_0000_1111 << 12) == 0b1_0010_0000_1111 << 12 {
+                        // This is an MSR instruction.
+                        // Bits 19..16 select the bytes of the PSR to write,
+                        // as described [here](https://mgba-emu.github.io/gbatek/#armopcodespsrtransfermrsmsr).
                         Some(Instr::Msr {
                             psr: if instr & (1 << 22) == 0 {
                                 Psr::Cpsr
                             } else {
                                 Psr::Spsr
                             },
+                            fields: PsrFields(((instr >> 16) & 0xF) as u8),
                             source: if instr & (1 << 25) == 0 {
                                 // The source operand is a register.
-                                MsrSource::RegisterFlags(Register::from_u4(instr, 0))
+                                MsrSource::Register(Register::from_u4(instr, 0))
                             } else {
                                 // The source operand is an immediate value.
-                                let imm = instr & 0xFF;
-                                let rotate = (instr >> 8) & 0xF;
-                                MsrSource::Flags(imm.rotate_right(rotate * 2))
-                            },
-                        })
-                    } else if instr & (0b1_1011_0000_1111 << 12) == 0b1_0010_0000_1111 << 12 {
-                        // This is an MSR register instruction.
-                        // Note that we don't check bits 16..13 because
-                        // the docs [here](https://mgba-emu.github.io/gbatek/#armopcodespsrtransfermrsmsr)
-                        // say those bits are variable.
-                        Some(Instr::Msr {
-                            psr: if instr & (1 << 22) == 0 {
-                                Psr::Cpsr
-                            } else {
-                                Psr::Spsr
+                                MsrSource::Constant(RotatedConstant {
+                                    immediate: instr as u8,
+                                    half_rotate: ((instr >> 8) & 0xF) as u8,
+                                })
                             },
-                            source: MsrSource::Register(Register::from_u4(instr, 0)),
                         })
                     } else {
                         // This is a data instruction.
@@ -239,7 +235,7 @@ impl Instr {
                     offset,
                 })
             }
-            0b111 if instr & (1 << 25) != 0 => {
+            0b111 if instr & (1 << 24) != 0 => {
                 // This is a software interrupt.
                 let comment = instr & ((1 << 24) - 1);
                 Some(Instr::SoftwareInterrupt { comment })
@@ -289,7 +285,13 @@ impl Instr {
 
 #[cfg(test)]
 mod tests {
-    use crate::instr::Instr;
+    use crate::{
+        assemble::assemble,
+        instr::{
+            Cond, Instr, MsrSource, PsrFields, Register, Shift, ShiftAmount, ShiftType,
+            TransferKind, TransferOperand, TransferSize,
+        },
+    };
 
     #[test]
     fn test() {
@@ -304,5 +306,340 @@ mod tests {
                 panic!("---")
             }
         }
+
+        // Coprocessor instructions are not software interrupts.
+        assert!(Instr::decode(0xEE000000).is_none());
+        assert!(Instr::decode(0xEF000000).is_some());
+    }
+
+    #[test]
+    fn halfword_store_round_trip() {
+        let cases = [
+            (
+                "strh r0, [r1, r2]!",
+                0xE1A100B2,
+                Instr::SingleTransfer {
+                    kind: TransferKind::Store,
+                    size: TransferSize::HalfWord,
+                    write_back: true,
+                    offset_positive: true,
+                    pre_index: true,
+                    data_register: Register::R0,
+                    base_register: Register::R1,
+                    offset: TransferOperand::Register(
+                        Register::R2,
+                        Shift {
+                            shift_type: ShiftType::LogicalLeft,
+                            shift_amount: ShiftAmount::Constant(0),
+                        },
+                    ),
+                },
+            ),
+            (
+                "strh r0, [r1], #2",
+                0xE0C100B2,
+                Instr::SingleTransfer {
+                    kind: TransferKind::Store,
+                    size: TransferSize::HalfWord,
+                    write_back: false,
+                    offset_positive: true,
+                    pre_index: false,
+                    data_register: Register::R0,
+                    base_register: Register::R1,
+                    offset: TransferOperand::Constant(2),
+                },
+            ),
+        ];
+        for (src, encoded, instr) in cases {
+            let assembled = assemble(&format!("{src}\n")).unwrap();
+            assert_eq!(assembled.instrs, [encoded], "{src}");
+            assert_eq!(Instr::decode(encoded), Some((Cond::AL, instr)), "{src}");
+            assert_eq!(instr.encode(Cond::AL).unwrap(), encoded, "{src}");
+        }
+    }
+
+    #[test]
+    fn msr_round_trip() {
+        let cases = [
+            (
+                "msr cpsr, r0",
+                0xE129F000,
+                PsrFields::DEFAULT,
+                None,
+                "MSR CPSR,R0",
+            ),
+            (
+                "msr cpsr_flg, r1",
+                0xE128F001,
+                PsrFields::FLAGS,
+                None,
+                "MSR CPSR_flg,R1",
+            ),
+            (
+                "msr spsr_c, r2",
+                0xE161F002,
+                PsrFields::CONTROL,
+                None,
+                "MSR SPSR_c,R2",
+            ),
+            (
+                "msr cpsr_fsxc, r3",
+                0xE12FF003,
+                PsrFields(0b1111),
+                None,
+                "MSR CPSR_fsxc,R3",
+            ),
+            // Bits outside the fields are ignored, so need not be encodable.
+            (
+                "msr cpsr_flg, 0xF0000123",
+                0xE328F20F,
+                PsrFields::FLAGS,
+                Some(0xF0000000),
+                "MSR CPSR_flg,#15,ROR 4",
+            ),
+            (
+                "msr cpsr_ctl, 0x1F",
+                0xE321F01F,
+                PsrFields::CONTROL,
+                Some(0x1F),
+                "MSR CPSR_c,#31",
+            ),
+        ];
+        for (src, encoded, fields, constant, display) in cases {
+            let assembled = assemble(&format!("{src}\n")).unwrap();
+            assert_eq!(assembled.instrs, [encoded], "{src}");
+            let (cond, instr) = Instr::decode(encoded).unwrap();
+            let Instr::Msr {
+                fields: decoded_fields,
+                source,
+                ..
+            } = instr
+            else {
+                panic!("{src} decoded as {instr:?}");
+            };
+            assert_eq!(decoded_fields, fields, "{src}");
+            match source {
+                MsrSource::Register(_) => assert_eq!(constant, None, "{src}"),
+                MsrSource::Constant(value) => assert_eq!(constant, Some(value.value().0), "{src}"),
+            }
+            assert_eq!(instr.display(cond), display);
+            assert_eq!(instr.encode(cond).unwrap(), encoded, "{src}");
+        }
+        assert!(assemble("msr cpsr_ff, r0\n").is_err());
+        assert!(assemble("msr cpsr_flg, 0x1234\n").is_ok());
+        assert!(assemble("msr cpsr_fc, 0xF0001234\n").is_err());
+    }
+
+    #[test]
+    fn block_transfer_round_trip() {
+        for kind in [TransferKind::Load, TransferKind::Store] {
+            for flags in 0..16 {
+                for (base_register, registers) in [
+                    (Register::R13, 0x4007),
+                    (Register::R0, 0x0001),
+                    (Register::R11, 0x8000),
+                    (Register::R2, 0xFFFF),
+                ] {
+                    let instr = Instr::BlockTransfer {
+                        kind,
+                        write_back: flags & 0b0001 != 0,
+                        offset_positive: flags & 0b0010 != 0,
+                        pre_index: flags & 0b0100 != 0,
+                        psr: flags & 0b1000 != 0,
+                        base_register,
+                        registers,
+                    };
+                    let encoded = instr.encode(Cond::AL).unwrap();
+                    assert_eq!(encoded >> 25 & 0b111, 0b100, "{instr:?}");
+                    assert_eq!(encoded as u16, registers, "{instr:?}");
+                    assert_eq!(Instr::decode(encoded), Some((Cond::AL, instr)));
+                }
+            }
+        }
+        // STMFD SP!, {R0-R2, LR}
+        assert_eq!(
+            Instr::BlockTransfer {
+                kind: TransferKind::Store,
+                write_back: true,
+                offset_positive: false,
+                pre_index: true,
+                psr: false,
+                base_register: Register::R13,
+                registers: 0x4007,
+            }
+            .encode(Cond::AL)
+            .unwrap(),
+            0xE92D4007
+        );
+    }
+
+    /// Re-encoding any decoded block transfer or swap must give back the original word.
+    #[test]
+    fn block_transfer_and_swap_words_round_trip() {
+        // A simple xorshift generator, so that the register lists vary.
+        let mut state = 0x12345678u32;
+        let mut next = || {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state
+        };
+        for cond in [0x0, 0x1, 0xA, 0xE] {
+            for bits in 0..32 {
+                for base in 0..16 {
+                    let word = cond << 28 | 0b100 << 25 | bits << 20 | base << 16 | next() & 0xFFFF;
+                    let (cond, instr) = Instr::decode(word).unwrap();
+                    assert!(matches!(instr, Instr::BlockTransfer { .. }), "{word:08X}");
+                    assert_eq!(instr.encode(cond).unwrap(), word, "{word:08X}");
+                }
+            }
+            for byte in 0..2 {
+                for _ in 0..64 {
+                    let registers = next();
+                    let word = cond << 28
+                        | 0b00010 << 23
+                        | byte << 22
+                        | (registers & 0xF) << 16
+                        | (registers >> 4 & 0xF) << 12
+                        | 0b1001 << 4
+                        | registers >> 8 & 0xF;
+                    let (cond, instr) = Instr::decode(word).unwrap();
+                    assert!(matches!(instr, Instr::Swap { .. }), "{word:08X}");
+                    assert_eq!(instr.encode(cond).unwrap(), word, "{word:08X}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn swap_round_trip() {
+        let swap = |byte, dest, source, base| Instr::Swap {
+            byte,
+            dest,
+            source,
+            base,
+        };
+        let cases = [
+            (
+                "swp r1, r2, [r3]",
+                0xE1031092,
+                swap(false, Register::R1, Register::R2, Register::R3),
+            ),
+            (
+                "swpb r1, r2, [r3]",
+                0xE1431092,
+                swap(true, Register::R1, Register::R2, Register::R3),
+            ),
+            (
+                "swpb r0, r1, [r2]",
+                0xE1420091,
+                swap(true, Register::R0, Register::R1, Register::R2),
+            ),
+        ];
+        for (src, encoded, instr) in cases {
+            assert_eq!(instr.encode(Cond::AL).unwrap(), encoded, "{src}");
+            let assembled = assemble(&format!("{src}\n")).unwrap();
+            assert_eq!(assembled.instrs, [encoded], "{src}");
+            assert_eq!(Instr::decode(encoded), Some((Cond::AL, instr)), "{src}");
+        }
+    }
+
+    #[test]
+    fn single_transfer_round_trip() {
+        let sizes = [
+            TransferSize::Byte,
+            TransferSize::Word,
+            TransferSize::HalfWord,
+            TransferSize::SignExtendedByte,
+            TransferSize::SignExtendedHalfWord,
+        ];
+        let offsets = [
+            TransferOperand::Constant(4),
+            TransferOperand::Constant(255),
+            TransferOperand::Register(
+                Register::R2,
+                Shift {
+                    shift_type: ShiftType::LogicalLeft,
+                    shift_amount: ShiftAmount::Constant(0),
+                },
+            ),
+        ];
+        for size in sizes {
+            for kind in [TransferKind::Store, TransferKind::Load] {
+                if kind == TransferKind::Store
+                    && matches!(
+                        size,
+                        TransferSize::SignExtendedByte | TransferSize::SignExtendedHalfWord
+                    )
+                {
+                    continue;
+                }
+                for offset in offsets {
+                    for (pre_index, write_back) in [(true, false), (true, true), (false, false)] {
+                        for offset_positive in [true, false] {
+                            let instr = Instr::SingleTransfer {
+                                kind,
+                                size,
+                                write_back,
+                                offset_positive,
+                                pre_index,
+                                data_register: Register::R0,
+                                base_register: Register::R1,
+                                offset,
+                            };
+                            let src = instr.display(Cond::AL);
+                            let encoded = instr.encode(Cond::AL).unwrap();
+                            assert_eq!(Instr::decode(encoded), Some((Cond::AL, instr)), "{src}");
+                            if !offset_positive {
+                                // The assembler reads `#-4` as the constant `-4`.
+                                continue;
+                            }
+                            assert_eq!(
+                                assemble(&format!("{src}\n")).unwrap().instrs,
+                                [encoded],
+                                "{src}"
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn single_transfer_offset_limits() {
+        let instr = |size, offset| Instr::SingleTransfer {
+            kind: TransferKind::Load,
+            size,
+            write_back: false,
+            offset_positive: true,
+            pre_index: true,
+            data_register: Register::R0,
+            base_register: Register::R1,
+            offset,
+        };
+        let shifted = TransferOperand::Register(
+            Register::R2,
+            Shift {
+                shift_type: ShiftType::LogicalLeft,
+                shift_amount: ShiftAmount::Constant(2),
+            },
+        );
+        assert!(
+            instr(TransferSize::Word, TransferOperand::Constant(4095))
+                .encode(Cond::AL)
+                .is_ok()
+        );
+        assert!(instr(TransferSize::Word, shifted).encode(Cond::AL).is_ok());
+        assert!(
+            instr(TransferSize::HalfWord, TransferOperand::Constant(256))
+                .encode(Cond::AL)
+                .is_err()
+        );
+        assert!(
+            instr(TransferSize::HalfWord, shifted)
+                .encode(Cond::AL)
+                .is_err()
+        );
     }
 }
@@ -1,13 +1,29 @@
-use std::fmt::Display;
+use std::{collections::BTreeMap, fmt::Display};
 
-use crate::instr::{
-    Cond, DataOp, Instr, MsrSource, Psr, TransferKind, TransferOperand,
+use crate::{
+    assemble::is_source_label,
+    instr::{Cond, DataOp, DataOperand, Instr, MsrSource, Psr, TransferKind, TransferOperand},
 };
 
-use super::SpecialOperand;
+/// Where an instruction is being rendered as source code, and the labels it may refer to.
+#[derive(Clone, Copy)]
+struct SourceContext<'a> {
+    address: u32,
+    labels: &'a BTreeMap<String, u32>,
+}
 
 impl Instr {
     pub fn write(&self, cond: Cond, f: &mut impl std::fmt::Write) -> std::fmt::Result {
+        self.write_in(cond, None, f)
+    }
+
+    /// Write this instruction, as source code that the assembler accepts if `context` is given.
+    fn write_in(
+        &self,
+        cond: Cond,
+        context: Option<SourceContext>,
+        f: &mut impl std::fmt::Write,
+    ) -> std::fmt::Result {
         match self {
             Instr::BranchExchange { operand } => {
                 write!(f, "BX{cond} {operand}")?;
@@ -17,7 +33,20 @@ impl Instr {
                 if *link {
                     write!(f, "L")?;
                 }
-                write!(f, "{cond} PC+#{offset}")?;
+                match context {
+                    Some(SourceContext { address, labels }) => {
+                        // The PC is two instructions ahead when the offset is added.
+                        let target = address.wrapping_add(8).wrapping_add(*offset as u32);
+                        match labels
+                            .iter()
+                            .find(|(label, value)| **value == target && is_source_label(label))
+                        {
+                            Some((label, _)) => write!(f, "{cond} {label}")?,
+                            None => write!(f, "{cond} 0x{target:08X}")?,
+                        }
+                    }
+                    None => write!(f, "{cond} PC+#{offset}")?,
+                }
             }
             Instr::Data {
                 set_condition_codes,
@@ -43,7 +72,10 @@ impl Instr {
                         write!(f, " {dest},{op1}")?;
                     }
                 }
-                write!(f, ",{op2}")?;
+                match (op2, context) {
+                    (DataOperand::Constant(c), Some(_)) => write!(f, ",#{}", c.value().0)?,
+                    _ => write!(f, ",{op2}")?,
+                }
             }
             Instr::Mrs { psr, target } => {
                 write!(f, "MRS{cond} {target},")?;
@@ -52,7 +84,11 @@ impl Instr {
                     Psr::Spsr => write!(f, "SPSR")?,
                 }
             }
-            Instr::Msr { psr, source } => {
+            Instr::Msr {
+                psr,
+                fields,
+                source,
+            } => {
                 write!(f, "MSR{cond} ")?;
                 match psr {
                     Psr::Cpsr => write!(f, "CPSR")?,
@@ -60,13 +96,13 @@ impl Instr {
                 }
                 match source {
                     MsrSource::Register(register) => {
-                        write!(f, ",{register}")?;
+                        write!(f, "{fields},{register}")?;
                     }
-                    MsrSource::RegisterFlags(register) => {
-                        write!(f, "_flg,{register}")?;
+                    MsrSource::Constant(c) if context.is_some() => {
+                        write!(f, "{fields},#{}", c.value().0)?;
                     }
-                    MsrSource::Flags(c) => {
-                        write!(f, "_flg,#{c}")?;
+                    MsrSource::Constant(c) => {
+                        write!(f, "{fields},#{c}")?;
                     }
                 }
             }
@@ -137,6 +173,10 @@ impl Instr {
                     data_register,
                     base_register,
                     match offset {
+                        // Without an offset, the address would be read as pre-indexed.
+                        TransferOperand::Constant(0) if context.is_some() && !*pre_index => {
+                            ",#0".to_owned()
+                        }
                         TransferOperand::Constant(0) => "".to_owned(),
                         TransferOperand::Constant(i) => {
                             if *offset_positive {
@@ -155,44 +195,6 @@ impl Instr {
                     },
                 )?;
             }
-            Instr::SingleTransferSpecial {
-                kind,
-                size,
-                write_back,
-                offset_positive,
-                pre_index,
-                data_register,
-                base_register,
-                offset,
-            } => {
-                write_single_transfer(
-                    cond,
-                    f,
-                    kind,
-                    size,
-                    write_back,
-                    pre_index,
-                    data_register,
-                    base_register,
-                    match offset {
-                        SpecialOperand::Constant(0) => "".to_owned(),
-                        SpecialOperand::Constant(i) => {
-                            if *offset_positive {
-                                format!(",#{i}")
-                            } else {
-                                format!(",#-{i}")
-                            }
-                        }
-                        SpecialOperand::Register(register) => {
-                            if *offset_positive {
-                                format!(",{register}")
-                            } else {
-                                format!(",-{register}")
-                            }
-                        }
-                    },
-                )?;
-            }
             Instr::BlockTransfer {
                 kind,
                 write_back,
@@ -212,6 +214,14 @@ impl Instr {
                 }
                 write!(f, "{cond}")?;
                 let offset = match (kind, pre_index, *offset_positive) {
+                    // The stack suffixes depend on the kind of transfer, so the
+                    // assembler's own addressing mode suffixes are clearer in source code.
+                    _ if context.is_some() => match (pre_index, *offset_positive) {
+                        (false, true) => "IA",
+                        (true, true) => "IB",
+                        (false, false) => "DA",
+                        (true, false) => "DB",
+                    },
                     (TransferKind::Store, true, true) => "FA",
                     (TransferKind::Store, true, false) => "FD",
                     (TransferKind::Store, false, true) => "EA",
@@ -226,11 +236,15 @@ impl Instr {
                     write!(f, "!")?;
                 }
                 write!(f, ",{{")?;
-                for (ix, i) in (0..16).filter(|i| (registers & (1 << i)) != 0).enumerate() {
-                    if ix != 0 {
-                        write!(f, ",")?;
+                if context.is_some() {
+                    write_register_ranges(f, *registers)?;
+                } else {
+                    for (ix, i) in (0..16).filter(|i| (registers & (1 << i)) != 0).enumerate() {
+                        if ix != 0 {
+                            write!(f, ",")?;
+                        }
+                        write!(f, "R{i}")?;
                     }
-                    write!(f, "R{i}")?;
                 }
                 write!(f, "}}")?;
                 if *psr {
@@ -261,6 +275,50 @@ impl Instr {
         self.write(cond, &mut w).unwrap();
         w
     }
+
+    /// Render this instruction, found at the given address, as source code that
+    /// assembles back to the same instruction.
+    /// Branches go to one of the given labels if there is one at their target,
+    /// and otherwise to the target's address.
+    /// Labels that can't be written in source code, such as local labels, are not used;
+    /// pass only code labels, such as from [`crate::assemble::AssemblerOutput::code_labels`], so that
+    /// a branch is never written in terms of an `EQU` constant.
+    ///
+    /// Constants are written as their values, so the assembler chooses how to rotate them.
+    /// Its choice is the same as the original's unless the original encoded the
+    /// constant in an unusual way.
+    pub fn to_source(&self, cond: Cond, address: u32, labels: &BTreeMap<String, u32>) -> String {
+        let mut w = String::new();
+        self.write_in(cond, Some(SourceContext { address, labels }), &mut w)
+            .unwrap();
+        w
+    }
+}
+
+/// Write the registers in this mask, with runs of three or more written as ranges like `R0-R3`.
+fn write_register_ranges(f: &mut impl std::fmt::Write, registers: u16) -> std::fmt::Result {
+    let mut first = true;
+    let mut i = 0;
+    while i < 16 {
+        if registers & (1 << i) == 0 {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        while i < 16 && registers & (1 << i) != 0 {
+            i += 1;
+        }
+        if !first {
+            write!(f, ",")?;
+        }
+        first = false;
+        match i - start {
+            1 => write!(f, "R{start}")?,
+            2 => write!(f, "R{start},R{}", start + 1)?,
+            _ => write!(f, "R{start}-R{}", i - 1)?,
+        }
+    }
+    Ok(())
 }
 
 #[allow(clippy::too_many_arguments)]
@@ -301,3 +359,117 @@ fn write_single_transfer(
     };
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use crate::{
+        assemble::{assemble, is_source_label},
+        instr::Instr,
+    };
+
+    /// Assemble the given program, render each instruction with [`Instr::to_source`],
+    /// and check that the rendered program assembles to the same words.
+    fn round_trip(src: &str) {
+        let output = assemble(src).unwrap();
+        let labels = output
+            .code_labels()
+            .filter(|(name, _)| is_source_label(name))
+            .map(|(name, value)| (name.to_owned(), value))
+            .collect::<BTreeMap<_, _>>();
+        let label_at = |address| {
+            labels
+                .iter()
+                .find(|(_, value)| **value == address)
+                .map(|(name, _)| name.as_str())
+        };
+        let mut rendered = String::new();
+        for (i, word) in output.instrs.iter().enumerate() {
+            let address = output.origin + 4 * i as u32;
+            if let Some(label) = label_at(address) {
+                rendered.push_str(label);
+            }
+            let (cond, instr) = Instr::decode(*word).unwrap();
+            assert_eq!(instr.encode(cond).unwrap(), *word);
+            let line = instr.to_source(cond, address, &labels);
+            rendered.push_str(&format!(" {line}\n"));
+        }
+        let end = output.origin + 4 * output.instrs.len() as u32;
+        if let Some(label) = label_at(end) {
+            rendered.push_str(&format!("{label}\n"));
+        }
+        let reassembled = assemble(&rendered).unwrap_or_else(|errors| {
+            panic!("{rendered}\ndid not assemble: {errors:?}");
+        });
+        for (i, (original, word)) in output.instrs.iter().zip(&reassembled.instrs).enumerate() {
+            assert_eq!(
+                original,
+                word,
+                "{} reassembled differently",
+                rendered.lines().nth(i).unwrap()
+            );
+        }
+        assert_eq!(output.instrs.len(), reassembled.instrs.len(), "{rendered}");
+    }
+
+    #[test]
+    fn round_trip_divide() {
+        round_trip(include_str!("../../test/divide.s"));
+    }
+
+    #[test]
+    fn round_trip_local_labels() {
+        // Branches to local labels and to addresses that only a constant names
+        // are written as addresses.
+        round_trip(
+            "
+        mov r0,#3
+1:      subs r0,r0,#1
+        bne 1b
+FOUR    equ 4
+        b 1f
+        mov r1,#1
+1:      b FOUR
+",
+        );
+    }
+
+    #[test]
+    fn round_trip_operands() {
+        round_trip(
+            "
+start   mov r0,#0xFF000000
+        mvneq r1,#3
+        add r2,r3,r4,lsl r5
+        movs r6,r7,lsr#32
+        rsb r8,r9,r10,asr#32
+        orr r1,r2,r3,rrx
+        msr cpsr_flg,#0xF0000000
+        msr spsr_fsxc,r4
+        mrs r5,spsr
+        b start
+        blne 0x1000
+        bx lr
+        ldr r0,[r1,#4]!
+        ldr r0,[r1],#-4
+        strb r2,[r3,-r4,lsl#2]
+        ldrt r0,[r1],#0
+        ldrsh r5,[r6,#-6]
+        strh r5,[r6],r7
+        str r0,[r1],-r2
+        ldrb r0,[r1],-r2,asr#3
+        ldmia r0!,{r1-r3,r5,r6,r8}
+        stmdb sp!,{r4-r11,lr}
+        ldmib r0,{r0,pc}^
+        stmda r1,{r2}
+        mul r0,r1,r2
+        mlas r0,r1,r2,r3
+        umlal r0,r1,r2,r3
+        smulls r0,r1,r2,r3
+        swpb r0,r1,[r2]
+        swi 0x123456
+",
+        );
+    }
+}
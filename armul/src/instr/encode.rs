@@ -1,8 +1,8 @@
 use crate::{
     assemble::LineError,
     instr::{
-        Cond, DataOperand, Instr, MsrSource, Psr, RotatedConstant, Shift, ShiftType,
-        SpecialOperand, TransferKind, TransferOperand, TransferSize, TransferSizeSpecial,
+        Cond, DataOperand, Instr, MsrSource, Psr, RotatedConstant, Shift, ShiftType, TransferKind,
+        TransferOperand, TransferSize,
     },
 };
 
@@ -50,21 +50,21 @@ impl Instr {
                     Psr::Spsr => 1 << 22,
                 }
                 | (target as u32) << 12),
-            Instr::Msr { psr, source } => {
-                let signature = 0b1_0010_1000_1111 << 12;
+            Instr::Msr {
+                psr,
+                fields,
+                source,
+            } => {
+                let signature = 0b1_0010_0000_1111 << 12;
                 let dest = match psr {
                     Psr::Cpsr => 0,
                     Psr::Spsr => 1 << 22,
                 };
                 let source = match source {
-                    MsrSource::Register(register) => (1 << 16) | register as u32,
-                    MsrSource::RegisterFlags(register) => register as u32,
-                    // The flags are encoded in a simple way:
-                    // the uper four bits are encoded as the lower four bits
-                    // of the immediate value, which are ROR'd by 4 places.
-                    MsrSource::Flags(flags) => (1 << 25) | (1 << 9) | (flags >> 28),
+                    MsrSource::Register(register) => register as u32,
+                    MsrSource::Constant(constant) => (1 << 25) | Instr::encode_constant(constant),
                 };
-                Ok(signature | dest | source)
+                Ok(signature | dest | (fields.0 as u32 & 0xF) << 16 | source)
             }
             Instr::Multiply {
                 set_condition_codes,
@@ -106,46 +106,30 @@ impl Instr {
                 data_register,
                 base_register,
                 offset,
-            } => Ok((1 << 26)
-                | (if pre_index { 1 << 24 } else { 0 })
-                | (if offset_positive { 1 << 23 } else { 0 })
-                | (if size == TransferSize::Byte {
-                    1 << 22
-                } else {
-                    0
-                })
-                | (if write_back && pre_index { 1 << 21 } else { 0 })
-                | (match kind {
-                    TransferKind::Store => 0,
-                    TransferKind::Load => 1 << 20,
-                })
-                | (base_register as u32) << 16
-                | (data_register as u32) << 12
-                | Instr::encode_transfer_operand(offset)?),
-            Instr::SingleTransferSpecial {
-                kind,
-                size,
-                write_back,
-                offset_positive,
-                pre_index,
-                data_register,
-                base_register,
-                offset,
             } => Ok((if pre_index { 1 << 24 } else { 0 })
                 | (if offset_positive { 1 << 23 } else { 0 })
-                | (if write_back && pre_index { 1 << 21 } else { 0 })
+                // After a post-indexed transfer, the base register is always written back,
+                // so in that case this bit is the T flag instead.
+                | (if write_back { 1 << 21 } else { 0 })
                 | (match kind {
                     TransferKind::Store => 0,
                     TransferKind::Load => 1 << 20,
                 })
                 | (base_register as u32) << 16
                 | (data_register as u32) << 12
-                | (match size {
-                    TransferSizeSpecial::HalfWord => 0b1011_0000,
-                    TransferSizeSpecial::SignExtendedByte => 0b1101_0000,
-                    TransferSizeSpecial::SignExtendedHalfWord => 0b1111_0000,
-                })
-                | Instr::encode_special_operand(offset)),
+                | match size {
+                    TransferSize::Word => 1 << 26 | Instr::encode_transfer_operand(offset)?,
+                    TransferSize::Byte => {
+                        1 << 26 | 1 << 22 | Instr::encode_transfer_operand(offset)?
+                    }
+                    TransferSize::HalfWord => 0b1011_0000 | Instr::encode_special_operand(offset)?,
+                    TransferSize::SignExtendedByte => {
+                        0b1101_0000 | Instr::encode_special_operand(offset)?
+                    }
+                    TransferSize::SignExtendedHalfWord => {
+                        0b1111_0000 | Instr::encode_special_operand(offset)?
+                    }
+                }),
             Instr::BlockTransfer {
                 kind,
                 write_back,
@@ -207,20 +191,31 @@ impl Instr {
         }
     }
 
-    /// Encodes a special operand in bits 22, 11..8, 3..0.
-    fn encode_special_operand(operand: SpecialOperand) -> u32 {
+    /// Encodes the operand of a halfword or signed transfer in bits 22, 11..8, 3..0.
+    fn encode_special_operand(operand: TransferOperand) -> Result<u32, LineError> {
         match operand {
-            SpecialOperand::Constant(value) => {
-                1 << 22 | ((value >> 4) as u32) << 8 | (value & 0xF) as u32
+            TransferOperand::Constant(value) => {
+                if value < 1 << 8 {
+                    Ok(1 << 22 | ((value >> 4) as u32) << 8 | (value & 0xF) as u32)
+                } else {
+                    Err(LineError::ImmediateOutOfRange(value as u32))
+                }
+            }
+            TransferOperand::Register(register, shift) => {
+                // These transfers cannot shift their offset register.
+                if Instr::encode_shift(shift)? == 0 {
+                    Ok(register as u32)
+                } else {
+                    Err(LineError::AddressTooComplex)
+                }
             }
-            SpecialOperand::Register(register) => register as u32,
         }
     }
 
     /// Encodes a shift in bits 11..4.
     fn encode_shift(mut shift: Shift) -> Result<u32, LineError> {
         match shift.shift_amount {
-            ShiftAmount::Constant(0) => {
+            ShiftAmount::Constant(0) if shift.shift_type != ShiftType::RotateRightExtended => {
                 // Any shift by zero is encoded as LSL #0.
                 // This is because the bit fields for (e.g.) LSR #0 are overloaded.
                 Ok(0)
@@ -1,10 +1,11 @@
 use serde::Serialize;
 
 use crate::{
+    addr::{Addr, Word},
     assemble::AssemblerOutput,
     instr::{
         Cond, DataOp, DataOperand, Instr, MsrSource, Psr, Register, Shift, ShiftAmount, ShiftType,
-        SpecialOperand, TransferKind, TransferOperand,
+        TransferKind, TransferOperand,
     },
 };
 
@@ -12,7 +13,7 @@ use crate::{
 #[derive(Debug, Serialize)]
 pub struct LineInfo {
     /// The raw 32-bit value that this line contains.
-    value: u32,
+    value: Word,
     /// The decoded instruction, if there was one.
     instr: Option<PrettyInstr>,
     /// The comment, if there was one.
@@ -21,12 +22,18 @@ pub struct LineInfo {
 
 impl LineInfo {
     /// Generate the line info for the given value, given the symbol table information in the assembler output.
-    pub fn new(address: u32, value: u32, assembled: Option<&AssemblerOutput>) -> Self {
+    pub fn new(address: Addr, value: Word, assembled: Option<&AssemblerOutput>) -> Self {
         LineInfo {
             value,
-            instr: Instr::decode(value).map(|(cond, instr)| PrettyInstr::new(address, cond, instr)),
+            instr: Instr::decode(value.0).map(|(cond, instr)| {
+                let mut pretty = PrettyInstr::new(address.0, cond, instr);
+                if let Some(assembled) = assembled {
+                    pretty.name_addresses(assembled);
+                }
+                pretty
+            }),
             comment: assembled
-                .and_then(|assembled| assembled.comments.get(&address))
+                .and_then(|assembled| assembled.comments.get(&address.0))
                 .cloned(),
         }
     }
@@ -50,14 +57,20 @@ pub enum PrettyArgument {
     },
     Psr {
         psr: Psr,
-        flag: bool,
+        /// The letters of the fields written by an `MSR`, in the order `fsxc`.
+        /// This is `None` for an `MRS`, which reads the whole register.
+        fields: Option<String>,
     },
     Shift(Shift),
     Constant {
         negative: bool,
         value: u32,
         style: ConstantStyle,
+        /// The name of the label at this address, if it is an address with a label.
+        label: Option<String>,
     },
+    /// A base register in brackets, with the offsets that go inside the brackets.
+    /// The offset of a post-indexed transfer follows this argument instead.
     Address {
         base_register: Register,
         operands: Vec<PrettyArgument>,
@@ -76,6 +89,7 @@ impl PrettyArgument {
                 negative: false,
                 value: rotated_constant.value().0,
                 style: ConstantStyle::Unknown,
+                label: None,
             }],
             DataOperand::Register(register, shift) => match shift.shift_amount {
                 ShiftAmount::Constant(0) if shift.shift_type != ShiftType::RotateRightExtended => {
@@ -115,6 +129,21 @@ impl PrettyInstr {
             args: instr_args(address, instr),
         }
     }
+
+    /// Give the name of a label to each address argument that has one.
+    fn name_addresses(&mut self, assembled: &AssemblerOutput) {
+        for arg in &mut self.args {
+            if let PrettyArgument::Constant {
+                value,
+                style: ConstantStyle::Address,
+                label,
+                ..
+            } = arg
+            {
+                *label = assembled.label_at(*value).map(str::to_owned);
+            }
+        }
+    }
 }
 
 fn opcode_parts(instr: Instr) -> (String, String) {
@@ -194,26 +223,6 @@ fn opcode_parts(instr: Instr) -> (String, String) {
                 }
             ),
         ),
-        Instr::SingleTransferSpecial {
-            kind,
-            size,
-            write_back,
-            pre_index,
-            ..
-        } => (
-            match kind {
-                TransferKind::Store => "STR".to_owned(),
-                TransferKind::Load => "LDR".to_owned(),
-            },
-            format!(
-                "{size}{}",
-                if write_back && !pre_index {
-                    "T".to_owned()
-                } else {
-                    "".to_owned()
-                }
-            ),
-        ),
         Instr::BlockTransfer {
             kind,
             offset_positive,
@@ -257,6 +266,7 @@ fn instr_args(address: u32, instr: Instr) -> Vec<PrettyArgument> {
                 negative: false,
                 value: absolute_address,
                 style: ConstantStyle::Address,
+                label: None,
             }]
         }
         Instr::Data {
@@ -281,30 +291,33 @@ fn instr_args(address: u32, instr: Instr) -> Vec<PrettyArgument> {
             args
         }
         Instr::Mrs { psr, target } => vec![
-            PrettyArgument::Psr { psr, flag: false },
+            PrettyArgument::Psr { psr, fields: None },
             PrettyArgument::Register {
                 register: target,
                 negative: false,
                 write_back: false,
             },
         ],
-        Instr::Msr { psr, source } => vec![
+        Instr::Msr {
+            psr,
+            fields,
+            source,
+        } => vec![
             PrettyArgument::Psr {
                 psr,
-                flag: matches!(source, MsrSource::RegisterFlags(_) | MsrSource::Flags(_)),
+                fields: Some(fields.letters()),
             },
             match source {
-                MsrSource::Register(register) | MsrSource::RegisterFlags(register) => {
-                    PrettyArgument::Register {
-                        register,
-                        negative: false,
-                        write_back: false,
-                    }
-                }
-                MsrSource::Flags(value) => PrettyArgument::Constant {
+                MsrSource::Register(register) => PrettyArgument::Register {
+                    register,
+                    negative: false,
+                    write_back: false,
+                },
+                MsrSource::Constant(constant) => PrettyArgument::Constant {
                     negative: false,
-                    value,
+                    value: constant.value().0,
                     style: ConstantStyle::Unknown,
+                    label: None,
                 },
             },
         ],
@@ -398,6 +411,7 @@ fn instr_args(address: u32, instr: Instr) -> Vec<PrettyArgument> {
                     negative: !offset_positive,
                     value: n as u32,
                     style: ConstantStyle::Unknown,
+                    label: None,
                 }],
                 TransferOperand::Register(register, shift) => {
                     if shift.shift_amount == ShiftAmount::Constant(0)
@@ -422,32 +436,6 @@ fn instr_args(address: u32, instr: Instr) -> Vec<PrettyArgument> {
             };
             pretty_transfer(write_back, pre_index, data_register, base_register, offset)
         }
-        Instr::SingleTransferSpecial {
-            write_back,
-            offset_positive,
-            pre_index,
-            data_register,
-            base_register,
-            offset,
-            ..
-        } => {
-            let offset = match offset {
-                SpecialOperand::Constant(0) => Vec::new(),
-                SpecialOperand::Constant(n) => vec![PrettyArgument::Constant {
-                    negative: !offset_positive,
-                    value: n as u32,
-                    style: ConstantStyle::Unknown,
-                }],
-                SpecialOperand::Register(register) => {
-                    vec![PrettyArgument::Register {
-                        register,
-                        negative: !offset_positive,
-                        write_back: false,
-                    }]
-                }
-            };
-            pretty_transfer(write_back, pre_index, data_register, base_register, offset)
-        }
         Instr::BlockTransfer {
             write_back,
             psr,
@@ -491,6 +479,7 @@ fn instr_args(address: u32, instr: Instr) -> Vec<PrettyArgument> {
             negative: false,
             value: comment,
             style: ConstantStyle::UnsignedDecimal,
+            label: None,
         }],
     }
 }
@@ -523,3 +512,173 @@ fn pretty_transfer(
     }
     args
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::{
+        addr::{Addr, Word},
+        assemble::assemble,
+        instr::{
+            Instr,
+            info::{LineInfo, PrettyInstr},
+        },
+    };
+
+    fn pretty(word: u32) -> serde_json::Value {
+        let (cond, instr) = Instr::decode(word).unwrap();
+        serde_json::to_value(PrettyInstr::new(0, cond, instr)).unwrap()
+    }
+
+    #[test]
+    fn single_transfer() {
+        // LDR R0,[R1,#-4]!
+        assert_eq!(
+            pretty(0xE5310004),
+            json!({
+                "opcode_prefix": "LDR",
+                "cond": "",
+                "opcode_suffix": "",
+                "args": [
+                    {"type": "Register", "register": 0, "negative": false, "write_back": false},
+                    {
+                        "type": "Address",
+                        "base_register": 1,
+                        "operands": [
+                            {
+                                "type": "Constant",
+                                "negative": true,
+                                "value": 4,
+                                "style": "Unknown",
+                                "label": null,
+                            },
+                        ],
+                        "write_back": true,
+                    },
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn swap() {
+        // SWPB R0,R1,[R2]
+        assert_eq!(
+            pretty(0xE1420091),
+            json!({
+                "opcode_prefix": "SWP",
+                "cond": "",
+                "opcode_suffix": "B",
+                "args": [
+                    {"type": "Register", "register": 0, "negative": false, "write_back": false},
+                    {"type": "Register", "register": 1, "negative": false, "write_back": false},
+                    {"type": "Address", "base_register": 2, "operands": [], "write_back": false},
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn halfword_transfer() {
+        // STRH R0,[R1,#6]
+        assert_eq!(
+            pretty(0xE1C100B6)["args"],
+            json!([
+                {"type": "Register", "register": 0, "negative": false, "write_back": false},
+                {
+                    "type": "Address",
+                    "base_register": 1,
+                    "operands": [
+                        {
+                            "type": "Constant",
+                            "negative": false,
+                            "value": 6,
+                            "style": "Unknown",
+                            "label": null,
+                        },
+                    ],
+                    "write_back": false,
+                },
+            ])
+        );
+        // STRH R0,[R1] omits the zero offset.
+        assert_eq!(pretty(0xE1C100B0)["args"][1]["operands"], json!([]));
+    }
+
+    #[test]
+    fn register_shifted_offset() {
+        // LDR R0,[R1,-R2,LSL #2]
+        assert_eq!(
+            pretty(0xE7110102)["args"][1],
+            json!({
+                "type": "Address",
+                "base_register": 1,
+                "operands": [
+                    {"type": "Register", "register": 2, "negative": true, "write_back": false},
+                    {
+                        "type": "Shift",
+                        "shift_type": "LogicalLeft",
+                        "shift_amount": {"type": "Constant", "value": 2},
+                    },
+                ],
+                "write_back": false,
+            })
+        );
+    }
+
+    #[test]
+    fn post_indexed() {
+        // STR R0,[R1],#-4
+        // The offset of a post-indexed transfer follows the brackets.
+        assert_eq!(
+            pretty(0xE4010004)["args"],
+            json!([
+                {"type": "Register", "register": 0, "negative": false, "write_back": false},
+                {"type": "Address", "base_register": 1, "operands": [], "write_back": false},
+                {
+                    "type": "Constant",
+                    "negative": true,
+                    "value": 4,
+                    "style": "Unknown",
+                    "label": null,
+                },
+            ])
+        );
+        // LDRSB R0,[R1],-R2
+        assert_eq!(
+            pretty(0xE01100D2)["args"][2],
+            json!({"type": "Register", "register": 2, "negative": true, "write_back": false})
+        );
+    }
+
+    #[test]
+    fn psr_fields() {
+        // MRS R0,CPSR
+        assert_eq!(
+            pretty(0xE10F0000)["args"][0],
+            json!({"type": "Psr", "psr": "Cpsr", "fields": null})
+        );
+        // MSR SPSR_sx,R0
+        assert_eq!(
+            pretty(0xE166F000)["args"][0],
+            json!({"type": "Psr", "psr": "Spsr", "fields": "sx"})
+        );
+        // MSR CPSR_c,R0
+        assert_eq!(pretty(0xE121F000)["args"][0]["fields"], json!("c"));
+    }
+
+    #[test]
+    fn branch_label() {
+        let output = assemble("        mov r0, #1\nloop    b loop\n").unwrap();
+        let info = |assembled| {
+            let info = LineInfo::new(Addr(4), Word(output.instrs[1]), assembled);
+            serde_json::to_value(info).unwrap()["instr"]["args"][0].clone()
+        };
+        assert_eq!(
+            info(Some(&output)),
+            json!({"type": "Constant", "negative": false, "value": 4, "style": "Address", "label": "loop"})
+        );
+        assert_eq!(info(None)["label"], json!(null));
+    }
+}
@@ -1,8 +1,11 @@
+mod cycles;
 mod decode;
 mod display;
 mod encode;
 mod info;
+mod thumb;
 mod ty;
 
+pub use cycles::*;
 pub use info::*;
 pub use ty::*;
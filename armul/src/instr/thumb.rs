@@ -0,0 +1,442 @@
+//! Decodes 16-bit THUMB instructions into their ARM equivalents.
+//!
+//! Every THUMB instruction other than the long branch with link has an ARM instruction that
+//! does the same thing, as described [here](https://mgba-emu.github.io/gbatek/#thumbinstructionsummary).
+//! The processor executes the two halves of a long branch with link itself.
+//! The register R15 still refers to the PC, which in THUMB state is only 4 bytes ahead of the
+//! current instruction, and branch offsets are relative to this value.
+
+use num_traits::FromPrimitive;
+
+use crate::instr::{
+    Cond, DataOp, DataOperand, Instr, Register, RotatedConstant, Shift, ShiftAmount, ShiftType,
+    TransferKind, TransferOperand, TransferSize,
+};
+
+impl Instr {
+    /// Attempt to decode the given 16-bit value as a THUMB instruction,
+    /// giving the ARM instruction that it is equivalent to.
+    /// If this instruction could not be decoded, or is half of a long branch with link,
+    /// return `None`.
+    pub fn decode_thumb(instr: u16) -> Option<(Cond, Instr)> {
+        let low = |offset: usize| Register::from_u4(instr as u32 & (0b111u32 << offset), offset);
+        match instr >> 13 {
+            0b000 if instr >> 11 == 0b00011 => {
+                // Add or subtract a register or a 3-bit constant.
+                let op2 = if instr & (1 << 10) == 0 {
+                    register(low(6))
+                } else {
+                    constant(((instr >> 6) & 0b111) as u32)?
+                };
+                let op = if instr & (1 << 9) == 0 {
+                    DataOp::Add
+                } else {
+                    DataOp::Sub
+                };
+                Some((Cond::AL, data(true, op, low(0), low(3), op2)))
+            }
+            0b000 => {
+                // Move a register shifted by a constant.
+                let shift_type = ShiftType::from_u16((instr >> 11) & 0b11).unwrap();
+                let mut shift_amount = ((instr >> 6) & 0b11111) as u8;
+                if shift_amount == 0 && shift_type != ShiftType::LogicalLeft {
+                    shift_amount = 32;
+                }
+                let op2 = DataOperand::Register(
+                    low(3),
+                    Shift {
+                        shift_type,
+                        shift_amount: ShiftAmount::Constant(shift_amount),
+                    },
+                );
+                Some((Cond::AL, data(true, DataOp::Mov, low(0), Register::R0, op2)))
+            }
+            0b001 => {
+                // Move, compare, add or subtract an 8-bit constant.
+                let dest = low(8);
+                let op = match (instr >> 11) & 0b11 {
+                    0b00 => DataOp::Mov,
+                    0b01 => DataOp::Cmp,
+                    0b10 => DataOp::Add,
+                    _ => DataOp::Sub,
+                };
+                let op1 = if op == DataOp::Mov {
+                    Register::R0
+                } else {
+                    dest
+                };
+                let op2 = constant(instr as u8 as u32)?;
+                Some((Cond::AL, data(true, op, dest, op1, op2)))
+            }
+            0b010 if instr >> 10 == 0b010000 => {
+                // ALU operations on two low registers.
+                let (dest, source) = (low(0), low(3));
+                let instr = match (instr >> 6) & 0b1111 {
+                    op @ (0x2 | 0x3 | 0x4 | 0x7) => {
+                        let shift_type = match op {
+                            0x2 => ShiftType::LogicalLeft,
+                            0x3 => ShiftType::LogicalRight,
+                            0x4 => ShiftType::ArithmeticRight,
+                            _ => ShiftType::RotateRight,
+                        };
+                        let op2 = DataOperand::Register(
+                            dest,
+                            Shift {
+                                shift_type,
+                                shift_amount: ShiftAmount::Register(source),
+                            },
+                        );
+                        data(true, DataOp::Mov, dest, Register::R0, op2)
+                    }
+                    0x9 => data(true, DataOp::Rsb, dest, source, constant(0)?),
+                    0xD => Instr::Multiply {
+                        set_condition_codes: true,
+                        dest,
+                        // The ARM7TDMI requires the destination to differ from the first operand.
+                        op1: source,
+                        op2: dest,
+                        addend: None,
+                    },
+                    op => {
+                        let op = match op {
+                            0x0 => DataOp::And,
+                            0x1 => DataOp::Eor,
+                            0x5 => DataOp::Adc,
+                            0x6 => DataOp::Sbc,
+                            0x8 => DataOp::Tst,
+                            0xA => DataOp::Cmp,
+                            0xB => DataOp::Cmn,
+                            0xC => DataOp::Orr,
+                            0xE => DataOp::Bic,
+                            _ => DataOp::Mvn,
+                        };
+                        let op1 = if op == DataOp::Mvn {
+                            Register::R0
+                        } else {
+                            dest
+                        };
+                        data(true, op, dest, op1, register(source))
+                    }
+                };
+                Some((Cond::AL, instr))
+            }
+            0b010 if instr >> 10 == 0b010001 => {
+                // Operations on high registers, and branch and exchange.
+                let dest = Register::from_u32(((instr >> 4) & 0b1000 | instr & 0b111) as u32)?;
+                let source = Register::from_u4(instr as u32, 3);
+                let instr = match (instr >> 8) & 0b11 {
+                    // At least one register must be a high register.
+                    _ if instr & (0b11 << 6) == 0 && (instr >> 8) & 0b11 != 0b11 => return None,
+                    0b00 => data(false, DataOp::Add, dest, dest, register(source)),
+                    0b01 => data(true, DataOp::Cmp, dest, dest, register(source)),
+                    0b10 => data(false, DataOp::Mov, dest, Register::R0, register(source)),
+                    _ if instr & (1 << 7) != 0 => return None,
+                    _ => Instr::BranchExchange { operand: source },
+                };
+                Some((Cond::AL, instr))
+            }
+            0b010 if instr >> 11 == 0b01001 => {
+                // Load a word relative to the PC.
+                let offset = TransferOperand::Constant((instr as u8 as u16) << 2);
+                Some((
+                    Cond::AL,
+                    transfer(true, TransferSize::Word, low(8), Register::R15, offset),
+                ))
+            }
+            0b010 => {
+                // Load or store with a register offset.
+                let size = match (instr >> 9) & 0b111 {
+                    0b000 | 0b100 => TransferSize::Word,
+                    0b010 | 0b110 => TransferSize::Byte,
+                    0b001 | 0b101 => TransferSize::HalfWord,
+                    0b011 => TransferSize::SignExtendedByte,
+                    _ => TransferSize::SignExtendedHalfWord,
+                };
+                // Only STR, STRH and STRB store.
+                let load = (instr >> 9) & 0b111 > 0b010;
+                let offset = TransferOperand::Register(
+                    low(6),
+                    Shift {
+                        shift_type: ShiftType::LogicalLeft,
+                        shift_amount: ShiftAmount::Constant(0),
+                    },
+                );
+                Some((Cond::AL, transfer(load, size, low(0), low(3), offset)))
+            }
+            0b011 => {
+                // Load or store with a 5-bit constant offset.
+                let (size, scale) = if instr & (1 << 12) == 0 {
+                    (TransferSize::Word, 2)
+                } else {
+                    (TransferSize::Byte, 0)
+                };
+                let offset = TransferOperand::Constant(((instr >> 6) & 0b11111) << scale);
+                Some((
+                    Cond::AL,
+                    transfer(instr & (1 << 11) != 0, size, low(0), low(3), offset),
+                ))
+            }
+            0b100 => {
+                // Load or store a halfword, or a word relative to the stack pointer.
+                let load = instr & (1 << 11) != 0;
+                let instr = if instr & (1 << 12) == 0 {
+                    let offset = TransferOperand::Constant(((instr >> 6) & 0b11111) << 1);
+                    transfer(load, TransferSize::HalfWord, low(0), low(3), offset)
+                } else {
+                    let offset = TransferOperand::Constant((instr as u8 as u16) << 2);
+                    transfer(load, TransferSize::Word, low(8), Register::R13, offset)
+                };
+                Some((Cond::AL, instr))
+            }
+            0b101 if instr & (1 << 12) == 0 => {
+                // Compute an address relative to the PC or the stack pointer.
+                let base = if instr & (1 << 11) == 0 {
+                    Register::R15
+                } else {
+                    Register::R13
+                };
+                let op2 = constant((instr as u8 as u32) << 2)?;
+                Some((Cond::AL, data(false, DataOp::Add, low(8), base, op2)))
+            }
+            0b101 if instr >> 8 == 0b1011_0000 => {
+                // Add a constant to the stack pointer.
+                let op = if instr & (1 << 7) == 0 {
+                    DataOp::Add
+                } else {
+                    DataOp::Sub
+                };
+                let op2 = constant(((instr & 0b111_1111) as u32) << 2)?;
+                Some((Cond::AL, data(false, op, Register::R13, Register::R13, op2)))
+            }
+            0b101 if instr & (0b11 << 9) == 0b10 << 9 => {
+                // Push registers onto, or pop registers from, a full descending stack.
+                // The extra bit pushes the link register, or pops the program counter.
+                let load = instr & (1 << 11) != 0;
+                let extra = if load { Register::R15 } else { Register::R14 };
+                let mut registers = instr & 0xFF;
+                if instr & (1 << 8) != 0 {
+                    registers |= 1 << extra as u16;
+                }
+                Some((
+                    Cond::AL,
+                    Instr::BlockTransfer {
+                        kind: if load {
+                            TransferKind::Load
+                        } else {
+                            TransferKind::Store
+                        },
+                        write_back: true,
+                        offset_positive: load,
+                        pre_index: !load,
+                        psr: false,
+                        base_register: Register::R13,
+                        registers,
+                    },
+                ))
+            }
+            0b110 if instr & (1 << 12) == 0 => {
+                // Load or store multiple registers, incrementing after.
+                Some((
+                    Cond::AL,
+                    Instr::BlockTransfer {
+                        kind: if instr & (1 << 11) == 0 {
+                            TransferKind::Store
+                        } else {
+                            TransferKind::Load
+                        },
+                        write_back: true,
+                        offset_positive: true,
+                        pre_index: false,
+                        psr: false,
+                        base_register: low(8),
+                        registers: instr & 0xFF,
+                    },
+                ))
+            }
+            0b110 if (instr >> 8) & 0b1111 == 0b1111 => {
+                // This is a software interrupt.
+                Some((
+                    Cond::AL,
+                    Instr::SoftwareInterrupt {
+                        comment: instr as u8 as u32,
+                    },
+                ))
+            }
+            0b110 => {
+                // This is a conditional branch, with a signed 8-bit offset in halfwords.
+                // The condition `AL` is undefined here.
+                let cond =
+                    Cond::from_u16((instr >> 8) & 0b1111).filter(|cond| *cond != Cond::AL)?;
+                let offset = (instr as i8 as i32) << 1;
+                Some((
+                    cond,
+                    Instr::Branch {
+                        link: false,
+                        offset,
+                    },
+                ))
+            }
+            0b111 if instr >> 11 == 0b11100 => {
+                // This is an unconditional branch, with a signed 11-bit offset in halfwords.
+                let offset = ((instr << 5) as i16 as i32) >> 4;
+                Some((
+                    Cond::AL,
+                    Instr::Branch {
+                        link: false,
+                        offset,
+                    },
+                ))
+            }
+            _ => {
+                // This is half of a long branch with link, or undefined.
+                None
+            }
+        }
+    }
+}
+
+/// Comparisons have no destination, and the ARM encoding gives them R0.
+fn data(
+    set_condition_codes: bool,
+    op: DataOp,
+    dest: Register,
+    op1: Register,
+    op2: DataOperand,
+) -> Instr {
+    Instr::Data {
+        set_condition_codes,
+        op,
+        dest: match op {
+            DataOp::Tst | DataOp::Teq | DataOp::Cmp | DataOp::Cmn => Register::R0,
+            _ => dest,
+        },
+        op1,
+        op2,
+    }
+}
+
+fn register(register: Register) -> DataOperand {
+    DataOperand::Register(
+        register,
+        Shift {
+            shift_type: ShiftType::LogicalLeft,
+            shift_amount: ShiftAmount::Constant(0),
+        },
+    )
+}
+
+fn constant(value: u32) -> Option<DataOperand> {
+    RotatedConstant::encode(value).map(DataOperand::Constant)
+}
+
+/// A pre-indexed transfer with a positive offset and no write-back,
+/// which is the only kind that THUMB has.
+fn transfer(
+    load: bool,
+    size: TransferSize,
+    data_register: Register,
+    base_register: Register,
+    offset: TransferOperand,
+) -> Instr {
+    Instr::SingleTransfer {
+        kind: if load {
+            TransferKind::Load
+        } else {
+            TransferKind::Store
+        },
+        size,
+        write_back: false,
+        offset_positive: true,
+        pre_index: true,
+        data_register,
+        base_register,
+        offset,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::instr::{Cond, Instr};
+
+    #[test]
+    fn matches_arm() {
+        // Each THUMB instruction, and the ARM instruction that does the same thing.
+        let cases = [
+            (0x0088, 0xE1B00101), // lsls r0, r1, #2
+            (0x0808, 0xE1B00021), // lsrs r0, r1, #32
+            (0x1888, 0xE0910002), // adds r0, r1, r2
+            (0x1ECB, 0xE2513003), // subs r3, r1, #3
+            (0x2001, 0xE3B00001), // movs r0, #1
+            (0x2DFF, 0xE35500FF), // cmp r5, #255
+            (0x3710, 0xE2977010), // adds r7, #16
+            (0x4008, 0xE0100001), // ands r0, r1
+            (0x4088, 0xE1B00110), // lsls r0, r1
+            (0x41C8, 0xE1B00170), // rors r0, r1
+            (0x4240, 0xE2700000), // negs r0, r0
+            (0x4348, 0xE0100091), // muls r0, r1
+            (0x43C8, 0xE1F00001), // mvns r0, r1
+            (0x448D, 0xE08DD001), // add sp, r1
+            (0x4685, 0xE1A0D000), // mov sp, r0
+            (0x45F0, 0xE158000E), // cmp r8, lr
+            (0x4770, 0xE12FFF1E), // bx lr
+            (0x4801, 0xE59F0004), // ldr r0, [pc, #4]
+            (0x5088, 0xE7810002), // str r0, [r1, r2]
+            (0x5C88, 0xE7D10002), // ldrb r0, [r1, r2]
+            (0x5288, 0xE18100B2), // strh r0, [r1, r2]
+            (0x5688, 0xE19100D2), // ldsb r0, [r1, r2]
+            (0x5E88, 0xE19100F2), // ldsh r0, [r1, r2]
+            (0x6848, 0xE5910004), // ldr r0, [r1, #4]
+            (0x7008, 0xE5C10000), // strb r0, [r1]
+            (0x8848, 0xE1D100B2), // ldrh r0, [r1, #2]
+            (0x9801, 0xE59D0004), // ldr r0, [sp, #4]
+            (0xA0FF, 0xE28F0FFF), // add r0, pc, #1020
+            (0xA801, 0xE28D0004), // add r0, sp, #4
+            (0xB082, 0xE24DD008), // sub sp, #8
+            (0xB5F0, 0xE92D40F0), // push {r4-r7, lr}
+            (0xBD01, 0xE8BD8001), // pop {r0, pc}
+            (0xC806, 0xE8B00006), // ldmia r0!, {r1, r2}
+            (0xDF02, 0xEF000002), // swi 2
+        ];
+        for (thumb, arm) in cases {
+            assert_eq!(
+                Instr::decode_thumb(thumb),
+                Instr::decode(arm),
+                "{thumb:04X}"
+            );
+        }
+    }
+
+    #[test]
+    fn branches() {
+        // Branch offsets are in bytes, relative to the PC 4 bytes ahead.
+        let branch = |cond, offset| {
+            Some((
+                cond,
+                Instr::Branch {
+                    link: false,
+                    offset,
+                },
+            ))
+        };
+        assert_eq!(Instr::decode_thumb(0xD0FE), branch(Cond::EQ, -4));
+        assert_eq!(Instr::decode_thumb(0xDC7F), branch(Cond::GT, 254));
+        assert_eq!(Instr::decode_thumb(0xE7FE), branch(Cond::AL, -4));
+        assert_eq!(Instr::decode_thumb(0xE3FF), branch(Cond::AL, 2046));
+        assert_eq!(Instr::decode_thumb(0xE400), branch(Cond::AL, -2048));
+    }
+
+    #[test]
+    fn undecodable() {
+        for instr in [
+            0xDE00, // conditional branch with condition AL
+            0xF000, // first half of a long branch with link
+            0xF800, // second half of a long branch with link
+            0xE800, // undefined
+            0x4600, // mov r0, r0 with low registers
+            0x47F0, // bx with a high destination bit
+            0xB100, // undefined miscellaneous instruction
+        ] {
+            assert_eq!(Instr::decode_thumb(instr), None, "{instr:04X}");
+        }
+    }
+}
@@ -5,12 +5,22 @@ use std::{fmt::Display, str::FromStr};
 use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
 use serde::Serialize;
-use serde_repr::Serialize_repr;
+use serde_repr::{Deserialize_repr, Serialize_repr};
 
 /// Enumerates the registers that can be directly referenced in code.
 /// In reality there are a total of 37 registers.
 #[derive(
-    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, FromPrimitive, Serialize_repr,
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    FromPrimitive,
+    Serialize_repr,
+    Deserialize_repr,
 )]
 #[repr(u8)]
 pub enum Register {
@@ -187,6 +197,8 @@ pub enum Instr {
     Msr {
         /// Where to transfer to.
         psr: Psr,
+        /// Which bytes of the PSR to write.
+        fields: PsrFields,
         source: MsrSource,
     },
     /// Multiply (MUL) and Multiply-Accumulate (MLA).
@@ -219,9 +231,13 @@ pub enum Instr {
         op1: Register,
         op2: Register,
     },
-    /// Single Data Transfer (LDR, STR).
+    /// Single Data Transfer (LDR, STR, LDRH, STRH, LDRSB, LDRSH).
+    ///
+    /// Word and byte transfers have a different encoding to halfword and
+    /// sign-extended transfers, which have a more restricted offset.
     SingleTransfer {
         kind: TransferKind,
+        /// Sign-extended transfers are only valid in loads.
         size: TransferSize,
         /// If this is true, the computed address is
         /// written back into the base register.
@@ -237,35 +253,15 @@ pub enum Instr {
         base_register: Register,
         /// The offset to use for this instruction.
         /// Some of these are unrepresentable.
-        /// The valid operands are:
+        /// For word and byte transfers, the valid operands are:
         /// - a 12-bit unsigned constant;
         /// - a shifted register not using a register-specified shift amount.
+        ///
+        /// For the other sizes, the valid operands are:
+        /// - an 8-bit unsigned constant;
+        /// - an unshifted register.
         offset: TransferOperand,
     },
-    /// Single Data Transfer Special (LDRH, LDRSB, LDRSH, STRH).
-    SingleTransferSpecial {
-        kind: TransferKind,
-        /// Sign-extended transfers are only valid in loads.
-        size: TransferSizeSpecial,
-        /// If this is true, the computed address is
-        /// written back into the base register.
-        write_back: bool,
-        /// If this is true, the offset is considered to be positive.
-        /// Otherwise, it is considered to be negative.
-        offset_positive: bool,
-        /// If this is true, the offset is added before the transfer.
-        pre_index: bool,
-        /// The register to read from or write to (depending on the transfer kind).
-        data_register: Register,
-        /// The base register to use for computing the memory location to use.
-        base_register: Register,
-        /// The offset to use for this instruction.
-        /// Some of these are unrepresentable.
-        /// The valid operands are:
-        /// - an unsigned 8-bit constant;
-        /// - a register.
-        offset: SpecialOperand,
-    },
     /// Block Data Transfer (LDM, STM).
     BlockTransfer {
         kind: TransferKind,
@@ -299,6 +295,34 @@ pub enum Instr {
     },
 }
 
+impl Instr {
+    pub fn category(&self) -> InstrCategory {
+        match self {
+            Instr::BranchExchange { .. } | Instr::Branch { .. } => InstrCategory::Branch,
+            Instr::Data { .. } => InstrCategory::DataProcessing,
+            Instr::Mrs { .. } | Instr::Msr { .. } => InstrCategory::Psr,
+            Instr::Multiply { .. } | Instr::MultiplyLong { .. } => InstrCategory::Multiply,
+            Instr::SingleTransfer { .. } | Instr::BlockTransfer { .. } | Instr::Swap { .. } => {
+                InstrCategory::LoadStore
+            }
+            Instr::SoftwareInterrupt { .. } => InstrCategory::Misc,
+        }
+    }
+}
+
+/// A broad grouping of instructions, used for example in syntax highlighting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+pub enum InstrCategory {
+    Branch,
+    DataProcessing,
+    LoadStore,
+    Multiply,
+    /// Transfers to and from the program status registers.
+    Psr,
+    /// Software interrupts, directives, and anything else.
+    Misc,
+}
+
 /// The possible data operations to use in a data-processing instruction.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, FromPrimitive)]
 #[repr(u8)]
@@ -398,7 +422,8 @@ impl DataOperand {
 /// The last operand used in a single transfer instruction.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TransferOperand {
-    /// The operand is a constant 12-bit value.
+    /// The operand is a constant 12-bit value,
+    /// or an 8-bit value for halfword and sign-extended transfers.
     Constant(u16),
     /// The operand is contained in a register.
     /// Register-specified shifts are not allowed.
@@ -414,24 +439,6 @@ impl Display for TransferOperand {
     }
 }
 
-/// The last operand used in a special single transfer instruction.
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum SpecialOperand {
-    /// The operand is a constant 8-bit value.
-    Constant(u8),
-    /// The operand is contained in a register.
-    Register(Register),
-}
-
-impl Display for SpecialOperand {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            SpecialOperand::Constant(i) => write!(f, "#{i}"),
-            SpecialOperand::Register(register) => write!(f, "{register}"),
-        }
-    }
-}
-
 /// A 32-bit value encoded as a bit-rotated 8-bit value.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct RotatedConstant {
@@ -541,18 +548,86 @@ impl Display for ShiftAmount {
 }
 
 /// The source to transfer into a PSR.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MsrSource {
-    /// Transfer entirely from a register.
     Register(Register),
-    /// Transfer only the flag bits from a register.
-    RegisterFlags(Register),
-    /// Transfer the flag bits of the given 32-bit value.
-    Flags(u32),
+    Constant(RotatedConstant),
+}
+
+/// The bytes of a PSR that an MSR instruction writes, as a mask in bits 19..16 of its encoding.
+/// Each bit of the mask selects a byte, from the control byte (bit 16) up to the flags byte.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PsrFields(pub u8);
+
+impl PsrFields {
+    /// The mode, T, F and I bits, written with the `_c` or `_ctl` suffix.
+    pub const CONTROL: PsrFields = PsrFields(0b0001);
+    pub const EXTENSION: PsrFields = PsrFields(0b0010);
+    pub const STATUS: PsrFields = PsrFields(0b0100);
+    /// The condition code flags, written with the `_f` or `_flg` suffix.
+    pub const FLAGS: PsrFields = PsrFields(0b1000);
+    /// The fields written when no suffix is given.
+    /// The other bytes are unused by the ARM7TDMI, so this writes every bit that has a meaning.
+    pub const DEFAULT: PsrFields = PsrFields(0b1001);
+    /// Every field, written with the `_all` or `_cxsf` suffix.
+    pub const ALL: PsrFields = PsrFields(0b1111);
+
+    /// The bits of the PSR in the selected bytes.
+    pub fn mask(self) -> u32 {
+        (0..4)
+            .filter(|byte| self.0 & (1 << byte) != 0)
+            .fold(0, |mask, byte| mask | 0xFF << (8 * byte))
+    }
+
+    /// The letter of each selected field, in the order `fsxc`.
+    pub fn letters(self) -> String {
+        [(0b1000, 'f'), (0b0100, 's'), (0b0010, 'x'), (0b0001, 'c')]
+            .into_iter()
+            .filter(|(bit, _)| self.0 & bit != 0)
+            .map(|(_, c)| c)
+            .collect()
+    }
+
+    /// Parse a suffix such as `flg` or `fsxc`, which must give each field at most once.
+    pub fn from_suffix(suffix: &str) -> Option<PsrFields> {
+        match suffix {
+            "all" => return Some(PsrFields::ALL),
+            "flg" => return Some(PsrFields::FLAGS),
+            "ctl" => return Some(PsrFields::CONTROL),
+            "" => return None,
+            _ => {}
+        }
+        let mut fields = 0;
+        for c in suffix.chars() {
+            let bit = match c {
+                'c' => PsrFields::CONTROL,
+                'x' => PsrFields::EXTENSION,
+                's' => PsrFields::STATUS,
+                'f' => PsrFields::FLAGS,
+                _ => return None,
+            };
+            if fields & bit.0 != 0 {
+                return None;
+            }
+            fields |= bit.0;
+        }
+        Some(PsrFields(fields))
+    }
+}
+
+/// Writes the suffix that selects these fields, including its leading underscore.
+impl Display for PsrFields {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            PsrFields::DEFAULT => Ok(()),
+            PsrFields::FLAGS => write!(f, "_flg"),
+            fields => write!(f, "_{}", fields.letters()),
+        }
+    }
 }
 
 /// Whether a data transfer is a store (0) or a load (1).
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
 #[repr(u8)]
 pub enum TransferKind {
     Store,
@@ -564,31 +639,41 @@ pub enum TransferKind {
 pub enum TransferSize {
     Byte,
     Word,
+    HalfWord,
+    SignExtendedByte,
+    SignExtendedHalfWord,
 }
 
-impl Display for TransferSize {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            TransferSize::Byte => write!(f, "B"),
-            TransferSize::Word => Ok(()),
-        }
+impl TransferSize {
+    /// Whether this size uses the halfword and signed data transfer encoding,
+    /// rather than the single data transfer encoding used for words and bytes.
+    pub fn is_special(self) -> bool {
+        !matches!(self, TransferSize::Byte | TransferSize::Word)
     }
-}
 
-/// How much data is to be transferred by a transfer instruction.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-pub enum TransferSizeSpecial {
-    HalfWord,
-    SignExtendedByte,
-    SignExtendedHalfWord,
+    /// Whether a load of this size sign-extends the value.
+    /// There are no signed stores.
+    pub fn is_signed(self) -> bool {
+        matches!(
+            self,
+            TransferSize::SignExtendedByte | TransferSize::SignExtendedHalfWord
+        )
+    }
+
+    /// The exclusive upper bound of a constant offset for this size.
+    pub fn offset_limit(self) -> u32 {
+        if self.is_special() { 1 << 8 } else { 1 << 12 }
+    }
 }
 
-impl Display for TransferSizeSpecial {
+impl Display for TransferSize {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            TransferSizeSpecial::HalfWord => write!(f, "H"),
-            TransferSizeSpecial::SignExtendedByte => write!(f, "SB"),
-            TransferSizeSpecial::SignExtendedHalfWord => write!(f, "SH"),
+            TransferSize::Byte => write!(f, "B"),
+            TransferSize::Word => Ok(()),
+            TransferSize::HalfWord => write!(f, "H"),
+            TransferSize::SignExtendedByte => write!(f, "SB"),
+            TransferSize::SignExtendedHalfWord => write!(f, "SH"),
         }
     }
 }
@@ -0,0 +1,202 @@
+//! Records how to undo each instruction, so that front-ends can step a program backwards.
+//!
+//! Wrap a listener in a [`JournalingListener`] to record the stores made by an instruction.
+//! See [`crate::processor::Processor::set_journal`].
+
+use std::collections::VecDeque;
+
+use crate::{
+    processor::{Cycle, ProcessorListener, ProcessorState},
+    registers::PhysicalRegister,
+};
+
+/// The number of instructions that a journal can undo unless another capacity is chosen.
+pub const DEFAULT_JOURNAL_CAPACITY: usize = 10_000;
+
+/// How to undo a single instruction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JournalEntry {
+    /// The physical registers that the instruction changed, with their old values.
+    pub registers: Vec<(PhysicalRegister, u32)>,
+    /// The stores that the instruction made, in the order that it made them.
+    pub writes: Vec<MemoryWrite>,
+    /// The state of the processor before the instruction, such as whether it was running.
+    pub state: ProcessorState,
+    pub irq_pending: bool,
+    pub fiq_pending: bool,
+}
+
+/// A store, with the value that it replaced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryWrite {
+    /// The address written, aligned to the size of the store.
+    pub addr: u32,
+    /// The number of bytes written.
+    pub size: u8,
+    pub old: u32,
+}
+
+/// The most recent entries, up to a capacity, after which the oldest are discarded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Journal {
+    entries: VecDeque<JournalEntry>,
+    capacity: usize,
+}
+
+impl Default for Journal {
+    fn default() -> Self {
+        Self::new(DEFAULT_JOURNAL_CAPACITY)
+    }
+}
+
+impl Journal {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Change the capacity, discarding the oldest entries if there are now too many.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        while self.entries.len() > capacity {
+            self.entries.pop_front();
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Record how to undo the latest instruction, discarding the oldest entry if the journal is full.
+    pub fn push(&mut self, entry: JournalEntry) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+
+    /// Remove and return how to undo the latest instruction.
+    pub fn pop(&mut self) -> Option<JournalEntry> {
+        self.entries.pop_back()
+    }
+}
+
+/// A listener that records every store,
+/// and passes every event on to another listener.
+#[derive(Debug)]
+pub struct JournalingListener<'a, L> {
+    pub listener: &'a mut L,
+    pub writes: &'a mut Vec<MemoryWrite>,
+}
+
+impl<L: ProcessorListener> ProcessorListener for JournalingListener<'_, L> {
+    fn cycle(&mut self, cycle: Cycle, count: usize, pc: u32) {
+        self.listener.cycle(cycle, count, pc);
+    }
+
+    fn pipeline_flush(&mut self, pc: u32) {
+        self.listener.pipeline_flush(pc);
+    }
+
+    fn getc(&mut self) -> Option<char> {
+        self.listener.getc()
+    }
+
+    fn putc(&mut self, c: char) {
+        self.listener.putc(c);
+    }
+
+    fn putint(&mut self, i: u32) {
+        self.listener.putint(i);
+    }
+
+    fn explain(&mut self, explanation: String) {
+        self.listener.explain(explanation);
+    }
+
+    fn program_overwritten(&mut self, pc: u32, address: u32) {
+        self.listener.program_overwritten(pc, address);
+    }
+
+    fn memory_read(&mut self, addr: u32, size: u8, value: u32, pc: u32) {
+        self.listener.memory_read(addr, size, value, pc);
+    }
+
+    fn memory_write(&mut self, addr: u32, size: u8, old: u32, new: u32, pc: u32) {
+        self.writes.push(MemoryWrite { addr, size, old });
+        self.listener.memory_write(addr, size, old, new, pc);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        instr::Register,
+        processor::{NullListener, test::builder_for},
+    };
+
+    #[test]
+    fn step_back() {
+        let src = "\
+        mov r0, #0x1000
+        mov r1, #0
+loop    strb r1, [r0], #1
+        str r1, [r0, #0x100]
+        add r1, r1, #1
+        stmfd sp!, {r0, r1}
+        b loop
+";
+        let mut proc = builder_for(src).sp(0x8000).build().unwrap();
+        proc.set_journal(true);
+        assert!(!proc.step_back());
+
+        let mut snapshots = Vec::new();
+        for _ in 0..50 {
+            snapshots.push(serde_json::to_string(&proc.snapshot()).unwrap());
+            proc.poll(&mut NullListener).unwrap();
+        }
+        for _ in 0..10 {
+            assert!(proc.step_back());
+        }
+        assert_eq!(
+            serde_json::to_string(&proc.snapshot()).unwrap(),
+            snapshots[40]
+        );
+
+        // Stepping forwards again does the same as before.
+        for snapshot in &snapshots[40..] {
+            assert_eq!(&serde_json::to_string(&proc.snapshot()).unwrap(), snapshot);
+            proc.poll(&mut NullListener).unwrap();
+        }
+        assert_eq!(proc.registers().get(Register::R1), 10);
+
+        // Only the most recent instructions can be undone.
+        proc.set_journal_capacity(5);
+        let mut steps = 0;
+        while proc.step_back() {
+            steps += 1;
+        }
+        assert_eq!(steps, 5);
+        assert_eq!(
+            serde_json::to_string(&proc.snapshot()).unwrap(),
+            snapshots[45]
+        );
+    }
+}
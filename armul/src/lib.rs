@@ -1,11 +1,21 @@
+pub mod addr;
 pub mod assemble;
+mod capabilities;
+pub mod disassemble;
 pub mod instr;
+pub mod journal;
 pub mod memory;
 pub mod mode;
 pub mod processor;
+pub mod profile;
 pub mod registers;
+pub mod run;
+pub mod semihosting;
 #[cfg(test)]
 pub mod test;
+pub mod watchpoints;
+
+pub use capabilities::{Capabilities, capabilities};
 
 #[cfg(test)]
 include!(concat!(env!("OUT_DIR"), "/tests.rs"));
@@ -2,20 +2,69 @@
 
 use std::{
     fmt::Debug,
-    ops::{Index, IndexMut},
+    ops::{Index, IndexMut, Range},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
 };
 
+use serde::{Deserialize, Serialize};
+
+use crate::addr::Addr;
+
 /// Virtualises a full 32-bit address space using pages.
 /// It doesn't try to reclaim memory that's reset to the default value.
 /// We emulate a little-endian architecture.
+///
+/// Addresses may be given either as an [`Addr`] or as a bare `u32`.
+///
+/// Ranges of addresses can be mapped to an [`MmioHandler`], which then handles
+/// every access to them instead of the pages.
+///
+/// Pages are shared between clones of a memory until one of them writes to a page,
+/// so cloning is cheap enough to do before every instruction.
+/// Mapped handlers are always shared between clones, since each stands for a single device.
 pub struct Memory {
     root: PageRoot,
     default_word: u32,
+    /// Handlers are called through a shared reference when reading,
+    /// since reading a device's register may change its state.
+    io: Vec<(Range<u32>, MappedIo)>,
+}
+
+/// A peripheral, such as a UART or timer, whose registers are mapped into memory.
+/// See [`Memory::map_io`].
+pub trait MmioHandler: Send + Sync {
+    /// Read the word at a word-aligned address in the mapped range.
+    fn read_word(&mut self, addr: Addr) -> u32;
+
+    /// Write the word at a word-aligned address in the mapped range.
+    fn write_word(&mut self, addr: Addr, value: u32);
+
+    /// Write a single byte in the mapped range.
+    /// By default, this reads the word containing it, then writes the word back.
+    fn write_byte(&mut self, addr: Addr, value: u8) {
+        let mut bytes = self.read_word(addr.align_down()).to_le_bytes();
+        bytes[addr.0 as usize % 4] = value;
+        self.write_word(addr.align_down(), u32::from_le_bytes(bytes));
+    }
 }
 
 impl Debug for Memory {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "<memory using {} x 4KiB pages>", self.count_pages())
+        write!(f, "<memory using {} x 4KiB pages", self.count_pages())?;
+        for (range, _) in &self.io {
+            write!(f, ", I/O at 0x{:08X}..0x{:08X}", range.start, range.end)?;
+        }
+        write!(f, ">")
+    }
+}
+
+impl Clone for Memory {
+    fn clone(&self) -> Self {
+        Memory {
+            root: self.root.clone(),
+            default_word: self.default_word,
+            io: self.io.clone(),
+        }
     }
 }
 
@@ -30,67 +79,201 @@ impl Memory {
         Memory {
             root: Default::default(),
             default_word,
+            io: Vec::new(),
         }
     }
 
+    /// Send every access to the given range of addresses to the handler.
+    /// Ranges that were mapped earlier take priority where they overlap.
+    #[cfg(feature = "device-mapping")]
+    pub fn map_io(&mut self, range: Range<u32>, handler: Box<dyn MmioHandler>) {
+        self.io.push((range, Arc::new(Mutex::new(handler))));
+    }
+
+    /// Whether accesses to this address are handled by a mapped [`MmioHandler`].
+    pub fn is_mapped(&self, addr: impl Into<Addr>) -> bool {
+        self.io_handler(addr.into()).is_some()
+    }
+
+    /// The handler of the mapped range containing this address, if there is one.
+    fn io_handler(&self, addr: Addr) -> Option<&MappedIo> {
+        self.io
+            .iter()
+            .find(|(range, _)| range.contains(&addr.0))
+            .map(|(_, handler)| handler)
+    }
+
     /// Access the word at a word-aligned (4-byte aligned) address.
-    pub fn get_word_aligned(&self, addr: u32) -> u32 {
+    pub fn get_word_aligned(&self, addr: impl Into<Addr>) -> u32 {
         self.get_word_aligned_option(addr)
             .unwrap_or(self.default_word)
     }
 
     /// Access the word at a word-aligned (4-byte aligned) address.
     /// If the given byte had no defined value, return [`None`].
-    pub fn get_word_aligned_option(&self, addr: u32) -> Option<u32> {
+    /// Mapped addresses always have a defined value.
+    pub fn get_word_aligned_option(&self, addr: impl Into<Addr>) -> Option<u32> {
+        let addr = addr.into();
+        if let Some(handler) = self.io_handler(addr) {
+            return Some(lock(handler).read_word(addr));
+        }
+        let (a, b, c, _) = to_indices(addr);
+        self.root[a]
+            .as_ref()
+            .and_then(|dir| dir[b].as_ref().map(|table| table[c]))
+    }
+
+    /// Access the word at a word-aligned (4-byte aligned) address without calling
+    /// any mapped handler, so that looking at it cannot change a device's state.
+    /// Mapped addresses give the default word.
+    pub fn peek_word_aligned(&self, addr: impl Into<Addr>) -> u32 {
+        self.peek_word_aligned_option(addr.into())
+            .unwrap_or(self.default_word)
+    }
+
+    /// The word stored in the pages at a word-aligned address, if its page has been written.
+    fn peek_word_aligned_option(&self, addr: Addr) -> Option<u32> {
         let (a, b, c, _) = to_indices(addr);
         self.root[a]
             .as_ref()
             .and_then(|dir| dir[b].as_ref().map(|table| table[c]))
     }
 
-    pub fn get_words_aligned(&self, addr: u32, result: &mut [u32]) {
+    pub fn get_words_aligned(&self, addr: impl Into<Addr>, result: &mut [u32]) {
+        let addr = addr.into();
         for (offset, value) in result.iter_mut().enumerate() {
-            *value = self.get_word_aligned(addr.wrapping_add(4 * offset as u32))
+            *value = self.get_word_aligned(addr + 4 * offset as u32)
         }
     }
 
-    pub fn get_byte(&self, addr: u32) -> u8 {
-        self.get_word_aligned(addr >> 2 << 2).to_le_bytes()[addr as usize % 4]
+    pub fn get_byte(&self, addr: impl Into<Addr>) -> u8 {
+        let addr = addr.into();
+        self.get_word_aligned(addr.align_down()).to_le_bytes()[addr.0 as usize % 4]
     }
 
     /// If the given byte had no defined value, return [`None`].
-    pub fn get_byte_option(&self, addr: u32) -> Option<u8> {
-        self.get_word_aligned_option(addr >> 2 << 2)
-            .map(|value| value.to_le_bytes()[addr as usize % 4])
+    pub fn get_byte_option(&self, addr: impl Into<Addr>) -> Option<u8> {
+        let addr = addr.into();
+        self.get_word_aligned_option(addr.align_down())
+            .map(|value| value.to_le_bytes()[addr.0 as usize % 4])
+    }
+
+    /// Load the word at any address, as `LDR` does.
+    /// An unaligned address reads the word containing it, rotated so that the
+    /// addressed byte is the least significant.
+    pub fn get_word(&self, addr: impl Into<Addr>) -> u32 {
+        let addr = addr.into();
+        self.get_word_aligned(addr.align_down())
+            .rotate_right(8 * (addr.0 % 4))
+    }
+
+    /// Load the halfword at a halfword-aligned address.
+    /// The lowest bit of the address is ignored.
+    pub fn get_halfword(&self, addr: impl Into<Addr>) -> u16 {
+        let addr = addr.into();
+        (self.get_word_aligned(addr.align_down()) >> (8 * (addr.0 & 0b10))) as u16
+    }
+
+    pub fn set_word_aligned(&mut self, addr: impl Into<Addr>, value: u32) {
+        let addr = addr.into();
+        if let Some(handler) = self.io_handler(addr) {
+            lock(handler).write_word(addr, value);
+            return;
+        }
+        *self.word_mut(addr) = value;
     }
 
-    pub fn set_word_aligned(&mut self, addr: u32, value: u32) {
+    /// The word containing this address, allocating its page if needed.
+    /// If the page is shared with a clone of this memory, it is copied first.
+    fn word_mut(&mut self, addr: Addr) -> &mut u32 {
         let (a, b, c, _) = to_indices(addr);
-        self.root[a].get_or_insert_default()[b].get_or_insert_with(|| {
-            Box::new(Page {
+        let dir = Arc::make_mut(self.root[a].get_or_insert_default());
+        let table = dir[b].get_or_insert_with(|| {
+            Arc::new(Page {
                 entries: std::array::from_fn(|_| self.default_word),
             })
-        })[c] = value;
+        });
+        &mut Arc::make_mut(table)[c]
+    }
+
+    /// Replace every page with those of the given memory, such as a clone taken earlier.
+    /// Mapped I/O is kept as it is.
+    pub fn restore(&mut self, snapshot: &Memory) {
+        self.root = snapshot.root.clone();
+        self.default_word = snapshot.default_word;
+    }
+
+    /// Store a word at any address, as `STR` does.
+    /// An unaligned address writes the word containing it.
+    pub fn set_word(&mut self, addr: impl Into<Addr>, value: u32) {
+        self.set_word_aligned(addr.into().align_down(), value);
+    }
+
+    /// Store a halfword at a halfword-aligned address, leaving the rest of its word unchanged.
+    /// The lowest bit of the address is ignored.
+    pub fn set_halfword(&mut self, addr: impl Into<Addr>, value: u16) {
+        let addr = addr.into();
+        let shift = 8 * (addr.0 & 0b10);
+        let original = self.get_word_aligned(addr.align_down());
+        let value = original & !(0xFFFF << shift) | (value as u32) << shift;
+        self.set_word_aligned(addr.align_down(), value);
     }
 
-    pub fn set_words_aligned(&mut self, addr: u32, values: &[u32]) {
+    pub fn set_words_aligned(&mut self, addr: impl Into<Addr>, values: &[u32]) {
+        let addr = addr.into();
         for (offset, value) in values.iter().enumerate() {
-            self.set_word_aligned(addr.wrapping_add(4 * offset as u32), *value);
+            self.set_word_aligned(addr + 4 * offset as u32, *value);
         }
     }
 
-    pub fn set_byte(&mut self, addr: u32, value: u8) {
-        let (a, b, c, d) = to_indices(addr);
-        let location = &mut self.root[a].get_or_insert_default()[b].get_or_insert_with(|| {
-            Box::new(Page {
-                entries: std::array::from_fn(|_| self.default_word),
-            })
-        })[c];
+    pub fn set_byte(&mut self, addr: impl Into<Addr>, value: u8) {
+        let addr = addr.into();
+        if let Some(handler) = self.io_handler(addr) {
+            lock(handler).write_byte(addr, value);
+            return;
+        }
+        let location = self.word_mut(addr);
         let mut bytes = location.to_le_bytes();
-        bytes[d as usize] = value;
+        bytes[addr.0 as usize % 4] = value;
         *location = u32::from_le_bytes(bytes)
     }
 
+    /// Write the given bytes to memory, starting at any address.
+    pub fn load_bytes(&mut self, addr: impl Into<Addr>, data: &[u8]) {
+        let addr = addr.into();
+        for (offset, byte) in data.iter().enumerate() {
+            self.set_byte(addr + offset as u32, *byte);
+        }
+    }
+
+    /// Read `len` bytes of memory, starting at any address, without calling any mapped
+    /// handler. Bytes in pages that have never been written come from the default word,
+    /// as in [`Memory::peek_word_aligned`].
+    pub fn read_bytes(&self, addr: impl Into<Addr>, len: usize) -> Vec<u8> {
+        self.peek_bytes(addr.into(), len, self.default_word)
+    }
+
+    /// Read `len` bytes of memory, starting at any address, without calling any mapped
+    /// handler. Unlike [`Memory::read_bytes`], bytes in pages that have never been written
+    /// are zero, so that dumps are easy to compare. Memory is allocated a 4KiB page at a time,
+    /// so an unwritten byte in the same page as a written one holds the default word.
+    pub fn dump_bytes(&self, addr: impl Into<Addr>, len: usize) -> Vec<u8> {
+        self.peek_bytes(addr.into(), len, 0)
+    }
+
+    /// Read `len` bytes from the pages, using `unwritten` for pages that have never been written.
+    fn peek_bytes(&self, addr: Addr, len: usize, unwritten: u32) -> Vec<u8> {
+        (0..len)
+            .map(|offset| {
+                let addr = addr + offset as u32;
+                let word = self
+                    .peek_word_aligned_option(addr.align_down())
+                    .unwrap_or(unwritten);
+                word.to_le_bytes()[addr.0 as usize % 4]
+            })
+            .collect()
+    }
+
     /// Return the number of pages in use to represent the memory of this processor.
     pub fn count_pages(&self) -> usize {
         1 + self
@@ -103,10 +286,105 @@ impl Memory {
     }
 }
 
+/// The version of the serialized form of memory.
+/// This must be increased whenever the form changes, such as if pages change size,
+/// so that old snapshots are rejected rather than misread.
+const SNAPSHOT_VERSION: u32 = 2;
+
+/// Memory is serialized as its default word and the pages that have been written,
+/// so that snapshots stay small.
+/// Pages whose words are all zero are listed by address alone.
+/// Mapped I/O handlers are not part of the snapshot.
+#[derive(Serialize, Deserialize)]
+struct MemorySnapshot {
+    version: u32,
+    default_word: u32,
+    pages: Vec<PageSnapshot>,
+    zero_pages: Vec<Addr>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PageSnapshot {
+    /// The address of the first word of the page, which is 4KiB-aligned.
+    address: Addr,
+    words: Vec<u32>,
+}
+
+impl Serialize for Memory {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut pages = Vec::new();
+        let mut zero_pages = Vec::new();
+        for (a, dir) in self.root.entries.iter().enumerate() {
+            let Some(dir) = dir else { continue };
+            for (b, table) in dir.entries.iter().enumerate() {
+                let Some(table) = table else { continue };
+                let address = Addr((a as u32) << 22 | (b as u32) << 12);
+                if table.entries.iter().all(|word| *word == 0) {
+                    zero_pages.push(address);
+                } else {
+                    pages.push(PageSnapshot {
+                        address,
+                        words: table.entries.to_vec(),
+                    });
+                }
+            }
+        }
+        MemorySnapshot {
+            version: SNAPSHOT_VERSION,
+            default_word: self.default_word,
+            pages,
+            zero_pages,
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Memory {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let snapshot = MemorySnapshot::deserialize(deserializer)?;
+        if snapshot.version != SNAPSHOT_VERSION {
+            return Err(serde::de::Error::custom(format!(
+                "memory snapshot has version {}, but only version {SNAPSHOT_VERSION} can be read",
+                snapshot.version
+            )));
+        }
+        let mut memory = Memory::new(snapshot.default_word);
+        let zero_pages = snapshot.zero_pages.into_iter().map(|address| PageSnapshot {
+            address,
+            words: vec![0; 1024],
+        });
+        for page in snapshot.pages.into_iter().chain(zero_pages) {
+            let (a, b, c, d) = to_indices(page.address);
+            if (c, d) != (U10(0), 0) {
+                return Err(serde::de::Error::custom(format!(
+                    "page address {} is not 4KiB-aligned",
+                    page.address
+                )));
+            }
+            let entries = page.words.try_into().map_err(|words: Vec<u32>| {
+                serde::de::Error::invalid_length(words.len(), &"1024 words in a page")
+            })?;
+            Arc::make_mut(memory.root[a].get_or_insert_default())[b] =
+                Some(Arc::new(Page { entries }));
+        }
+        Ok(memory)
+    }
+}
+
+/// A mutex rather than a cell, so that memory can be shared between threads.
+type MappedIo = Arc<Mutex<Box<dyn MmioHandler>>>;
+
+/// Lock a mapped handler. A handler that panicked is still usable,
+/// since it can only have left its own state inconsistent.
+fn lock(handler: &MappedIo) -> MutexGuard<'_, Box<dyn MmioHandler>> {
+    handler.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
 type PageTable = Page<u32>;
-type PageDir = Page<Option<Box<PageTable>>>;
-type PageRoot = Page<Option<Box<PageDir>>>;
+type PageDir = Page<Option<Arc<PageTable>>>;
+type PageRoot = Page<Option<Arc<PageDir>>>;
 
+#[derive(Clone)]
 struct Page<T> {
     entries: [T; 1 << 10],
 }
@@ -141,7 +419,7 @@ impl<T> IndexMut<U10> for Page<T> {
 
 /// Converts an address to its page indices, together with a final offset (either 0, 1, 2, or 3).
 #[inline]
-fn to_indices(addr: u32) -> (U10, U10, U10, u32) {
+fn to_indices(Addr(addr): Addr) -> (U10, U10, U10, u32) {
     (
         U10((addr >> 22) as u16),
         U10(((addr >> 12) & 0x3FF) as u16),
@@ -149,3 +427,133 @@ fn to_indices(addr: u32) -> (U10, U10, U10, u32) {
         addr % 4,
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::memory::Memory;
+
+    #[test]
+    fn load_and_dump_bytes() {
+        let mut memory = Memory::default();
+        // This straddles two pages.
+        memory.load_bytes(0xFFE, &[1, 2, 3, 4, 5]);
+        assert_eq!(memory.count_pages(), 4);
+        assert_eq!(memory.get_word_aligned(0xFFC), 0x0201AAAA);
+        assert_eq!(memory.get_word_aligned(0x1000), 0xAA050403);
+        assert_eq!(memory.dump_bytes(0xFFF, 4), [2, 3, 4, 5]);
+        assert_eq!(memory.dump_bytes(0x2FFE, 3), [0, 0, 0]);
+        // The rest of a written page holds the default word.
+        assert_eq!(memory.dump_bytes(0xFFC, 2), [0xAA, 0xAA]);
+        assert!(memory.dump_bytes(0, 0).is_empty());
+    }
+
+    #[test]
+    fn zero_pages_serialized_by_address() {
+        // New pages are filled with the default word.
+        let mut memory = Memory::new(0);
+        memory.set_word_aligned(0x1000, 0);
+        memory.set_word_aligned(0x2000, 5);
+        let json = serde_json::to_value(&memory).unwrap();
+        assert_eq!(json["zero_pages"], serde_json::json!([0x1000]));
+        assert_eq!(json["pages"].as_array().unwrap().len(), 1);
+
+        // The zero page is still written, unlike the pages around it.
+        let restored: Memory = serde_json::from_value(json).unwrap();
+        assert_eq!(restored.get_word_aligned_option(0x1FFC), Some(0));
+        assert_eq!(restored.get_word_aligned_option(0x3000), None);
+        assert_eq!(restored.get_word_aligned(0x2000), 5);
+        assert_eq!(restored.count_pages(), memory.count_pages());
+    }
+
+    #[test]
+    fn unaligned_access() {
+        let mut memory = Memory::default();
+        memory.set_word_aligned(0xFFC, 0x44332211);
+        memory.set_word_aligned(0x1000, 0x88776655);
+        assert_eq!(memory.get_word(0xFFC), 0x44332211);
+        assert_eq!(memory.get_word(0xFFD), 0x11443322);
+        assert_eq!(memory.get_word(0xFFE), 0x22114433);
+        // Accesses just before a page boundary stay within the word containing them.
+        assert_eq!(memory.get_word(0xFFF), 0x33221144);
+        assert_eq!(memory.get_halfword(0xFFE), 0x4433);
+        assert_eq!(memory.get_halfword(0x1000), 0x6655);
+        assert_eq!(memory.read_bytes(0xFFE, 4), [0x33, 0x44, 0x55, 0x66]);
+
+        memory.set_halfword(0xFFE, 0xBBAA);
+        memory.set_halfword(0x1000, 0xDDCC);
+        assert_eq!(memory.get_word_aligned(0xFFC), 0xBBAA2211);
+        assert_eq!(memory.get_word_aligned(0x1000), 0x8877DDCC);
+        memory.set_word(0xFFF, 0x12345678);
+        assert_eq!(memory.get_word_aligned(0xFFC), 0x12345678);
+        assert_eq!(memory.get_word_aligned(0x1000), 0x8877DDCC);
+        assert_eq!(memory.read_bytes(0x2000, 2), [0xAA, 0xAA]);
+    }
+
+    #[test]
+    #[cfg(feature = "device-mapping")]
+    fn map_io() {
+        use std::sync::{Arc, Mutex};
+
+        use crate::{addr::Addr, instr::Register, memory::MmioHandler, processor::test::load};
+
+        /// Counts up on each read, and records each write.
+        struct Device {
+            count: u32,
+            writes: Arc<Mutex<Vec<(Addr, u32)>>>,
+        }
+
+        impl MmioHandler for Device {
+            fn read_word(&mut self, _addr: Addr) -> u32 {
+                self.count += 1;
+                self.count
+            }
+
+            fn write_word(&mut self, addr: Addr, value: u32) {
+                self.writes.lock().unwrap().push((addr, value));
+            }
+        }
+
+        let writes = Arc::new(Mutex::new(Vec::new()));
+        let mut memory = Memory::default();
+        memory.map_io(
+            0x1000..0x1010,
+            Box::new(Device {
+                count: 0,
+                writes: writes.clone(),
+            }),
+        );
+        memory.set_word_aligned(0x1010, 5);
+        assert_eq!(memory.get_word_aligned(0x1000), 1);
+        assert_eq!(memory.get_byte(0x1004), 2);
+        assert_eq!(memory.get_word_aligned(0x1010), 5);
+        assert_eq!(memory.get_word_aligned_option(0x100C), Some(3));
+        // Reading bytes does not call the device, but gives the page underneath it.
+        assert_eq!(memory.dump_bytes(0x1000, 4), [0xAA; 4]);
+        assert_eq!(memory.read_bytes(0x1000, 4), [0xAA; 4]);
+
+        // The processor's loads and stores go to the device too.
+        let src = "\
+        mov r1, #0x1000
+        ldr r0, [r1]
+        str r0, [r1, #4]
+        strb r0, [r1, #9]
+        swi 2
+";
+        let mut proc = load(src);
+        // Give the processor the memory with the device, keeping the five words of its program.
+        memory.load_bytes(0, &proc.memory().read_bytes(0, 20));
+        *proc.memory_mut() = memory;
+        assert_eq!(proc.run(10), Ok(5));
+        assert_eq!(proc.registers().get(Register::R0), 4);
+        // Storing a byte reads the word first.
+        assert_eq!(
+            *writes.lock().unwrap(),
+            [(Addr(0x1004), 4), (Addr(0x1008), 0x0405)]
+        );
+
+        // A clone of the memory, such as in a snapshot, shares the device.
+        let clone = proc.memory().clone();
+        assert_eq!(clone.get_word_aligned(0x1000), 6);
+        assert_eq!(proc.memory().get_word_aligned(0x1000), 7);
+    }
+}
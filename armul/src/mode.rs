@@ -1,7 +1,7 @@
 //! The ARM7TDMI supports seven modes of operation.
 //! This file describes these modes.
 
-use std::fmt::Display;
+use std::{fmt::Display, str::FromStr};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Mode {
@@ -34,3 +34,20 @@ impl Display for Mode {
         }
     }
 }
+
+impl FromStr for Mode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "usr" => Ok(Mode::Usr),
+            "fiq" => Ok(Mode::Fiq),
+            "irq" => Ok(Mode::Irq),
+            "svc" => Ok(Mode::Supervisor),
+            "abt" => Ok(Mode::Abort),
+            "sys" => Ok(Mode::System),
+            "und" => Ok(Mode::Undefined),
+            _ => Err(()),
+        }
+    }
+}
@@ -1,33 +1,437 @@
 //! A model of the ARM7TDMI processor.
 
-use std::fmt::Display;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt::Display,
+    ops::Range,
+};
 
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 use crate::{
+    addr::Addr,
     instr::{
-        DataOp, DataOperand, Instr, MsrSource, Psr, Register, Shift, ShiftAmount, ShiftType,
-        SpecialOperand, TransferKind, TransferOperand, TransferSize, TransferSizeSpecial,
+        DataOp, DataOperand, Instr, MsrSource, Psr, PsrFields, Register, Shift, ShiftAmount,
+        ShiftType, TransferKind, TransferOperand, TransferSize,
     },
+    journal::{Journal, JournalEntry, JournalingListener},
     memory::Memory,
     mode::Mode,
     registers::{PhysicalRegister, Registers},
+    semihosting::{SEMIHOSTING_SWI, SemihostingHandler, SemihostingOutcome},
+    watchpoints::{WatchHit, WatchKind, WatchingListener, Watchpoints},
 };
 
-#[derive(Debug, Default)]
+/// The software interrupt comments that are handled by the processor itself.
+/// - `SWI 0` outputs R0 as a character.
+/// - `SWI 1` inputs a character to R0.
+/// - `SWI 2` halts the processor.
+/// - `SWI 3` outputs the null-terminated string with address in R0.
+/// - `SWI 4` outputs R0 as an integer.
+pub const BUILTIN_SWIS: [u32; 5] = [0, 1, 2, 3, 4];
+
+/// A processor can be serialized, such as to JSON, to snapshot its state and restore it later.
+/// Everything that affects how it executes is included, except for the handlers
+/// given by the host: SWI handlers and services, and mapped I/O.
+/// Breakpoints and watchpoints belong to the debugger rather than the program,
+/// so they are not included either.
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct Processor {
     registers: Registers,
     memory: Memory,
     state: ProcessorState,
+    config: ProcessorConfig,
+    #[serde(skip)]
+    swi_handler: Option<Box<dyn SwiHandler>>,
+    /// Set by [`Processor::raise_irq`] until the interrupt is taken.
+    irq_pending: bool,
+    /// Set by [`Processor::raise_fiq`] until the interrupt is taken.
+    fiq_pending: bool,
+    /// The addresses at which [`Processor::poll_until_breakpoint`] and
+    /// [`crate::run::Executor`] stop.
+    #[serde(skip)]
+    breakpoints: BTreeSet<u32>,
+    /// The addresses whose accesses stop [`Processor::poll_until_breakpoint`],
+    /// with the accesses made by the last instruction.
+    #[serde(skip)]
+    watchpoints: Watchpoints,
+    /// How to undo the latest instructions, if [`Processor::set_journal`] is enabled.
+    #[serde(skip)]
+    journal: Option<Journal>,
+}
+
+/// An exception that the processor can take.
+/// Apart from interrupts, these are only taken when [`ProcessorConfig::exceptions`] is set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exception {
+    UndefinedInstruction,
+    SoftwareInterrupt,
+    Irq,
+    Fiq,
+}
+
+impl Exception {
+    /// The address that the processor jumps to when taking this exception.
+    pub fn vector(self) -> u32 {
+        match self {
+            Exception::UndefinedInstruction => 0x04,
+            Exception::SoftwareInterrupt => 0x08,
+            Exception::Irq => 0x18,
+            Exception::Fiq => 0x1C,
+        }
+    }
+
+    /// The mode that handles this exception.
+    pub fn mode(self) -> Mode {
+        match self {
+            Exception::UndefinedInstruction => Mode::Undefined,
+            Exception::SoftwareInterrupt => Mode::Supervisor,
+            Exception::Irq => Mode::Irq,
+            Exception::Fiq => Mode::Fiq,
+        }
+    }
+}
+
+/// Handles software interrupts before the built-in services in [`BUILTIN_SWIS`],
+/// so that simple operating system services can be emulated.
+/// [`SwiServices`] implements this with a separate service for each comment.
+/// See [`Processor::set_swi_handler`].
+pub trait SwiHandler: std::fmt::Debug + Send + Sync {
+    /// Handle the interrupt `SWI comment`.
+    fn handle(
+        &mut self,
+        comment: u32,
+        registers: &mut Registers,
+        memory: &mut Memory,
+    ) -> SwiOutcome;
 }
 
-#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+/// Handles a single software interrupt comment.
+/// See [`SwiServices::service`].
+pub type SwiService = Box<dyn FnMut(&mut Registers, &mut Memory) -> ProcessorResult + Send + Sync>;
+
+/// A [`SwiHandler`] that calls a separate service for each comment.
+/// Comments without a service are left to the built-in services.
+#[derive(Default)]
+pub struct SwiServices(HashMap<u32, Service>);
+
+enum Service {
+    Call(SwiService),
+    Halt,
+}
+
+impl SwiServices {
+    /// Handle the interrupt `SWI comment` with the given service, replacing any service
+    /// previously given for that comment.
+    /// Giving a service for comment 2 replaces the built-in halt.
+    pub fn service(
+        mut self,
+        comment: u32,
+        service: impl FnMut(&mut Registers, &mut Memory) -> ProcessorResult + Send + Sync + 'static,
+    ) -> Self {
+        self.0.insert(comment, Service::Call(Box::new(service)));
+        self
+    }
+
+    /// Halt the processor on the interrupt `SWI comment`, as an operating system's exit call does.
+    pub fn halt(mut self, comment: u32) -> Self {
+        self.0.insert(comment, Service::Halt);
+        self
+    }
+}
+
+impl SwiHandler for SwiServices {
+    fn handle(
+        &mut self,
+        comment: u32,
+        registers: &mut Registers,
+        memory: &mut Memory,
+    ) -> SwiOutcome {
+        match self.0.get_mut(&comment) {
+            Some(Service::Call(service)) => match service(registers, memory) {
+                Ok(()) => SwiOutcome::Continue,
+                Err(error) => SwiOutcome::Error(error),
+            },
+            Some(Service::Halt) => SwiOutcome::Halt,
+            None => SwiOutcome::Unhandled,
+        }
+    }
+}
+
+impl std::fmt::Debug for SwiServices {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut comments = self.0.keys().collect::<Vec<_>>();
+        comments.sort();
+        f.debug_set().entries(comments).finish()
+    }
+}
+
+/// What the processor should do after a [`SwiHandler`] has been called.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SwiOutcome {
+    Continue,
+    Halt,
+    Error(ProcessorError),
+    /// Handle the interrupt as if there were no handler.
+    Unhandled,
+}
+
+/// Optional behaviour of the processor, beyond that of the ARM7TDMI.
+/// Everything is disabled by default.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProcessorConfig {
+    /// See [`Processor::set_null_check`].
+    pub null_check: Option<NullCheck>,
+    /// See [`Processor::set_semihosting`].
+    pub semihosting: Option<SemihostingHandler>,
+    /// Describe each instruction as it executes; see [`ProcessorListener::explain`].
+    pub explain: bool,
+    /// The addresses of the originally loaded program.
+    /// Stores into this region are reported to [`ProcessorListener::program_overwritten`],
+    /// since unless the program modifies itself, they are usually a bug,
+    /// such as the stack growing into the code.
+    pub program: Option<Range<u32>>,
+    /// See [`Processor::set_exceptions_enabled`].
+    pub exceptions: bool,
+    /// See [`Processor::set_undefined_trap`].
+    pub undefined_trap: bool,
+}
+
+/// An initial state for a processor, which can be read from a file such as JSON.
+/// Anything not given keeps its default value.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MachineState {
+    /// Registers are set after the CPSR, so they refer to the banked registers of its mode.
+    pub registers: BTreeMap<Register, u32>,
+    pub cpsr: Option<u32>,
+    pub memory: Vec<MemoryRegion>,
+}
+
+/// A run of words to be written to memory.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MemoryRegion {
+    /// The word-aligned address of the first word.
+    pub address: Addr,
+    pub words: Vec<u32>,
+}
+
+/// Faults loads and stores near address zero.
+/// These usually mean that an uninitialised register was used as a pointer,
+/// and would otherwise silently read or overwrite the program's code.
+///
+/// Transfers relative to the program counter are never faulted.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NullCheck {
+    /// Transfers to addresses below this limit are faulted.
+    pub limit: u32,
+    /// Regions below the limit that hold data, and so may be transferred to.
+    pub data: Vec<Range<u32>>,
+}
+
+impl Default for NullCheck {
+    fn default() -> Self {
+        Self {
+            limit: 64,
+            data: Vec::new(),
+        }
+    }
+}
+
+impl NullCheck {
+    /// Allow transfers to the given region.
+    pub fn mark_data(&mut self, region: Range<u32>) {
+        self.data.push(region);
+    }
+
+    fn faults(&self, address: u32) -> bool {
+        address < self.limit && !self.data.iter().any(|region| region.contains(&address))
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ProcessorState {
     #[default]
     Running,
     Stopped,
 }
 
+/// A copy of the processor's state taken by [`Processor::snapshot`],
+/// which [`Processor::restore`] returns to.
+/// Memory pages are shared with the processor until either writes to them,
+/// so a snapshot can be taken before every instruction to step backwards.
+/// Snapshots can also be serialized, such as to keep them across reloads.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProcessorSnapshot {
+    registers: Registers,
+    memory: Memory,
+    state: ProcessorState,
+    irq_pending: bool,
+    fiq_pending: bool,
+}
+
+/// The result of a successful call to [`Processor::poll`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum PollOutcome {
+    /// An instruction was executed, and the processor is still running.
+    Running,
+    /// An instruction was executed, and it stopped the processor.
+    Halted,
+    /// The processor had already stopped, so nothing was executed.
+    AlreadyHalted,
+}
+
+/// The result of a successful call to [`Processor::poll_until_breakpoint`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum BreakpointOutcome {
+    /// The next instruction to execute is at this breakpoint.
+    Breakpoint(u32),
+    /// The last instruction accessed a watched address.
+    /// If it made several such accesses, this is the first.
+    Watchpoint {
+        /// The watched address.
+        addr: u32,
+        hit: WatchHit,
+    },
+    /// The processor halted, or had already halted.
+    Halted,
+    /// The step limit was reached before anything else stopped the processor.
+    StepLimit,
+}
+
+/// Builds a [`Processor`] that is ready to run.
+///
+/// ```
+/// use armul::{instr::Register, mode::Mode, processor::ProcessorBuilder};
+///
+/// // MOV R0,#1; SWI 2
+/// let processor = ProcessorBuilder::default()
+///     .mode(Mode::Supervisor)
+///     .sp(0x10000)
+///     .load_words(0x100, &[0xE3A00001, 0xEF000002])
+///     .pc(0x100)
+///     .build()
+///     .unwrap();
+/// assert_eq!(processor.registers().pc(), 0x100);
+/// ```
+#[derive(Debug, Clone)]
+pub struct ProcessorBuilder {
+    mode: Mode,
+    registers: Vec<(Register, u32)>,
+    words: Vec<(Addr, Vec<u32>)>,
+    images: Vec<(Addr, Vec<u8>)>,
+    config: ProcessorConfig,
+}
+
+impl Default for ProcessorBuilder {
+    fn default() -> Self {
+        Self {
+            mode: Mode::Usr,
+            registers: Vec::new(),
+            words: Vec::new(),
+            images: Vec::new(),
+            config: ProcessorConfig::default(),
+        }
+    }
+}
+
+impl ProcessorBuilder {
+    /// Set the mode that the processor starts in.
+    /// Registers are set after the mode, so banked registers of this mode are the ones written.
+    pub fn mode(mut self, mode: Mode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Set the address of the first instruction to execute.
+    pub fn pc(self, pc: impl Into<Addr>) -> Self {
+        self.register(Register::R15, pc.into().0)
+    }
+
+    /// Set the initial stack pointer.
+    pub fn sp(self, sp: impl Into<Addr>) -> Self {
+        self.register(Register::R13, sp.into().0)
+    }
+
+    pub fn register(mut self, register: Register, value: u32) -> Self {
+        self.registers.push((register, value));
+        self
+    }
+
+    /// Write the given words to memory, starting at a word-aligned address.
+    pub fn load_words(mut self, addr: impl Into<Addr>, words: &[u32]) -> Self {
+        self.words.push((addr.into(), words.to_vec()));
+        self
+    }
+
+    /// Write the given bytes to memory, starting at any address.
+    pub fn load_image(mut self, addr: impl Into<Addr>, image: &[u8]) -> Self {
+        self.images.push((addr.into(), image.to_vec()));
+        self
+    }
+
+    pub fn config(mut self, config: ProcessorConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn build(self) -> Result<Processor, BuildError> {
+        let mut processor = Processor {
+            config: self.config,
+            ..Default::default()
+        };
+        processor.registers.set_mode(self.mode);
+        for (register, value) in self.registers {
+            match register {
+                Register::R15 if value & 0b11 != 0 => {
+                    return Err(BuildError::UnalignedPc(Addr(value)));
+                }
+                Register::R13 if value & 0b11 != 0 => {
+                    return Err(BuildError::UnalignedSp(Addr(value)));
+                }
+                _ => processor.registers.set(register, value),
+            }
+        }
+        for (addr, words) in self.words {
+            if !addr.is_word_aligned() {
+                return Err(BuildError::UnalignedLoad(addr));
+            }
+            processor.memory.set_words_aligned(addr, &words);
+        }
+        for (addr, image) in self.images {
+            processor.memory.load_bytes(addr, &image);
+        }
+        Ok(processor)
+    }
+}
+
+/// The ways in which a [`ProcessorBuilder`] can describe an invalid initial state.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BuildError {
+    /// The program counter was not 4-byte aligned.
+    UnalignedPc(Addr),
+    /// The stack pointer was not 4-byte aligned.
+    UnalignedSp(Addr),
+    /// Words were loaded at an address that was not 4-byte aligned.
+    UnalignedLoad(Addr),
+}
+
+impl Display for BuildError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BuildError::UnalignedPc(pc) => {
+                write!(f, "initial program counter {pc} is not 4-byte aligned")
+            }
+            BuildError::UnalignedSp(sp) => {
+                write!(f, "initial stack pointer {sp} is not 4-byte aligned")
+            }
+            BuildError::UnalignedLoad(addr) => {
+                write!(f, "words loaded at {addr}, which is not 4-byte aligned")
+            }
+        }
+    }
+}
+
 impl Processor {
     pub fn registers(&self) -> &Registers {
         &self.registers
@@ -41,6 +445,9 @@ impl Processor {
         &self.memory
     }
 
+    /// Edits made through this reference are seen by the next instruction executed.
+    /// The processor doesn't cache decoded instructions, so there is nothing to invalidate;
+    /// any such cache added in future must be invalidated here.
     pub fn memory_mut(&mut self) -> &mut Memory {
         &mut self.memory
     }
@@ -53,21 +460,439 @@ impl Processor {
         self.state = ProcessorState::Running;
     }
 
-    pub fn poll(&mut self) -> ProcessorResult {
-        todo!()
+    /// Overwrite the given parts of the processor's state.
+    /// Nothing is changed if the state is invalid.
+    pub fn load_state(&mut self, state: &MachineState) -> Result<(), BuildError> {
+        if let Some(pc) = state.registers.get(&Register::R15)
+            && pc & 0b11 != 0
+        {
+            return Err(BuildError::UnalignedPc(Addr(*pc)));
+        }
+        if let Some(region) = state
+            .memory
+            .iter()
+            .find(|region| !region.address.is_word_aligned())
+        {
+            return Err(BuildError::UnalignedLoad(region.address));
+        }
+
+        if let Some(cpsr) = state.cpsr {
+            *self.registers.cpsr_mut() = cpsr;
+        }
+        for (register, value) in &state.registers {
+            self.registers.set(*register, *value);
+        }
+        for region in &state.memory {
+            self.memory.set_words_aligned(region.address, &region.words);
+        }
+        Ok(())
+    }
+
+    /// Copy the registers, memory and execution state of the processor.
+    /// Its configuration, handlers and debugging aids are not part of the snapshot,
+    /// and mapped devices are shared with it rather than copied.
+    pub fn snapshot(&self) -> ProcessorSnapshot {
+        ProcessorSnapshot {
+            registers: self.registers.clone(),
+            memory: self.memory.clone(),
+            state: self.state,
+            irq_pending: self.irq_pending,
+            fiq_pending: self.fiq_pending,
+        }
+    }
+
+    /// Return to the state recorded in the given snapshot.
+    /// Mapped I/O is kept as it is, and devices are not told about the change.
+    /// The journal is cleared, since it cannot undo instructions from before the snapshot.
+    pub fn restore(&mut self, snapshot: &ProcessorSnapshot) {
+        self.registers = snapshot.registers.clone();
+        self.memory.restore(&snapshot.memory);
+        self.state = snapshot.state;
+        self.irq_pending = snapshot.irq_pending;
+        self.fiq_pending = snapshot.fiq_pending;
+        if let Some(journal) = &mut self.journal {
+            journal.clear();
+        }
+    }
+
+    pub fn config(&self) -> &ProcessorConfig {
+        &self.config
+    }
+
+    pub fn set_config(&mut self, config: ProcessorConfig) {
+        self.config = config;
+    }
+
+    pub fn null_check(&self) -> Option<&NullCheck> {
+        self.config.null_check.as_ref()
+    }
+
+    /// Enable or disable the check for transfers near address zero.
+    /// This is disabled by default.
+    pub fn set_null_check(&mut self, null_check: Option<NullCheck>) {
+        self.config.null_check = null_check;
+    }
+
+    pub fn semihosting(&self) -> Option<&SemihostingHandler> {
+        self.config.semihosting.as_ref()
+    }
+
+    /// Enable or disable handling of semihosting calls made with `SWI 0x123456`.
+    /// This is disabled by default.
+    pub fn set_semihosting(&mut self, semihosting: Option<SemihostingHandler>) {
+        self.config.semihosting = semihosting;
+    }
+
+    /// Enable or disable exception entry.
+    /// When enabled, software interrupts that are not otherwise handled and undefined instructions
+    /// enter Supervisor and Undefined mode through their vectors, as on the ARM7TDMI,
+    /// rather than returning [`ProcessorError::InvalidSwi`] or
+    /// [`ProcessorError::UnrecognisedInstruction`].
+    /// This is disabled by default.
+    pub fn set_exceptions_enabled(&mut self, enabled: bool) {
+        self.config.exceptions = enabled;
+    }
+
+    /// Enable or disable the undefined instruction trap on its own.
+    /// When enabled, an instruction that cannot be decoded enters Undefined mode through
+    /// the vector at 0x04, so that a handler can emulate it,
+    /// even if other exceptions are disabled.
+    /// This is disabled by default.
+    pub fn set_undefined_trap(&mut self, enabled: bool) {
+        self.config.undefined_trap = enabled;
+    }
+
+    /// Raise an interrupt request, which is taken before the next instruction
+    /// once the I bit of the CPSR is clear.
+    pub fn raise_irq(&mut self) {
+        self.irq_pending = true;
+    }
+
+    /// Raise a fast interrupt request, which is taken before the next instruction
+    /// once the F bit of the CPSR is clear.
+    /// Fast interrupts take priority over interrupt requests.
+    pub fn raise_fiq(&mut self) {
+        self.fiq_pending = true;
+    }
+
+    /// Returns true if an interrupt request has been raised but not yet taken.
+    pub fn irq_pending(&self) -> bool {
+        self.irq_pending
+    }
+
+    /// Returns true if a fast interrupt request has been raised but not yet taken.
+    pub fn fiq_pending(&self) -> bool {
+        self.fiq_pending
+    }
+
+    /// Enter the given exception, so that `return_address` is in R14 of the exception's mode,
+    /// and the next instruction to execute is at the exception's vector.
+    /// This must be called while executing an instruction, since it leaves the PC
+    /// 4 bytes before the vector, ready to be advanced.
+    fn enter_exception(&mut self, exception: Exception, return_address: u32) {
+        let cpsr = self.registers.cpsr();
+        let mode = exception.mode();
+        self.registers.set_mode(mode);
+        self.registers
+            .set_physical(Psr::Spsr.physical(mode).unwrap(), cpsr);
+        self.registers.set(Register::R14, return_address);
+        self.registers.set_irq_disable(true);
+        if exception == Exception::Fiq {
+            self.registers.set_fiq_disable(true);
+        }
+        self.registers.set_thumb_state(false);
+        self.registers.set_pc(exception.vector().wrapping_sub(4));
+    }
+
+    /// Install a handler that is called for every software interrupt
+    /// before the built-in services, or remove it.
+    /// There is no handler by default.
+    pub fn set_swi_handler(&mut self, handler: Option<Box<dyn SwiHandler>>) {
+        self.swi_handler = handler;
+    }
+
+    /// The `size` bytes of memory containing the given address, for reporting to
+    /// [`ProcessorListener::memory_write`].
+    fn peek(&self, address: u32, size: u8) -> u32 {
+        let word = self.memory.peek_word_aligned(address & !0b11);
+        match size {
+            1 => word >> (8 * (address & 0b11)) & 0xFF,
+            2 => word >> (8 * (address & 0b10)) & 0xFFFF,
+            _ => word,
+        }
+    }
+
+    /// Check that a transfer to the given address is permitted by the null check.
+    fn check_null_access(
+        &self,
+        kind: TransferKind,
+        address: u32,
+        base_register: Register,
+        offset: i32,
+    ) -> ProcessorResult {
+        match &self.config.null_check {
+            Some(null_check) if base_register != Register::R15 && null_check.faults(address) => {
+                Err(ProcessorError::NullAccess {
+                    kind,
+                    address,
+                    base_register,
+                    offset,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Report a store to the given address if it lies in [`ProcessorConfig::program`].
+    fn check_program_write(&self, pc: u32, address: u32, listener: &mut impl ProcessorListener) {
+        if let Some(program) = &self.config.program
+            && program.contains(&address)
+        {
+            listener.program_overwritten(pc, address);
+        }
+    }
+
+    /// Execute a single instruction and advance the program counter past it,
+    /// unless the processor has stopped.
+    /// If an error occurs, the program counter is left at the faulting instruction.
+    pub fn poll(
+        &mut self,
+        listener: &mut impl ProcessorListener,
+    ) -> Result<PollOutcome, ProcessorError> {
+        if self.state == ProcessorState::Stopped {
+            return Ok(PollOutcome::AlreadyHalted);
+        }
+        self.watchpoints.take_hits();
+        if self.journal.is_none() {
+            self.poll_watching(listener)?;
+        } else {
+            // Every store is reported to the listener, with the value that it replaced.
+            let registers = self.registers.clone();
+            let (state, irq_pending, fiq_pending) =
+                (self.state, self.irq_pending, self.fiq_pending);
+            let mut writes = Vec::new();
+            let result = self.poll_watching(&mut JournalingListener {
+                listener,
+                writes: &mut writes,
+            });
+            let registers = self.registers.changes_since(&registers);
+            // An instruction that failed without changing anything has nothing to undo.
+            if result.is_ok() || !registers.is_empty() || !writes.is_empty() {
+                self.journal.as_mut().unwrap().push(JournalEntry {
+                    registers,
+                    writes,
+                    state,
+                    irq_pending,
+                    fiq_pending,
+                });
+            }
+            result?;
+        }
+        Ok(match self.state {
+            ProcessorState::Running => PollOutcome::Running,
+            ProcessorState::Stopped => PollOutcome::Halted,
+        })
+    }
+
+    /// Execute an instruction and advance the PC past it, recording hits on any watchpoints.
+    fn poll_watching(&mut self, listener: &mut impl ProcessorListener) -> ProcessorResult {
+        if self.watchpoints.watchpoints().is_empty() {
+            self.try_execute(listener)?;
+        } else {
+            // Every access is reported to the listener, so the watchpoints can see them there.
+            let mut watchpoints = std::mem::take(&mut self.watchpoints);
+            let result = self.try_execute(&mut WatchingListener {
+                listener,
+                watchpoints: &mut watchpoints,
+            });
+            self.watchpoints = watchpoints;
+            result?;
+        }
+        self.registers.advance_pc();
+        Ok(())
+    }
+
+    /// Record how to undo each instruction that [`Processor::poll`] executes,
+    /// so that [`Processor::step_back`] can undo them.
+    /// Disabling the journal discards it.
+    ///
+    /// Only the effects of instructions are recorded.
+    /// Changes made through [`Processor::registers_mut`] and [`Processor::memory_mut`],
+    /// or by SWI handlers and services, are not, and devices on mapped I/O are not rewound.
+    pub fn set_journal(&mut self, enabled: bool) {
+        match (enabled, &self.journal) {
+            (true, None) => self.journal = Some(Journal::default()),
+            (false, _) => self.journal = None,
+            (true, Some(_)) => {}
+        }
+    }
+
+    pub fn journal(&self) -> Option<&Journal> {
+        self.journal.as_ref()
+    }
+
+    /// Set how many instructions the journal can undo, discarding the oldest entries if needed.
+    /// This also enables the journal.
+    pub fn set_journal_capacity(&mut self, capacity: usize) {
+        self.journal
+            .get_or_insert_with(|| Journal::new(capacity))
+            .set_capacity(capacity);
+    }
+
+    /// Undo the latest instruction recorded in the journal.
+    /// Returns false if there was nothing to undo.
+    pub fn step_back(&mut self) -> bool {
+        let Some(entry) = self.journal.as_mut().and_then(Journal::pop) else {
+            return false;
+        };
+        for write in entry.writes.iter().rev() {
+            if self.memory.is_mapped(write.addr) {
+                continue;
+            }
+            match write.size {
+                1 => self.memory.set_byte(write.addr, write.old as u8),
+                2 => self.memory.set_halfword(write.addr, write.old as u16),
+                _ => self.memory.set_word_aligned(write.addr, write.old),
+            }
+        }
+        for (register, value) in entry.registers {
+            self.registers.set_physical(register, value);
+        }
+        self.state = entry.state;
+        self.irq_pending = entry.irq_pending;
+        self.fiq_pending = entry.fiq_pending;
+        true
+    }
+
+    /// Stop [`Processor::poll_until_breakpoint`] before executing the instruction at this address.
+    /// Returns false if there was already a breakpoint here.
+    pub fn add_breakpoint(&mut self, addr: u32) -> bool {
+        self.breakpoints.insert(addr)
+    }
+
+    /// Returns false if there was no breakpoint at this address.
+    pub fn remove_breakpoint(&mut self, addr: u32) -> bool {
+        self.breakpoints.remove(&addr)
+    }
+
+    pub fn breakpoints(&self) -> &BTreeSet<u32> {
+        &self.breakpoints
+    }
+
+    /// Stop [`Processor::poll_until_breakpoint`] after an instruction that makes
+    /// the given kind of access to this address.
+    /// Accesses to any of the bytes of a word or halfword containing the address count.
+    pub fn add_watchpoint(&mut self, addr: u32, on: WatchKind) {
+        self.watchpoints.add(addr..=addr, on.read(), on.write());
+    }
+
+    /// Returns false if there was no watchpoint on this address.
+    pub fn remove_watchpoint(&mut self, addr: u32) -> bool {
+        self.watchpoints.remove(addr..=addr)
+    }
+
+    /// The accesses to watched addresses made by the last instruction executed.
+    pub fn watch_hits(&self) -> &[WatchHit] {
+        self.watchpoints.hits()
+    }
+
+    /// Poll the processor until the next instruction to execute is at a breakpoint,
+    /// an instruction accesses a watched address, the processor stops,
+    /// or `max_steps` instructions have been executed.
+    /// The first instruction is always executed, so that a processor that stopped
+    /// at a breakpoint can continue past it.
+    pub fn poll_until_breakpoint(
+        &mut self,
+        listener: &mut impl ProcessorListener,
+        max_steps: usize,
+    ) -> Result<BreakpointOutcome, ProcessorError> {
+        for _ in 0..max_steps {
+            match self.poll(listener)? {
+                PollOutcome::Running => {}
+                PollOutcome::Halted | PollOutcome::AlreadyHalted => {
+                    return Ok(BreakpointOutcome::Halted);
+                }
+            }
+            if let Some(outcome) = self.breakpoint_hit() {
+                return Ok(outcome);
+            }
+        }
+        Ok(BreakpointOutcome::StepLimit)
+    }
+
+    /// Whether the last instruction accessed a watched address,
+    /// or else whether the next instruction to execute is at a breakpoint.
+    pub(crate) fn breakpoint_hit(&self) -> Option<BreakpointOutcome> {
+        if let Some(hit) = self.watchpoints.hits().first()
+            && let Some(watchpoint) = self.watchpoints.find(hit)
+        {
+            return Some(BreakpointOutcome::Watchpoint {
+                addr: *watchpoint.range.start(),
+                hit: *hit,
+            });
+        }
+        let pc = self.registers.pc();
+        self.breakpoints
+            .contains(&pc)
+            .then_some(BreakpointOutcome::Breakpoint(pc))
+    }
+
+    /// Poll the processor until it stops or `max_steps` instructions have been executed,
+    /// discarding any output. Returns the number of instructions executed.
+    pub fn run(&mut self, max_steps: usize) -> Result<usize, ProcessorError> {
+        for steps in 0..max_steps {
+            match self.poll(&mut NullListener)? {
+                PollOutcome::Running => {}
+                PollOutcome::Halted => return Ok(steps + 1),
+                PollOutcome::AlreadyHalted => return Ok(steps),
+            }
+        }
+        Ok(max_steps)
     }
 
     /// Immediately execute the instruction at the current program counter.
     pub fn try_execute(&mut self, listener: &mut impl ProcessorListener) -> ProcessorResult {
-        let pc = self.registers.get(Register::R15);
+        // Interrupts are taken between instructions.
+        // The handler returns with `SUBS PC,LR,#4`, so LR is one instruction ahead.
+        let interrupt = if self.fiq_pending && !self.registers.fiq_disable() {
+            self.fiq_pending = false;
+            Some(Exception::Fiq)
+        } else if self.irq_pending && !self.registers.irq_disable() {
+            self.irq_pending = false;
+            Some(Exception::Irq)
+        } else {
+            None
+        };
+        if let Some(interrupt) = interrupt {
+            let pc = self.registers.pc();
+            self.enter_exception(interrupt, pc.wrapping_add(4));
+            self.registers.advance_pc();
+            listener.pipeline_flush(pc);
+        }
+        let pc = self.registers.pc();
 
-        // Check that the program counter is aligned.
-        if pc & 0b11 != 0 {
+        // Check that the program counter is aligned to the size of an instruction.
+        let size = self.registers.instruction_size();
+        if pc & (size - 1) != 0 {
             return Err(ProcessorError::UnalignedPc);
         }
 
-        let Some((cond, instr)) = Instr::decode(self.memory.get_word_aligned(pc)) else {
-            return Err(ProcessorError::UnrecognisedInstruction);
+        // THUMB instructions are executed as the ARM instructions that they are equivalent to,
+        // except for the two halves of a long branch with link, which have none.
+        let decoded = if self.registers.thumb_state() {
+            if !cfg!(feature = "thumb") {
+                return self.undefined_instruction(pc, size, listener);
+            }
+            let halfword = self.memory.get_halfword(pc);
+            if halfword >> 12 == 0b1111 {
+                return self.execute_thumb_long_branch(pc, halfword, listener);
+            }
+            Instr::decode_thumb(halfword)
+        } else {
+            Instr::decode(self.memory.get_word_aligned(pc))
+        };
+        let Some((cond, instr)) = decoded else {
+            return self.undefined_instruction(pc, size, listener);
         };
 
         // Check whether the condition code holds.
@@ -76,9 +901,20 @@ impl Processor {
             // According to page 10-19, unexecuted instructions
             // take one S-cycle.
             listener.cycle(Cycle::Seq, 1, pc);
+            if self.config.explain {
+                listener.explain(format!(
+                    "{} skipped: condition {cond:?} not met",
+                    instr.display(cond)
+                ));
+            }
             return Ok(());
         }
 
+        // Data-processing instructions explain themselves in more detail.
+        if self.config.explain && !matches!(instr, Instr::Data { .. }) {
+            listener.explain(instr.display(cond));
+        }
+
         match instr {
             Instr::BranchExchange { operand } => {
                 self.execute_branch_exchange(pc, operand, listener)
@@ -94,7 +930,11 @@ impl Processor {
                 self.execute_data_processing(pc, set_condition_codes, op, dest, op1, op2, listener)
             }
             Instr::Mrs { psr, target } => self.execute_mrs(pc, psr, target, listener),
-            Instr::Msr { psr, source } => self.execute_msr(pc, psr, source, listener),
+            Instr::Msr {
+                psr,
+                fields,
+                source,
+            } => self.execute_msr(pc, psr, fields, source, listener),
             Instr::Multiply {
                 set_condition_codes,
                 dest,
@@ -142,27 +982,6 @@ impl Processor {
                 offset,
                 listener,
             ),
-            Instr::SingleTransferSpecial {
-                kind,
-                size,
-                write_back,
-                offset_positive,
-                pre_index,
-                data_register,
-                base_register,
-                offset,
-            } => self.execute_single_transfer_special(
-                pc,
-                kind,
-                size,
-                write_back,
-                offset_positive,
-                pre_index,
-                data_register,
-                base_register,
-                offset,
-                listener,
-            ),
             Instr::BlockTransfer {
                 kind,
                 write_back,
@@ -188,56 +1007,111 @@ impl Processor {
                 source,
                 base,
             } => self.execute_swap(pc, byte, dest, source, base, listener),
-            Instr::SoftwareInterrupt { comment } => match comment {
-                0 => {
-                    // Output R0 as a character.
-                    // We use the replacement character U+FFFD if R0 wasn't a character.
-                    listener.putc(
-                        char::from_u32(self.registers.get(Register::R0)).unwrap_or('\u{FFFD}'),
-                    );
-                    Ok(())
-                }
-                1 => {
-                    // Input a character to R0.
-                    match listener.getc() {
-                        Some(c) => {
-                            self.registers.set(Register::R0, c as u32);
-                            Ok(())
-                        }
-                        None => Err(ProcessorError::WaitingForInput),
+            Instr::SoftwareInterrupt { comment } => {
+                self.execute_software_interrupt(pc, comment, listener)
+            }
+        }
+    }
+
+    /// Take the undefined instruction trap if it is enabled, or fail otherwise.
+    fn undefined_instruction(
+        &mut self,
+        pc: u32,
+        size: u32,
+        listener: &mut impl ProcessorListener,
+    ) -> ProcessorResult {
+        if self.config.exceptions || self.config.undefined_trap {
+            self.enter_exception(Exception::UndefinedInstruction, pc.wrapping_add(size));
+            listener.pipeline_flush(pc);
+            return Ok(());
+        }
+        Err(ProcessorError::UnrecognisedInstruction)
+    }
+
+    #[inline]
+    fn execute_software_interrupt(
+        &mut self,
+        pc: u32,
+        comment: u32,
+        listener: &mut impl ProcessorListener,
+    ) -> ProcessorResult {
+        let outcome = match &mut self.swi_handler {
+            Some(handler) => handler.handle(comment, &mut self.registers, &mut self.memory),
+            None => SwiOutcome::Unhandled,
+        };
+        match outcome {
+            SwiOutcome::Continue => return Ok(()),
+            SwiOutcome::Halt => {
+                self.state = ProcessorState::Stopped;
+                return Ok(());
+            }
+            SwiOutcome::Error(error) => return Err(error),
+            SwiOutcome::Unhandled => {}
+        }
+        match comment {
+            0 => {
+                // Output R0 as a character.
+                // We use the replacement character U+FFFD if R0 wasn't a character.
+                listener
+                    .putc(char::from_u32(self.registers.get(Register::R0)).unwrap_or('\u{FFFD}'));
+                Ok(())
+            }
+            1 => {
+                // Input a character to R0.
+                match listener.getc() {
+                    Some(c) => {
+                        self.registers.set(Register::R0, c as u32);
+                        Ok(())
                     }
+                    None => Err(ProcessorError::WaitingForInput),
                 }
-                2 => {
-                    // Halt the processor.
-                    self.state = ProcessorState::Stopped;
-                    Ok(())
-                }
-                3 => {
-                    // Print the null-terminated byte-sized C-string with address in R0.
-                    // This could cause problems if unchecked so we'll make sure that the
-                    // string does actually terminate by stopping once we reach undefined memory.
-                    let mut addr = self.registers.get(Register::R0);
-                    loop {
-                        // We use the replacement character U+FFFD if R0 wasn't a character.
-                        let byte = self
-                            .memory
-                            .get_byte_option(addr)
-                            .ok_or(ProcessorError::StringNotTerminated)?;
-                        if byte == 0 {
-                            break;
-                        }
-                        listener.putc(char::from_u32(byte as u32).unwrap_or('\u{FFFD}'));
-                        addr += 1;
+            }
+            2 => {
+                // Halt the processor.
+                self.state = ProcessorState::Stopped;
+                Ok(())
+            }
+            3 => {
+                // Print the null-terminated byte-sized C-string with address in R0.
+                // This could cause problems if unchecked so we'll make sure that the
+                // string does actually terminate by stopping once we reach undefined memory.
+                let mut addr = self.registers.get(Register::R0);
+                loop {
+                    // We use the replacement character U+FFFD if R0 wasn't a character.
+                    let byte = self
+                        .memory
+                        .get_byte_option(addr)
+                        .ok_or(ProcessorError::StringNotTerminated)?;
+                    if byte == 0 {
+                        break;
                     }
-                    Ok(())
+                    listener.putc(char::from_u32(byte as u32).unwrap_or('\u{FFFD}'));
+                    addr += 1;
                 }
-                4 => {
-                    // Print the integer in R0.
-                    listener.putint(self.registers.get(Register::R0));
-                    Ok(())
+                Ok(())
+            }
+            4 => {
+                // Print the integer in R0.
+                listener.putint(self.registers.get(Register::R0));
+                Ok(())
+            }
+            SEMIHOSTING_SWI
+                if cfg!(feature = "semihosting") && self.config.semihosting.is_some() =>
+            {
+                let handler = self.config.semihosting.as_mut().unwrap();
+                match handler.handle(&self.registers, &self.memory, listener)? {
+                    SemihostingOutcome::Continue => {}
+                    SemihostingOutcome::Halt => self.state = ProcessorState::Stopped,
                 }
-                _ => Err(ProcessorError::InvalidSwi),
-            },
+                Ok(())
+            }
+            _ if self.config.exceptions => {
+                let size = self.registers.instruction_size();
+                self.enter_exception(Exception::SoftwareInterrupt, pc.wrapping_add(size));
+                listener.pipeline_flush(pc);
+                Ok(())
+            }
+            _ => Err(ProcessorError::InvalidSwi),
         }
     }
 
@@ -250,15 +1124,31 @@ impl Processor {
     ) -> ProcessorResult {
         listener.cycle(Cycle::Seq, 1, pc);
         let new_pc = self.registers.get(operand);
-        if new_pc & 0b11 != 0 {
-            // We don't emulate THUMB instructions.
+        // Bit 0 of the target chooses the state to continue in, and is not part of the address.
+        let thumb = new_pc & 1 != 0;
+        let new_pc = new_pc & !1;
+        if !thumb && new_pc & 0b10 != 0 {
             return Err(ProcessorError::UnalignedPc);
         }
-        self.registers.set(Register::R15, new_pc);
+        self.registers.set_thumb_state(thumb);
+        // The PC is advanced by one instruction of the new state after this instruction.
+        self.registers
+            .set_pc(new_pc.wrapping_sub(self.registers.instruction_size()));
         listener.pipeline_flush(pc);
         Ok(())
     }
 
+    /// The address that a value written to the PC by anything other than `BX` will branch to.
+    /// In THUMB state bit 0 is ignored, so that a return address left in LR by `BL` can be
+    /// moved or popped straight into the PC.
+    fn pc_target(&self, value: u32) -> u32 {
+        if self.registers.thumb_state() {
+            value & !1
+        } else {
+            value
+        }
+    }
+
     #[inline]
     fn execute_branch(
         &mut self,
@@ -268,22 +1158,54 @@ impl Processor {
         listener: &mut impl ProcessorListener,
     ) -> ProcessorResult {
         listener.cycle(Cycle::Seq, 1, pc);
+        let size = self.registers.instruction_size();
         if link {
             // Write the address of the next instruction into R14 (LR).
-            self.registers.set(
-                Register::R14,
-                self.registers.get(Register::R15).wrapping_add(4),
-            );
+            self.registers.set(Register::R14, pc.wrapping_add(size));
         }
-        let pc_reg = self.registers.get_mut(Register::R15);
-        // Only add 4 bytes instead of the actual PC offset (8 bytes)
+        // Only add one instruction instead of the actual PC offset (two instructions)
         // because we're about to auto-increment the PC anyway at the
         // end of this execution step.
-        *pc_reg = pc_reg.wrapping_add(4).wrapping_add_signed(offset);
+        self.registers
+            .set_pc(pc.wrapping_add(size).wrapping_add_signed(offset));
         listener.pipeline_flush(pc);
         Ok(())
     }
 
+    /// Execute one half of a THUMB long branch with link.
+    /// The first half puts the PC plus the upper part of the offset into LR,
+    /// and the second branches to LR plus the lower part of the offset,
+    /// putting the address of the next instruction into LR.
+    fn execute_thumb_long_branch(
+        &mut self,
+        pc: u32,
+        instr: u16,
+        listener: &mut impl ProcessorListener,
+    ) -> ProcessorResult {
+        listener.cycle(Cycle::Seq, 1, pc);
+        let offset = (instr & 0x7FF) as u32;
+        if instr & (1 << 11) == 0 {
+            // Sign-extend the 11-bit offset, then shift it into bits 12 to 22.
+            let high = (((offset << 21) as i32) >> 9) as u32;
+            let lr = pc.wrapping_add(4).wrapping_add(high);
+            if self.config.explain {
+                listener.explain(format!("BL (first half): LR = 0x{lr:08X}"));
+            }
+            self.registers.set(Register::R14, lr);
+        } else {
+            let target = self.registers.get(Register::R14).wrapping_add(offset << 1);
+            if self.config.explain {
+                listener.explain(format!("BL (second half): branch to 0x{target:08X}"));
+            }
+            // Bit 0 of the return address records that it is in THUMB state.
+            self.registers.set(Register::R14, pc.wrapping_add(2) | 1);
+            // The PC is about to be advanced past this instruction.
+            self.registers.set_pc(target.wrapping_sub(2));
+            listener.pipeline_flush(pc);
+        }
+        Ok(())
+    }
+
     #[allow(clippy::too_many_arguments)]
     #[inline]
     fn execute_data_processing(
@@ -301,10 +1223,18 @@ impl Processor {
             listener.cycle(Cycle::Internal, 1, pc);
             12
         } else {
-            8
+            self.registers.pc_ahead()
         };
         let mut val1 = self.registers.get_pc_offset(op1, pc_offset);
+        if op1 == Register::R15
+            && self.registers.thumb_state()
+            && matches!(op2, DataOperand::Constant(_))
+        {
+            // The THUMB `ADD Rd,PC,#imm` uses a word-aligned PC.
+            val1 &= !0b11;
+        }
         let (mut val2, barrel_carry) = self.evaluate_operand(op2, pc_offset)?;
+        let operands = (val1, val2);
 
         let carry_value = if self.registers.carry() { 1 } else { 0 };
         let mut carry = false;
@@ -314,6 +1244,8 @@ impl Processor {
             DataOp::Sub | DataOp::Cmp => {
                 // We implement subtraction by using the fact that
                 // a - b is the same as a + ~b + 1.
+                // The carry out of this addition is set exactly when no borrow occurs,
+                // which is the sense of the ARM carry flag after a subtraction.
                 // We reassign to val2 to get correct behaviour of flags.
                 val2 = !val2;
                 if let Some(v) = val1.checked_add(val2).and_then(|x| x.checked_add(1)) {
@@ -433,19 +1365,130 @@ impl Processor {
             DataOp::Tst | DataOp::Teq | DataOp::Cmp | DataOp::Cmn => {}
             _ => {
                 *self.registers_mut().get_mut(dest) = if dest == Register::R15 {
-                    // We need to decrement the PC by 4 bytes to
+                    // We need to decrement the PC by one instruction to
                     // take the auto-increment into account.
                     listener.pipeline_flush(pc);
-                    result.wrapping_sub(4)
+                    self.pc_target(result)
+                        .wrapping_sub(self.registers.instruction_size())
                 } else {
                     result
                 };
             }
         }
 
+        if self.config.explain {
+            listener.explain(self.explain_data_processing(
+                set_condition_codes && dest != Register::R15,
+                op,
+                dest,
+                op1,
+                op2,
+                operands,
+                carry_value,
+                result,
+            ));
+        }
+
         Ok(())
     }
 
+    /// Describe a data-processing instruction that has just been executed,
+    /// given the values of its operands and its result.
+    #[allow(clippy::too_many_arguments)]
+    fn explain_data_processing(
+        &self,
+        set_flags: bool,
+        op: DataOp,
+        dest: Register,
+        op1: Register,
+        op2: DataOperand,
+        (val1, val2): (u32, u32),
+        carry_value: u32,
+        result: u32,
+    ) -> String {
+        let logical = matches!(
+            op,
+            DataOp::And
+                | DataOp::Eor
+                | DataOp::Tst
+                | DataOp::Teq
+                | DataOp::Orr
+                | DataOp::Mov
+                | DataOp::Bic
+                | DataOp::Mvn
+        );
+        // Logical operations are easier to follow in hexadecimal.
+        let value = |x: u32| {
+            if logical {
+                format!("0x{x:X}")
+            } else {
+                (x as i32).to_string()
+            }
+        };
+        let (a, b, c, r) = (value(val1), value(val2), carry_value, value(result));
+        // Wrap shifted registers so that they read as a single operand.
+        let op2 = match op2.to_string() {
+            op2 if op2.contains(',') => format!("({op2})"),
+            op2 => op2,
+        };
+        let (symbolic, numeric) = match op {
+            DataOp::And | DataOp::Tst => (format!("{op1} & {op2}"), format!("{a} & {b}")),
+            DataOp::Eor | DataOp::Teq => (format!("{op1} ^ {op2}"), format!("{a} ^ {b}")),
+            DataOp::Sub | DataOp::Cmp => (format!("{op1} - {op2}"), format!("{a} - {b}")),
+            DataOp::Rsb => (format!("{op2} - {op1}"), format!("{b} - {a}")),
+            DataOp::Add | DataOp::Cmn => (format!("{op1} + {op2}"), format!("{a} + {b}")),
+            DataOp::Adc => (format!("{op1} + {op2} + C"), format!("{a} + {b} + {c}")),
+            DataOp::Sbc => (
+                format!("{op1} - {op2} + C - 1"),
+                format!("{a} - {b} + {c} - 1"),
+            ),
+            DataOp::Rsc => (
+                format!("{op2} - {op1} + C - 1"),
+                format!("{b} - {a} + {c} - 1"),
+            ),
+            DataOp::Orr => (format!("{op1} | {op2}"), format!("{a} | {b}")),
+            DataOp::Mov => (op2, b),
+            DataOp::Bic => (format!("{op1} & ~{op2}"), format!("{a} & ~{b}")),
+            DataOp::Mvn => (format!("~{op2}"), format!("~{b}")),
+        };
+        let mut explanation = match op {
+            DataOp::Tst | DataOp::Teq | DataOp::Cmp | DataOp::Cmn => {
+                format!("{symbolic} = {numeric} = {r}")
+            }
+            DataOp::Mov => format!("{dest} = {symbolic} = {r}"),
+            _ => format!("{dest} = {symbolic} = {numeric} = {r}"),
+        };
+        if set_flags {
+            let mut flags = vec![
+                ("N", self.registers.negative()),
+                ("Z", self.registers.zero()),
+                ("C", self.registers.carry()),
+            ];
+            // Logical operations don't affect the overflow flag.
+            if !logical {
+                flags.push(("V", self.registers.overflow()));
+            }
+            let list = |set: bool| {
+                let names = flags
+                    .iter()
+                    .filter(|(_, value)| *value == set)
+                    .map(|(name, _)| *name)
+                    .collect::<Vec<_>>();
+                if names.is_empty() {
+                    "none".to_owned()
+                } else {
+                    names.join(", ")
+                }
+            };
+            explanation.push_str(&format!(
+                "; flags set: {}; flags clear: {}",
+                list(true),
+                list(false)
+            ));
+        }
+        explanation
+    }
+
     #[inline]
     fn execute_mrs(
         &mut self,
@@ -469,40 +1512,26 @@ impl Processor {
         &mut self,
         pc: u32,
         psr: Psr,
+        fields: PsrFields,
         source: MsrSource,
         listener: &mut impl ProcessorListener,
     ) -> ProcessorResult {
         listener.cycle(Cycle::Seq, 1, pc);
         let mode = self.registers.mode().unwrap_or(Mode::Usr);
-        match source {
-            MsrSource::Register(register) => {
-                let value = self.registers.get(register);
-                let target = self
-                    .registers
-                    .get_physical_mut(psr.physical(mode).ok_or(ProcessorError::NoSpsr)?);
-                if mode == Mode::Usr {
-                    *target = (*target & 0x0FFFFFFF) | (value & 0xF0000000);
-                } else {
-                    *target = value;
-                }
-                Ok(())
-            }
-            MsrSource::RegisterFlags(register) => {
-                let value = self.registers.get(register);
-                let target = self
-                    .registers
-                    .get_physical_mut(psr.physical(mode).ok_or(ProcessorError::NoSpsr)?);
-                *target = (*target & 0x0FFFFFFF) | (value & 0xF0000000);
-                Ok(())
-            }
-            MsrSource::Flags(flags) => {
-                let target = self
-                    .registers
-                    .get_physical_mut(psr.physical(mode).ok_or(ProcessorError::NoSpsr)?);
-                *target = (*target & 0x0FFFFFFF) | (flags & 0xF0000000);
-                Ok(())
-            }
+        let value = match source {
+            MsrSource::Register(register) => self.registers.get(register),
+            MsrSource::Constant(constant) => constant.value().0,
+        };
+        let mut mask = fields.mask();
+        if mode == Mode::Usr {
+            // User mode programs may only change the condition code flags.
+            mask &= PsrFields::FLAGS.mask();
         }
+        let target = self
+            .registers
+            .get_physical_mut(psr.physical(mode).ok_or(ProcessorError::NoSpsr)?);
+        *target = (*target & !mask) | (value & mask);
+        Ok(())
     }
 
     #[allow(clippy::too_many_arguments)]
@@ -619,151 +1648,37 @@ impl Processor {
 
         if set_condition_codes {
             self.registers.set_carry(false);
-            self.registers.set_negative(result & (1 << 31) != 0);
-            self.registers.set_zero(result == 0);
-        }
-
-        self.registers.set(dest_hi, (result >> 32) as u32);
-        self.registers.set(dest_lo, result as u32);
-
-        Ok(())
-    }
-
-    #[allow(clippy::too_many_arguments)]
-    #[inline]
-    fn execute_single_transfer(
-        &mut self,
-        pc: u32,
-        kind: TransferKind,
-        size: TransferSize,
-        mut write_back: bool,
-        offset_positive: bool,
-        pre_index: bool,
-        data_register: Register,
-        base_register: Register,
-        offset: TransferOperand,
-        listener: &mut impl ProcessorListener,
-    ) -> ProcessorResult {
-        match kind {
-            TransferKind::Store => {
-                listener.cycle(Cycle::NonSeq, 2, pc);
-            }
-            TransferKind::Load if data_register == Register::R15 => {
-                listener.cycle(Cycle::Seq, 2, pc);
-                listener.cycle(Cycle::NonSeq, 2, pc);
-                listener.cycle(Cycle::Internal, 1, pc);
-            }
-            TransferKind::Load => {
-                listener.cycle(Cycle::Seq, 1, pc);
-                listener.cycle(Cycle::NonSeq, 1, pc);
-                listener.cycle(Cycle::Internal, 1, pc);
-            }
-        }
-
-        if !pre_index {
-            write_back = true;
-        }
-
-        match offset {
-            TransferOperand::Constant(_) => {}
-            TransferOperand::Register(register, shift) => {
-                if register == Register::R15 {
-                    return Err(ProcessorError::InvalidUseOfPc);
-                }
-                match shift.shift_amount {
-                    ShiftAmount::Constant(_) => {}
-                    _ => return Err(ProcessorError::AddressTooComplex),
-                }
-            }
-        }
-
-        // The barrel shifter carry out is not used.
-        // R15 cannot be used here so we set the PC offset to 0.
-        let offset = self.evaluate_transfer_operand(offset, 0)?;
-        let offset = if offset_positive {
-            offset as i32
-        } else {
-            -(offset as i32)
-        };
-
-        if write_back && base_register == Register::R15 {
-            return Err(ProcessorError::InvalidUseOfPc);
-        }
-
-        // We emulate a little-endian architecture.
-        let address = self
-            .registers
-            .get_pc_offset(base_register, 8)
-            .wrapping_add_signed(if pre_index { offset } else { 0 });
-
-        if kind == TransferKind::Load && write_back {
-            let base = self.registers.get_mut(base_register);
-            *base = base.wrapping_add_signed(offset);
-        }
-
-        match (kind, size) {
-            (TransferKind::Store, TransferSize::Byte) => {
-                self.memory.set_byte(
-                    address,
-                    self.registers.get_pc_offset(data_register, 12) as u8,
-                );
-            }
-            (TransferKind::Store, TransferSize::Word) => {
-                // Auto-align the address.
-                self.memory.set_word_aligned(
-                    address >> 2 << 2,
-                    self.registers.get_pc_offset(data_register, 12),
-                );
-            }
-            (TransferKind::Load, TransferSize::Byte) => {
-                let mut value = self.memory.get_byte(address) as u32;
-                if data_register == Register::R15 {
-                    // Pre-decrement by 4 to compensate for auto-increment.
-                    value = value.wrapping_sub(4);
-                }
-                self.registers.set(data_register, value);
-            }
-            (TransferKind::Load, TransferSize::Word) => {
-                let value = self.memory.get_word_aligned(address >> 2 << 2);
-                // Rotate it to match the desired offset from word alignment.
-                let mut value = match address & 0b11 {
-                    0 => value,
-                    1 => value.rotate_right(8),
-                    2 => value.rotate_right(16),
-                    3 => value.rotate_left(8),
-                    _ => unreachable!(),
-                };
-                if data_register == Register::R15 {
-                    // Pre-decrement by 4.
-                    value = value.wrapping_sub(4);
-                }
-                self.registers.set(data_register, value);
-            }
+            self.registers.set_negative(result & (1 << 31) != 0);
+            self.registers.set_zero(result == 0);
         }
 
-        if kind == TransferKind::Store && write_back {
-            let base = self.registers.get_mut(base_register);
-            *base = base.wrapping_add_signed(offset);
-        }
+        self.registers.set(dest_hi, (result >> 32) as u32);
+        self.registers.set(dest_lo, result as u32);
 
         Ok(())
     }
 
     #[allow(clippy::too_many_arguments)]
     #[inline]
-    fn execute_single_transfer_special(
+    fn execute_single_transfer(
         &mut self,
         pc: u32,
         kind: TransferKind,
-        size: TransferSizeSpecial,
+        size: TransferSize,
         mut write_back: bool,
         offset_positive: bool,
         pre_index: bool,
         data_register: Register,
         base_register: Register,
-        offset: SpecialOperand,
+        offset: TransferOperand,
         listener: &mut impl ProcessorListener,
     ) -> ProcessorResult {
+        // The encodings of STRSB and STRSH are architecturally invalid,
+        // so reject them before anything else happens.
+        if kind == TransferKind::Store && size.is_signed() {
+            return Err(ProcessorError::InvalidStoreSize);
+        }
+
         match kind {
             TransferKind::Store => {
                 listener.cycle(Cycle::NonSeq, 2, pc);
@@ -784,16 +1699,22 @@ impl Processor {
             write_back = true;
         }
 
-        if let SpecialOperand::Register(register) = offset
-            && register == Register::R15
-        {
-            return Err(ProcessorError::InvalidUseOfPc);
+        match offset {
+            TransferOperand::Constant(_) => {}
+            TransferOperand::Register(register, shift) => {
+                if register == Register::R15 {
+                    return Err(ProcessorError::InvalidUseOfPc);
+                }
+                match shift.shift_amount {
+                    ShiftAmount::Constant(_) => {}
+                    _ => return Err(ProcessorError::AddressTooComplex),
+                }
+            }
         }
 
-        let offset = match offset {
-            SpecialOperand::Constant(offset) => offset as u32,
-            SpecialOperand::Register(register) => self.registers.get(register),
-        };
+        // The barrel shifter carry out is not used.
+        // R15 cannot be used here so we set the PC offset to 0.
+        let offset = self.evaluate_transfer_operand(offset, 0)?;
         let offset = if offset_positive {
             offset as i32
         } else {
@@ -805,73 +1726,91 @@ impl Processor {
         }
 
         // We emulate a little-endian architecture.
-        let address = self
+        let mut base = self
             .registers
-            .get_pc_offset(base_register, 8)
-            .wrapping_add_signed(if pre_index { offset } else { 0 });
+            .get_pc_offset(base_register, self.registers.pc_ahead());
+        if base_register == Register::R15 {
+            // THUMB loads relative to the PC use a word-aligned PC.
+            base &= !0b11;
+        }
+        let address = base.wrapping_add_signed(if pre_index { offset } else { 0 });
+        self.check_null_access(
+            kind,
+            address,
+            base_register,
+            if pre_index { offset } else { 0 },
+        )?;
 
         if kind == TransferKind::Load && write_back {
             let base = self.registers.get_mut(base_register);
             *base = base.wrapping_add_signed(offset);
         }
 
-        match (kind, size) {
-            (TransferKind::Store, TransferSizeSpecial::HalfWord) => {
-                if address & 0b1 != 0 {
-                    return Err(ProcessorError::UnalignedTransfer);
-                }
-                let original_value = self.memory.get_word_aligned(address >> 2 << 2);
+        match kind {
+            TransferKind::Store => {
+                self.check_program_write(pc, address, listener);
                 let operand = self.registers.get_pc_offset(data_register, 12);
-                let new_value = if address & 0b10 == 0 {
-                    // This is word-aligned. Set the least significant two bytes.
-                    original_value & 0xFFFF0000 | operand & 0x0000FFFF
-                } else {
-                    // This is not word-aligned. Set the most significant two bytes.
-                    original_value & 0x0000FFFF | operand << 16
-                };
-                self.memory.set_word_aligned(address >> 2 << 2, new_value);
-            }
-            (TransferKind::Store, TransferSizeSpecial::SignExtendedByte) => {
-                return Err(ProcessorError::InvalidTransfer);
-            }
-            (TransferKind::Store, TransferSizeSpecial::SignExtendedHalfWord) => {
-                return Err(ProcessorError::InvalidTransfer);
-            }
-            (TransferKind::Load, TransferSizeSpecial::HalfWord) => {
-                if address & 0b1 != 0 {
-                    return Err(ProcessorError::UnalignedTransfer);
+                match size {
+                    TransferSize::Byte => {
+                        let old = self.peek(address, 1);
+                        self.memory.set_byte(address, operand as u8);
+                        listener.memory_write(address, 1, old, operand & 0xFF, pc);
+                    }
+                    TransferSize::Word => {
+                        let old = self.peek(address, 4);
+                        self.memory.set_word(address, operand);
+                        listener.memory_write(address & !0b11, 4, old, operand, pc);
+                    }
+                    TransferSize::HalfWord => {
+                        if address & 0b1 != 0 {
+                            return Err(ProcessorError::UnalignedTransfer);
+                        }
+                        let old = self.peek(address, 2);
+                        self.memory.set_halfword(address, operand as u16);
+                        listener.memory_write(address, 2, old, operand & 0xFFFF, pc);
+                    }
+                    TransferSize::SignExtendedByte | TransferSize::SignExtendedHalfWord => {
+                        unreachable!("signed stores were rejected above")
+                    }
                 }
-                let value = self.memory.get_word_aligned(address >> 2 << 2);
-                self.registers.set(
-                    data_register,
-                    if address & 0b10 == 0 {
-                        // This is word-aligned. Load the least significant two bytes.
-                        value as u16 as u32
-                    } else {
-                        // This is not word-aligned. Load the most significant two bytes.
-                        value >> 16
-                    },
-                );
-            }
-            (TransferKind::Load, TransferSizeSpecial::SignExtendedByte) => {
-                self.registers.set(
-                    data_register,
-                    self.memory.get_byte(address) as i8 as i32 as u32,
-                );
             }
-            (TransferKind::Load, TransferSizeSpecial::SignExtendedHalfWord) => {
-                if address & 0b1 != 0 {
-                    return Err(ProcessorError::UnalignedTransfer);
+            TransferKind::Load => {
+                let mut value = match size {
+                    TransferSize::Byte | TransferSize::SignExtendedByte => {
+                        let value = self.memory.get_byte(address);
+                        listener.memory_read(address, 1, value as u32, pc);
+                        if size == TransferSize::Byte {
+                            value as u32
+                        } else {
+                            value as i8 as i32 as u32
+                        }
+                    }
+                    TransferSize::Word => {
+                        let value = self.memory.get_word(address);
+                        let stored = value.rotate_left(8 * (address & 0b11));
+                        listener.memory_read(address & !0b11, 4, stored, pc);
+                        value
+                    }
+                    TransferSize::HalfWord | TransferSize::SignExtendedHalfWord => {
+                        if address & 0b1 != 0 {
+                            return Err(ProcessorError::UnalignedTransfer);
+                        }
+                        let value = self.memory.get_halfword(address);
+                        listener.memory_read(address, 2, value as u32, pc);
+                        if size == TransferSize::HalfWord {
+                            value as u32
+                        } else {
+                            value as i16 as i32 as u32
+                        }
+                    }
+                };
+                if data_register == Register::R15 {
+                    // Pre-decrement to compensate for auto-increment.
+                    value = self
+                        .pc_target(value)
+                        .wrapping_sub(self.registers.instruction_size());
                 }
-                let value = self.memory.get_word_aligned(address >> 2 << 2);
-                self.registers.set(
-                    data_register,
-                    if address & 0b10 == 0 {
-                        value as u16 as i16 as i32 as u32
-                    } else {
-                        (value as i32 >> 16) as u32
-                    },
-                );
+                self.registers.set(data_register, value);
             }
         }
 
@@ -907,18 +1846,13 @@ impl Processor {
                 listener.cycle(Cycle::NonSeq, 2, pc);
             }
             TransferKind::Load => {
-                listener.cycle(
-                    Cycle::Seq,
-                    registers.count_ones() as usize
-                        + if registers & (1 << 15) == 0 { 0 } else { 1 },
-                    pc,
-                );
-                listener.cycle(
-                    Cycle::NonSeq,
-                    1 + if registers & (1 << 15) == 0 { 0 } else { 1 },
-                    pc,
-                );
+                listener.cycle(Cycle::Seq, registers.count_ones() as usize, pc);
+                listener.cycle(Cycle::NonSeq, 1, pc);
                 listener.cycle(Cycle::Internal, 1, pc);
+                if registers & (1 << 15) != 0 {
+                    // Loading the PC costs a further 1S + 1N.
+                    listener.pipeline_flush(pc);
+                }
             }
         }
 
@@ -933,6 +1867,18 @@ impl Processor {
         };
         // Auto-align the address.
         address = address >> 2 << 2;
+        // Check the lowest address that will be transferred.
+        let lowest = if pre_index == offset_positive {
+            address.wrapping_add(4)
+        } else {
+            address
+        };
+        self.check_null_access(
+            kind,
+            lowest,
+            base_register,
+            lowest.wrapping_sub(self.registers.get(base_register)) as i32,
+        )?;
 
         // println!("Block transfer: {kind:?} pos={offset_positive} pre={pre_index}");
 
@@ -959,21 +1905,16 @@ impl Processor {
 
             match kind {
                 TransferKind::Store => {
-                    self.memory.set_word_aligned(
-                        address,
-                        self.registers.get_physical_pc_offset(register, 12),
-                    );
+                    self.check_program_write(pc, address, listener);
+                    let old = self.peek(address, 4);
+                    let value = self.registers.get_physical_pc_offset(register, 12);
+                    self.memory.set_word_aligned(address, value);
+                    listener.memory_write(address, 4, old, value, pc);
                 }
                 TransferKind::Load => {
-                    self.registers
-                        .set_physical(register, self.memory.get_word_aligned(address));
-                    if register == PhysicalRegister::R15 {
-                        // Pre-decrement.
-                        self.registers.set_physical(
-                            register,
-                            self.registers.get_physical(register).wrapping_sub(4),
-                        );
-                    }
+                    let value = self.memory.get_word_aligned(address);
+                    listener.memory_read(address, 4, value, pc);
+                    self.registers.set_physical(register, value);
                 }
             }
 
@@ -998,6 +1939,13 @@ impl Processor {
                 .set_physical(PhysicalRegister::Cpsr, self.registers.get_physical(spsr));
         }
 
+        if kind == TransferKind::Load && registers & (1 << 15) != 0 {
+            // Pre-decrement, once the state to continue in is known.
+            let pc = self.pc_target(self.registers.pc());
+            self.registers
+                .set_pc(pc.wrapping_sub(self.registers.instruction_size()));
+        }
+
         if !written_back {
             self.registers.set(base_register, final_address);
         }
@@ -1024,25 +1972,24 @@ impl Processor {
         }
 
         let addr = self.registers.get(base);
+        self.check_null_access(TransferKind::Load, addr, base, 0)?;
+        self.check_program_write(pc, addr, listener);
         match byte {
             true => {
-                let b = self.memory.get_byte(addr);
-                self.memory.set_byte(addr, self.registers.get(source) as u8);
-                self.registers.set(dest, b as u32);
+                let b = self.memory.get_byte(addr) as u32;
+                listener.memory_read(addr, 1, b, pc);
+                let new = self.registers.get(source) & 0xFF;
+                self.memory.set_byte(addr, new as u8);
+                listener.memory_write(addr, 1, b, new, pc);
+                self.registers.set(dest, b);
             }
             false => {
-                let value = self.memory.get_word_aligned(addr >> 2 << 2);
-                // Rotate it to match the desired offset from word alignment.
-                let value = match addr & 0b11 {
-                    0 => value,
-                    1 => value.rotate_right(8),
-                    2 => value.rotate_right(16),
-                    3 => value.rotate_left(8),
-                    _ => unreachable!(),
-                };
-                // Auto-align the address.
-                self.memory
-                    .set_word_aligned(addr >> 2 << 2, self.registers.get(source));
+                let value = self.memory.get_word(addr);
+                let stored = value.rotate_left(8 * (addr & 0b11));
+                listener.memory_read(addr & !0b11, 4, stored, pc);
+                let new = self.registers.get(source);
+                self.memory.set_word(addr, new);
+                listener.memory_write(addr & !0b11, 4, stored, new, pc);
                 self.registers.set(dest, value);
             }
         }
@@ -1174,9 +2121,37 @@ pub trait ProcessorListener {
             self.putc(c);
         }
     }
+    /// Receive a description of the instruction that was just executed,
+    /// such as `R1 = R2 - R3 = 5 - 2 = 3; flags set: C; flags clear: N, Z, V`.
+    /// This is only called if [`ProcessorConfig::explain`] is set.
+    fn explain(&mut self, _explanation: String) {}
+    /// The instruction at `pc` stored to `address`, which is part of [`ProcessorConfig::program`].
+    fn program_overwritten(&mut self, _pc: u32, _address: u32) {}
+    /// The instruction at `pc` loaded `size` bytes from `addr`.
+    /// The address is aligned to the size of the access,
+    /// and the value is given as it was stored in memory, before any rotation or sign extension.
+    fn memory_read(&mut self, _addr: u32, _size: u8, _value: u32, _pc: u32) {}
+    /// The instruction at `pc` stored `size` bytes to `addr`, replacing `old` with `new`.
+    /// The address is aligned to the size of the access.
+    /// The old value of a mapped address is not read from its handler.
+    fn memory_write(&mut self, _addr: u32, _size: u8, _old: u32, _new: u32, _pc: u32) {}
+}
+
+/// A listener that ignores everything, and has no input.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NullListener;
+
+impl ProcessorListener for NullListener {
+    fn cycle(&mut self, _cycle: Cycle, _count: usize, _pc: u32) {}
+    fn pipeline_flush(&mut self, _pc: u32) {}
+    fn getc(&mut self) -> Option<char> {
+        None
+    }
+    fn putc(&mut self, _c: char) {}
 }
 
 /// One of the four cycle types in the CPU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Cycle {
     /// The processor accessed a portion of memory unrelated to the address
     /// used in the preceding cycle.
@@ -1198,14 +2173,18 @@ pub type ProcessorResult = Result<(), ProcessorError>;
 
 /// The type of possible errors that can be encountered
 /// while executing an instruction.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ProcessorError {
-    /// The program counter was not 4-byte aligned.
+    /// The program counter was not aligned to the size of an instruction in the current state,
+    /// such as after a `BX` in ARM state to an address that is 2 more than a multiple of 4.
+    /// A `BX` to an odd address is not an error, since it enters THUMB state.
     UnalignedPc,
     /// The address used for transfer was not aligned.
     UnalignedTransfer,
     /// This transfer type was not supported.
     InvalidTransfer,
+    /// A store was sign-extending, which is only possible for loads.
+    InvalidStoreSize,
     /// The instruction at the program counter could not be decoded.
     UnrecognisedInstruction,
     /// The program counter was used in an invalid place in an instruction.
@@ -1225,6 +2204,16 @@ pub enum ProcessorError {
     WaitingForInput,
     /// The given string was not null-terminated.
     StringNotTerminated,
+    /// A semihosting call was made with an operation that we don't support.
+    UnsupportedSemihostingOperation(u32),
+    /// A transfer was made near address zero while the [`NullCheck`] was enabled.
+    NullAccess {
+        kind: TransferKind,
+        address: u32,
+        base_register: Register,
+        /// The offset from the base register used to compute the address.
+        offset: i32,
+    },
 }
 
 impl Display for ProcessorError {
@@ -1233,6 +2222,7 @@ impl Display for ProcessorError {
             ProcessorError::UnalignedPc => write!(f, "PC not aligned"),
             ProcessorError::UnalignedTransfer => write!(f, "Transfer not aligned"),
             ProcessorError::InvalidTransfer => write!(f, "Invalid transfer"),
+            ProcessorError::InvalidStoreSize => write!(f, "Stores cannot be sign-extended"),
             ProcessorError::UnrecognisedInstruction => write!(f, "Unrecognised instruction"),
             ProcessorError::InvalidUseOfPc => write!(f, "Invalid use of PC"),
             ProcessorError::PcUsedInShift => write!(f, "PC used in shift"),
@@ -1242,14 +2232,43 @@ impl Display for ProcessorError {
             ProcessorError::RegisterListEmpty => write!(f, "Register list empty"),
             ProcessorError::WaitingForInput => write!(f, "Waiting for input"),
             ProcessorError::StringNotTerminated => write!(f, "String not null-terminated"),
+            ProcessorError::UnsupportedSemihostingOperation(operation) => {
+                write!(f, "Unsupported semihosting operation 0x{operation:X}")
+            }
+            ProcessorError::NullAccess {
+                kind,
+                address,
+                base_register,
+                offset,
+            } => write!(
+                f,
+                "{} address 0x{address:08X} near zero (base register {base_register}, offset {offset}); is {base_register} uninitialised?",
+                match kind {
+                    TransferKind::Store => "Store to",
+                    TransferKind::Load => "Load from",
+                },
+            ),
         }
     }
 }
 
 #[cfg(test)]
 pub mod test {
+    use crate::assemble::assemble;
+    use crate::instr::Cycles;
     use crate::processor::Cycle;
-    use crate::processor::ProcessorListener;
+    use crate::processor::{Processor, ProcessorBuilder, ProcessorListener};
+
+    /// A builder for a processor with the given source assembled and loaded at address 0.
+    pub fn builder_for(src: &str) -> ProcessorBuilder {
+        let assembled = assemble(src).unwrap();
+        ProcessorBuilder::default().load_words(0, &assembled.instrs)
+    }
+
+    /// A processor with the given source assembled and loaded at address 0.
+    pub fn load(src: &str) -> Processor {
+        builder_for(src).build().unwrap()
+    }
 
     #[derive(Default, Debug)]
     pub struct TestProcessorListener {
@@ -1259,6 +2278,16 @@ pub mod test {
 
         pub input_reversed: Vec<char>,
         pub output: String,
+        pub explanations: Vec<String>,
+        /// The program counter and target address of each store into the program.
+        pub program_writes: Vec<(u32, u32)>,
+    }
+
+    impl TestProcessorListener {
+        /// The cycles counted so far.
+        pub fn cycles(&self) -> Cycles {
+            Cycles::new(self.s_cycles, self.n_cycles, self.i_cycles)
+        }
     }
 
     impl ProcessorListener for TestProcessorListener {
@@ -1283,5 +2312,1130 @@ pub mod test {
         fn putc(&mut self, c: char) {
             self.output.push(c)
         }
+
+        fn explain(&mut self, explanation: String) {
+            self.explanations.push(explanation);
+        }
+
+        fn program_overwritten(&mut self, pc: u32, address: u32) {
+            self.program_writes.push((pc, address));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        addr::Addr,
+        assemble::assemble,
+        instr::{Cycles, Instr, Register, TransferKind, TransferSize},
+        memory::Memory,
+        mode::Mode,
+        processor::{
+            BreakpointOutcome, BuildError, MachineState, MemoryRegion, NullCheck, NullListener,
+            PollOutcome, Processor, ProcessorBuilder, ProcessorConfig, ProcessorError,
+            ProcessorSnapshot, ProcessorState, SwiHandler, SwiOutcome, SwiServices,
+            test::{TestProcessorListener, builder_for, load},
+        },
+        registers::{PhysicalRegister, Registers},
+        semihosting::SemihostingHandler,
+        watchpoints::{WatchHit, WatchKind},
+    };
+
+    fn store_through_zero(null_check: Option<NullCheck>) -> Processor {
+        builder_for("mov r0, 123\nstr r0, [r1]\n")
+            .config(ProcessorConfig {
+                null_check,
+                ..Default::default()
+            })
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn builder() {
+        let config = ProcessorConfig {
+            null_check: None,
+            semihosting: Some(SemihostingHandler::default()),
+            explain: true,
+            program: None,
+            exceptions: true,
+            undefined_trap: false,
+        };
+        let proc = ProcessorBuilder::default()
+            .mode(Mode::Supervisor)
+            .pc(0x100)
+            .sp(0x8000)
+            .register(Register::R3, 42)
+            .load_words(0x100, &[0xE3A00001, 0xEF000002])
+            .load_image(0x201, b"hi")
+            .config(config.clone())
+            .build()
+            .unwrap();
+        assert_eq!(proc.registers().mode(), Some(Mode::Supervisor));
+        assert_eq!(proc.registers().pc(), 0x100);
+        // The stack pointer is banked, so only the supervisor's is set.
+        assert_eq!(
+            proc.registers().get_physical(PhysicalRegister::R13Svc),
+            0x8000
+        );
+        assert_eq!(proc.registers().get_physical(PhysicalRegister::R13), 0);
+        assert_eq!(proc.registers().get(Register::R3), 42);
+        assert_eq!(proc.memory().get_word_aligned(0x104), 0xEF000002);
+        assert_eq!(proc.memory().get_byte(0x201), b'h');
+        assert_eq!(proc.memory().get_byte(0x202), b'i');
+        assert_eq!(proc.config(), &config);
+    }
+
+    #[test]
+    fn explain() {
+        let mut proc = builder_for("mov r2, #5\nmov r3, #2\nadds r1, r2, r3\nsubeq r1, r1, #1\n")
+            .config(ProcessorConfig {
+                explain: true,
+                ..Default::default()
+            })
+            .build()
+            .unwrap();
+        let mut listener = TestProcessorListener::default();
+        for _ in 0..4 {
+            proc.try_execute(&mut listener).unwrap();
+            proc.registers_mut().advance_pc();
+        }
+        assert_eq!(
+            listener.explanations,
+            [
+                "R2 = #5 = 0x5",
+                "R3 = #2 = 0x2",
+                "R1 = R2 + R3 = 5 + 2 = 7; flags set: none; flags clear: N, Z, C, V",
+                "SUBEQ R1,R1,#1 skipped: condition EQ not met",
+            ]
+        );
+    }
+
+    #[test]
+    fn load_state() {
+        let json = r#"{
+            "registers": { "0": 5, "13": 4096, "15": 8 },
+            "cpsr": 2415919123,
+            "memory": [{ "address": 4096, "words": [1, 2, 3] }]
+        }"#;
+        let state = serde_json::from_str::<MachineState>(json).unwrap();
+        assert_eq!(
+            serde_json::from_str::<MachineState>(&serde_json::to_string(&state).unwrap()).unwrap(),
+            state
+        );
+
+        let mut proc = Processor::default();
+        proc.load_state(&state).unwrap();
+        // The CPSR has the N and V flags set, and is in supervisor mode.
+        assert_eq!(proc.registers().mode(), Some(Mode::Supervisor));
+        assert!(proc.registers().negative() && proc.registers().overflow());
+        assert_eq!(proc.registers().get(Register::R0), 5);
+        assert_eq!(
+            proc.registers().get_physical(PhysicalRegister::R13Svc),
+            4096
+        );
+        assert_eq!(proc.registers().pc(), 8);
+        assert_eq!(proc.memory().get_word_aligned(4104), 3);
+
+        let mut state = MachineState::default();
+        state.memory.push(MemoryRegion {
+            address: Addr(2),
+            words: vec![0],
+        });
+        assert_eq!(
+            proc.load_state(&state),
+            Err(BuildError::UnalignedLoad(Addr(2)))
+        );
+    }
+
+    #[test]
+    fn program_overwritten() {
+        // The program is three words long.
+        let mut proc = builder_for("mov r1, #8\nstr r0, [r1]\nstr r0, [r1, #4]\n")
+            .config(ProcessorConfig {
+                program: Some(0..12),
+                ..Default::default()
+            })
+            .build()
+            .unwrap();
+        let mut listener = TestProcessorListener::default();
+        for _ in 0..3 {
+            proc.try_execute(&mut listener).unwrap();
+            proc.registers_mut().advance_pc();
+        }
+        // Only the first store was into the program.
+        assert_eq!(listener.program_writes, [(4, 8)]);
+    }
+
+    /// Each step leaves the PC at the next instruction to execute,
+    /// including after branches.
+    #[test]
+    fn stepping_advances_pc() {
+        let mut proc = load(
+            "\
+        mov r0, #16
+        bl func
+        bx r0
+        mov r1, #1
+        mov r2, #2
+func    mov pc, lr
+",
+        );
+        let mut listener = TestProcessorListener::default();
+        let mut trace = vec![proc.registers().pc()];
+        for _ in 0..5 {
+            proc.try_execute(&mut listener).unwrap();
+            proc.registers_mut().advance_pc();
+            trace.push(proc.registers().pc());
+        }
+        assert_eq!(trace, [0, 4, 20, 8, 16, 20]);
+        assert_eq!(proc.registers().get(Register::R1), 0);
+        assert_eq!(proc.registers().get(Register::R2), 2);
+    }
+
+    fn run(src: &str, steps: usize) -> (Processor, TestProcessorListener) {
+        let mut proc = load(src);
+        let mut listener = TestProcessorListener::default();
+        for _ in 0..steps {
+            proc.try_execute(&mut listener).unwrap();
+            proc.registers_mut().advance_pc();
+        }
+        (proc, listener)
+    }
+
+    #[test]
+    fn push_pop_around_call() {
+        let src = "\
+        mov sp, #0x1000
+        mov r4, #4
+        mov r5, #5
+        bl func
+        mov r0, #1
+func    stmfd sp!, {r4, r5, lr}
+        mov r4, #40
+        mov r5, #50
+        ldmfd sp!, {r4, r5, pc}
+";
+        let (proc, listener) = run(src, 8);
+        assert_eq!(proc.registers().pc(), 16);
+        assert_eq!(proc.registers().get(Register::R13), 0x1000);
+        assert_eq!(proc.registers().get(Register::R4), 4);
+        assert_eq!(proc.registers().get(Register::R5), 5);
+        let mut stack = [0; 3];
+        proc.memory().get_words_aligned(0xFF4, &mut stack);
+        assert_eq!(stack, [4, 5, 16]);
+
+        // The STM takes 2S + 2N, and the LDM takes 4S + 2N + 1I including the flush.
+        let (_, listener_before) = run(src, 4);
+        let (_, listener_after_stm) = run(src, 5);
+        assert_eq!(
+            listener_after_stm.cycles(),
+            listener_before.cycles() + Cycles::new(2, 2, 0)
+        );
+        let (_, listener_before_ldm) = run(src, 7);
+        assert_eq!(
+            listener.cycles(),
+            listener_before_ldm.cycles() + Cycles::new(4, 2, 1)
+        );
+    }
+
+    #[test]
+    fn base_in_register_list() {
+        let src = "\
+        mov r0, #0x100
+        mov r1, #1
+        stmia r0!, {r0, r1}
+        mov r2, #0x100
+        ldmia r2!, {r1, r2}
+";
+        let (proc, _) = run(src, 5);
+        // The base is first in the list, so its original value is stored.
+        assert_eq!(proc.memory().get_word_aligned(0x100), 0x100);
+        assert_eq!(proc.memory().get_word_aligned(0x104), 1);
+        assert_eq!(proc.registers().get(Register::R0), 0x108);
+        // A loaded base overrides the write-back.
+        assert_eq!(proc.registers().get(Register::R1), 0x100);
+        assert_eq!(proc.registers().get(Register::R2), 1);
+    }
+
+    #[test]
+    fn stack_types() {
+        // Each type of stack pushes R0 and R1, then pops them into R2 and R3.
+        for (push, pop, low) in [
+            ("stmfd", "ldmfd", 0xFF8),
+            ("stmed", "ldmed", 0xFFC),
+            ("stmfa", "ldmfa", 0x1004),
+            ("stmea", "ldmea", 0x1000),
+        ] {
+            let src = format!(
+                "\
+        mov sp, #0x1000
+        mov r0, #10
+        mov r1, #11
+        {push} sp!, {{r0, r1}}
+        {pop} sp!, {{r2, r3}}
+"
+            );
+            let (proc, _) = run(&src, 4);
+            // The lowest register is always stored at the lowest address.
+            assert_eq!(proc.memory().get_word_aligned(low), 10, "{push}");
+            assert_eq!(proc.memory().get_word_aligned(low + 4), 11, "{push}");
+            let (proc, _) = run(&src, 5);
+            assert_eq!(proc.registers().get(Register::R2), 10, "{pop}");
+            assert_eq!(proc.registers().get(Register::R3), 11, "{pop}");
+            assert_eq!(proc.registers().get(Register::R13), 0x1000, "{pop}");
+        }
+    }
+
+    #[test]
+    fn stm_base_not_first() {
+        let (proc, _) = run("mov r1, #0x100\nmov r0, #1\nstmia r1!, {r0, r1}\n", 3);
+        // The base is written back after the first register is stored.
+        assert_eq!(proc.memory().get_word_aligned(0x100), 1);
+        assert_eq!(proc.memory().get_word_aligned(0x104), 0x108);
+    }
+
+    #[test]
+    fn ldm_restores_cpsr() {
+        let mut proc = builder_for(
+            "\
+        mov sp, #0x1000
+        adr r0, target
+        stmfd sp!, {r0}
+        ldmfd sp!, {pc}^
+        mov r1, #1
+target  mov r2, #2
+",
+        )
+        .mode(Mode::Supervisor)
+        .build()
+        .unwrap();
+        let user_with_negative = Mode::Usr as u32 | 1 << 31;
+        proc.registers_mut()
+            .set_physical(PhysicalRegister::SpsrSvc, user_with_negative);
+        let mut listener = TestProcessorListener::default();
+        for _ in 0..5 {
+            proc.try_execute(&mut listener).unwrap();
+            proc.registers_mut().advance_pc();
+        }
+        assert_eq!(proc.registers().cpsr(), user_with_negative);
+        assert_eq!(proc.registers().get(Register::R1), 0);
+        assert_eq!(proc.registers().get(Register::R2), 2);
+    }
+
+    #[test]
+    fn swap() {
+        let src = "\
+        adr r3, data
+        mov r1, #0xAB
+        swp r2, r1, [r3]
+        add r4, r3, #4
+        mov r5, #0xCD
+        swpb r5, r5, [r4]
+        add r6, r3, #9
+        mov r7, #7
+        swp r8, r7, [r6]
+data    dw 0x11223344
+        dw 0x55667788
+        dw 0x99AABBCC
+";
+        let (proc, _) = run(src, 9);
+        assert_eq!(proc.registers().get(Register::R2), 0x11223344);
+        assert_eq!(proc.memory().get_word_aligned(36), 0xAB);
+        // The destination and source may be the same register.
+        assert_eq!(proc.registers().get(Register::R5), 0x88);
+        assert_eq!(proc.memory().get_word_aligned(40), 0x556677CD);
+        // An unaligned word is rotated as by LDR, but the store is aligned.
+        assert_eq!(proc.registers().get(Register::R8), 0xCC99AABB);
+        assert_eq!(proc.memory().get_word_aligned(44), 7);
+
+        let (_, before) = run(src, 2);
+        let (_, after) = run(src, 3);
+        assert_eq!(after.cycles(), before.cycles() + Cycles::new(1, 2, 1));
+    }
+
+    #[test]
+    fn swap_prefilled_memory() {
+        let mut proc = builder_for("mov r0, #0x200\nmov r1, #5\nswp r2, r1, [r0]\n")
+            .load_words(0x200, &[0xDEADBEEF])
+            .build()
+            .unwrap();
+        let mut listener = TestProcessorListener::default();
+        for _ in 0..3 {
+            proc.try_execute(&mut listener).unwrap();
+            proc.registers_mut().advance_pc();
+        }
+        assert_eq!(proc.registers().get(Register::R2), 0xDEADBEEF);
+        assert_eq!(proc.memory().get_word_aligned(0x200), 5);
+        assert_eq!(proc.registers().get(Register::R1), 5);
+    }
+
+    #[test]
+    fn signed_store() {
+        // STRSB and STRSH R1,[R0], then post-indexed and register offset forms with write-back.
+        // These are the encodings of LDRSB and LDRSH with the L bit clear.
+        for word in [0xE1C010D0, 0xE1C010F0, 0xE0C014D4, 0xE1A010F2] {
+            let (_, instr) = Instr::decode(word).unwrap();
+            assert!(
+                matches!(
+                    instr,
+                    Instr::SingleTransfer {
+                        kind: TransferKind::Store,
+                        size: TransferSize::SignExtendedByte | TransferSize::SignExtendedHalfWord,
+                        ..
+                    }
+                ),
+                "{instr:?}"
+            );
+            let mut proc = ProcessorBuilder::default()
+                .load_words(0, &[word])
+                .register(Register::R0, 0x100)
+                .register(Register::R1, 0x12345678)
+                .register(Register::R2, 4)
+                .config(ProcessorConfig {
+                    program: Some(0..0x200),
+                    ..Default::default()
+                })
+                .build()
+                .unwrap();
+            let memory = proc.memory().get_word_aligned(0x100);
+            let mut listener = TestProcessorListener::default();
+            assert_eq!(
+                proc.poll(&mut listener),
+                Err(ProcessorError::InvalidStoreSize),
+                "{word:08X}"
+            );
+            // Nothing was written, and the base was not updated.
+            assert_eq!(proc.registers().get(Register::R0), 0x100);
+            assert_eq!(proc.registers().pc(), 0);
+            assert_eq!(proc.memory().get_word_aligned(0x100), memory);
+            assert!(listener.program_writes.is_empty());
+        }
+    }
+
+    #[test]
+    fn poll_until_halted() {
+        let src = format!("{}        swi 2\n", include_str!("../test/divide.s"));
+        let mut proc = load(&src);
+        let mut listener = TestProcessorListener::default();
+        let mut steps = 1;
+        while proc.poll(&mut listener).unwrap() == PollOutcome::Running {
+            steps += 1;
+            assert!(steps < 100, "did not halt");
+        }
+        assert_eq!(steps, 49);
+        assert_eq!(proc.registers().get(Register::R1), 1);
+        assert_eq!(proc.registers().get(Register::R3), 6);
+
+        // Polling again does nothing.
+        let pc = proc.registers().pc();
+        assert_eq!(proc.poll(&mut listener), Ok(PollOutcome::AlreadyHalted));
+        assert_eq!(proc.registers().pc(), pc);
+    }
+
+    /// Stores twice R0 at the address in R1 on `SWI 0x10`, and halts on `SWI 0x11`.
+    #[derive(Debug)]
+    struct DoubleService;
+
+    impl SwiHandler for DoubleService {
+        fn handle(
+            &mut self,
+            comment: u32,
+            registers: &mut Registers,
+            memory: &mut Memory,
+        ) -> SwiOutcome {
+            match comment {
+                0x10 => {
+                    memory.set_word_aligned(
+                        registers.get(Register::R1),
+                        registers.get(Register::R0) * 2,
+                    );
+                    SwiOutcome::Continue
+                }
+                0x11 => SwiOutcome::Halt,
+                0x12 => SwiOutcome::Error(ProcessorError::InvalidSwi),
+                _ => SwiOutcome::Unhandled,
+            }
+        }
+    }
+
+    #[test]
+    fn processor_is_sync() {
+        // The Tauri front-end keeps the processor in managed state behind a read-write lock,
+        // which requires it to be both sendable and shareable between threads.
+        fn assert_sync<T: Send + Sync>() {}
+        assert_sync::<Processor>();
+    }
+
+    #[test]
+    fn swi_handler() {
+        let src = "\
+        mov r0, #65
+        swi 0
+        mov r1, #0x100
+        swi 0x10
+        swi 0x11
+        swi 0x12
+";
+        let mut proc = load(src);
+        proc.set_swi_handler(Some(Box::new(DoubleService)));
+        let mut listener = TestProcessorListener::default();
+        let mut outcomes = Vec::new();
+        for _ in 0..5 {
+            outcomes.push(proc.poll(&mut listener).unwrap());
+        }
+        assert_eq!(outcomes.last(), Some(&PollOutcome::Halted));
+        // Interrupts that the handler doesn't handle use the built-in services.
+        assert_eq!(listener.output, "A");
+        assert_eq!(proc.memory().get_word_aligned(0x100), 130);
+
+        proc.set_running();
+        assert_eq!(proc.poll(&mut listener), Err(ProcessorError::InvalidSwi));
+    }
+
+    #[test]
+    fn swi_services() {
+        let src = "\
+        mov r0, #65
+        swi 0
+        swi 0x11
+        swi 3
+        swi 2
+";
+        let mut proc = load(src);
+        let services = SwiServices::default()
+            .service(0, |registers, _| {
+                registers.set(Register::R0, registers.get(Register::R0) + 1);
+                Ok(())
+            })
+            .service(3, |_, _| Err(ProcessorError::InvalidSwi))
+            .halt(0x11);
+        assert_eq!(format!("{services:?}"), "{0, 3, 17}");
+        proc.set_swi_handler(Some(Box::new(services)));
+        let mut listener = TestProcessorListener::default();
+        for _ in 0..2 {
+            assert_eq!(proc.poll(&mut listener), Ok(PollOutcome::Running));
+        }
+        assert_eq!(proc.poll(&mut listener), Ok(PollOutcome::Halted));
+        // The service replaced the built-in character output.
+        assert_eq!(proc.registers().get(Register::R0), 66);
+        assert_eq!(listener.output, "");
+
+        proc.set_running();
+        assert_eq!(proc.poll(&mut listener), Err(ProcessorError::InvalidSwi));
+        // Comments without a service use the built-in services.
+        proc.registers_mut().set_pc(16);
+        assert_eq!(proc.poll(&mut listener), Ok(PollOutcome::Halted));
+    }
+
+    #[test]
+    fn interrupts() {
+        let src = "\
+        b start
+        org 0x18
+        b irq
+fiq     add r8, r8, #1
+        subs pc, lr, #4
+irq     add r7, r7, #1
+        subs pc, lr, #4
+start   mov r0, #1
+        swi 2
+";
+        let mut proc = load(src);
+        proc.registers_mut().set_irq_disable(false);
+        proc.raise_irq();
+        proc.raise_fiq();
+
+        // The fast interrupt is taken first, and masks the interrupt request.
+        let mut listener = TestProcessorListener::default();
+        assert_eq!(proc.poll(&mut listener), Ok(PollOutcome::Running));
+        assert_eq!(proc.registers().mode(), Some(Mode::Fiq));
+        assert_eq!(proc.registers().pc(), 0x20);
+        assert!(proc.registers().irq_disable());
+        assert!(proc.registers().fiq_disable());
+        assert_eq!(proc.registers().get(Register::R14), 4);
+        assert_eq!(proc.registers().get(Register::R8), 1);
+        assert!(!proc.fiq_pending());
+        assert!(proc.irq_pending());
+
+        // Returning re-enables the interrupt request, which is taken next.
+        assert_eq!(proc.poll(&mut listener), Ok(PollOutcome::Running));
+        assert_eq!(proc.registers().mode(), Some(Mode::Usr));
+        assert_eq!(proc.poll(&mut listener), Ok(PollOutcome::Running));
+        assert_eq!(proc.registers().mode(), Some(Mode::Irq));
+        assert_eq!(proc.registers().pc(), 0x24);
+        assert!(!proc.irq_pending());
+        assert_eq!(proc.run(10), Ok(5));
+        assert_eq!(proc.registers().mode(), Some(Mode::Usr));
+        assert_eq!(proc.registers().get(Register::R7), 1);
+        assert_eq!(proc.registers().get(Register::R8), 0);
+        assert_eq!(proc.registers().get_physical(PhysicalRegister::R8Fiq), 1);
+    }
+
+    #[test]
+    fn exceptions() {
+        let src = "\
+        b start
+        b undef
+        b service
+        dw 0
+        dw 0
+        dw 0
+        b irq
+start   swi 0x100
+        dw 0xEE000000 ; a coprocessor instruction
+        mov r5, #1
+        swi 2
+service mrs r4, cpsr
+        mrs r3, spsr
+        movs pc, lr
+undef   add r6, r6, #1
+        movs pc, lr
+irq     add r7, r7, #1
+        subs pc, lr, #4
+";
+        let build = || {
+            let mut proc = load(src);
+            proc.set_exceptions_enabled(true);
+            proc
+        };
+
+        let mut proc = build();
+        assert_eq!(proc.run(20), Ok(12));
+        // The handler ran in Supervisor mode with interrupts disabled,
+        // and the user mode CPSR was saved.
+        assert_eq!(proc.registers().get(Register::R4) & 0xFF, 0x93);
+        assert_eq!(
+            proc.registers().get(Register::R3),
+            ProcessorBuilder::default()
+                .build()
+                .unwrap()
+                .registers()
+                .cpsr()
+        );
+        assert_eq!(
+            proc.registers().get_physical(PhysicalRegister::R14Svc),
+            0x20
+        );
+        assert_eq!(
+            proc.registers().get_physical(PhysicalRegister::R14Und),
+            0x24
+        );
+        assert_eq!(proc.registers().get(Register::R5), 1);
+        assert_eq!(proc.registers().get(Register::R6), 1);
+        assert_eq!(proc.registers().mode(), Some(Mode::Usr));
+
+        // The interrupt is taken before the first instruction.
+        let mut proc = build();
+        proc.registers_mut().set_irq_disable(false);
+        proc.raise_irq();
+        assert_eq!(proc.run(20), Ok(15));
+        assert_eq!(proc.registers().get(Register::R7), 1);
+        assert_eq!(proc.registers().get_physical(PhysicalRegister::R14Irq), 4);
+        assert!(!proc.irq_pending());
+        assert!(!proc.registers().irq_disable());
+
+        // Disabled interrupts stay pending.
+        let mut proc = build();
+        proc.registers_mut().set_irq_disable(true);
+        proc.raise_irq();
+        assert_eq!(proc.run(20), Ok(12));
+        assert_eq!(proc.registers().get(Register::R7), 0);
+        assert!(proc.irq_pending());
+
+        // Without exceptions, the same program fails.
+        let mut proc = build();
+        proc.set_exceptions_enabled(false);
+        assert_eq!(proc.run(20), Err(ProcessorError::InvalidSwi));
+
+        // The undefined instruction trap can be enabled on its own.
+        let mut proc = build();
+        proc.set_exceptions_enabled(false);
+        proc.set_undefined_trap(true);
+        proc.registers_mut().set_pc(0x20);
+        assert_eq!(proc.run(20), Ok(6));
+        assert_eq!(proc.registers().get(Register::R6), 1);
+        assert_eq!(
+            proc.registers().get_physical(PhysicalRegister::R14Und),
+            0x24
+        );
+        assert_eq!(
+            proc.registers().get_physical(PhysicalRegister::SpsrUnd),
+            ProcessorBuilder::default()
+                .build()
+                .unwrap()
+                .registers()
+                .cpsr()
+        );
+        proc.registers_mut().set_pc(0x1C);
+        proc.set_running();
+        assert_eq!(proc.run(20), Err(ProcessorError::InvalidSwi));
+    }
+
+    #[test]
+    fn run_until_halted() {
+        let mut proc = load("loop    b loop\n");
+        assert_eq!(proc.run(10), Ok(10));
+
+        let mut proc = load("mov r0, #1\nswi 2\nmov r0, #2\n");
+        assert_eq!(proc.run(10), Ok(2));
+        assert_eq!(proc.registers().get(Register::R0), 1);
+        assert_eq!(proc.run(10), Ok(0));
+    }
+
+    #[test]
+    fn breakpoints() {
+        let src = "\
+        mov r0, #0
+loop    add r0, r0, #1
+        cmp r0, #3
+        bne loop
+        swi 2
+";
+        let mut proc = load(src);
+        assert!(proc.add_breakpoint(8));
+        assert!(!proc.add_breakpoint(8));
+        for r0 in 1..=3 {
+            assert_eq!(
+                proc.poll_until_breakpoint(&mut NullListener, 100),
+                Ok(BreakpointOutcome::Breakpoint(8))
+            );
+            assert_eq!(proc.registers().get(Register::R0), r0);
+        }
+        assert!(proc.remove_breakpoint(8));
+        assert!(!proc.remove_breakpoint(8));
+        assert_eq!(
+            proc.poll_until_breakpoint(&mut NullListener, 100),
+            Ok(BreakpointOutcome::Halted)
+        );
+        assert_eq!(proc.registers().pc(), 20);
+        assert_eq!(
+            proc.poll_until_breakpoint(&mut NullListener, 100),
+            Ok(BreakpointOutcome::Halted)
+        );
+
+        // A loop without breakpoints stops at the step limit.
+        let mut proc = load("loop    b loop\n");
+        assert_eq!(
+            proc.poll_until_breakpoint(&mut NullListener, 100),
+            Ok(BreakpointOutcome::StepLimit)
+        );
+    }
+
+    #[test]
+    fn watchpoints() {
+        let src = "\
+        mov r0, #0x1000
+        mov r1, #7
+        str r1, [r0, #4]
+        ldrb r2, [r0, #5]
+        ldr r3, [r0, #4]
+        stmia r0, {r1, r2}
+        swp r4, r1, [r0]
+        swi 2
+";
+        let mut proc = load(src);
+        proc.add_watchpoint(0x1005, WatchKind::Read);
+        proc.add_watchpoint(0x1000, WatchKind::Write);
+        let hit = |pc, kind, addr, size, old, new| WatchHit {
+            pc,
+            kind,
+            addr,
+            size,
+            old,
+            new,
+        };
+        // The word store to 0x1004 covers the watched byte, but is not a read.
+        assert_eq!(
+            proc.poll_until_breakpoint(&mut NullListener, 100),
+            Ok(BreakpointOutcome::Watchpoint {
+                addr: 0x1005,
+                hit: hit(12, TransferKind::Load, 0x1005, 1, 0, 0),
+            })
+        );
+        assert_eq!(
+            proc.poll_until_breakpoint(&mut NullListener, 100),
+            Ok(BreakpointOutcome::Watchpoint {
+                addr: 0x1005,
+                hit: hit(16, TransferKind::Load, 0x1004, 4, 7, 7),
+            })
+        );
+        assert_eq!(proc.watch_hits().len(), 1);
+        assert_eq!(
+            proc.poll_until_breakpoint(&mut NullListener, 100),
+            Ok(BreakpointOutcome::Watchpoint {
+                addr: 0x1000,
+                hit: hit(20, TransferKind::Store, 0x1000, 4, 0xAAAAAAAA, 7),
+            })
+        );
+        // The block transfer also stored R2 over the watched read address.
+        assert_eq!(proc.watch_hits().len(), 1);
+        assert!(proc.remove_watchpoint(0x1005));
+        assert!(!proc.remove_watchpoint(0x1005));
+        assert_eq!(
+            proc.poll_until_breakpoint(&mut NullListener, 100),
+            Ok(BreakpointOutcome::Watchpoint {
+                addr: 0x1000,
+                hit: hit(24, TransferKind::Store, 0x1000, 4, 7, 7),
+            })
+        );
+        assert_eq!(
+            proc.poll_until_breakpoint(&mut NullListener, 100),
+            Ok(BreakpointOutcome::Halted)
+        );
+        assert!(proc.watch_hits().is_empty());
+
+        // The last address in memory can be watched.
+        proc.add_watchpoint(u32::MAX, WatchKind::Write);
+        assert!(proc.remove_watchpoint(u32::MAX));
+    }
+
+    #[test]
+    #[cfg(feature = "thumb")]
+    fn thumb_interworking() {
+        let src = "\
+        mov r0, #5
+        ldr r1, =thumb + 1
+        mov lr, pc
+        bx r1
+        swi 2
+; mov r2, #0; loop: add r2, #1; sub r0, #1; bne loop
+thumb   dw 0x32012200, 0xD1FC3801
+; mov r4, #0; ldr r3, [pc, #4]; bx lr
+        dw 0x4B012400, 0x00004770
+        dw 0x12345678
+";
+        let mut proc = load(src);
+        assert_eq!(proc.run(100), Ok(24));
+        assert_eq!(proc.registers().get(Register::R0), 0);
+        assert_eq!(proc.registers().get(Register::R2), 5);
+        // The load is relative to the word-aligned PC.
+        assert_eq!(proc.registers().get(Register::R3), 0x12345678);
+        assert!(!proc.registers().thumb_state());
+        assert_eq!(proc.registers().pc(), 20);
+    }
+
+    #[test]
+    #[cfg(feature = "thumb")]
+    fn branch_exchange_to_odd_address() {
+        // Bit 0 of the target selects THUMB state, and is not part of the address.
+        let mut proc = load("mov r0, #9\nbx r0\ndw 0xDF02DF02\n");
+        assert_eq!(proc.run(2), Ok(2));
+        assert!(proc.registers().thumb_state());
+        assert_eq!(proc.registers().pc(), 8);
+        // `SWI 2` in THUMB state halts as it does in ARM state.
+        assert_eq!(proc.run(10), Ok(1));
+    }
+
+    #[test]
+    #[cfg(feature = "thumb")]
+    fn thumb_long_branch_with_link() {
+        let src = "\
+        mov r0, #13
+        bx r0
+; sub: mov r1, #7; bx lr
+        dw 0x47702107
+; bl sub; swi 2
+        dw 0xFFFCF7FF, 0x0000DF02
+";
+        let mut proc = load(src);
+        assert_eq!(proc.run(100), Ok(7));
+        assert_eq!(proc.registers().get(Register::R1), 7);
+        // The return address has bit 0 set, to return to THUMB state.
+        assert_eq!(proc.registers().get(Register::R14), 17);
+        assert!(proc.registers().thumb_state());
+    }
+
+    #[test]
+    #[cfg(feature = "thumb")]
+    fn thumb_return_by_pop() {
+        let src = "\
+        mov sp, #0x100
+        mov r0, #13
+        bx r0
+; bl sub; swi 2
+        dw 0xF802F000, 0x0000DF02
+; sub: push {lr}; pop {pc}
+        dw 0xBD00B500
+";
+        let mut proc = load(src);
+        // Popping the return address ignores its bit 0 and stays in THUMB state.
+        assert_eq!(proc.run(100), Ok(8));
+        assert_eq!(proc.registers().get(Register::R14), 17);
+        assert!(proc.registers().thumb_state());
+    }
+
+    #[test]
+    fn branch_exchange_to_unaligned_address() {
+        // In ARM state, the target of a branch and exchange must be word-aligned.
+        let mut proc = load("mov r0, #6\nbx r0\n");
+        assert_eq!(proc.run(10), Err(ProcessorError::UnalignedPc));
+        assert!(!proc.registers().thumb_state());
+        assert_eq!(proc.registers().pc(), 4);
+    }
+
+    #[test]
+    fn builder_validation() {
+        let error = ProcessorBuilder::default().pc(2).build().unwrap_err();
+        assert_eq!(error, BuildError::UnalignedPc(Addr(2)));
+        assert_eq!(
+            error.to_string(),
+            "initial program counter 0x00000002 is not 4-byte aligned"
+        );
+        assert_eq!(
+            ProcessorBuilder::default().sp(0x7FFF).build().unwrap_err(),
+            BuildError::UnalignedSp(Addr(0x7FFF))
+        );
+        assert_eq!(
+            ProcessorBuilder::default()
+                .load_words(0x101, &[0])
+                .build()
+                .unwrap_err(),
+            BuildError::UnalignedLoad(Addr(0x101))
+        );
+    }
+
+    #[test]
+    fn edit_code() {
+        let mut listener = TestProcessorListener::default();
+        let mut proc = store_through_zero(None);
+        // Replace `str r0, [r1]` with `mov r1, 7` before it executes.
+        let replacement = assemble("mov r1, 7\n").unwrap().instrs;
+        proc.try_execute(&mut listener).unwrap();
+        proc.registers_mut().advance_pc();
+        proc.memory_mut().set_words_aligned(4, &replacement);
+        proc.try_execute(&mut listener).unwrap();
+        assert_eq!(proc.registers().get(Register::R1), 7);
+        assert_ne!(proc.memory().get_word_aligned(0), 123);
+    }
+
+    #[test]
+    fn null_check() {
+        let mut listener = TestProcessorListener::default();
+        let mut proc = store_through_zero(Some(NullCheck::default()));
+        proc.try_execute(&mut listener).unwrap();
+        proc.registers_mut().advance_pc();
+        let error = proc.try_execute(&mut listener).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Store to address 0x00000000 near zero (base register R1, offset 0); is R1 uninitialised?"
+        );
+        assert_ne!(proc.memory().get_word_aligned(0), 123);
+
+        // Data regions may be accessed.
+        let mut null_check = NullCheck::default();
+        null_check.mark_data(0..4);
+        let mut proc = store_through_zero(Some(null_check));
+        for _ in 0..2 {
+            proc.try_execute(&mut listener).unwrap();
+            proc.registers_mut().advance_pc();
+        }
+        assert_eq!(proc.memory().get_word_aligned(0), 123);
+
+        // Without the check, the store overwrites the first instruction.
+        let mut proc = store_through_zero(None);
+        for _ in 0..2 {
+            proc.try_execute(&mut listener).unwrap();
+            proc.registers_mut().advance_pc();
+        }
+        assert_eq!(proc.memory().get_word_aligned(0), 123);
+    }
+
+    /// Load the division test, which pushes its results onto a stack far from the code,
+    /// so that memory has two pages.
+    fn divide_and_push() -> Processor {
+        let src = format!(
+            "        mov sp,#0x10000\n{}        stmfd sp!,{{r0-r3}}\n        swi 2\n",
+            include_str!("../test/divide.s")
+        );
+        load(&src)
+    }
+
+    #[test]
+    fn snapshot_round_trip() {
+        let mut proc = divide_and_push();
+        proc.run(10).unwrap();
+
+        let json = serde_json::to_string(&proc).unwrap();
+        assert!(json.contains(r#""mode":"usr""#), "{json}");
+        let mut restored: Processor = serde_json::from_str(&json).unwrap();
+        assert_eq!(serde_json::to_string(&restored).unwrap(), json);
+
+        let steps = proc.run(100).unwrap();
+        assert_eq!(restored.run(100), Ok(steps));
+        assert_eq!(proc.memory().count_pages(), 4);
+        assert_eq!(
+            serde_json::to_string(&restored).unwrap(),
+            serde_json::to_string(&proc).unwrap()
+        );
+        assert_eq!(restored.registers().get(Register::R3), 6);
+        assert_eq!(restored.memory().get_word_aligned(0xFFF4), 1);
+    }
+
+    #[test]
+    fn snapshot_and_restore() {
+        let mut proc = divide_and_push();
+        let first_word = proc.memory().get_word_aligned(0);
+
+        // Snapshot before every step, as a debugger stepping backwards would.
+        let mut snapshots = Vec::new();
+        while proc.state() == ProcessorState::Running {
+            snapshots.push(proc.snapshot());
+            proc.poll(&mut NullListener).unwrap();
+        }
+        assert_eq!(proc.registers().get(Register::R3), 6);
+        assert_eq!(proc.memory().get_word_aligned(0xFFFC), 6);
+
+        // Step back over the halt and the push.
+        proc.restore(&snapshots[snapshots.len() - 2]);
+        assert_eq!(proc.state(), ProcessorState::Running);
+        assert_eq!(proc.registers().get(Register::R13), 0x10000);
+        assert_eq!(proc.memory().get_word_aligned(0xFFFC), 0xAAAAAAAA);
+
+        // Restoring the first snapshot runs the program again from the start.
+        proc.memory_mut().set_word_aligned(0, 0);
+        proc.restore(&snapshots[0]);
+        assert_eq!(proc.registers().pc(), 0);
+        assert_eq!(proc.run(100), Ok(snapshots.len()));
+        assert_eq!(proc.registers().get(Register::R1), 1);
+
+        // Writes after a snapshot do not change it.
+        assert_eq!(snapshots[0].memory.get_word_aligned(0), first_word);
+        assert_eq!(snapshots[0].memory.count_pages(), 3);
+    }
+
+    #[test]
+    fn serialized_snapshot() {
+        let mut proc = divide_and_push();
+        proc.run(20).unwrap();
+        let json = serde_json::to_string(&proc.snapshot()).unwrap();
+        let steps = proc.run(100).unwrap();
+        let finished = serde_json::to_string(&proc.snapshot()).unwrap();
+
+        let snapshot: ProcessorSnapshot = serde_json::from_str(&json).unwrap();
+        proc.restore(&snapshot);
+        assert_eq!(proc.run(100), Ok(steps));
+        assert_eq!(serde_json::to_string(&proc.snapshot()).unwrap(), finished);
+
+        // Memory from another version of the snapshot format is rejected.
+        let json = json.replace(r#""version":2"#, r#""version":3"#);
+        let error = serde_json::from_str::<ProcessorSnapshot>(&json).unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .starts_with("memory snapshot has version 3, but only version 2 can be read"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn post_indexed_register_offset() {
+        let src = "\
+        mov r0, #7
+        mov r1, #0x100
+        mov r2, #8
+        str r0, [r1], -r2
+        mov r3, #0x100
+        ldr r4, [r3], r2, lsl #2
+        swi 2
+";
+        let mut proc = load(src);
+        proc.run(100).unwrap();
+        // The store uses the base as it was, then subtracts the offset from it.
+        assert_eq!(proc.memory().get_word_aligned(0x100), 7);
+        assert_eq!(proc.registers().get(Register::R1), 0x100 - 8);
+        // The load uses the base as it was, then adds the shifted offset to it.
+        assert_eq!(proc.registers().get(Register::R4), 7);
+        assert_eq!(proc.registers().get(Register::R3), 0x100 + 32);
+    }
+
+    /// Execute `instr` with `r0 = a`, `r1 = b`, and the given carry flag,
+    /// returning `r2` and the flags `[N, Z, C, V]` afterwards.
+    fn subtract(instr: &str, a: u32, b: u32, carry: bool) -> (u32, [bool; 4]) {
+        let mut proc = load(&format!("{instr}\n"));
+        *proc.registers_mut().get_mut(Register::R0) = a;
+        *proc.registers_mut().get_mut(Register::R1) = b;
+        *proc.registers_mut().get_mut(Register::R2) = 0xDEAD;
+        proc.registers_mut().set_carry(carry);
+        proc.try_execute(&mut NullListener).unwrap();
+        let regs = proc.registers();
+        (
+            regs.get(Register::R2),
+            [regs.negative(), regs.zero(), regs.carry(), regs.overflow()],
+        )
+    }
+
+    #[test]
+    fn subtraction_flags() {
+        // The carry flag is set when no borrow occurs, that is, when `a >= b` as unsigned integers.
+        // Each row is `a`, `b`, `a - b`, and the flags N, Z, C, V.
+        let cases = [
+            (5, 3, 2, [false, false, true, false]),
+            (3, 5, 0xFFFF_FFFE, [true, false, false, false]),
+            (7, 7, 0, [false, true, true, false]),
+            (0, 0, 0, [false, true, true, false]),
+            (0, 1, 0xFFFF_FFFF, [true, false, false, false]),
+            (0xFFFF_FFFF, 1, 0xFFFF_FFFE, [true, false, true, false]),
+            (0x8000_0000, 1, 0x7FFF_FFFF, [false, false, true, true]),
+            (
+                0x7FFF_FFFF,
+                0xFFFF_FFFF,
+                0x8000_0000,
+                [true, false, false, true],
+            ),
+            (0x8000_0000, 0x8000_0000, 0, [false, true, true, false]),
+            (1, 0x8000_0000, 0x8000_0001, [true, false, false, true]),
+        ];
+        for (a, b, result, flags) in cases {
+            for carry in [false, true] {
+                assert_eq!(
+                    subtract("subs r2, r0, r1", a, b, carry),
+                    (result, flags),
+                    "sub {a:#x}, {b:#x}"
+                );
+                assert_eq!(
+                    subtract("rsbs r2, r1, r0", a, b, carry),
+                    (result, flags),
+                    "rsb {a:#x}, {b:#x}"
+                );
+                assert_eq!(
+                    subtract("cmp r0, r1", a, b, carry),
+                    (0xDEAD, flags),
+                    "cmp {a:#x}, {b:#x}"
+                );
+            }
+            // With the carry flag set, there is no borrow in.
+            assert_eq!(
+                subtract("sbcs r2, r0, r1", a, b, true),
+                (result, flags),
+                "sbc {a:#x}, {b:#x}"
+            );
+            assert_eq!(
+                subtract("rscs r2, r1, r0", a, b, true),
+                (result, flags),
+                "rsc {a:#x}, {b:#x}"
+            );
+        }
+
+        // With the carry flag clear, a borrow in can cause a borrow out, even when `a >= b`.
+        let cases = [
+            (5, 3, 1, [false, false, true, false]),
+            (3, 3, 0xFFFF_FFFF, [true, false, false, false]),
+            (0, 0xFFFF_FFFF, 0, [false, true, false, false]),
+            (0x8000_0000, 0, 0x7FFF_FFFF, [false, false, true, true]),
+        ];
+        for (a, b, result, flags) in cases {
+            assert_eq!(
+                subtract("sbcs r2, r0, r1", a, b, false),
+                (result, flags),
+                "sbc {a:#x}, {b:#x}"
+            );
+            assert_eq!(
+                subtract("rscs r2, r1, r0", a, b, false),
+                (result, flags),
+                "rsc {a:#x}, {b:#x}"
+            );
+        }
     }
 }
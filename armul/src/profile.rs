@@ -0,0 +1,233 @@
+//! Counts the cycles taken by each instruction of a running program,
+//! so that its hot spots can be found.
+
+use std::{collections::BTreeMap, fmt::Display};
+
+use crate::{
+    instr::Cycles,
+    processor::{Cycle, ProcessorListener},
+};
+
+/// Counts the cycles taken by the instructions at each address.
+/// As a listener, it has no input and discards any output;
+/// wrap another listener in a [`ProfilingListener`] to keep them.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CycleCounter {
+    cycles: BTreeMap<u32, Cycles>,
+    total: Cycles,
+}
+
+impl CycleCounter {
+    /// Attribute cycles to the instruction at `pc`.
+    pub fn record(&mut self, cycle: Cycle, count: usize, pc: u32) {
+        let cycles = match cycle {
+            Cycle::Seq => Cycles::new(count, 0, 0),
+            Cycle::NonSeq => Cycles::new(0, count, 0),
+            Cycle::Internal => Cycles::new(0, 0, count),
+            Cycle::Coprocessor => return,
+        };
+        let entry = self.cycles.entry(pc).or_default();
+        *entry = *entry + cycles;
+        self.total = self.total + cycles;
+    }
+
+    /// The cycles taken by the instruction at `pc` so far.
+    pub fn cycles_at(&self, pc: u32) -> Cycles {
+        self.cycles.get(&pc).copied().unwrap_or_default()
+    }
+
+    /// The cycles taken by every instruction so far.
+    pub fn total(&self) -> Cycles {
+        self.total
+    }
+
+    /// The time taken so far; see [`Cycles::estimated_time`].
+    pub fn estimated_time(&self) -> f64 {
+        self.total.estimated_time()
+    }
+
+    /// The cycles taken at each address, most time-consuming first.
+    /// Addresses that took the same time are in increasing order.
+    pub fn report(&self) -> Vec<(u32, Cycles)> {
+        let mut report = self
+            .cycles
+            .iter()
+            .map(|(pc, cycles)| (*pc, *cycles))
+            .collect::<Vec<_>>();
+        report.sort_by(|(_, a), (_, b)| b.estimated_time().total_cmp(&a.estimated_time()));
+        report
+    }
+}
+
+/// Prints a table of the addresses that took the most time.
+impl Display for CycleCounter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let total = self.estimated_time();
+        writeln!(
+            f,
+            "{:<10}  {:<12}  {:>8}  {:>6}",
+            "PC", "CYCLES", "TIME", "SHARE"
+        )?;
+        for (pc, cycles) in self.report().into_iter().take(10) {
+            let time = cycles.estimated_time();
+            // Nothing has a share of no time at all.
+            let share = if total == 0.0 {
+                0.0
+            } else {
+                100.0 * time / total
+            };
+            writeln!(
+                f,
+                "{:08X}    {:<12}  {time:>8.1}  {share:>5.1}%",
+                pc,
+                cycles.to_string(),
+            )?;
+        }
+        writeln!(
+            f,
+            "{:<10}  {:<12}  {total:>8.1}",
+            "total",
+            self.total.to_string()
+        )
+    }
+}
+
+impl ProcessorListener for CycleCounter {
+    fn cycle(&mut self, cycle: Cycle, count: usize, pc: u32) {
+        self.record(cycle, count, pc);
+    }
+
+    fn pipeline_flush(&mut self, pc: u32) {
+        self.record(Cycle::Seq, 1, pc);
+        self.record(Cycle::NonSeq, 1, pc);
+    }
+
+    fn getc(&mut self) -> Option<char> {
+        None
+    }
+
+    fn putc(&mut self, _c: char) {}
+}
+
+/// A listener that counts cycles with a [`CycleCounter`],
+/// and passes every event on to another listener.
+#[derive(Debug)]
+pub struct ProfilingListener<'a, L> {
+    pub listener: &'a mut L,
+    pub counter: &'a mut CycleCounter,
+}
+
+impl<L: ProcessorListener> ProcessorListener for ProfilingListener<'_, L> {
+    fn cycle(&mut self, cycle: Cycle, count: usize, pc: u32) {
+        self.counter.record(cycle, count, pc);
+        self.listener.cycle(cycle, count, pc);
+    }
+
+    fn pipeline_flush(&mut self, pc: u32) {
+        self.counter.pipeline_flush(pc);
+        self.listener.pipeline_flush(pc);
+    }
+
+    fn getc(&mut self) -> Option<char> {
+        self.listener.getc()
+    }
+
+    fn putc(&mut self, c: char) {
+        self.listener.putc(c);
+    }
+
+    fn putint(&mut self, i: u32) {
+        self.listener.putint(i);
+    }
+
+    fn explain(&mut self, explanation: String) {
+        self.listener.explain(explanation);
+    }
+
+    fn program_overwritten(&mut self, pc: u32, address: u32) {
+        self.listener.program_overwritten(pc, address);
+    }
+
+    fn memory_read(&mut self, addr: u32, size: u8, value: u32, pc: u32) {
+        self.listener.memory_read(addr, size, value, pc);
+    }
+
+    fn memory_write(&mut self, addr: u32, size: u8, old: u32, new: u32, pc: u32) {
+        self.listener.memory_write(addr, size, old, new, pc);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        instr::Cycles,
+        processor::{Cycle, PollOutcome, test::load},
+        profile::CycleCounter,
+    };
+
+    #[test]
+    fn per_instruction() {
+        let src = "\
+        mov r0, #2
+        mov r1, #0x100
+loop    str r0, [r1]
+        ldr r2, [r1]
+        subs r0, r0, #1
+        bne loop
+        stmia r1, {r0-r2}
+        swi 2
+";
+        let mut proc = load(src);
+        let mut counter = CycleCounter::default();
+        while proc.poll(&mut counter).unwrap() != PollOutcome::Halted {}
+
+        assert_eq!(counter.cycles_at(0x00), Cycles::new(1, 0, 0));
+        // Each instruction in the loop runs twice.
+        assert_eq!(counter.cycles_at(0x08), Cycles::new(0, 4, 0));
+        assert_eq!(counter.cycles_at(0x0C), Cycles::new(2, 2, 2));
+        assert_eq!(counter.cycles_at(0x10), Cycles::new(2, 0, 0));
+        // The branch is taken once, flushing the pipeline, and skipped once.
+        assert_eq!(counter.cycles_at(0x14), Cycles::new(3, 1, 0));
+        assert_eq!(counter.cycles_at(0x18), Cycles::new(2, 2, 0));
+        assert_eq!(counter.cycles_at(0x1C), Cycles::default());
+        assert_eq!(counter.total(), Cycles::new(11, 9, 2));
+        assert_eq!(counter.estimated_time(), 35.5);
+
+        assert_eq!(
+            counter.report()[..3],
+            [
+                (0x08, Cycles::new(0, 4, 0)),
+                (0x0C, Cycles::new(2, 2, 2)),
+                (0x18, Cycles::new(2, 2, 0)),
+            ]
+        );
+        assert_eq!(
+            counter.to_string(),
+            "\
+PC          CYCLES            TIME   SHARE
+00000008    4N                10.0   28.2%
+0000000C    2S+2N+2I           9.0   25.4%
+00000018    2S+2N              7.0   19.7%
+00000014    3S+1N              5.5   15.5%
+00000010    2S                 2.0    5.6%
+00000000    1S                 1.0    2.8%
+00000004    1S                 1.0    2.8%
+total       11S+9N+2I         35.5
+"
+        );
+    }
+
+    #[test]
+    fn no_time() {
+        let mut counter = CycleCounter::default();
+        counter.record(Cycle::Seq, 0, 0x100);
+        assert_eq!(
+            counter.to_string(),
+            "\
+PC          CYCLES            TIME   SHARE
+00000100    0                  0.0    0.0%
+total       0                  0.0
+"
+        );
+    }
+}
@@ -1,9 +1,10 @@
 //! Describes the physical registers in the processor's hardware.
 
-use std::fmt::Display;
+use std::{fmt::Display, str::FromStr};
 
 use num_derive::FromPrimitive;
-use serde::Serialize;
+use num_traits::FromPrimitive;
+use serde::{Deserialize, Serialize, ser::SerializeStruct};
 
 use crate::{
     instr::{Cond, Psr, Register},
@@ -106,13 +107,67 @@ impl Psr {
     }
 }
 
-#[derive(Debug, Clone, Serialize)]
+/// A bit of the CPSR that may be edited directly, such as from a debugger.
+/// The T bit is not included, since changing state without `BX` would leave the PC
+/// in the middle of an instruction of the other state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flag {
+    Negative,
+    Zero,
+    Carry,
+    Overflow,
+    IrqDisable,
+    FiqDisable,
+}
+
+impl FromStr for Flag {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "N" | "n" => Ok(Flag::Negative),
+            "Z" | "z" => Ok(Flag::Zero),
+            "C" | "c" => Ok(Flag::Carry),
+            "V" | "v" => Ok(Flag::Overflow),
+            "I" | "i" => Ok(Flag::IrqDisable),
+            "F" | "f" => Ok(Flag::FiqDisable),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Registers {
     /// 31 general-purpose data registers and 6 status registers.
-    #[serde(serialize_with = "<[_]>::serialize")]
     regs: [u32; 37],
 }
 
+/// The registers are serialized with the mode that the CPSR gives, for convenience.
+/// The mode is ignored when deserializing, since the CPSR already holds it.
+impl Serialize for Registers {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("Registers", 2)?;
+        state.serialize_field("regs", &self.regs[..])?;
+        state.serialize_field("mode", &self.mode().map(|mode| mode.to_string()))?;
+        state.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for Registers {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        struct RegistersSnapshot {
+            regs: Vec<u32>,
+        }
+
+        let snapshot = RegistersSnapshot::deserialize(deserializer)?;
+        let regs = snapshot.regs.try_into().map_err(|regs: Vec<u32>| {
+            serde::de::Error::invalid_length(regs.len(), &"37 registers")
+        })?;
+        Ok(Self { regs })
+    }
+}
+
 impl Default for Registers {
     fn default() -> Self {
         let mut this = Self { regs: [0; 37] };
@@ -169,12 +224,21 @@ impl Display for Registers {
         write!(f, "R12 {:0>8X}  ", self.get(Register::R12))?;
         write!(f, "SP  {:0>8X}  ", self.get(Register::R13))?;
         write!(f, "LR  {:0>8X}  ", self.get(Register::R14))?;
-        write!(f, "PC  {:0>8X}", self.get(Register::R15))?;
+        write!(f, "PC  {:0>8X}", self.pc())?;
         Ok(())
     }
 }
 
 impl Registers {
+    /// The physical registers whose values differ from those in `earlier`,
+    /// with their values in `earlier`.
+    pub fn changes_since(&self, earlier: &Registers) -> Vec<(PhysicalRegister, u32)> {
+        (0..self.regs.len())
+            .filter(|&i| self.regs[i] != earlier.regs[i])
+            .map(|i| (PhysicalRegister::from_usize(i).unwrap(), earlier.regs[i]))
+            .collect()
+    }
+
     pub fn get_physical(&self, register: PhysicalRegister) -> u32 {
         self.regs[register as usize]
     }
@@ -225,6 +289,41 @@ impl Registers {
         *self.get_mut(register) = value;
     }
 
+    /// The value of R15 in the current mode.
+    ///
+    /// While an instruction executes, this is the address of that instruction.
+    /// Because of the pipeline, the program itself sees an R15 that is two instructions ahead
+    /// of this (see [`Self::pc_ahead`]), or 12 bytes ahead when R15 is the shifted operand
+    /// of a register-specified shift or the data register of a store.
+    /// After each instruction, the program counter is advanced by [`Self::instruction_size`];
+    /// see [`Self::advance_pc`].
+    /// So an instruction that branches to some address must set the PC that far before it.
+    pub fn pc(&self) -> u32 {
+        self.get(Register::R15)
+    }
+
+    /// Set the value of R15; see [`Self::pc`] for how this relates to the pipeline.
+    pub fn set_pc(&mut self, value: u32) {
+        self.set(Register::R15, value);
+    }
+
+    /// Move the program counter on to the next instruction,
+    /// as happens after every instruction is executed.
+    pub fn advance_pc(&mut self) {
+        self.set_pc(self.pc().wrapping_add(self.instruction_size()));
+    }
+
+    /// The size in bytes of an instruction: 2 in THUMB state, and 4 in ARM state.
+    pub fn instruction_size(&self) -> u32 {
+        if self.thumb_state() { 2 } else { 4 }
+    }
+
+    /// How far ahead of the executing instruction the program sees R15:
+    /// 4 bytes in THUMB state, and 8 bytes in ARM state.
+    pub fn pc_ahead(&self) -> u32 {
+        2 * self.instruction_size()
+    }
+
     /// Return the current program status register.
     pub fn cpsr(&self) -> u32 {
         self.get_physical(PhysicalRegister::Cpsr)
@@ -259,8 +358,8 @@ impl Registers {
             Mode::Irq => 0b10010,
             Mode::Supervisor => 0b10011,
             Mode::Abort => 0b10111,
-            Mode::System => 0b11011,
-            Mode::Undefined => 0b11111,
+            Mode::System => 0b11111,
+            Mode::Undefined => 0b11011,
         };
     }
 
@@ -327,25 +426,48 @@ impl Registers {
         set_bit(self.cpsr_mut(), 5, set);
     }
 
+    pub fn set_flag(&mut self, flag: Flag, set: bool) {
+        match flag {
+            Flag::Negative => self.set_negative(set),
+            Flag::Zero => self.set_zero(set),
+            Flag::Carry => self.set_carry(set),
+            Flag::Overflow => self.set_overflow(set),
+            Flag::IrqDisable => self.set_irq_disable(set),
+            Flag::FiqDisable => self.set_fiq_disable(set),
+        }
+    }
+
     /// Returns true if the given condition holds.
     pub fn test_condition(&self, cond: Cond) -> bool {
-        match cond {
-            Cond::EQ => self.zero(),
-            Cond::NE => !self.zero(),
-            Cond::CS => self.carry(),
-            Cond::CC => !self.carry(),
-            Cond::MI => self.negative(),
-            Cond::PL => !self.negative(),
-            Cond::VS => self.overflow(),
-            Cond::VC => !self.overflow(),
-            Cond::HI => self.carry() && !self.zero(),
-            Cond::LS => !self.carry() || self.zero(),
-            Cond::GE => self.negative() == self.overflow(),
-            Cond::LT => self.negative() != self.overflow(),
-            Cond::GT => !self.zero() && (self.negative() == self.overflow()),
-            Cond::LE => self.zero() || (self.negative() || self.overflow()),
-            Cond::AL => true,
-        }
+        condition_holds(
+            cond,
+            self.negative(),
+            self.zero(),
+            self.carry(),
+            self.overflow(),
+        )
+    }
+}
+
+/// Returns true if the given condition holds when the N, Z, C and V flags
+/// have the given values.
+pub fn condition_holds(cond: Cond, n: bool, z: bool, c: bool, v: bool) -> bool {
+    match cond {
+        Cond::EQ => z,
+        Cond::NE => !z,
+        Cond::CS => c,
+        Cond::CC => !c,
+        Cond::MI => n,
+        Cond::PL => !n,
+        Cond::VS => v,
+        Cond::VC => !v,
+        Cond::HI => c && !z,
+        Cond::LS => !c || z,
+        Cond::GE => n == v,
+        Cond::LT => n != v,
+        Cond::GT => !z && (n == v),
+        Cond::LE => z || (n != v),
+        Cond::AL => true,
     }
 }
 
@@ -356,3 +478,62 @@ fn set_bit(value: &mut u32, bit: usize, set: bool) {
         *value &= !(1 << bit);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        instr::{Cond, Register},
+        registers::{Registers, condition_holds},
+    };
+
+    #[test]
+    fn pc() {
+        let mut registers = Registers::default();
+        registers.set_pc(0x100);
+        assert_eq!(registers.pc(), 0x100);
+        assert_eq!(registers.get(Register::R15), 0x100);
+        assert_eq!(registers.get_pc_offset(Register::R15, 8), 0x108);
+        registers.advance_pc();
+        assert_eq!(registers.pc(), 0x104);
+        registers.set_pc(0xFFFFFFFC);
+        registers.advance_pc();
+        assert_eq!(registers.pc(), 0);
+    }
+
+    #[test]
+    fn condition_truth_table() {
+        for flags in 0..16 {
+            let n = flags & 0b1000 != 0;
+            let z = flags & 0b0100 != 0;
+            let c = flags & 0b0010 != 0;
+            let v = flags & 0b0001 != 0;
+            // Signed comparisons, phrased in terms of `N == V` meaning "not less than".
+            let signed_ge = n == v;
+            let unsigned_gt = c && !z;
+            let signed_gt = signed_ge && !z;
+            for (cond, expected) in [
+                (Cond::EQ, z),
+                (Cond::NE, !z),
+                (Cond::CS, c),
+                (Cond::CC, !c),
+                (Cond::MI, n),
+                (Cond::PL, !n),
+                (Cond::VS, v),
+                (Cond::VC, !v),
+                (Cond::HI, unsigned_gt),
+                (Cond::LS, !unsigned_gt),
+                (Cond::GE, signed_ge),
+                (Cond::LT, !signed_ge),
+                (Cond::GT, signed_gt),
+                (Cond::LE, !signed_gt),
+                (Cond::AL, true),
+            ] {
+                assert_eq!(
+                    condition_holds(cond, n, z, c, v),
+                    expected,
+                    "condition {cond:?} with flags N={n} Z={z} C={c} V={v}"
+                );
+            }
+        }
+    }
+}
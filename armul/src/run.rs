@@ -0,0 +1,301 @@
+//! Runs a processor until it reaches a breakpoint, halts, or fails,
+//! as a debugger's step and continue buttons do.
+//!
+//! The breakpoints and watchpoints are those set on the [`Processor`].
+
+use std::collections::BTreeSet;
+
+use crate::{
+    addr::Addr,
+    processor::{
+        BreakpointOutcome, Cycle, PollOutcome, Processor, ProcessorError, ProcessorListener,
+    },
+    watchpoints::WatchHit,
+};
+
+/// The number of cycles that [`Executor::continue_until_break`] may take by default,
+/// so that a program that never halts cannot hang its caller.
+pub const DEFAULT_MAX_CYCLES: usize = 10_000_000;
+
+/// Why [`Executor::continue_until_break`] stopped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StopReason {
+    /// The next instruction to execute is at a breakpoint.
+    Breakpoint(Addr),
+    /// The last instruction accessed a watched address.
+    Watchpoint {
+        /// The watched address.
+        addr: Addr,
+        hit: WatchHit,
+    },
+    /// The processor halted, or had already halted.
+    Halted,
+    /// An instruction could not be executed.
+    Error(ProcessorError),
+    /// The cycle budget was used up before anything else stopped the processor.
+    CycleLimit,
+}
+
+/// Owns a processor, and executes it until it reaches one of its breakpoints or watchpoints.
+#[derive(Debug)]
+pub struct Executor {
+    processor: Processor,
+    max_cycles: Option<usize>,
+}
+
+impl Executor {
+    pub fn new(processor: Processor) -> Self {
+        Self {
+            processor,
+            max_cycles: Some(DEFAULT_MAX_CYCLES),
+        }
+    }
+
+    pub fn processor(&self) -> &Processor {
+        &self.processor
+    }
+
+    pub fn processor_mut(&mut self) -> &mut Processor {
+        &mut self.processor
+    }
+
+    pub fn into_processor(self) -> Processor {
+        self.processor
+    }
+
+    pub fn breakpoints(&self) -> &BTreeSet<u32> {
+        self.processor.breakpoints()
+    }
+
+    /// Returns false if there was already a breakpoint at this address.
+    /// See [`Processor::add_breakpoint`].
+    pub fn add_breakpoint(&mut self, addr: impl Into<Addr>) -> bool {
+        self.processor.add_breakpoint(addr.into().0)
+    }
+
+    /// Returns false if there was no breakpoint at this address.
+    pub fn remove_breakpoint(&mut self, addr: impl Into<Addr>) -> bool {
+        self.processor.remove_breakpoint(addr.into().0)
+    }
+
+    /// Limit the number of cycles that each call to [`Executor::continue_until_break`]
+    /// may take, or remove the limit. The limit is [`DEFAULT_MAX_CYCLES`] by default.
+    pub fn set_max_cycles(&mut self, max_cycles: Option<usize>) {
+        self.max_cycles = max_cycles;
+    }
+
+    /// Execute a single instruction, ignoring breakpoints.
+    pub fn step(
+        &mut self,
+        listener: &mut impl ProcessorListener,
+    ) -> Result<PollOutcome, ProcessorError> {
+        self.processor.poll(listener)
+    }
+
+    /// Execute instructions until the next one is at a breakpoint,
+    /// an instruction accesses a watched address,
+    /// the processor halts, or an instruction fails.
+    /// The first instruction is always executed, so that a processor that stopped
+    /// at a breakpoint can continue past it.
+    pub fn continue_until_break(&mut self, listener: &mut impl ProcessorListener) -> StopReason {
+        let mut listener = CountingListener {
+            listener,
+            cycles: 0,
+        };
+        loop {
+            match self.processor.poll(&mut listener) {
+                Ok(PollOutcome::Running) => {}
+                Ok(PollOutcome::Halted | PollOutcome::AlreadyHalted) => {
+                    return StopReason::Halted;
+                }
+                Err(error) => return StopReason::Error(error),
+            }
+            match self.processor.breakpoint_hit() {
+                Some(BreakpointOutcome::Breakpoint(pc)) => return StopReason::Breakpoint(Addr(pc)),
+                Some(BreakpointOutcome::Watchpoint { addr, hit }) => {
+                    return StopReason::Watchpoint {
+                        addr: Addr(addr),
+                        hit,
+                    };
+                }
+                _ => {}
+            }
+            if self.max_cycles.is_some_and(|max| listener.cycles >= max) {
+                return StopReason::CycleLimit;
+            }
+        }
+    }
+}
+
+/// Counts the cycles taken, and passes every event on to another listener.
+struct CountingListener<'a, L> {
+    listener: &'a mut L,
+    cycles: usize,
+}
+
+impl<L: ProcessorListener> ProcessorListener for CountingListener<'_, L> {
+    fn cycle(&mut self, cycle: Cycle, count: usize, pc: u32) {
+        self.cycles += count;
+        self.listener.cycle(cycle, count, pc);
+    }
+
+    fn pipeline_flush(&mut self, pc: u32) {
+        self.cycles += 2;
+        self.listener.pipeline_flush(pc);
+    }
+
+    fn getc(&mut self) -> Option<char> {
+        self.listener.getc()
+    }
+
+    fn putc(&mut self, c: char) {
+        self.listener.putc(c);
+    }
+
+    fn putint(&mut self, i: u32) {
+        self.listener.putint(i);
+    }
+
+    fn explain(&mut self, explanation: String) {
+        self.listener.explain(explanation);
+    }
+
+    fn program_overwritten(&mut self, pc: u32, address: u32) {
+        self.listener.program_overwritten(pc, address);
+    }
+
+    fn memory_read(&mut self, addr: u32, size: u8, value: u32, pc: u32) {
+        self.listener.memory_read(addr, size, value, pc);
+    }
+
+    fn memory_write(&mut self, addr: u32, size: u8, old: u32, new: u32, pc: u32) {
+        self.listener.memory_write(addr, size, old, new, pc);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        addr::Addr,
+        instr::Register,
+        processor::{NullListener, PollOutcome, ProcessorError, test},
+        run::{Executor, StopReason},
+        watchpoints::WatchKind,
+    };
+
+    fn load(src: &str) -> Executor {
+        Executor::new(test::load(src))
+    }
+
+    #[test]
+    fn breakpoint_in_loop() {
+        let mut executor = load(
+            "\
+        mov r0, #0
+loop    add r0, r0, #1
+        cmp r0, #10
+        bne loop
+        swi 2
+",
+        );
+        assert!(executor.add_breakpoint(4));
+        assert!(!executor.add_breakpoint(4));
+        let mut hits = 0;
+        loop {
+            match executor.continue_until_break(&mut NullListener) {
+                StopReason::Breakpoint(pc) => {
+                    assert_eq!(pc, Addr(4));
+                    hits += 1;
+                    // Stop breaking once R0 is about to become 4.
+                    if executor.processor().registers().get(Register::R0) == 3 {
+                        assert!(executor.remove_breakpoint(4));
+                    }
+                }
+                StopReason::Halted => break,
+                reason => panic!("unexpected stop: {reason:?}"),
+            }
+        }
+        assert_eq!(hits, 4);
+        assert_eq!(executor.processor().registers().get(Register::R0), 10);
+        assert_eq!(
+            executor.continue_until_break(&mut NullListener),
+            StopReason::Halted
+        );
+        assert_eq!(
+            executor.step(&mut NullListener),
+            Ok(PollOutcome::AlreadyHalted)
+        );
+    }
+
+    #[test]
+    fn processor_breakpoints_and_watchpoints() {
+        let mut executor = load(
+            "\
+        mov r0, #0x1000
+        mov r1, #7
+        str r1, [r0]
+        swi 2
+",
+        );
+        // Breakpoints and watchpoints set on the processor are shared with the executor.
+        executor.processor_mut().add_breakpoint(4);
+        assert!(executor.breakpoints().contains(&4));
+        executor
+            .processor_mut()
+            .add_watchpoint(0x1000, WatchKind::Write);
+        assert_eq!(
+            executor.continue_until_break(&mut NullListener),
+            StopReason::Breakpoint(Addr(4))
+        );
+        assert!(matches!(
+            executor.continue_until_break(&mut NullListener),
+            StopReason::Watchpoint { addr: Addr(0x1000), hit } if hit.new == 7
+        ));
+        assert_eq!(
+            executor.continue_until_break(&mut NullListener),
+            StopReason::Halted
+        );
+    }
+
+    #[test]
+    fn errors_and_cycle_limit() {
+        let mut executor = load(
+            "\
+loop    b loop
+",
+        );
+        // Each branch takes 2S + 1N cycles.
+        executor.set_max_cycles(Some(7));
+        assert_eq!(
+            executor.continue_until_break(&mut NullListener),
+            StopReason::CycleLimit
+        );
+        assert_eq!(executor.step(&mut NullListener), Ok(PollOutcome::Running));
+
+        let mut executor = load(
+            "\
+        mov r0, #1
+        swi 0x99
+",
+        );
+        assert_eq!(
+            executor.continue_until_break(&mut NullListener),
+            StopReason::Error(ProcessorError::InvalidSwi)
+        );
+        assert_eq!(executor.into_processor().registers().pc(), 4);
+    }
+
+    #[test]
+    fn default_cycle_limit() {
+        let mut executor = load(
+            "\
+loop    b loop
+",
+        );
+        // An infinite loop stops once the default budget is used up.
+        assert_eq!(
+            executor.continue_until_break(&mut NullListener),
+            StopReason::CycleLimit
+        );
+    }
+}
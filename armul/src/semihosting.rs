@@ -0,0 +1,58 @@
+//! Support for the ARM semihosting interface, which lets programs request
+//! services from the host by issuing `SWI 0x123456` with an operation number in R0.
+//!
+//! Semihosting is disabled by default; see [`crate::processor::Processor::set_semihosting`].
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    instr::Register,
+    memory::Memory,
+    processor::{ProcessorError, ProcessorListener},
+    registers::Registers,
+};
+
+/// The software interrupt comment used to make a semihosting call in ARM state.
+pub const SEMIHOSTING_SWI: u32 = 0x123456;
+
+/// Write the character at the address in R1 to the console.
+pub const SYS_WRITEC: u32 = 0x03;
+/// Halt the program, with the reason code in R1.
+pub const SYS_EXIT: u32 = 0x18;
+
+/// What the processor should do after a semihosting call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SemihostingOutcome {
+    Continue,
+    Halt,
+}
+
+/// Implements the `SYS_WRITEC` and `SYS_EXIT` semihosting operations.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SemihostingHandler {
+    /// The reason code passed to `SYS_EXIT`, if the program has exited.
+    pub exit_reason: Option<u32>,
+}
+
+impl SemihostingHandler {
+    /// Perform the semihosting operation given in R0.
+    pub fn handle(
+        &mut self,
+        registers: &Registers,
+        memory: &Memory,
+        listener: &mut impl ProcessorListener,
+    ) -> Result<SemihostingOutcome, ProcessorError> {
+        let parameter = registers.get(Register::R1);
+        match registers.get(Register::R0) {
+            SYS_WRITEC => {
+                listener.putc(memory.get_byte(parameter) as char);
+                Ok(SemihostingOutcome::Continue)
+            }
+            SYS_EXIT => {
+                self.exit_reason = Some(parameter);
+                Ok(SemihostingOutcome::Halt)
+            }
+            operation => Err(ProcessorError::UnsupportedSemihostingOperation(operation)),
+        }
+    }
+}
@@ -1,34 +1,72 @@
 //! Provides a test procedure for assembly routines.
 
-use std::collections::BTreeMap;
+use std::{collections::BTreeMap, path::Path};
 
 use crate::{
     assemble::{AssemblerError, AssemblerOutput, assemble},
-    instr::{Instr, Register},
+    instr::Instr,
     mode::Mode,
-    processor::{Processor, ProcessorError, ProcessorState, test::TestProcessorListener},
+    processor::{
+        BuildError, NullCheck, PollOutcome, ProcessorBuilder, ProcessorConfig, ProcessorError,
+        test::TestProcessorListener,
+    },
     registers::PhysicalRegister,
+    semihosting::SemihostingHandler,
 };
 
 #[derive(Debug)]
 pub enum TestError {
     FileError(String),
     AssemblerError(Vec<AssemblerError>),
+    BuildError(BuildError),
     ProcessorError(ProcessorError),
     InvalidComment(String),
     InvalidParams(&'static str, String),
     StepsNotGiven,
 }
 
-pub fn test(src: &str) -> Result<(), TestError> {
-    let assembled = assemble(src).map_err(TestError::AssemblerError)?;
+/// Assemble and run the given test.
+/// Relative paths in the test's directives are resolved against `base_dir`,
+/// which is usually the directory containing the test.
+pub fn test(src: &str, base_dir: &Path) -> Result<(), TestError> {
+    // Included files are appended to the source before it is assembled.
+    let mut full_src = src.to_owned();
+    for line in src.lines() {
+        if let Some((kwd, params)) = line
+            .trim_start()
+            .strip_prefix(";!")
+            .and_then(|comment| comment.trim().split_once(' '))
+            && kwd.eq_ignore_ascii_case("include")
+        {
+            let included = std::fs::read_to_string(base_dir.join(params.trim()))
+                .map_err(|x| TestError::FileError(x.to_string()))?;
+            full_src.push('\n');
+            full_src.push_str(&included);
+            full_src.push('\n');
+        }
+    }
+
+    let assembled = assemble(&full_src).map_err(TestError::AssemblerError)?;
     println!("assembled in {} passes", assembled.passes);
-    for instr in &assembled.instrs {
+    for (index, instr) in assembled.instrs.iter().enumerate() {
         println!(
             "{}",
             Instr::decode(*instr)
                 .map_or_else(|| "???".to_owned(), |(cond, i)| Instr::display(&i, cond))
         );
+        // Words of data, such as from `DW` or in literal pools, need not be valid encodings.
+        let data = assembled
+            .source_map
+            .get(index)
+            .is_some_and(|span| span.data);
+        // Check that the encoder agrees with the decoder.
+        if !data && let Some((cond, i)) = Instr::decode(*instr) {
+            assert_eq!(
+                i.encode(cond).ok(),
+                Some(*instr),
+                "{instr:08X} did not round trip through {i:?}"
+            );
+        }
     }
 
     // Extract the test comments at the start of the file.
@@ -40,6 +78,11 @@ pub fn test(src: &str) -> Result<(), TestError> {
     // The content of the standard input and output streams.
     let mut input = String::new();
     let mut output = String::new();
+    // Tests fault on transfers near address zero unless they opt out.
+    let mut null_check = Some(NullCheck::default());
+    let mut semihosting = false;
+    // Binary files to load into memory, and their addresses.
+    let mut images = Vec::<(u32, Vec<u8>)>::new();
 
     let mut registers = BTreeMap::<PhysicalRegister, u32>::new();
     for line in src.lines() {
@@ -149,6 +192,53 @@ pub fn test(src: &str) -> Result<(), TestError> {
                     "OUTPUT" => {
                         output.push_str(&unescape::unescape(params).unwrap());
                     }
+                    "SEMIHOSTING" => {
+                        semihosting = match params.trim() {
+                            "on" => true,
+                            "off" => false,
+                            param => {
+                                return Err(TestError::InvalidParams(
+                                    "semihosting",
+                                    param.to_owned(),
+                                ));
+                            }
+                        };
+                    }
+                    "ADDR" => {
+                        let Some((label, value)) = params.trim().split_once(' ') else {
+                            return Err(TestError::InvalidParams("addr", params.to_owned()));
+                        };
+                        let Some(address) = assembled.labels.get(label) else {
+                            return Err(TestError::InvalidParams("addr", label.to_owned()));
+                        };
+                        assert_eq!(
+                            *address,
+                            parse_param(&assembled, value.trim())?,
+                            "mismatch on address of label {label}"
+                        );
+                    }
+                    "INCLUDE" => {
+                        // This was dealt with before assembling.
+                    }
+                    "LOADBIN" => {
+                        let Some((file, address)) = params.trim().split_once(' ') else {
+                            return Err(TestError::InvalidParams("loadbin", params.to_owned()));
+                        };
+                        let image = std::fs::read(base_dir.join(file))
+                            .map_err(|x| TestError::FileError(x.to_string()))?;
+                        images.push((parse_param(&assembled, address.trim())?, image));
+                    }
+                    "NULLCHECK" => {
+                        null_check = match params.trim() {
+                            "off" => None,
+                            limit => Some(NullCheck {
+                                limit: limit.parse::<u32>().map_err(|x| {
+                                    TestError::InvalidParams("nullcheck", x.to_string())
+                                })?,
+                                ..Default::default()
+                            }),
+                        };
+                    }
                     _ => return Err(TestError::InvalidComment(comment.to_owned())),
                 }
             }
@@ -158,15 +248,31 @@ pub fn test(src: &str) -> Result<(), TestError> {
     let Some(steps) = steps else {
         return Err(TestError::StepsNotGiven);
     };
+    if semihosting && !cfg!(feature = "semihosting") {
+        // This build cannot make semihosting calls, so there is nothing to test.
+        return Ok(());
+    }
 
-    let mut proc = Processor::default();
-    proc.registers_mut().set_mode(mode);
+    let mut builder = ProcessorBuilder::default()
+        .mode(mode)
+        .load_words(assembled.origin, &assembled.instrs)
+        .pc(assembled.origin);
+    for (address, image) in &images {
+        builder = builder.load_image(*address, image);
+    }
+    let mut proc = builder
+        .config(ProcessorConfig {
+            null_check,
+            semihosting: semihosting.then(SemihostingHandler::default),
+            ..Default::default()
+        })
+        .build()
+        .map_err(TestError::BuildError)?;
     let mut listener = TestProcessorListener::default();
     listener.input_reversed = input.chars().rev().collect();
     let mut halted = false;
-    proc.memory_mut().set_words_aligned(0x0, &assembled.instrs);
     for i in 0..steps {
-        let pc = proc.registers().get(Register::R15);
+        let pc = proc.registers().pc();
         println!();
         println!("{}", proc.registers());
         println!(
@@ -175,15 +281,16 @@ pub fn test(src: &str) -> Result<(), TestError> {
             Instr::decode(proc.memory().get_word_aligned(pc))
                 .map_or_else(|| "???".to_owned(), |(cond, i)| Instr::display(&i, cond))
         );
-        proc.try_execute(&mut listener)
-            .map_err(TestError::ProcessorError)?;
-        // Advance the program counter.
-        *proc.registers_mut().get_mut(Register::R15) += 4;
-
-        if proc.state() == ProcessorState::Stopped {
-            println!("Halted.");
-            halted = true;
-            break;
+        match proc
+            .poll(&mut listener)
+            .map_err(TestError::ProcessorError)?
+        {
+            PollOutcome::Running => {}
+            PollOutcome::Halted | PollOutcome::AlreadyHalted => {
+                println!("Halted.");
+                halted = true;
+                break;
+            }
         }
     }
 
@@ -0,0 +1,265 @@
+//! Records accesses to chosen ranges of memory, so that front-ends can stop
+//! a program when it touches them.
+//!
+//! Wrap a listener in a [`WatchingListener`] to record the accesses made by each instruction.
+
+use std::ops::RangeInclusive;
+
+use serde::Serialize;
+
+use crate::{
+    instr::TransferKind,
+    processor::{Cycle, ProcessorListener},
+};
+
+/// The kinds of access that a watchpoint records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl WatchKind {
+    pub fn read(self) -> bool {
+        matches!(self, WatchKind::Read | WatchKind::ReadWrite)
+    }
+
+    pub fn write(self) -> bool {
+        matches!(self, WatchKind::Write | WatchKind::ReadWrite)
+    }
+}
+
+/// A range of addresses, and the kinds of access to it that should be recorded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Watchpoint {
+    /// The watched addresses, which may include the last address in memory.
+    pub range: RangeInclusive<u32>,
+    pub read: bool,
+    pub write: bool,
+}
+
+impl Watchpoint {
+    /// Returns true if this access overlaps the watched range, and is of a watched kind.
+    pub fn matches(&self, hit: &WatchHit) -> bool {
+        let wanted = match hit.kind {
+            TransferKind::Load => self.read,
+            TransferKind::Store => self.write,
+        };
+        // Accesses are aligned to their size, so the last byte accessed doesn't overflow.
+        let last = hit.addr + (hit.size as u32 - 1);
+        wanted && hit.addr <= *self.range.end() && *self.range.start() <= last
+    }
+}
+
+/// An access to a watched range of memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct WatchHit {
+    /// The address of the instruction that made the access.
+    pub pc: u32,
+    pub kind: TransferKind,
+    /// The address accessed, aligned to the size of the access.
+    pub addr: u32,
+    /// The number of bytes accessed.
+    pub size: u8,
+    /// The value in memory before the access.
+    pub old: u32,
+    /// The value in memory after the access, which is `old` for a read.
+    pub new: u32,
+}
+
+/// A set of watchpoints, together with the accesses that have hit them.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Watchpoints {
+    watchpoints: Vec<Watchpoint>,
+    hits: Vec<WatchHit>,
+}
+
+impl Watchpoints {
+    /// Record reads from the given range of addresses if `read` is set,
+    /// and writes to it if `write` is set.
+    pub fn add(&mut self, range: RangeInclusive<u32>, read: bool, write: bool) {
+        self.watchpoints.push(Watchpoint { range, read, write });
+    }
+
+    /// Remove the watchpoints on exactly this range, returning whether there were any.
+    pub fn remove(&mut self, range: RangeInclusive<u32>) -> bool {
+        let len = self.watchpoints.len();
+        self.watchpoints
+            .retain(|watchpoint| watchpoint.range != range);
+        self.watchpoints.len() != len
+    }
+
+    /// Remove every watchpoint, but not the hits that have been recorded.
+    pub fn clear(&mut self) {
+        self.watchpoints.clear();
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    /// The accesses that hit a watchpoint, in the order that they were made.
+    pub fn hits(&self) -> &[WatchHit] {
+        &self.hits
+    }
+
+    /// Remove and return the hits recorded so far.
+    pub fn take_hits(&mut self) -> Vec<WatchHit> {
+        std::mem::take(&mut self.hits)
+    }
+
+    /// Record this access if it overlaps a watchpoint for its kind of access.
+    pub fn access(&mut self, hit: WatchHit) {
+        if self.find(&hit).is_some() {
+            self.hits.push(hit);
+        }
+    }
+
+    /// The first watchpoint that this access hits, if any.
+    pub fn find(&self, hit: &WatchHit) -> Option<&Watchpoint> {
+        self.watchpoints
+            .iter()
+            .find(|watchpoint| watchpoint.matches(hit))
+    }
+}
+
+/// A listener that records accesses to watched memory,
+/// and passes every event on to another listener.
+#[derive(Debug)]
+pub struct WatchingListener<'a, L> {
+    pub listener: &'a mut L,
+    pub watchpoints: &'a mut Watchpoints,
+}
+
+impl<L: ProcessorListener> ProcessorListener for WatchingListener<'_, L> {
+    fn cycle(&mut self, cycle: Cycle, count: usize, pc: u32) {
+        self.listener.cycle(cycle, count, pc);
+    }
+
+    fn pipeline_flush(&mut self, pc: u32) {
+        self.listener.pipeline_flush(pc);
+    }
+
+    fn getc(&mut self) -> Option<char> {
+        self.listener.getc()
+    }
+
+    fn putc(&mut self, c: char) {
+        self.listener.putc(c);
+    }
+
+    fn putint(&mut self, i: u32) {
+        self.listener.putint(i);
+    }
+
+    fn explain(&mut self, explanation: String) {
+        self.listener.explain(explanation);
+    }
+
+    fn program_overwritten(&mut self, pc: u32, address: u32) {
+        self.listener.program_overwritten(pc, address);
+    }
+
+    fn memory_read(&mut self, addr: u32, size: u8, value: u32, pc: u32) {
+        self.watchpoints.access(WatchHit {
+            pc,
+            kind: TransferKind::Load,
+            addr,
+            size,
+            old: value,
+            new: value,
+        });
+        self.listener.memory_read(addr, size, value, pc);
+    }
+
+    fn memory_write(&mut self, addr: u32, size: u8, old: u32, new: u32, pc: u32) {
+        self.watchpoints.access(WatchHit {
+            pc,
+            kind: TransferKind::Store,
+            addr,
+            size,
+            old,
+            new,
+        });
+        self.listener.memory_write(addr, size, old, new, pc);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        instr::TransferKind,
+        processor::{NullListener, PollOutcome, test::load},
+        watchpoints::{WatchHit, WatchingListener, Watchpoint, Watchpoints},
+    };
+
+    #[test]
+    fn store_loop() {
+        let src = "\
+        mov r0, #0x1000
+        mov r1, #0
+loop    str r1, [r0], #4
+        add r1, r1, #1
+        cmp r1, #6
+        bne loop
+        sub r4, r0, #8
+        ldrb r2, [r4]
+        swp r3, r2, [r0]
+        stmia r0, {r1, r2}
+        swi 2
+";
+        let mut proc = load(src);
+        let mut watchpoints = Watchpoints::default();
+        // Only the middle of the stores, and the byte read back, are watched.
+        watchpoints.add(0x1006..=0x100B, false, true);
+        watchpoints.add(0x1010..=0x1010, true, false);
+        watchpoints.add(0x1018..=0x101B, true, true);
+        let mut listener = WatchingListener {
+            listener: &mut NullListener,
+            watchpoints: &mut watchpoints,
+        };
+        while proc.poll(&mut listener).unwrap() != PollOutcome::Halted {}
+
+        let hit = |pc, kind, addr, size, old, new| WatchHit {
+            pc,
+            kind,
+            addr,
+            size,
+            old,
+            new,
+        };
+        assert_eq!(
+            watchpoints.take_hits(),
+            [
+                hit(8, TransferKind::Store, 0x1004, 4, 0xAAAAAAAA, 1),
+                hit(8, TransferKind::Store, 0x1008, 4, 0xAAAAAAAA, 2),
+                hit(28, TransferKind::Load, 0x1010, 1, 4, 4),
+                hit(32, TransferKind::Load, 0x1018, 4, 0xAAAAAAAA, 0xAAAAAAAA),
+                hit(32, TransferKind::Store, 0x1018, 4, 0xAAAAAAAA, 4),
+                hit(36, TransferKind::Store, 0x1018, 4, 4, 6),
+            ]
+        );
+        assert!(watchpoints.hits().is_empty());
+    }
+
+    #[test]
+    fn top_of_memory() {
+        let watchpoint = Watchpoint {
+            range: u32::MAX..=u32::MAX,
+            read: false,
+            write: true,
+        };
+        let store = |addr, size| WatchHit {
+            pc: 0,
+            kind: TransferKind::Store,
+            addr,
+            size,
+            old: 0,
+            new: 0,
+        };
+        assert!(watchpoint.matches(&store(0xFFFF_FFFC, 4)));
+        assert!(watchpoint.matches(&store(0xFFFF_FFFE, 2)));
+        assert!(!watchpoint.matches(&store(0xFFFF_FFFC, 2)));
+    }
+}
@@ -0,0 +1,11 @@
+; Words of data need not be valid encodings of the instructions they decode as.
+; 0xE000B99A decodes as MUL, but has bits set that the encoder leaves clear.
+
+;! halts 2
+
+;! r0 3758143898
+
+        ldr     r0, word
+        swi     2
+
+word    dw 0xE000B99A
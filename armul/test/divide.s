@@ -7,6 +7,10 @@
 ;! r3 6
 ;! pc end
 
+;! addr div1 12
+;! addr div2 36
+;! addr end 60
+
         mov r1,#37
         mov r2,#6
         mov r0,#1
@@ -0,0 +1,11 @@
+; Sums a table loaded from a binary file, using a routine included from another file.
+
+;! include sum.inc
+;! loadbin table.bin 256
+;! halts 100
+;! r0 10
+
+        mov r1, #256
+        mov r2, #4
+        bl sum
+        swi 2
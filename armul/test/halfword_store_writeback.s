@@ -0,0 +1,31 @@
+; Halfword stores with register offset and write-back, and post-indexing.
+
+;! halts 20
+
+;! r0 43981
+;! r1 50331652
+;! r2 4
+;! r3 4660
+;! r4 50331656
+;! r5 2863311821
+;! r6 305441741
+
+;! addr MEM 50331648
+
+MEM equ 0x03000000
+
+        ; Pre-indexed register offset with write-back.
+        ; Stores to the least significant half of MEM + 4.
+        mov     r0, 0xABCD
+        mov     r1, MEM
+        mov     r2, 4
+        strh    r0, [r1, r2]!
+        ldr     r5, [r1]
+
+        ; Post-indexed constant offset.
+        ; Stores to the most significant half of MEM + 4.
+        mov     r3, 0x1234
+        mov     r4, MEM + 6
+        strh    r3, [r4], #2
+        ldr     r6, [r1]
+        swi     2
@@ -0,0 +1,22 @@
+; Prints a character and exits using semihosting calls.
+
+;! halts 20
+;! semihosting on
+;! output A
+
+SYS_WRITEC equ 0x03
+SYS_EXIT equ 0x18
+
+        mov     r0, SYS_WRITEC
+        adr     r1, char
+        swi     0x123456
+
+        mov     r0, SYS_EXIT
+        mov     r1, 0x20026 ; ADP_Stopped_ApplicationExit
+        swi     0x123456
+
+        ; This should not be reached.
+        mov     r0, 66
+        swi     0
+
+char    dw 65
@@ -0,0 +1,49 @@
+; MSR writes only the fields of the PSR selected by its suffix.
+
+;! halts 100
+;! mode sys
+
+MODE_USR equ 0x10
+MODE_FIQ equ 0x11
+MODE_SYS equ 0x1F
+
+        ; Writing the control field keeps the flags.
+        msr     cpsr_flg, 0x60000000
+        mov     r0, MODE_FIQ
+        msr     cpsr_c, r0
+        mrs     r1, cpsr
+        teq     r1, 0x60000000 or MODE_FIQ
+        swine   201
+
+        ; Writing the flags field keeps the mode.
+        mvn     r0, 0
+        msr     cpsr_f, r0
+        mrs     r1, cpsr
+        teq     r1, 0xFF000000 or MODE_FIQ
+        swine   202
+
+        ; Constants can be written to the control field.
+        msr     cpsr_c, MODE_SYS
+        mrs     r1, cpsr
+        and     r1, r1, 0x1F
+        teq     r1, MODE_SYS
+        swine   203
+
+        ; The SPSR can be written one field at a time.
+        msr     cpsr_c, MODE_FIQ
+        mov     r0, MODE_USR
+        msr     spsr_fsxc, r0
+        msr     spsr_flg, 0x80000000
+        mrs     r1, spsr
+        teq     r1, 0x80000000 or MODE_USR
+        swine   204
+
+        ; User mode may not change the control field.
+        msr     cpsr_c, MODE_USR
+        msr     cpsr_c, MODE_SYS
+        mrs     r1, cpsr
+        and     r1, r1, 0x1F
+        teq     r1, MODE_USR
+        swine   205
+
+        swi     2
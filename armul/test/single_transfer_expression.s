@@ -3,6 +3,11 @@
 ;! r0 100
 ;! r1 -200
 
+;! addr behind 4
+;! addr main 8
+; The load from `behind` needs a negative offset, which is encoded by subtracting.
+;! addr ahead 20
+
 b main
 
 behind
@@ -1,10 +1,15 @@
-use std::{collections::BTreeSet, path::Path};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::Path,
+};
 
 use armul::{
-    assemble::{assemble, AssemblerOutput},
-    instr::{Cond, LineInfo, Register},
-    processor::{Processor, ProcessorListener, ProcessorState},
-    registers::Registers,
+    addr::{Addr, Word},
+    assemble::{assemble, AssemblerError, AssemblerOutput, SourceBlock},
+    instr::{Cond, LineInfo},
+    mode::Mode,
+    processor::{PollOutcome, Processor, ProcessorListener, ProcessorState},
+    registers::{Flag, PhysicalRegister, Registers},
 };
 use num::FromPrimitive;
 use parking_lot::RwLock;
@@ -15,10 +20,14 @@ use serde::Serialize;
 #[derive(Default)]
 struct MyState {
     assembled: Option<AssemblerOutput>,
+    /// The source code that `assembled` was assembled from.
+    source: String,
+    /// The words that were loaded, and the address of the first, for hard resets.
+    program: (Addr, Vec<u32>),
     processor: Processor,
     info: ProcessorInformation,
     user_input: String,
-    breakpoints: BTreeSet<u32>,
+    breakpoints: BTreeSet<Addr>,
 }
 
 impl MyState {
@@ -26,9 +35,159 @@ impl MyState {
         let current_instruction = self
             .processor
             .memory()
-            .get_word_aligned(self.processor.registers().get(Register::R15));
+            .get_word_aligned(self.processor.registers().pc());
         self.info.current_cond = Cond::from_u32(current_instruction >> 28).unwrap_or(Cond::AL);
     }
+
+    /// Replace the processor with a new one that will execute the given words.
+    pub fn load_words(&mut self, origin: Addr, words: Vec<u32>) {
+        self.processor = Processor::default();
+        self.processor
+            .memory_mut()
+            .set_words_aligned(origin, &words);
+        self.processor.registers_mut().set_pc(origin.0);
+        self.program = (origin, words);
+        self.update_cond();
+    }
+
+    /// Assemble the given source and load it, replacing the current program.
+    ///
+    /// The words are loaded at the program's origin, which is 0 unless the source uses `ORG`.
+    /// Labels and PC-relative operands are resolved against that origin,
+    /// so loading the words anywhere else would break them.
+    pub fn load_source(&mut self, src: String) -> Result<AssembleResult, Vec<AssemblerError>> {
+        let assembled = assemble(&src)?;
+        let result = AssembleResult {
+            origin: Addr(assembled.origin),
+            words: assembled.instrs.len(),
+            labels: assembled.labels.clone(),
+            warnings: assembled
+                .warnings
+                .iter()
+                .map(|warning| format!("line {}: {}", warning.line_number, warning.warning))
+                .collect(),
+        };
+        self.load_words(Addr(assembled.origin), assembled.instrs.clone());
+        self.assembled = Some(assembled);
+        self.source = src;
+        self.info = ProcessorInformation::default();
+        Ok(result)
+    }
+
+    /// Execute up to the given number of instructions,
+    /// stopping early if the processor halts, fails, or reaches a breakpoint.
+    pub fn run_steps(&mut self, steps: usize) -> StepResult {
+        let mut executed = 0;
+        let mut stop = None;
+        let mut input_used = false;
+        while executed < steps && stop.is_none() {
+            let before = self.info.steps;
+            let stepped = self.step();
+            stop = stepped.stop;
+            input_used |= stepped.input_used;
+            executed += self.info.steps - before;
+        }
+        StepResult {
+            executed,
+            stop,
+            registers: self.processor.registers().clone(),
+            user_input: input_used.then(|| self.user_input.clone()),
+        }
+    }
+
+    /// Execute one instruction.
+    pub fn step(&mut self) -> Stepped {
+        self.info.previous_pc = Addr(self.processor.registers().pc());
+
+        // Save some of the old info.
+        let old_n = self.info.nonseq_cycles;
+        let old_s = self.info.seq_cycles;
+        let old_i = self.info.internal_cycles;
+
+        let mut listener = TauriProcessorListener {
+            info: &mut self.info,
+            user_input: &mut self.user_input,
+            input_used: false,
+        };
+        let result = self.processor.poll(&mut listener);
+        let input_used = listener.input_used;
+        let stop = match result {
+            Ok(PollOutcome::AlreadyHalted) => {
+                self.info.state = Ok(self.processor.state());
+                Some(StopReason::Halted)
+            }
+            Ok(outcome @ (PollOutcome::Running | PollOutcome::Halted)) => {
+                // Log that we've done a step.
+                self.info.steps += 1;
+
+                if self
+                    .breakpoints
+                    .contains(&Addr(self.processor.registers().pc()))
+                {
+                    self.info.state = Err("Hit breakpoint".to_string());
+                    Some(StopReason::Breakpoint)
+                } else {
+                    self.info.state = Ok(self.processor.state());
+                    (outcome == PollOutcome::Halted).then_some(StopReason::Halted)
+                }
+            }
+            Err(err) => {
+                // Reset the old info because we didn't complete a step.
+                self.info.nonseq_cycles = old_n;
+                self.info.seq_cycles = old_s;
+                self.info.internal_cycles = old_i;
+
+                self.info.state = Err(err.to_string());
+                Some(StopReason::Error(err.to_string()))
+            }
+        };
+        self.update_cond();
+        Stepped { input_used, stop }
+    }
+
+    pub fn reset(&mut self, hard: bool) {
+        self.info.reset();
+        if hard {
+            // Hard resets put everything (even memory) back to where it was at the start.
+            let (origin, words) = std::mem::take(&mut self.program);
+            self.load_words(origin, words);
+        } else {
+            // Soft resets just put the PC back to the program's origin and set Running state.
+            self.processor.registers_mut().set_pc(self.program.0.into());
+            self.processor.set_running();
+        }
+
+        self.update_cond();
+    }
+
+    pub fn disassemble(&self, addr: Addr, count: usize) -> Vec<LineInfo> {
+        (0..count as u32)
+            .map(|i| {
+                let addr = addr.align_down() + 4 * i;
+                LineInfo::new(
+                    addr,
+                    Word(self.processor.memory().get_word_aligned(addr)),
+                    self.assembled.as_ref(),
+                )
+            })
+            .collect()
+    }
+
+    pub fn set_flag(&mut self, name: &str, value: bool) -> Result<Registers, String> {
+        let flag = name
+            .parse::<Flag>()
+            .map_err(|()| format!("unknown flag '{name}'"))?;
+        self.processor.registers_mut().set_flag(flag, value);
+        Ok(self.processor.registers().clone())
+    }
+
+    pub fn set_mode(&mut self, name: &str) -> Result<Registers, String> {
+        let mode = name
+            .parse::<Mode>()
+            .map_err(|()| format!("unknown mode '{name}'"))?;
+        self.processor.registers_mut().set_mode(mode);
+        Ok(self.processor.registers().clone())
+    }
 }
 
 #[derive(Default)]
@@ -40,6 +199,107 @@ struct PrettyAssemblerError {
     error: String,
 }
 
+/// Format each error as the command line interface does.
+fn format_errors(errs: Vec<AssemblerError>) -> Vec<String> {
+    errs.into_iter()
+        .map(|err| format!("line {}: {}", err.line_number, err.error))
+        .collect()
+}
+
+/// Split each error into its line number and message for the front-end,
+/// leaving out the line number of parse errors, which already give their position.
+fn pretty_errors(errs: Vec<AssemblerError>) -> Vec<PrettyAssemblerError> {
+    errs.into_iter()
+        .map(|err| match err.error {
+            armul::assemble::LineError::ParseError(parse_error) => PrettyAssemblerError {
+                line_number: None,
+                error: parse_error,
+            },
+            error => PrettyAssemblerError {
+                line_number: Some(err.line_number),
+                error: error.to_string(),
+            },
+        })
+        .collect()
+}
+
+/// A summary of a program that was assembled and loaded by [`load_source`].
+#[derive(Debug, Serialize)]
+pub struct AssembleResult {
+    origin: Addr,
+    /// The number of words that were loaded.
+    words: usize,
+    labels: BTreeMap<String, u32>,
+    warnings: Vec<String>,
+}
+
+/// The effect of a single call to [`MyState::step`].
+pub struct Stepped {
+    input_used: bool,
+    /// Why execution should not continue, if it should not.
+    stop: Option<StopReason>,
+}
+
+/// Why [`MyState::run_steps`] executed fewer instructions than it was asked to.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "reason", content = "message")]
+pub enum StopReason {
+    Halted,
+    Breakpoint,
+    Error(String),
+}
+
+#[derive(Debug, Serialize)]
+pub struct StepResult {
+    /// The number of instructions that were executed.
+    executed: usize,
+    /// If this is `None`, every step asked for was executed.
+    stop: Option<StopReason>,
+    registers: Registers,
+    /// The user input that is left, if the program read any of it.
+    user_input: Option<String>,
+}
+
+/// Every physical register, along with the flags and mode that the CPSR gives.
+#[derive(Debug, Serialize)]
+pub struct RegistersDto {
+    /// The physical registers, in the order of [`PhysicalRegister`].
+    regs: Vec<u32>,
+    mode: Option<String>,
+    flags: FlagsDto,
+}
+
+#[derive(Debug, Serialize)]
+struct FlagsDto {
+    n: bool,
+    z: bool,
+    c: bool,
+    v: bool,
+    i: bool,
+    f: bool,
+    t: bool,
+}
+
+impl RegistersDto {
+    pub fn new(registers: &Registers) -> Self {
+        Self {
+            regs: (0..=PhysicalRegister::SpsrUnd as usize)
+                .map(|i| registers.get_physical(PhysicalRegister::from_usize(i).unwrap()))
+                .collect(),
+            mode: registers.mode().map(|mode| mode.to_string()),
+            flags: FlagsDto {
+                n: registers.negative(),
+                z: registers.zero(),
+                c: registers.carry(),
+                v: registers.overflow(),
+                i: registers.irq_disable(),
+                f: registers.fiq_disable(),
+                t: registers.thumb_state(),
+            },
+        }
+    }
+}
+
 #[tauri::command]
 async fn load_program(
     state: tauri::State<'_, MyStateLock>,
@@ -51,48 +311,99 @@ async fn load_program(
             error: e.to_string(),
         }]
     })?;
-    let assembled = assemble(&contents).map_err(|errs| {
-        errs.into_iter()
-            .map(|err| match err.error {
-                armul::assemble::LineError::ParseError(parse_error) => PrettyAssemblerError {
-                    line_number: None,
-                    error: parse_error,
-                },
-                error => PrettyAssemblerError {
-                    line_number: Some(err.line_number),
-                    error: error.to_string(),
-                },
-            })
-            .collect::<Vec<_>>()
-    })?;
-    let mut new_processor = Processor::default();
-    new_processor
-        .memory_mut()
-        .set_words_aligned(0, &assembled.instrs);
     let mut state = state.0.write();
-    state.processor = new_processor;
-    state.assembled = Some(assembled);
+    state.load_source(contents).map_err(pretty_errors)?;
     state.info = ProcessorInformation::new(path.file_name().map_or_else(
         || path.to_string_lossy().to_string(),
         |base| base.to_string_lossy().to_string(),
     ));
-    state.update_cond();
     Ok(())
 }
 
+/// Load words that were assembled elsewhere at address zero, without any source code.
+#[tauri::command]
+fn load_words(state: tauri::State<'_, MyStateLock>, instrs: Vec<u32>) {
+    let mut state = state.0.write();
+    state.load_words(Addr(0), instrs);
+    state.assembled = None;
+    state.source = String::new();
+    state.info = ProcessorInformation::default();
+}
+
+/// Describe `count` consecutive words of memory, starting at the word-aligned address `addr`.
 #[tauri::command]
-fn line_at(state: tauri::State<'_, MyStateLock>, addr: u32) -> LineInfo {
+fn disassemble(state: tauri::State<'_, MyStateLock>, addr: Addr, count: usize) -> Vec<LineInfo> {
+    state.0.read().disassemble(addr, count)
+}
+
+#[tauri::command]
+fn line_at(state: tauri::State<'_, MyStateLock>, addr: Addr) -> LineInfo {
     let state = state.0.read();
     LineInfo::new(
         addr,
-        state.processor.memory().get_word_aligned(addr),
+        Word(state.processor.memory().get_word_aligned(addr)),
         state.assembled.as_ref(),
     )
 }
 
+/// Assemble the given source and load it at its origin, replacing the current program.
+#[tauri::command]
+fn load_source(
+    state: tauri::State<'_, MyStateLock>,
+    src: String,
+) -> Result<AssembleResult, Vec<String>> {
+    state.0.write().load_source(src).map_err(format_errors)
+}
+
+/// Assemble the given source without loading it, describing each word that it produced.
+/// On failure, returns a message for each error.
+#[tauri::command]
+fn assemble_source(src: String) -> Result<Vec<LineInfo>, Vec<String>> {
+    let assembled = assemble(&src).map_err(format_errors)?;
+    Ok(assembled
+        .instrs
+        .iter()
+        .enumerate()
+        .map(|(i, word)| {
+            LineInfo::new(
+                Addr(assembled.origin) + 4 * i as u32,
+                Word(*word),
+                Some(&assembled),
+            )
+        })
+        .collect())
+}
+
+/// Returns each line of the loaded program's source along with the words it produced.
 #[tauri::command]
-fn registers(state: tauri::State<'_, MyStateLock>) -> Registers {
-    state.0.read().processor.registers().clone()
+fn source_blocks(state: tauri::State<'_, MyStateLock>) -> Vec<SourceBlock> {
+    let state = state.0.read();
+    state
+        .assembled
+        .as_ref()
+        .map_or_else(Vec::new, |assembled| assembled.interleave(&state.source))
+}
+
+/// Every physical register, including the banked ones, with the flags and mode decoded.
+#[tauri::command]
+fn get_registers(state: tauri::State<'_, MyStateLock>) -> RegistersDto {
+    RegistersDto::new(state.0.read().processor.registers())
+}
+
+/// Set or clear one of the N, Z, C, V, I and F bits of the CPSR.
+#[tauri::command]
+fn set_flag(
+    state: tauri::State<'_, MyStateLock>,
+    name: &str,
+    value: bool,
+) -> Result<Registers, String> {
+    state.0.write().set_flag(name, value)
+}
+
+/// Change the mode of the processor, given its short name such as `svc`.
+#[tauri::command]
+fn set_mode(state: tauri::State<'_, MyStateLock>, name: &str) -> Result<Registers, String> {
+    state.0.write().set_mode(name)
 }
 
 #[tauri::command]
@@ -104,7 +415,7 @@ fn set_user_input(state: tauri::State<'_, MyStateLock>, user_input: String) {
 pub struct ProcessorInformation {
     file: String,
     state: Result<ProcessorState, String>,
-    previous_pc: u32,
+    previous_pc: Addr,
     current_cond: Cond,
 
     steps: usize,
@@ -128,7 +439,7 @@ impl ProcessorInformation {
         Self {
             file,
             state: Ok(Default::default()),
-            previous_pc: 0,
+            previous_pc: Addr(0),
             current_cond: Cond::AL,
             steps: Default::default(),
             nonseq_cycles: Default::default(),
@@ -186,89 +497,21 @@ impl<'a> ProcessorListener for TauriProcessorListener<'a> {
     }
 }
 
-/// Returns the new user input field, if it was changed.
+/// Execute up to the given number of instructions, stopping at the first halt, error or breakpoint.
+/// With one step, this executes a single instruction and returns the new registers.
+/// The cycle counts and output are read afterwards from [`processor_info`].
 #[tauri::command]
-fn step_times(state: tauri::State<'_, MyStateLock>, steps: usize) -> Option<String> {
-    let mut guard = state.0.write();
-    let state = &mut *guard;
-    let mut input_used = false;
-
-    for _ in 0..steps {
-        state.info.previous_pc = state.processor.registers().get(Register::R15);
-
-        // Save some of the old info.
-        let old_n = state.info.nonseq_cycles;
-        let old_s = state.info.seq_cycles;
-        let old_i = state.info.internal_cycles;
-
-        let mut listener = TauriProcessorListener {
-            info: &mut state.info,
-            user_input: &mut state.user_input,
-            input_used: false,
-        };
-        match state.processor.try_execute(&mut listener) {
-            Ok(()) => {
-                input_used |= listener.input_used;
-
-                // Advance the program counter and log that we've done a step.
-                state.info.steps += 1;
-                *state.processor.registers_mut().get_mut(Register::R15) += 4;
-
-                if state
-                    .breakpoints
-                    .contains(&state.processor.registers().get(Register::R15))
-                {
-                    state.info.state = Err("Hit breakpoint".to_string());
-                } else {
-                    state.info.state = Ok(state.processor.state());
-                }
-            }
-            Err(err) => {
-                input_used |= listener.input_used;
-                // Reset the old info because we didn't complete a step.
-                state.info.nonseq_cycles = old_n;
-                state.info.seq_cycles = old_s;
-                state.info.internal_cycles = old_i;
-
-                state.info.state = Err(err.to_string());
-            }
-        }
-        state.update_cond();
-    }
-
-    if input_used {
-        Some(state.user_input.clone())
-    } else {
-        None
-    }
+fn step(state: tauri::State<'_, MyStateLock>, steps: usize) -> StepResult {
+    state.0.write().run_steps(steps)
 }
 
 #[tauri::command]
 fn reset(state: tauri::State<'_, MyStateLock>, hard: bool) {
-    let mut guard = state.0.write();
-    let state = &mut *guard;
-
-    state.info.reset();
-    if hard {
-        // Hard resets put everything (even memory) back to where it was at the start.
-        state.processor = Processor::default();
-        if let Some(assembled) = state.assembled.as_ref() {
-            state
-                .processor
-                .memory_mut()
-                .set_words_aligned(0, &assembled.instrs);
-        }
-    } else {
-        // Soft resets just put the PC back to 0 and set Running state.
-        state.processor.registers_mut().set(Register::R15, 0);
-        state.processor.set_running();
-    }
-
-    state.update_cond();
+    state.0.write().reset(hard);
 }
 
 #[tauri::command]
-fn breakpoint(state: tauri::State<'_, MyStateLock>, addr: u32, set: bool) {
+fn breakpoint(state: tauri::State<'_, MyStateLock>, addr: Addr, set: bool) {
     if set {
         state.0.write().breakpoints.insert(addr);
     } else {
@@ -276,6 +519,11 @@ fn breakpoint(state: tauri::State<'_, MyStateLock>, addr: u32, set: bool) {
     }
 }
 
+#[tauri::command]
+fn capabilities() -> armul::Capabilities {
+    armul::capabilities()
+}
+
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
     tauri::Builder::default()
@@ -285,14 +533,135 @@ pub fn run() {
         .manage(MyStateLock::default())
         .invoke_handler(tauri::generate_handler![
             load_program,
+            load_source,
+            load_words,
             line_at,
-            registers,
+            disassemble,
+            assemble_source,
+            source_blocks,
+            get_registers,
+            set_flag,
+            set_mode,
             set_user_input,
-            step_times,
+            step,
             processor_info,
             reset,
             breakpoint,
+            capabilities,
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
 }
+
+#[cfg(test)]
+mod tests {
+    use armul::{addr::Addr, assemble::assemble, instr::Register};
+
+    use serde_json::json;
+
+    use crate::{assemble_source, format_errors, MyState, RegistersDto};
+
+    #[test]
+    fn toggle_carry() {
+        let mut state = MyState::default();
+        assert!(state.set_flag("C", true).unwrap().carry());
+        assert!(!state.set_flag("C", false).unwrap().carry());
+        assert!(state.set_flag("Q", true).is_err());
+        assert!(state.set_mode("xyz").is_err());
+    }
+
+    #[test]
+    fn step_and_reset() {
+        let mut state = MyState::default();
+        let assembled = assemble("mov r0, #1\nswi 2\n").unwrap();
+        state.load_words(Addr(0), assembled.instrs);
+        assert!(!state.step().input_used);
+        assert_eq!(state.processor.registers().get(Register::R0), 1);
+        assert_eq!(state.info.steps, 1);
+
+        // A soft reset keeps memory, and a hard reset reloads the program.
+        state.processor.memory_mut().set_word_aligned(0, 0);
+        state.reset(false);
+        assert_eq!(state.info.steps, 0);
+        assert_eq!(state.processor.memory().get_word_aligned(0), 0);
+        state.reset(true);
+        state.step();
+        assert_eq!(state.processor.registers().get(Register::R0), 1);
+    }
+
+    #[test]
+    fn soft_reset_to_origin() {
+        let mut state = MyState::default();
+        let assembled = assemble("mov r0, #1\nswi 2\n").unwrap();
+        state.load_words(Addr(0x100), assembled.instrs);
+        state.step();
+        state.reset(false);
+        assert_eq!(state.processor.registers().pc(), 0x100);
+    }
+
+    #[test]
+    fn assemble_source_errors() {
+        let lines = assemble_source("loop b loop\n".to_owned()).unwrap();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(
+            assemble_source("mov r0, #1\nb nowhere\n".to_owned()).unwrap_err(),
+            ["line 2: label 'nowhere' not found"]
+        );
+    }
+
+    #[test]
+    fn registers_shape() {
+        let state = MyState::default();
+        let dto = serde_json::to_value(RegistersDto::new(state.processor.registers())).unwrap();
+        let regs = dto["regs"].as_array().unwrap();
+        assert_eq!(regs.len(), 37);
+        assert_eq!(regs[31], json!(0b10010000));
+        assert_eq!(dto["mode"], json!("usr"));
+        assert_eq!(
+            dto["flags"],
+            json!({"n": false, "z": false, "c": false, "v": false, "i": true, "f": false, "t": false})
+        );
+    }
+
+    #[test]
+    fn load_source_and_step() {
+        let mut state = MyState::default();
+        let result = serde_json::to_value(
+            state
+                .load_source("start mov r0, #1\nswi 2\n".to_owned())
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            result,
+            json!({"origin": 0, "words": 2, "labels": {"start": 0}, "warnings": []})
+        );
+        assert_eq!(
+            format_errors(state.load_source("b nowhere\n".to_owned()).unwrap_err()),
+            ["line 1: label 'nowhere' not found"]
+        );
+        assert_eq!(state.disassemble(Addr(0), 4).len(), 4);
+
+        let result = serde_json::to_value(state.run_steps(1)).unwrap();
+        assert_eq!(result["executed"], json!(1));
+        assert_eq!(result["stop"], json!(null));
+        let result = serde_json::to_value(state.run_steps(10)).unwrap();
+        assert_eq!(result["executed"], json!(1));
+        assert_eq!(result["stop"], json!({"reason": "Halted"}));
+        let result = serde_json::to_value(state.run_steps(10)).unwrap();
+        assert_eq!(result["executed"], json!(0));
+        assert_eq!(result["stop"], json!({"reason": "Halted"}));
+    }
+
+    #[test]
+    fn load_source_at_origin() {
+        let mut state = MyState::default();
+        let result = state
+            .load_source("        org 0x100\nstart   b start\n".to_owned())
+            .unwrap();
+        assert_eq!(result.origin, Addr(0x100));
+        assert_eq!(state.processor.registers().pc(), 0x100);
+        state.run_steps(1);
+        assert_eq!(state.processor.registers().pc(), 0x100);
+    }
+}
@@ -1,6 +1,6 @@
 import { ReactNode } from "react";
 import * as processor from "./lib/processor";
-import { LineInfo } from "./lib/serde-types";
+import { LineInfo, StepResult } from "./lib/serde-types";
 import { invoke } from "@tauri-apps/api/core";
 import { open } from '@tauri-apps/plugin-dialog';
 import { toast } from "sonner";
@@ -262,8 +262,8 @@ function play(processor: processor.Processor, dispatch: AppDispatch): () => void
     var newUserInput: string | undefined = undefined;
     var shouldStop = false;
 
-    const nextUserInput: string | undefined = await invoke('step_times', {steps: processor.simulation_speed});
-    if (nextUserInput !== undefined) newUserInput = nextUserInput;
+    const result: StepResult = await invoke('step', {steps: processor.simulation_speed});
+    if (result.user_input !== null) newUserInput = result.user_input;
 
     // Check if the processor is now stopped.
     const info: processor.ProcessorInformation = await invoke('processor_info');
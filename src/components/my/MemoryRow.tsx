@@ -26,12 +26,25 @@ function renderPrettyInstr(pretty: PrettyInstr): ReactNode {
   </span>)
 }
 
+/** The suffix that selects the fields written by an MSR, omitted when it writes the default fields. */
+function psrSuffix(fields: string | null): string {
+  switch (fields) {
+    case null:
+    case "fc":
+      return "";
+    case "f":
+      return "_flg";
+    default:
+      return "_" + fields;
+  }
+}
+
 function renderPrettyArgument(arg: PrettyArgument): ReactNode {
   switch (arg.type) {
     case 'Register':
       return <span className="faint">{arg.negative ? "-" : ""}<span className="register">{registerToString(arg.register)}</span>{arg.write_back ? "!" : ""}</span>;
     case 'Psr':
-      return <span className="register">{arg.psr.toUpperCase()}{arg.flag ? "_flg" : ""}</span>;
+      return <span className="register">{arg.psr.toUpperCase()}{psrSuffix(arg.fields)}</span>;
     case 'Shift':
       var amount;
       switch (arg.shift_amount.type) {
@@ -46,7 +59,7 @@ function renderPrettyArgument(arg: PrettyArgument): ReactNode {
     case 'Constant':
       switch (arg.style) {
         case 'Address':
-          return <span>{arg.negative ? '-' : ''}{renderAddress(arg.value, 'addr-faint', 'addr', true)}</span>;
+          return <span>{arg.negative ? '-' : ''}{arg.label ? <span className="addr">{arg.label}</span> : renderAddress(arg.value, 'addr-faint', 'addr', true)}</span>;
         case 'UnsignedDecimal':
           return <span className="addr">{arg.negative ? '-' : ''}{arg.value}</span>;
         case 'Unknown':
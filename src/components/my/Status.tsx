@@ -8,15 +8,16 @@ import { useContext } from "react";
 import { DispatchContext } from "@/lib/DispatchContext";
 import { AppDispatch } from "@/AppAction";
 import { Processor, resynchronise } from "@/lib/processor";
+import { StepResult } from "@/lib/serde-types";
 import { invoke } from "@tauri-apps/api/core";
 
 async function stepOnce(processor: Processor, dispatch: AppDispatch) {
   if (processor.playing)
     return;
 
-  const newUserInput: string | undefined = await invoke('step_times', { steps: 1 });
-  if (newUserInput) {
-    dispatch({ type: "user_input_update", newUserInput })
+  const result: StepResult = await invoke('step', { steps: 1 });
+  if (result.user_input !== null) {
+    dispatch({ type: "user_input_update", newUserInput: result.user_input })
   }
   const newProcessor = await resynchronise(processor);
   dispatch({ type: "processor_update", newProcessor });
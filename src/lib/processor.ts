@@ -43,7 +43,11 @@ export interface ProcessorInformation {
 export function newProcessor(): Processor {
     return {
         memory: new Map(),
-        registers: { regs: Array(37).fill(0) },
+        registers: {
+            regs: Array(37).fill(0),
+            mode: null,
+            flags: { n: false, z: false, c: false, v: false, i: false, f: false, t: false },
+        },
         visible_memory_disas: { start: 0, end: 0 },
         visible_memory_memory: { start: 0, end: 0 },
         info: {
@@ -65,7 +69,7 @@ export function newProcessor(): Processor {
  * Returns an update function that can be executed on a processor (which may have since been updated!)
  */
 export async function resynchronise(processor: Processor): Promise<(proc: Processor) => Processor> {
-    const registers: Registers = await invoke('registers');
+    const registers: Registers = await invoke('get_registers');
     const info: ProcessorInformation = await invoke('processor_info');
     const keys = [];
     for (var i = processor.visible_memory_disas.start; i < processor.visible_memory_disas.end; i += 4) {
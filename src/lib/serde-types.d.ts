@@ -23,7 +23,8 @@ interface RegisterArgument {
 interface PsrArgument {
     type: 'Psr',
     psr: string,
-    flag: boolean,
+    /** The letters of the fields written by an MSR, in the order `fsxc`, or null for an MRS. */
+    fields: string | null,
 };
 
 interface AddressArgument {
@@ -58,6 +59,7 @@ interface ConstantArgument {
     negative: boolean,
     value: number,
     style: ConstantStyle,
+    label?: string,
 };
 
 type ConstantStyle = 'Address' | 'UnsignedDecimal' | 'Unknown';
@@ -69,5 +71,18 @@ interface RegisterSetArgument {
 };
 
 interface Registers {
+    /** Every physical register, including the banked ones. */
     regs: number[],
+    mode: string | null,
+    flags: { n: boolean, z: boolean, c: boolean, v: boolean, i: boolean, f: boolean, t: boolean },
+}
+
+type StopReason = { reason: 'Halted' } | { reason: 'Breakpoint' } | { reason: 'Error', message: string };
+
+interface StepResult {
+    executed: number,
+    stop: StopReason | null,
+    registers: { regs: number[], mode: string | null },
+    /** The user input that is left, if the program read any of it. */
+    user_input: string | null,
 }